@@ -3,6 +3,8 @@ use clap::{
     Parser,
 };
 
+use crate::steal::StealBackend;
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct Args {
@@ -22,13 +24,23 @@ pub struct Args {
     #[clap(short = 't', long, default_value_t = 30, value_parser)]
     pub communication_timeout: u16,
 
-    /// Interface to use
-    #[clap(short = 'i', long, default_value = "eth0", value_parser)]
-    pub interface: String,
+    /// Interfaces to sniff on, comma separated. By default `lo` when a service mesh sidecar is
+    /// detected (it talks plaintext to the target there), otherwise every interface carrying the
+    /// pod's addresses.
+    #[clap(short = 'i', long = "interface", value_parser, value_delimiter = ',')]
+    pub interfaces: Vec<String>,
 
     /// Inform the agent to use `proc/1/root` as the root directory.
     #[clap(short = 'e', long, default_value_t = false, value_parser)]
     pub ephemeral_container: bool,
+
+    /// Firewall used to steal traffic (iptables/nftables), detected from the target when not set
+    #[clap(long, value_parser)]
+    pub steal_backend: Option<StealBackend>,
+
+    /// Only remove the steal rules mirrord left in the target (live agents' included), then exit
+    #[clap(long, default_value_t = false, value_parser)]
+    pub cleanup: bool,
 }
 
 const DEFAULT_RUNTIME: &str = "containerd";
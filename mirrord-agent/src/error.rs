@@ -4,12 +4,12 @@ use mirrord_protocol::{
         udp::{DaemonUdpOutgoing, LayerUdpOutgoing},
         LayerConnect,
     },
-    tcp::{DaemonTcp, LayerTcpSteal},
-    FileRequest, FileResponse, Port,
+    tcp::DaemonTcp,
+    FileRequest, FileResponse,
 };
 use thiserror::Error;
 
-use crate::sniffer::SnifferCommand;
+use crate::{sniffer::SnifferCommand, steal::StealerCommand};
 
 #[derive(Debug, Error)]
 pub enum AgentError {
@@ -76,15 +76,15 @@ pub enum AgentError {
     #[error("Bollard failed with `{0}`")]
     Bollard(#[from] bollard::errors::Error),
 
-    #[error("Connection received from unexepcted port `{0}`")]
-    UnexpectedConnection(Port),
-
-    #[error("LayerTcpSteal sender failed with `{0}`")]
-    SendLayerTcpSteal(#[from] tokio::sync::mpsc::error::SendError<LayerTcpSteal>),
+    #[error("StealerCommand sender failed with `{0}`")]
+    SendStealerCommand(#[from] tokio::sync::mpsc::error::SendError<StealerCommand>),
 
     #[error("IPTables failed with `{0}`")]
     IPTablesError(String),
 
+    #[error("nftables failed with `{0}`")]
+    NfTablesError(String),
+
     #[error("Join task failed")]
     JoinTask,
 
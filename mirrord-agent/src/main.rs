@@ -18,8 +18,8 @@ use futures::{
     SinkExt,
 };
 use mirrord_protocol::{
-    tcp::{DaemonTcp, LayerTcp, LayerTcpSteal},
-    ClientMessage, DaemonCodec, DaemonMessage, GetEnvVarsRequest, RemoteResult,
+    tcp::LayerTcp, udp::LayerUdp, ClientMessage, DaemonCodec, DaemonMessage, GetEnvVarsRequest,
+    RemoteResult,
 };
 use outgoing::{udp::UdpOutgoingApi, TcpOutgoingApi};
 use sniffer::{SnifferCommand, TCPConnectionSniffer, TCPSnifferAPI};
@@ -27,7 +27,8 @@ use tokio::{
     io::AsyncReadExt,
     net::{TcpListener, TcpStream},
     select,
-    sync::mpsc::{self, Receiver, Sender},
+    signal::unix::{signal, SignalKind},
+    sync::mpsc::{self, Sender},
 };
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, trace};
@@ -35,8 +36,8 @@ use tracing_subscriber::{fmt::format::FmtSpan, prelude::*};
 
 use crate::{
     runtime::get_container_pid,
-    steal::steal_worker,
-    util::{run_thread, ClientID, IndexAllocator},
+    steal::{clean_stale_rules, steal_worker, StealerCommand, TcpStealerAPI},
+    util::{join_thread, run_thread, ClientID, IndexAllocator},
 };
 
 mod cli;
@@ -140,8 +141,7 @@ struct ClientConnectionHandler {
     stream: Framed<TcpStream, DaemonCodec>,
     pid: Option<u64>,
     tcp_sniffer_api: TCPSnifferAPI,
-    tcp_stealer_sender: Sender<LayerTcpSteal>,
-    tcp_stealer_receiver: Receiver<DaemonTcp>,
+    tcp_stealer_api: TcpStealerAPI,
     tcp_outgoing_api: TcpOutgoingApi,
     udp_outgoing_api: UdpOutgoingApi,
     dns_sender: Sender<DnsRequest>,
@@ -149,6 +149,7 @@ struct ClientConnectionHandler {
 
 impl ClientConnectionHandler {
     /// A loop that handles client connection and state. Breaks upon receiver/sender drop.
+    #[allow(clippy::too_many_arguments)]
     pub async fn start(
         id: ClientID,
         stream: TcpStream,
@@ -157,6 +158,7 @@ impl ClientConnectionHandler {
         sniffer_command_sender: Sender<SnifferCommand>,
         cancel_token: CancellationToken,
         dns_sender: Sender<DnsRequest>,
+        stealer_command_sender: Sender<StealerCommand>,
     ) -> Result<(), AgentError> {
         let file_manager = match pid {
             Some(_) => FileManager::new(pid),
@@ -166,18 +168,24 @@ impl ClientConnectionHandler {
         let stream = actix_codec::Framed::new(stream, DaemonCodec::new());
 
         let (tcp_sender, tcp_receiver) = mpsc::channel(CHANNEL_SIZE);
-        let tcp_sniffer_api =
-            TCPSnifferAPI::new(id, sniffer_command_sender, tcp_receiver, tcp_sender).await?;
-        let (tcp_steal_layer_sender, tcp_steal_layer_receiver) = mpsc::channel(CHANNEL_SIZE);
-        let (tcp_steal_daemon_sender, tcp_steal_daemon_receiver) = mpsc::channel(CHANNEL_SIZE);
-
-        let _ = run_thread(async move {
-            if let Err(err) =
-                steal_worker(tcp_steal_layer_receiver, tcp_steal_daemon_sender, pid).await
-            {
-                error!("steal_worker error {:?}", err)
-            }
-        });
+        let (udp_sender, udp_receiver) = mpsc::channel(CHANNEL_SIZE);
+        let tcp_sniffer_api = TCPSnifferAPI::new(
+            id,
+            sniffer_command_sender,
+            tcp_receiver,
+            tcp_sender,
+            udp_receiver,
+            udp_sender,
+        )
+        .await?;
+        let (tcp_steal_sender, tcp_steal_receiver) = mpsc::channel(CHANNEL_SIZE);
+        let tcp_stealer_api = TcpStealerAPI::new(
+            id,
+            stealer_command_sender,
+            tcp_steal_receiver,
+            tcp_steal_sender,
+        )
+        .await?;
 
         let tcp_outgoing_api = TcpOutgoingApi::new(pid);
         let udp_outgoing_api = UdpOutgoingApi::new(pid);
@@ -188,8 +196,7 @@ impl ClientConnectionHandler {
             stream,
             pid,
             tcp_sniffer_api,
-            tcp_stealer_receiver: tcp_steal_daemon_receiver,
-            tcp_stealer_sender: tcp_steal_layer_sender,
+            tcp_stealer_api,
             tcp_outgoing_api,
             udp_outgoing_api,
             dns_sender,
@@ -225,7 +232,15 @@ impl ClientConnectionHandler {
                         break;
                     }
                 },
-                message = self.tcp_stealer_receiver.recv() => {
+                message = self.tcp_sniffer_api.recv_udp() => {
+                    if let Some(message) = message {
+                        self.respond(DaemonMessage::Udp(message)).await?;
+                    } else {
+                        error!("udp sniffer stopped?");
+                        break;
+                    }
+                },
+                message = self.tcp_stealer_api.recv() => {
                     if let Some(message) = message {
                         self.stream.send(DaemonMessage::TcpSteal(message)).await?;
                     } else {
@@ -298,7 +313,10 @@ impl ClientConnectionHandler {
             }
             ClientMessage::Ping => self.respond(DaemonMessage::Pong).await?,
             ClientMessage::Tcp(message) => self.handle_client_tcp(message).await?,
-            ClientMessage::TcpSteal(message) => self.tcp_stealer_sender.send(message).await?,
+            ClientMessage::Udp(message) => self.handle_client_udp(message).await?,
+            ClientMessage::TcpSteal(message) => {
+                self.tcp_stealer_api.handle_client_message(message).await?
+            }
             ClientMessage::Close => {
                 return Ok(false);
             }
@@ -308,13 +326,25 @@ impl ClientConnectionHandler {
 
     async fn handle_client_tcp(&mut self, message: LayerTcp) -> Result<(), AgentError> {
         match message {
-            LayerTcp::PortSubscribe(port) => self.tcp_sniffer_api.subscribe(port).await,
+            LayerTcp::PortSubscribe(port, sources) => {
+                self.tcp_sniffer_api.subscribe(port, sources).await
+            }
             LayerTcp::ConnectionUnsubscribe(connection_id) => {
                 self.tcp_sniffer_api
                     .connection_unsubscribe(connection_id)
                     .await
             }
             LayerTcp::PortUnsubscribe(port) => self.tcp_sniffer_api.port_unsubscribe(port).await,
+            LayerTcp::Session(session) => self.tcp_sniffer_api.session(session).await,
+        }
+    }
+
+    async fn handle_client_udp(&mut self, message: LayerUdp) -> Result<(), AgentError> {
+        match message {
+            LayerUdp::PortSubscribe(port) => self.tcp_sniffer_api.udp_subscribe(port).await,
+            LayerUdp::PortUnsubscribe(port) => {
+                self.tcp_sniffer_api.udp_port_unsubscribe(port).await
+            }
         }
     }
 }
@@ -337,6 +367,23 @@ async fn start_agent() -> Result<(), AgentError> {
         _ => None,
     };
 
+    // Rules of agents that crashed before cleaning up would keep redirecting traffic nowhere.
+    let cleanup = args.cleanup;
+    if join_thread(std::thread::spawn(move || clean_stale_rules(pid, cleanup)))
+        .await?
+        .is_err()
+    {
+        error!("start_agent -> failed sweeping stale steal rules");
+    }
+
+    if args.cleanup {
+        info!("cleanup done");
+        return Ok(());
+    }
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
     let mut state = State::new();
     let cancellation_token = CancellationToken::new();
     // Cancel all other tasks on exit
@@ -347,7 +394,14 @@ async fn start_agent() -> Result<(), AgentError> {
     let sniffer_task = run_thread(TCPConnectionSniffer::start(
         sniffer_command_rx,
         pid,
-        args.interface,
+        args.interfaces,
+        cancellation_token.clone(),
+    ));
+    let (stealer_command_tx, stealer_command_rx) = mpsc::channel::<StealerCommand>(1000);
+    let steal_task = run_thread(steal_worker(
+        stealer_command_rx,
+        pid,
+        args.steal_backend,
         cancellation_token.clone(),
     ));
 
@@ -364,8 +418,9 @@ async fn start_agent() -> Result<(), AgentError> {
                     let sniffer_command_tx = sniffer_command_tx.clone();
                     let cancellation_token = cancellation_token.clone();
                     let dns_sender = dns_sender.clone();
+                    let stealer_command_tx = stealer_command_tx.clone();
                     let client = tokio::spawn(async move {
-                        match ClientConnectionHandler::start(client_id, stream, pid, args.ephemeral_container, sniffer_command_tx, cancellation_token, dns_sender).await {
+                        match ClientConnectionHandler::start(client_id, stream, pid, args.ephemeral_container, sniffer_command_tx, cancellation_token, dns_sender, stealer_command_tx).await {
                             Ok(_) => {
                                 debug!("ClientConnectionHandler::start -> Client {} disconnected", client_id);
                             }
@@ -391,13 +446,40 @@ async fn start_agent() -> Result<(), AgentError> {
                     debug!("start_agent -> main thread timeout, no clients connected");
                     break;
                 }
+            },
+            _ = terminate.recv() => {
+                info!("start_agent -> received SIGTERM, shutting down");
+                break;
+            },
+            _ = interrupt.recv() => {
+                info!("start_agent -> received SIGINT, shutting down");
+                break;
             }
         }
     }
 
     debug!("start_agent -> shutting down start");
     drop(cancel_guard);
-    if let Err(err) = sniffer_task.join().map_err(|_| AgentError::JoinTask)? {
+
+    while let Some(client) = clients.next().await {
+        if let Err(err) = client {
+            error!("start_agent -> client task failed with error: {}", err);
+        }
+    }
+
+    // The stealer removes its rules on its way out.
+    if let Err(err) = join_thread(steal_task).await? {
+        error!("start_agent -> steal task failed with error: {}", err);
+    }
+
+    // Whatever a client couldn't remove (e.g. it panicked) goes here.
+    if join_thread(std::thread::spawn(move || clean_stale_rules(pid, false)))
+        .await?
+        .is_err()
+    {
+        error!("start_agent -> failed sweeping stale steal rules");
+    }
+    if let Err(err) = join_thread(sniffer_task).await? {
         error!("start_agent -> sniffer task failed with error: {}", err);
     }
 
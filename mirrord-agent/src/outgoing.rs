@@ -1,4 +1,4 @@
-use std::{collections::HashMap, path::PathBuf, thread, time::Duration};
+use std::{collections::HashMap, io::ErrorKind, path::PathBuf, thread, time::Duration};
 
 use mirrord_protocol::{
     outgoing::{tcp::*, *},
@@ -118,6 +118,30 @@ async fn layer_recv(
             writers.remove(connection_id);
             readers.remove(connection_id);
         }
+        // [user] -> [layer] -> [agent] -> [remote]
+        // `user` is done writing, the remote host may still answer.
+        LayerTcpOutgoing::Shutdown(LayerClose { connection_id }) => {
+            if let Some(mut writer) = writers.remove(&connection_id) {
+                if let Err(fail) = writer.shutdown().await {
+                    warn!("LayerTcpOutgoing::Shutdown -> Failed with {:#?}", fail);
+                }
+            }
+        }
+        // [user] -> [layer] -> [agent] -> [remote]
+        // `user` reset the connection, so do we.
+        LayerTcpOutgoing::Reset(LayerClose { connection_id }) => {
+            // Without a writer (`user` shut down first) it's just closed.
+            if let Some(writer) = writers.remove(&connection_id) {
+                if let Err(fail) = writer.as_ref().set_linger(Some(Duration::ZERO)) {
+                    warn!("LayerTcpOutgoing::Reset -> Failed with {:#?}", fail);
+                }
+
+                // Dropping the writer would shut the socket down, sending a FIN before the RST.
+                writer.forget();
+            }
+
+            readers.remove(&connection_id);
+        }
     }
 
     Ok(())
@@ -176,6 +200,13 @@ impl TcpOutgoingApi {
                     trace!("interceptor_task -> read connection_id {:#?}", connection_id);
 
                     match remote_read {
+                        Some(Err(fail)) if fail.kind() == ErrorKind::ConnectionReset => {
+                            trace!("interceptor_task -> reset connection {:#?}", connection_id);
+                            writers.remove(&connection_id);
+
+                            let daemon_message = DaemonTcpOutgoing::Reset(connection_id);
+                            daemon_tx.send(daemon_message).await?
+                        }
                         Some(read) => {
                             let daemon_read = read
                                 .map_err(ResponseError::from)
@@ -184,9 +215,15 @@ impl TcpOutgoingApi {
                             let daemon_message = DaemonTcpOutgoing::Read(daemon_read);
                             daemon_tx.send(daemon_message).await?
                         }
+                        // The `user` may still be writing, unless it already shut down its side.
+                        None if writers.contains_key(&connection_id) => {
+                            trace!("interceptor_task -> shutdown connection {:#?}", connection_id);
+
+                            let daemon_message = DaemonTcpOutgoing::Shutdown(connection_id);
+                            daemon_tx.send(daemon_message).await?
+                        }
                         None => {
                             trace!("interceptor_task -> close connection {:#?}", connection_id);
-                            writers.remove(&connection_id);
 
                             let daemon_message = DaemonTcpOutgoing::Close(connection_id);
                             daemon_tx.send(daemon_message).await?
@@ -221,3 +258,51 @@ impl TcpOutgoingApi {
             .ok_or(AgentError::ReceiverClosed)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use super::*;
+
+    /// The remote host gets a RST when the `user` resets, without a FIN before it.
+    #[tokio::test]
+    async fn reset_without_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote_address = listener.local_addr().unwrap();
+
+        let mut allocator = IndexAllocator::new();
+        let mut writers = HashMap::new();
+        let mut readers = StreamMap::default();
+        let (daemon_tx, mut daemon_rx) = mpsc::channel(8);
+
+        let connect = LayerTcpOutgoing::Connect(LayerConnect { remote_address });
+        layer_recv(
+            connect,
+            &mut allocator,
+            &mut writers,
+            &mut readers,
+            daemon_tx.clone(),
+        )
+        .await
+        .unwrap();
+        let (mut remote, _) = listener.accept().await.unwrap();
+        let connection_id = match daemon_rx.recv().await {
+            Some(DaemonTcpOutgoing::Connect(Ok(DaemonConnect { connection_id, .. }))) => {
+                connection_id
+            }
+            other => panic!("unexpected {other:?}"),
+        };
+
+        let reset = LayerTcpOutgoing::Reset(LayerClose { connection_id });
+        layer_recv(reset, &mut allocator, &mut writers, &mut readers, daemon_tx)
+            .await
+            .unwrap();
+
+        let mut buffer = [0; 8];
+        assert_eq!(
+            remote.read(&mut buffer).await.unwrap_err().kind(),
+            ErrorKind::ConnectionReset
+        );
+    }
+}
@@ -1,43 +1,60 @@
 use std::{
     collections::{HashMap, HashSet},
     hash::{Hash, Hasher},
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, SocketAddr},
     path::PathBuf,
+    time::{Duration, Instant},
 };
 
-use futures::StreamExt;
+use futures::{stream::SelectAll, StreamExt};
 use mirrord_protocol::{
-    tcp::{DaemonTcp, NewTcpConnection, TcpClose, TcpData},
+    cidr::Cidr,
+    tcp::{
+        DaemonTcp, MirrorLimits, MirrorSession, NewTcpConnection, SniffedFrame, TcpClose, TcpData,
+    },
+    udp::{DaemonUdp, UdpDatagram},
     ConnectionId, Port,
 };
 use pcap::{Active, Capture, Device, Linktype, PacketCodec, PacketStream};
 use pnet::packet::{
-    ethernet::{EtherTypes, EthernetPacket},
-    ip::IpNextHeaderProtocols,
+    ethernet::EtherTypes,
+    ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
     ipv4::Ipv4Packet,
+    ipv6::Ipv6Packet,
     tcp::{TcpFlags, TcpPacket},
+    udp::UdpPacket,
     Packet,
 };
+use rand::Rng;
 use tokio::{
     select,
     sync::mpsc::{Receiver, Sender},
 };
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, trace, warn};
+use tracing::{debug, error, info, trace, warn};
 
+use self::{
+    link::LinkType,
+    mesh::{MeshVendor, LOOPBACK_INTERFACE},
+    reassembly::TcpReassembler,
+};
 use crate::{
     error::AgentError,
     runtime::set_namespace,
     util::{ClientID, IndexAllocator, Subscriptions},
 };
 
+mod link;
+mod mesh;
+mod reassembly;
+
 const DUMMY_BPF: &str =
     "tcp dst port 1 and tcp src port 1 and dst host 8.1.2.3 and src host 8.1.2.3";
 
 #[derive(Debug, Eq, Copy, Clone)]
 pub struct TcpSessionIdentifier {
-    source_addr: Ipv4Addr,
-    dest_addr: Ipv4Addr,
+    source_addr: IpAddr,
+    dest_addr: IpAddr,
     source_port: u16,
     dest_port: u16,
 }
@@ -79,107 +96,373 @@ impl Hash for TcpSessionIdentifier {
 struct TCPSession {
     id: ConnectionId,
     clients: HashSet<ClientID>,
+    /// Reassembles the bytes sent by the connecting peer, the ones we forward to the clients.
+    client_stream: TcpReassembler,
+    /// Reassembles what the remote service answers, only when a client of the session asked for
+    /// it with [`MirrorSession::shadow_compare`].
+    server_stream: Option<TcpReassembler>,
 }
 
 type TCPSessionMap = HashMap<TcpSessionIdentifier, TCPSession>;
 
+/// What's left of a client's [`MirrorLimits`], new connections are only mirrored to the client
+/// while they fit.
+#[derive(Debug)]
+struct MirrorBudget {
+    limits: MirrorLimits,
+    /// Connections currently mirrored to the client.
+    connections: u32,
+    /// Bytes mirrored to the client since `window_start`.
+    window_bytes: u64,
+    window_start: Instant,
+}
+
+impl MirrorBudget {
+    fn new(limits: MirrorLimits, now: Instant) -> Self {
+        MirrorBudget {
+            limits,
+            connections: 0,
+            window_bytes: 0,
+            window_start: now,
+        }
+    }
+
+    /// Whether a new connection gets mirrored, counting it when it does. `roll` is a random
+    /// number in `0..100`, used for sampling.
+    fn admit(&mut self, now: Instant, roll: u8) -> bool {
+        self.refresh(now);
+
+        let sampled = self
+            .limits
+            .sample_percent
+            .map_or(true, |percent| roll < percent);
+        let below_max = self
+            .limits
+            .max_connections
+            .map_or(true, |max| self.connections < max);
+        let within_budget = self
+            .limits
+            .bytes_per_second
+            .map_or(true, |budget| self.window_bytes < budget);
+
+        let admitted = sampled && below_max && within_budget;
+        if admitted {
+            self.connections += 1;
+        }
+        admitted
+    }
+
+    fn record(&mut self, now: Instant, bytes: usize) {
+        self.refresh(now);
+        self.window_bytes += bytes as u64;
+    }
+
+    /// A mirrored connection closed.
+    fn release(&mut self) {
+        self.connections = self.connections.saturating_sub(1);
+    }
+
+    fn refresh(&mut self, now: Instant) {
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+    }
+}
+
 fn is_new_connection(flags: u16) -> bool {
     0 != (flags & TcpFlags::SYN) && 0 == (flags & (TcpFlags::ACK | TcpFlags::RST | TcpFlags::FIN))
 }
 
-fn is_closed_connection(flags: u16) -> bool {
-    0 != (flags & (TcpFlags::FIN | TcpFlags::RST))
+/// The remote service accepting a connection, its side of the stream starts here.
+fn is_syn_ack(flags: u16) -> bool {
+    0 != (flags & TcpFlags::SYN) && 0 != (flags & TcpFlags::ACK)
+}
+
+fn is_fin(flags: u16) -> bool {
+    0 != (flags & TcpFlags::FIN)
+}
+
+fn is_reset(flags: u16) -> bool {
+    0 != (flags & TcpFlags::RST)
 }
 
+/// Turns the captured packets into Ethernet frames, `None` for the ones we can't parse.
 #[derive(Debug, Clone)]
-pub struct TcpManagerCodec;
+pub struct TcpManagerCodec {
+    link_type: LinkType,
+}
 
 impl PacketCodec for TcpManagerCodec {
-    type Item = Vec<u8>;
+    type Item = Option<SniffedFrame>;
 
     fn decode(&mut self, packet: pcap::Packet) -> Self::Item {
-        packet.data.to_vec()
+        let timestamp = packet.header.ts;
+
+        Some(SniffedFrame {
+            timestamp_us: timestamp.tv_sec as u64 * 1_000_000 + timestamp.tv_usec as u64,
+            bytes: self.link_type.to_ethernet(packet.data)?,
+        })
+    }
+}
+
+/// Interfaces that carry one of the pod's addresses, anything that's not loopback or link-local.
+///
+/// Usually that's just `eth0`, but CNIs name it differently and multi-homed pods (Multus) have
+/// several.
+fn routable_interfaces(devices: impl IntoIterator<Item = (String, Vec<IpAddr>)>) -> Vec<String> {
+    let routable = |address: &IpAddr| match address {
+        IpAddr::V4(address) => !address.is_loopback() && !address.is_link_local(),
+        // `fe80::/10`
+        IpAddr::V6(address) => !address.is_loopback() && (address.segments()[0] & 0xffc0) != 0xfe80,
+    };
+
+    devices
+        .into_iter()
+        .filter(|(_, addresses)| addresses.iter().any(routable))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Interfaces to sniff on: the ones given, the mesh sidecar's loopback hop, or the ones carrying
+/// the pod's addresses, in that order.
+fn select_interfaces(
+    interfaces: Vec<String>,
+    mesh: Option<MeshVendor>,
+) -> Result<Vec<String>, AgentError> {
+    if !interfaces.is_empty() {
+        return Ok(interfaces);
+    }
+
+    if let Some(mesh) = mesh {
+        info!("{mesh} sidecar detected, sniffing on {LOOPBACK_INTERFACE}");
+        return Ok(vec![LOOPBACK_INTERFACE.to_string()]);
+    }
+
+    let interfaces = routable_interfaces(Device::list()?.into_iter().map(|device| {
+        let addresses = device
+            .addresses
+            .iter()
+            .map(|address| address.addr)
+            .collect();
+
+        (device.name, addresses)
+    }));
+
+    if interfaces.is_empty() {
+        Err(AgentError::NotFound(
+            "No interface carries the pod's address!".to_string(),
+        ))
+    } else {
+        info!("sniffing on {interfaces:?}");
+        Ok(interfaces)
     }
 }
 
-fn prepare_sniffer(interface: String) -> Result<Capture<Active>, AgentError> {
+/// Opens a capture on `interface`, with the link type its packets come in.
+fn prepare_sniffer(interface: String) -> Result<(Capture<Active>, LinkType), AgentError> {
     debug!("prepare_sniffer -> Preparing interface.");
 
     let interface_names_match = |iface: &Device| iface.name == interface;
     let interfaces = Device::list()?;
 
-    let interface = interfaces
+    let device = interfaces
         .into_iter()
         .find(interface_names_match)
-        .ok_or_else(|| AgentError::NotFound("Interface not found!".to_string()))?;
+        .ok_or_else(|| AgentError::NotFound(format!("Interface {interface} not found!")))?;
 
-    let mut capture = Capture::from_device(interface)?
-        .immediate_mode(true)
-        .open()?;
+    let mut capture = Capture::from_device(device)?.immediate_mode(true).open()?;
+
+    // Ethernet when the interface offers it, otherwise whatever it has (SLL for `any`, raw IP for
+    // tun devices...).
+    if capture.list_datalinks()?.contains(&Linktype::ETHERNET) {
+        capture.set_datalink(Linktype::ETHERNET)?;
+    }
+    let datalink = capture.get_datalink();
+    let link_type = LinkType::from_datalink(datalink.0).ok_or_else(|| {
+        AgentError::NotFound(format!(
+            "Interface {interface} has unsupported link type {}!",
+            datalink.0
+        ))
+    })?;
+    debug!("prepare_sniffer -> {interface} link type {link_type:?}");
 
-    capture.set_datalink(Linktype::ETHERNET)?;
     // Set a dummy filter that shouldn't capture anything. This makes the code easier.
     capture.filter(DUMMY_BPF, true)?;
     capture = capture.setnonblock()?;
 
-    Ok(capture)
+    Ok((capture, link_type))
 }
 
 #[derive(Debug)]
 struct TcpPacketData {
     bytes: Vec<u8>,
     flags: u16,
+    sequence: u32,
 }
 
-fn get_tcp_packet(eth_packet: Vec<u8>) -> Option<(TcpSessionIdentifier, TcpPacketData)> {
-    let eth_packet = EthernetPacket::new(&eth_packet[..])?;
-    debug!("get_tcp_packet_start");
-    let ip_packet = match eth_packet.get_ethertype() {
-        EtherTypes::Ipv4 => Ipv4Packet::new(eth_packet.payload())?,
-        _ => return None,
-    };
-    debug!("ip_packet");
-    let tcp_packet = match ip_packet.get_next_level_protocol() {
-        IpNextHeaderProtocols::Tcp => TcpPacket::new(ip_packet.payload())?,
-        _ => return None,
-    };
+/// Walks the IPv6 extension headers starting at `next_header`, returning the protocol and
+/// payload of the upper layer.
+///
+/// Returns `None` for non first fragments (they don't carry the transport header) and for headers
+/// we can't see through (ESP, No Next Header).
+fn ipv6_upper_layer(
+    mut next_header: IpNextHeaderProtocol,
+    mut payload: &[u8],
+) -> Option<(IpNextHeaderProtocol, &[u8])> {
+    loop {
+        let header_length = match next_header {
+            IpNextHeaderProtocols::Hopopt
+            | IpNextHeaderProtocols::Ipv6Route
+            | IpNextHeaderProtocols::Ipv6Opts => (usize::from(*payload.get(1)?) + 1) * 8,
+            IpNextHeaderProtocols::Ipv6Frag => {
+                let fragment_offset = u16::from_be_bytes([*payload.get(2)?, *payload.get(3)?]) >> 3;
+                if fragment_offset != 0 {
+                    return None;
+                }
+                8
+            }
+            IpNextHeaderProtocols::Ah => (usize::from(*payload.get(1)?) + 2) * 4,
+            IpNextHeaderProtocols::Esp | IpNextHeaderProtocols::Ipv6NoNxt => return None,
+            protocol => return Some((protocol, payload)),
+        };
 
-    let dest_port = tcp_packet.get_destination();
-    let source_port = tcp_packet.get_source();
+        next_header = IpNextHeaderProtocol::new(*payload.first()?);
+        payload = payload.get(header_length..)?;
+    }
+}
 
-    let identifier = TcpSessionIdentifier {
-        source_addr: ip_packet.get_source(),
-        dest_addr: ip_packet.get_destination(),
-        source_port,
-        dest_port,
-    };
-    debug!("identifier {identifier:?}");
-    Some((
-        identifier,
-        TcpPacketData {
-            flags: tcp_packet.get_flags(),
-            bytes: tcp_packet.payload().to_vec(),
-        },
-    ))
+/// Source, destination, upper layer protocol and payload of the IP packet inside `eth_packet`.
+fn get_ip_packet(eth_packet: &[u8]) -> Option<(IpAddr, IpAddr, IpNextHeaderProtocol, Vec<u8>)> {
+    let (ethertype, payload) = LinkType::Ethernet.network_layer(eth_packet)?;
+    trace!("get_ip_packet -> ethertype {ethertype}");
+
+    match ethertype {
+        EtherTypes::Ipv4 => {
+            let ip_packet = Ipv4Packet::new(payload)?;
+
+            Some((
+                IpAddr::V4(ip_packet.get_source()),
+                IpAddr::V4(ip_packet.get_destination()),
+                ip_packet.get_next_level_protocol(),
+                ip_packet.payload().to_vec(),
+            ))
+        }
+        EtherTypes::Ipv6 => {
+            let ip_packet = Ipv6Packet::new(payload)?;
+            let (protocol, payload) =
+                ipv6_upper_layer(ip_packet.get_next_header(), ip_packet.payload())?;
+
+            Some((
+                IpAddr::V6(ip_packet.get_source()),
+                IpAddr::V6(ip_packet.get_destination()),
+                protocol,
+                payload.to_vec(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// The parts of a captured packet the sniffer cares about.
+#[derive(Debug)]
+enum CapturedPacket {
+    Tcp(TcpSessionIdentifier, TcpPacketData),
+    Udp(UdpDatagram),
 }
-/// Build a filter of format: "tcp port (80 or 443 or 50 or 90)"
-fn format_bpf(ports: &[u16]) -> String {
-    format!(
-        "tcp port ({})",
+
+fn get_packet(eth_packet: &[u8]) -> Option<CapturedPacket> {
+    let (source_addr, dest_addr, protocol, payload) = get_ip_packet(eth_packet)?;
+
+    match protocol {
+        IpNextHeaderProtocols::Tcp => {
+            let tcp_packet = TcpPacket::new(&payload)?;
+
+            let identifier = TcpSessionIdentifier {
+                source_addr,
+                dest_addr,
+                source_port: tcp_packet.get_source(),
+                dest_port: tcp_packet.get_destination(),
+            };
+            trace!("get_packet -> identifier {identifier:?}");
+
+            Some(CapturedPacket::Tcp(
+                identifier,
+                TcpPacketData {
+                    flags: tcp_packet.get_flags(),
+                    sequence: tcp_packet.get_sequence(),
+                    bytes: tcp_packet.payload().to_vec(),
+                },
+            ))
+        }
+        IpNextHeaderProtocols::Udp => {
+            let udp_packet = UdpPacket::new(&payload)?;
+
+            Some(CapturedPacket::Udp(UdpDatagram {
+                source: SocketAddr::new(source_addr, udp_packet.get_source()),
+                destination_port: udp_packet.get_destination(),
+                bytes: udp_packet.payload().to_vec(),
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Build a filter of format: "tcp port (80 or 443) or (tcp port 8080 and (net 10.0.0.0/8)) or
+/// (ip6 protochain 6 and not ip6 proto 6) or udp dst port (8125) or (ip6 protochain 17 and not
+/// ip6 proto 17)".
+///
+/// `filtered_tcp_ports` are only captured from/to the given sources (`net` keeps the responses
+/// too).
+///
+/// BPF `tcp`/`udp` don't look past IPv6 extension headers, so those packets are captured
+/// regardless of port and filtered in [`TCPConnectionSniffer::handle_packet`].
+fn format_bpf(
+    tcp_ports: &[u16],
+    filtered_tcp_ports: &[(u16, Vec<Cidr>)],
+    udp_ports: &[u16],
+) -> String {
+    let join = |ports: &[u16]| {
         ports
             .iter()
             .map(|p| p.to_string())
             .collect::<Vec<String>>()
             .join(" or ")
-    )
+    };
+
+    let mut filters = Vec::with_capacity(4 + filtered_tcp_ports.len());
+    if !tcp_ports.is_empty() {
+        filters.push(format!("tcp port ({})", join(tcp_ports)));
+    }
+    for (port, sources) in filtered_tcp_ports {
+        let sources = sources
+            .iter()
+            .map(|source| format!("net {source}"))
+            .collect::<Vec<String>>()
+            .join(" or ");
+        filters.push(format!("(tcp port {port} and ({sources}))"));
+    }
+    if !tcp_ports.is_empty() || !filtered_tcp_ports.is_empty() {
+        filters.push("(ip6 protochain 6 and not ip6 proto 6)".to_string());
+    }
+    if !udp_ports.is_empty() {
+        filters.push(format!("udp dst port ({})", join(udp_ports)));
+        filters.push("(ip6 protochain 17 and not ip6 proto 17)".to_string());
+    }
+
+    filters.join(" or ")
 }
 
 #[derive(Debug)]
 enum SnifferCommands {
-    NewAgent(Sender<DaemonTcp>),
-    Subscribe(Port),
+    NewAgent(Sender<DaemonTcp>, Sender<DaemonUdp>),
+    /// Mirrors the port, only from the given sources when it isn't empty.
+    Subscribe(Port, Vec<Cidr>),
     UnsubscribePort(Port),
+    SubscribeUdp(Port),
+    UnsubscribeUdpPort(Port),
     UnsubscribeConnection(ConnectionId),
+    Session(MirrorSession),
     AgentClosed,
 }
 
@@ -193,6 +476,7 @@ pub struct TCPSnifferAPI {
     client_id: ClientID,
     sender: Sender<SnifferCommand>,
     pub receiver: Receiver<DaemonTcp>,
+    udp_receiver: Receiver<DaemonUdp>,
 }
 
 impl TCPSnifferAPI {
@@ -201,25 +485,28 @@ impl TCPSnifferAPI {
         sniffer_sender: Sender<SnifferCommand>,
         receiver: Receiver<DaemonTcp>,
         tcp_sender: Sender<DaemonTcp>,
+        udp_receiver: Receiver<DaemonUdp>,
+        udp_sender: Sender<DaemonUdp>,
     ) -> Result<TCPSnifferAPI, AgentError> {
         sniffer_sender
             .send(SnifferCommand {
                 client_id,
-                command: SnifferCommands::NewAgent(tcp_sender),
+                command: SnifferCommands::NewAgent(tcp_sender, udp_sender),
             })
             .await?;
         Ok(Self {
             client_id,
             sender: sniffer_sender,
             receiver,
+            udp_receiver,
         })
     }
 
-    pub async fn subscribe(&mut self, port: Port) -> Result<(), AgentError> {
+    pub async fn subscribe(&mut self, port: Port, sources: Vec<Cidr>) -> Result<(), AgentError> {
         self.sender
             .send(SnifferCommand {
                 client_id: self.client_id,
-                command: SnifferCommands::Subscribe(port),
+                command: SnifferCommands::Subscribe(port, sources),
             })
             .await
             .map_err(From::from)
@@ -248,9 +535,43 @@ impl TCPSnifferAPI {
             .map_err(From::from)
     }
 
+    pub async fn udp_subscribe(&mut self, port: Port) -> Result<(), AgentError> {
+        self.sender
+            .send(SnifferCommand {
+                client_id: self.client_id,
+                command: SnifferCommands::SubscribeUdp(port),
+            })
+            .await
+            .map_err(From::from)
+    }
+
+    pub async fn udp_port_unsubscribe(&mut self, port: Port) -> Result<(), AgentError> {
+        self.sender
+            .send(SnifferCommand {
+                client_id: self.client_id,
+                command: SnifferCommands::UnsubscribeUdpPort(port),
+            })
+            .await
+            .map_err(From::from)
+    }
+
+    pub async fn session(&mut self, session: MirrorSession) -> Result<(), AgentError> {
+        self.sender
+            .send(SnifferCommand {
+                client_id: self.client_id,
+                command: SnifferCommands::Session(session),
+            })
+            .await
+            .map_err(From::from)
+    }
+
     pub async fn recv(&mut self) -> Option<DaemonTcp> {
         self.receiver.recv().await
     }
+
+    pub async fn recv_udp(&mut self) -> Option<DaemonUdp> {
+        self.udp_receiver.recv().await
+    }
 }
 
 impl Drop for TCPSnifferAPI {
@@ -265,14 +586,28 @@ impl Drop for TCPSnifferAPI {
 }
 
 pub struct TCPConnectionSniffer {
-    port_subscriptions: Subscriptions<Port, ClientID>,
+    /// Subscribed TCP ports, with the sources each client mirrors them from (every source when
+    /// empty).
+    port_subscriptions: Subscriptions<Port, ClientID, Vec<Cidr>>,
+    udp_port_subscriptions: Subscriptions<Port, ClientID>,
     receiver: Receiver<SnifferCommand>,
     client_senders: HashMap<ClientID, Sender<DaemonTcp>>,
-    stream: PacketStream<Active, TcpManagerCodec>,
+    udp_client_senders: HashMap<ClientID, Sender<DaemonUdp>>,
+    /// Clients that limited what gets mirrored to them.
+    mirror_budgets: HashMap<ClientID, MirrorBudget>,
+    /// Clients that get the raw frames of their ports, see [`MirrorSession::capture_frames`].
+    capturing: HashSet<ClientID>,
+    /// Clients that get the remote service's side of their connections too, see
+    /// [`MirrorSession::shadow_compare`].
+    shadowing: HashSet<ClientID>,
+    /// Packets of all the interfaces we sniff on, merged.
+    stream: SelectAll<PacketStream<Active, TcpManagerCodec>>,
     sessions: TCPSessionMap,
     //todo: impl drop for index allocator and connection id..
     connection_id_to_tcp_identifier: HashMap<ConnectionId, TcpSessionIdentifier>,
     index_allocator: IndexAllocator<ConnectionId>,
+    /// Mesh sidecar in front of the target, connections sniffed behind it come from the proxy.
+    mesh: Option<MeshVendor>,
 }
 
 impl TCPConnectionSniffer {
@@ -286,7 +621,9 @@ impl TCPConnectionSniffer {
                 },
                 packet = self.stream.next() => {
                     if let Some(packet) = packet {
-                        self.handle_packet(packet?).await?;
+                        if let Some(frame) = packet? {
+                            self.handle_packet(frame).await?;
+                        }
                     } else { break; }
                 }
                 _ = cancel_token.cancelled() => {
@@ -301,7 +638,7 @@ impl TCPConnectionSniffer {
     pub async fn new(
         receiver: Receiver<SnifferCommand>,
         pid: Option<u64>,
-        interface: String,
+        interfaces: Vec<String>,
     ) -> Result<TCPConnectionSniffer, AgentError> {
         if let Some(pid) = pid {
             let namespace = PathBuf::from("/proc")
@@ -311,66 +648,201 @@ impl TCPConnectionSniffer {
             set_namespace(namespace).unwrap();
         }
 
+        let mesh = MeshVendor::detect();
+
         debug!("preparing sniffer");
-        let sniffer = prepare_sniffer(interface)?;
-        let codec = TcpManagerCodec {};
-        let stream = sniffer.stream(codec)?;
-        Ok(TCPConnectionSniffer {
+        let stream = select_interfaces(interfaces, mesh)?
+            .into_iter()
+            .map(|interface| {
+                let (capture, link_type) = prepare_sniffer(interface)?;
+                Ok(capture.stream(TcpManagerCodec { link_type })?)
+            })
+            .collect::<Result<Vec<_>, AgentError>>()?;
+        Ok(Self::from_stream(
+            receiver,
+            futures::stream::select_all(stream),
+            mesh,
+        ))
+    }
+
+    fn from_stream(
+        receiver: Receiver<SnifferCommand>,
+        stream: SelectAll<PacketStream<Active, TcpManagerCodec>>,
+        mesh: Option<MeshVendor>,
+    ) -> Self {
+        TCPConnectionSniffer {
             receiver,
             stream,
             port_subscriptions: Subscriptions::new(),
+            udp_port_subscriptions: Subscriptions::new(),
             client_senders: HashMap::new(),
+            udp_client_senders: HashMap::new(),
+            mirror_budgets: HashMap::new(),
+            capturing: HashSet::new(),
+            shadowing: HashSet::new(),
             sessions: TCPSessionMap::new(),
             //todo: impl drop for index allocator and connection id..
             connection_id_to_tcp_identifier: HashMap::new(),
             index_allocator: IndexAllocator::new(),
-        })
+            mesh,
+        }
     }
 
     pub async fn start(
         receiver: Receiver<SnifferCommand>,
         pid: Option<u64>,
-        interface: String,
+        interfaces: Vec<String>,
         cancel_token: CancellationToken,
     ) -> Result<(), AgentError> {
-        let sniffer = Self::new(receiver, pid, interface).await?;
+        let sniffer = Self::new(receiver, pid, interfaces).await?;
         sniffer.run(cancel_token).await
     }
 
-    fn handle_new_client(&mut self, client_id: ClientID, sender: Sender<DaemonTcp>) {
+    fn handle_new_client(
+        &mut self,
+        client_id: ClientID,
+        sender: Sender<DaemonTcp>,
+        udp_sender: Sender<DaemonUdp>,
+    ) {
         self.client_senders.insert(client_id, sender);
+        self.udp_client_senders.insert(client_id, udp_sender);
     }
 
     async fn handle_subscribe(
         &mut self,
         client_id: ClientID,
         port: Port,
+        sources: Vec<Cidr>,
     ) -> Result<(), AgentError> {
-        self.port_subscriptions.subscribe(client_id, port);
+        if let Some(mesh) = self.mesh.filter(|_| !sources.is_empty()) {
+            warn!(
+                "source filter of {client_id} on port {port} is matched against the {mesh} \
+                 sidecar's address, sniffing behind a mesh"
+            );
+        }
+
+        self.port_subscriptions
+            .subscribe_with(client_id, port, sources);
         self.update_sniffer()?;
         self.send_message_to_client(&client_id, DaemonTcp::Subscribed)
             .await
     }
 
+    async fn handle_udp_subscribe(
+        &mut self,
+        client_id: ClientID,
+        port: Port,
+    ) -> Result<(), AgentError> {
+        self.udp_port_subscriptions.subscribe(client_id, port);
+        self.update_sniffer()?;
+
+        if let Some(sender) = self.udp_client_senders.get(&client_id) {
+            if sender.send(DaemonUdp::Subscribed).await.is_err() {
+                warn!("failed to send message to client {}", client_id);
+                self.handle_client_closed(client_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_session(
+        &mut self,
+        client_id: ClientID,
+        session: MirrorSession,
+    ) -> Result<(), AgentError> {
+        let MirrorSession {
+            limits,
+            capture_frames,
+            shadow_compare,
+        } = session;
+
+        match limits {
+            // Keeps counting the connections that are already mirrored.
+            Some(limits) => {
+                self.mirror_budgets
+                    .entry(client_id)
+                    .and_modify(|budget| budget.limits = limits)
+                    .or_insert_with(|| MirrorBudget::new(limits, Instant::now()));
+            }
+            None => {
+                self.mirror_budgets.remove(&client_id);
+            }
+        }
+
+        if capture_frames {
+            self.capturing.insert(client_id);
+        } else {
+            self.capturing.remove(&client_id);
+        }
+
+        if shadow_compare {
+            self.shadowing.insert(client_id);
+        } else {
+            self.shadowing.remove(&client_id);
+        }
+
+        self.update_sniffer()
+    }
+
     fn handle_client_closed(&mut self, client_id: ClientID) -> Result<(), AgentError> {
         self.client_senders.remove(&client_id);
+        self.udp_client_senders.remove(&client_id);
+        self.mirror_budgets.remove(&client_id);
+        self.capturing.remove(&client_id);
+        self.shadowing.remove(&client_id);
         self.port_subscriptions.remove_client(client_id);
+        self.udp_port_subscriptions.remove_client(client_id);
         self.update_sniffer()
     }
 
-    fn update_sniffer(&mut self) -> Result<(), AgentError> {
-        let ports = self.port_subscriptions.get_subscribed_topics();
-        let sniffer = self.stream.capture_mut();
+    /// Splits the subscribed TCP ports into the ones captured from every source, and the ones
+    /// only captured from the sources of their subscribers (when all of them have a filter).
+    fn tcp_ports(&self) -> (Vec<Port>, Vec<(Port, Vec<Cidr>)>) {
+        let mut ports = Vec::new();
+        let mut filtered_ports = Vec::new();
+
+        for port in self.port_subscriptions.get_subscribed_topics() {
+            let sources = self
+                .port_subscriptions
+                .get_topic_subscribers(port)
+                .iter()
+                .map(|client_id| {
+                    self.port_subscriptions
+                        .get_subscription(port, *client_id)
+                        .filter(|sources| !sources.is_empty())
+                })
+                .collect::<Option<Vec<_>>>();
+
+            match sources {
+                Some(sources) => {
+                    let mut sources = sources.into_iter().flatten().copied().collect::<Vec<_>>();
+                    sources.sort_by_key(ToString::to_string);
+                    sources.dedup();
+                    filtered_ports.push((port, sources));
+                }
+                None => ports.push(port),
+            }
+        }
 
-        if ports.is_empty() {
+        (ports, filtered_ports)
+    }
+
+    fn update_sniffer(&mut self) -> Result<(), AgentError> {
+        let (ports, filtered_ports) = self.tcp_ports();
+        let udp_ports = self.udp_port_subscriptions.get_subscribed_topics();
+        let bpf = if ports.is_empty() && filtered_ports.is_empty() && udp_ports.is_empty() {
             debug!("packet_worker -> empty ports, setting dummy bpf");
-            sniffer.filter(DUMMY_BPF, true)?
+            DUMMY_BPF.to_string()
         } else {
-            let bpf = format_bpf(&ports);
+            let bpf = format_bpf(&ports, &filtered_ports, &udp_ports);
             debug!("packet_worker -> setting bpf to {:?}", &bpf);
-
-            sniffer.filter(&bpf, true)?
+            bpf
         };
+
+        for stream in self.stream.iter_mut() {
+            stream.capture_mut().filter(&bpf, true)?;
+        }
         Ok(())
     }
 
@@ -384,15 +856,15 @@ impl TCPConnectionSniffer {
         match command {
             SnifferCommand {
                 client_id,
-                command: SnifferCommands::NewAgent(sender),
+                command: SnifferCommands::NewAgent(sender, udp_sender),
             } => {
-                self.handle_new_client(client_id, sender);
+                self.handle_new_client(client_id, sender, udp_sender);
             }
             SnifferCommand {
                 client_id,
-                command: SnifferCommands::Subscribe(port),
+                command: SnifferCommands::Subscribe(port, sources),
             } => {
-                self.handle_subscribe(client_id, port).await?;
+                self.handle_subscribe(client_id, port, sources).await?;
             }
             SnifferCommand {
                 client_id,
@@ -404,13 +876,27 @@ impl TCPConnectionSniffer {
                 client_id,
                 command: SnifferCommands::UnsubscribeConnection(connection_id),
             } => {
-                self.connection_id_to_tcp_identifier
+                let unsubscribed = self
+                    .connection_id_to_tcp_identifier
                     .get(&connection_id)
                     .and_then(|identifier| {
                         self.sessions
                             .get_mut(identifier)
                             .map(|session| session.clients.remove(&client_id))
-                    });
+                    })
+                    .unwrap_or(false);
+
+                if unsubscribed {
+                    if let Some(budget) = self.mirror_budgets.get_mut(&client_id) {
+                        budget.release();
+                    }
+                }
+            }
+            SnifferCommand {
+                client_id,
+                command: SnifferCommands::Session(session),
+            } => {
+                self.handle_session(client_id, session)?;
             }
             SnifferCommand {
                 client_id,
@@ -419,6 +905,19 @@ impl TCPConnectionSniffer {
                 self.port_subscriptions.unsubscribe(client_id, port);
                 self.update_sniffer()?;
             }
+            SnifferCommand {
+                client_id,
+                command: SnifferCommands::SubscribeUdp(port),
+            } => {
+                self.handle_udp_subscribe(client_id, port).await?;
+            }
+            SnifferCommand {
+                client_id,
+                command: SnifferCommands::UnsubscribeUdpPort(port),
+            } => {
+                self.udp_port_subscriptions.unsubscribe(client_id, port);
+                self.update_sniffer()?;
+            }
         }
         Ok(())
     }
@@ -455,14 +954,76 @@ impl TCPConnectionSniffer {
         Ok(())
     }
 
-    async fn handle_packet(&mut self, eth_packet: Vec<u8>) -> Result<(), AgentError> {
+    /// Sends `frame` to the capturing clients subscribed to either of its ports.
+    async fn capture_frame(
+        &mut self,
+        frame: &SniffedFrame,
+        packet: &CapturedPacket,
+    ) -> Result<(), AgentError> {
+        let subscribers = match packet {
+            CapturedPacket::Tcp(identifier, _) => [identifier.source_port, identifier.dest_port]
+                .map(|port| self.port_subscriptions.get_topic_subscribers(port)),
+            CapturedPacket::Udp(datagram) => [datagram.source.port(), datagram.destination_port]
+                .map(|port| self.udp_port_subscriptions.get_topic_subscribers(port)),
+        };
+
+        let client_ids = self
+            .capturing
+            .iter()
+            .copied()
+            .filter(|client_id| subscribers.iter().flatten().any(|id| id == client_id))
+            .collect::<Vec<_>>();
+
+        self.send_message_to_clients(client_ids.iter(), DaemonTcp::Frame(frame.clone()))
+            .await
+    }
+
+    /// Datagrams are sent as they are to every client subscribed to the destination port.
+    async fn handle_datagram(&mut self, datagram: UdpDatagram) -> Result<(), AgentError> {
+        let client_ids = self
+            .udp_port_subscriptions
+            .get_topic_subscribers(datagram.destination_port);
+
+        trace!(
+            "TcpConnectionSniffer::handle_datagram -> {:?} to clients {:?}",
+            datagram,
+            client_ids
+        );
+
+        for client_id in client_ids {
+            let sender = match self.udp_client_senders.get(&client_id) {
+                Some(sender) => sender,
+                None => continue,
+            };
+
+            if sender
+                .send(DaemonUdp::Datagram(datagram.clone()))
+                .await
+                .is_err()
+            {
+                warn!("failed to send message to client {}", client_id);
+                self.handle_client_closed(client_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_packet(&mut self, frame: SniffedFrame) -> Result<(), AgentError> {
         trace!(
             "TcpConnectionSniffer::handle_packet -> eth_packet {:#?}",
-            eth_packet.len()
+            frame.bytes.len()
         );
 
-        let (identifier, tcp_packet) = match get_tcp_packet(eth_packet) {
-            Some(res) => res,
+        let packet = get_packet(&frame.bytes);
+
+        if let Some(packet) = packet.as_ref().filter(|_| !self.capturing.is_empty()) {
+            self.capture_frame(&frame, packet).await?;
+        }
+
+        let (identifier, tcp_packet) = match packet {
+            Some(CapturedPacket::Tcp(identifier, tcp_packet)) => (identifier, tcp_packet),
+            Some(CapturedPacket::Udp(datagram)) => return self.handle_datagram(datagram).await,
             None => return Ok(()),
         };
 
@@ -477,7 +1038,7 @@ impl TCPConnectionSniffer {
             is_client_packet
         );
 
-        let session = match self.sessions.remove(&identifier) {
+        let mut session = match self.sessions.remove(&identifier) {
             Some(session) => session,
             None => {
                 if !is_new_connection(tcp_flags) {
@@ -492,25 +1053,57 @@ impl TCPConnectionSniffer {
                     return Ok(());
                 }
 
+                let now = Instant::now();
+                let mut rng = rand::thread_rng();
+                let client_ids = self
+                    .port_subscriptions
+                    .get_topic_subscribers(dest_port)
+                    .into_iter()
+                    .filter(|client_id| {
+                        self.port_subscriptions
+                            .get_subscription(dest_port, *client_id)
+                            .map_or(true, |sources| {
+                                sources.is_empty()
+                                    || sources
+                                        .iter()
+                                        .any(|source| source.contains(identifier.source_addr))
+                            })
+                    })
+                    .filter(|client_id| {
+                        self.mirror_budgets
+                            .get_mut(client_id)
+                            .map_or(true, |budget| budget.admit(now, rng.gen_range(0..100)))
+                    })
+                    .collect::<Vec<_>>();
+                debug!(
+                    "TcpConnectionSniffer::handle_packet -> client_ids {:#?}",
+                    client_ids
+                );
+
+                if client_ids.is_empty() {
+                    debug!("connection is filtered out or over the mirror limits of every client");
+                    return Ok(());
+                }
+
                 let id = match self.index_allocator.next_index() {
                     Some(id) => id,
                     None => {
                         error!("connection index exhausted, dropping new connection");
+                        for client_id in &client_ids {
+                            if let Some(budget) = self.mirror_budgets.get_mut(client_id) {
+                                budget.release();
+                            }
+                        }
                         return Ok(());
                     }
                 };
 
-                let client_ids = self.port_subscriptions.get_topic_subscribers(dest_port);
-                debug!(
-                    "TcpConnectionSniffer::handle_packet -> client_ids {:#?}",
-                    client_ids
-                );
-
                 let message = DaemonTcp::NewConnection(NewTcpConnection {
                     destination_port: dest_port,
                     source_port,
                     connection_id: id,
-                    address: IpAddr::V4(identifier.source_addr),
+                    address: identifier.source_addr,
+                    alpn_protocol: None,
                 });
                 debug!(
                     "TcpConnectionSniffer::handle_packet -> message {:#?}",
@@ -525,6 +1118,8 @@ impl TCPConnectionSniffer {
                 TCPSession {
                     id,
                     clients: client_ids.into_iter().collect(),
+                    client_stream: TcpReassembler::new(tcp_packet.sequence),
+                    server_stream: None,
                 }
             }
         };
@@ -533,22 +1128,83 @@ impl TCPConnectionSniffer {
             session
         );
 
-        if is_client_packet && !tcp_packet.bytes.is_empty() {
-            let message = DaemonTcp::Data(TcpData {
-                bytes: tcp_packet.bytes,
-                connection_id: session.id,
-            });
-
-            debug!(
-                "TcpConnectionSniffer::handle_packet -> message {:#?}",
-                message
+        if is_client_packet {
+            let bytes = session.client_stream.push(
+                tcp_packet.sequence,
+                &tcp_packet.bytes,
+                is_fin(tcp_flags),
             );
 
-            self.send_message_to_clients(session.clients.iter(), message)
-                .await?;
+            if !bytes.is_empty() {
+                let now = Instant::now();
+                for client_id in &session.clients {
+                    if let Some(budget) = self.mirror_budgets.get_mut(client_id) {
+                        budget.record(now, bytes.len());
+                    }
+                }
+
+                let message = DaemonTcp::Data(TcpData {
+                    bytes,
+                    connection_id: session.id,
+                });
+
+                debug!(
+                    "TcpConnectionSniffer::handle_packet -> message {:#?}",
+                    message
+                );
+
+                self.send_message_to_clients(session.clients.iter(), message)
+                    .await?;
+            }
+        } else if is_syn_ack(tcp_flags) {
+            let shadowed = session
+                .clients
+                .iter()
+                .any(|client_id| self.shadowing.contains(client_id));
+
+            if shadowed {
+                session.server_stream = Some(TcpReassembler::new(tcp_packet.sequence));
+            }
+        } else if let Some(server_stream) = &mut session.server_stream {
+            let bytes =
+                server_stream.push(tcp_packet.sequence, &tcp_packet.bytes, is_fin(tcp_flags));
+
+            if !bytes.is_empty() {
+                let client_ids = session
+                    .clients
+                    .iter()
+                    .filter(|client_id| self.shadowing.contains(client_id))
+                    .copied()
+                    .collect::<Vec<_>>();
+
+                let message = DaemonTcp::RemoteData(TcpData {
+                    bytes,
+                    connection_id: session.id,
+                });
+
+                self.send_message_to_clients(client_ids.iter(), message)
+                    .await?;
+            }
         }
 
-        if is_closed_connection(tcp_flags) {
+        // A FIN from the connecting peer only closes the session once every byte before it was
+        // forwarded. The remote service's FIN doesn't, the connecting peer may keep sending. When
+        // its responses are shadow compared, both sides have to finish, the remote service
+        // usually answers after the peer is done sending.
+        let closed = is_reset(tcp_flags)
+            || (session.client_stream.is_finished()
+                && session
+                    .server_stream
+                    .as_ref()
+                    .map_or(true, TcpReassembler::is_finished));
+
+        if closed {
+            for client_id in &session.clients {
+                if let Some(budget) = self.mirror_budgets.get_mut(client_id) {
+                    budget.release();
+                }
+            }
+
             self.index_allocator.free_index(session.id);
             self.connection_id_to_tcp_identifier.remove(&session.id);
             let message = DaemonTcp::Close(TcpClose {
@@ -570,3 +1226,642 @@ impl TCPConnectionSniffer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use pnet::packet::{
+        ethernet::{EthernetPacket, MutableEthernetPacket},
+        ipv4::MutableIpv4Packet,
+        ipv6::MutableIpv6Packet,
+        tcp::MutableTcpPacket,
+        udp::MutableUdpPacket,
+    };
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    const TCP_HEADER_LENGTH: usize = 20;
+    const UDP_HEADER_LENGTH: usize = 8;
+
+    fn get_tcp_packet(eth_packet: Vec<u8>) -> Option<(TcpSessionIdentifier, TcpPacketData)> {
+        match get_packet(&eth_packet)? {
+            CapturedPacket::Tcp(identifier, data) => Some((identifier, data)),
+            CapturedPacket::Udp(_) => None,
+        }
+    }
+
+    fn tcp_segment(source_port: u16, dest_port: u16, flags: u16, data: &[u8]) -> Vec<u8> {
+        tcp_segment_at(source_port, dest_port, 0, flags, data)
+    }
+
+    fn tcp_segment_at(
+        source_port: u16,
+        dest_port: u16,
+        sequence: u32,
+        flags: u16,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut buffer = vec![0; TCP_HEADER_LENGTH + data.len()];
+        let mut tcp = MutableTcpPacket::new(&mut buffer).unwrap();
+        tcp.set_source(source_port);
+        tcp.set_destination(dest_port);
+        tcp.set_sequence(sequence);
+        tcp.set_data_offset(5);
+        tcp.set_flags(flags);
+        tcp.set_payload(data);
+        buffer
+    }
+
+    fn udp_datagram(source_port: u16, dest_port: u16, data: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0; UDP_HEADER_LENGTH + data.len()];
+        let mut udp = MutableUdpPacket::new(&mut buffer).unwrap();
+        udp.set_source(source_port);
+        udp.set_destination(dest_port);
+        udp.set_length((UDP_HEADER_LENGTH + data.len()) as u16);
+        udp.set_payload(data);
+        buffer
+    }
+
+    fn ethernet_frame(ethertype: pnet::packet::ethernet::EtherType, payload: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0; EthernetPacket::minimum_packet_size() + payload.len()];
+        let mut ethernet = MutableEthernetPacket::new(&mut buffer).unwrap();
+        ethernet.set_ethertype(ethertype);
+        ethernet.set_payload(payload);
+        buffer
+    }
+
+    fn ipv4_frame(source: Ipv4Addr, dest: Ipv4Addr, segment: &[u8]) -> Vec<u8> {
+        let total_length = Ipv4Packet::minimum_packet_size() + segment.len();
+        let mut buffer = vec![0; total_length];
+        let mut ip = MutableIpv4Packet::new(&mut buffer).unwrap();
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length(total_length as u16);
+        ip.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+        ip.set_source(source);
+        ip.set_destination(dest);
+        ip.set_payload(segment);
+        ethernet_frame(EtherTypes::Ipv4, &buffer)
+    }
+
+    /// `payload` starts with the extension headers (if any), `next_header` is the first one.
+    fn ipv6_frame(
+        source: Ipv6Addr,
+        dest: Ipv6Addr,
+        next_header: IpNextHeaderProtocol,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut buffer = vec![0; Ipv6Packet::minimum_packet_size() + payload.len()];
+        let mut ip = MutableIpv6Packet::new(&mut buffer).unwrap();
+        ip.set_version(6);
+        ip.set_payload_length(payload.len() as u16);
+        ip.set_next_header(next_header);
+        ip.set_hop_limit(64);
+        ip.set_source(source);
+        ip.set_destination(dest);
+        ip.set_payload(payload);
+        ethernet_frame(EtherTypes::Ipv6, &buffer)
+    }
+
+    #[test]
+    fn ipv4_tcp_packet() {
+        let source = Ipv4Addr::new(10, 0, 0, 1);
+        let dest = Ipv4Addr::new(10, 0, 0, 2);
+        let frame = ipv4_frame(
+            source,
+            dest,
+            &tcp_segment(4000, 80, TcpFlags::PSH, b"hello"),
+        );
+
+        let (identifier, data) = get_tcp_packet(frame).unwrap();
+
+        assert_eq!(identifier.source_addr, IpAddr::V4(source));
+        assert_eq!(identifier.dest_addr, IpAddr::V4(dest));
+        assert_eq!(identifier.source_port, 4000);
+        assert_eq!(identifier.dest_port, 80);
+        assert_eq!(data.flags, TcpFlags::PSH);
+        assert_eq!(data.bytes, b"hello");
+    }
+
+    /// Frames of the other link types are parsed the same once turned into Ethernet.
+    #[test]
+    fn tcp_packet_per_link_type() {
+        let source = Ipv4Addr::new(10, 0, 0, 1);
+        let dest = Ipv4Addr::new(10, 0, 0, 2);
+        let ethernet = ipv4_frame(
+            source,
+            dest,
+            &tcp_segment(4000, 80, TcpFlags::PSH, b"hello"),
+        );
+        let ip_packet = &ethernet[EthernetPacket::minimum_packet_size()..];
+
+        let mut sll = vec![0, 0, 0, 1, 0, 6, 1, 2, 3, 4, 5, 6, 0, 0, 0x08, 0x00];
+        sll.extend_from_slice(ip_packet);
+
+        let mut sll2 = vec![
+            0x08, 0x00, 0, 0, 0, 0, 0, 2, 0, 1, 0, 6, 1, 2, 3, 4, 5, 6, 0, 0,
+        ];
+        sll2.extend_from_slice(ip_packet);
+
+        let mut vlan = ethernet_frame(EtherTypes::Vlan, &[0x00, 0x64, 0x08, 0x00]);
+        vlan.extend_from_slice(ip_packet);
+
+        for (link_type, frame) in [
+            (LinkType::Ethernet, ethernet.clone()),
+            (LinkType::Ethernet, vlan),
+            (LinkType::LinuxSll, sll),
+            (LinkType::LinuxSll2, sll2),
+            (LinkType::RawIp, ip_packet.to_vec()),
+            (LinkType::Ipv4, ip_packet.to_vec()),
+        ] {
+            let (identifier, data) =
+                get_tcp_packet(link_type.to_ethernet(&frame).unwrap()).unwrap();
+
+            assert_eq!(identifier.source_addr, IpAddr::V4(source), "{link_type:?}");
+            assert_eq!(identifier.dest_addr, IpAddr::V4(dest), "{link_type:?}");
+            assert_eq!(identifier.dest_port, 80, "{link_type:?}");
+            assert_eq!(data.bytes, b"hello", "{link_type:?}");
+        }
+    }
+
+    #[test]
+    fn ipv6_tcp_packet() {
+        let source: Ipv6Addr = "fd00::1".parse().unwrap();
+        let dest: Ipv6Addr = "fd00::2".parse().unwrap();
+        let frame = ipv6_frame(
+            source,
+            dest,
+            IpNextHeaderProtocols::Tcp,
+            &tcp_segment(4000, 80, TcpFlags::SYN, &[]),
+        );
+
+        let (identifier, data) = get_tcp_packet(frame).unwrap();
+
+        assert_eq!(identifier.source_addr, IpAddr::V6(source));
+        assert_eq!(identifier.dest_addr, IpAddr::V6(dest));
+        assert_eq!(identifier.dest_port, 80);
+        assert!(is_new_connection(data.flags));
+        assert!(data.bytes.is_empty());
+    }
+
+    #[test]
+    fn ipv6_extension_headers() {
+        // Hop-by-Hop (8 bytes) -> Destination Options (16 bytes) -> Fragment (first) -> TCP.
+        let mut payload = vec![IpNextHeaderProtocols::Ipv6Opts.0, 0, 1, 4, 0, 0, 0, 0];
+        payload.extend([IpNextHeaderProtocols::Ipv6Frag.0, 1, 1, 12]);
+        payload.extend([0; 12]);
+        payload.extend([IpNextHeaderProtocols::Tcp.0, 0, 0, 1, 0, 0, 0, 42]);
+        payload.extend(tcp_segment(4000, 80, TcpFlags::PSH, b"hello"));
+
+        let frame = ipv6_frame(
+            "fd00::1".parse().unwrap(),
+            "fd00::2".parse().unwrap(),
+            IpNextHeaderProtocols::Hopopt,
+            &payload,
+        );
+
+        let (identifier, data) = get_tcp_packet(frame).unwrap();
+
+        assert_eq!(identifier.source_port, 4000);
+        assert_eq!(identifier.dest_port, 80);
+        assert_eq!(data.bytes, b"hello");
+    }
+
+    #[test]
+    fn ipv6_skips_unreadable_packets() {
+        let source: Ipv6Addr = "fd00::1".parse().unwrap();
+        let dest: Ipv6Addr = "fd00::2".parse().unwrap();
+
+        // Fragment with a non zero offset doesn't carry the TCP header.
+        let mut fragment = vec![IpNextHeaderProtocols::Tcp.0, 0, 0, 8, 0, 0, 0, 42];
+        fragment.extend(b"the rest of the segment");
+        let frame = ipv6_frame(source, dest, IpNextHeaderProtocols::Ipv6Frag, &fragment);
+        assert!(get_tcp_packet(frame).is_none());
+
+        // Extension header claiming more bytes than the packet has.
+        let truncated = [IpNextHeaderProtocols::Tcp.0, 4, 0, 0, 0, 0, 0, 0];
+        let frame = ipv6_frame(source, dest, IpNextHeaderProtocols::Hopopt, &truncated);
+        assert!(get_tcp_packet(frame).is_none());
+
+        let frame = ipv6_frame(source, dest, IpNextHeaderProtocols::Udp, &[0; 16]);
+        assert!(get_tcp_packet(frame).is_none());
+    }
+
+    #[test]
+    fn udp_datagram_keeps_source() {
+        let source: Ipv6Addr = "fd00::1".parse().unwrap();
+        let frame = ipv6_frame(
+            source,
+            "fd00::2".parse().unwrap(),
+            IpNextHeaderProtocols::Udp,
+            &udp_datagram(4000, 8125, b"requests:1|c"),
+        );
+
+        match get_packet(&frame) {
+            Some(CapturedPacket::Udp(datagram)) => {
+                assert_eq!(datagram.source, SocketAddr::new(IpAddr::V6(source), 4000));
+                assert_eq!(datagram.destination_port, 8125);
+                assert_eq!(datagram.bytes, b"requests:1|c");
+            }
+            other => panic!("expected a udp datagram, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn session_identifier_is_bidirectional() {
+        let client = TcpSessionIdentifier {
+            source_addr: "fd00::1".parse().unwrap(),
+            dest_addr: "fd00::2".parse().unwrap(),
+            source_port: 4000,
+            dest_port: 80,
+        };
+        let server = TcpSessionIdentifier {
+            source_addr: client.dest_addr,
+            dest_addr: client.source_addr,
+            source_port: client.dest_port,
+            dest_port: client.source_port,
+        };
+
+        let mut sessions = HashSet::new();
+        sessions.insert(client);
+        assert!(sessions.contains(&server));
+    }
+
+    /// Captured client packets go through [`get_tcp_packet`] and the session's reassembler,
+    /// like [`TCPConnectionSniffer::handle_packet`] does.
+    #[test]
+    fn replay_captured_packets() {
+        let source = Ipv4Addr::new(10, 0, 0, 1);
+        let dest = Ipv4Addr::new(10, 0, 0, 2);
+        let frame = |sequence, flags, data: &[u8]| {
+            ipv4_frame(
+                source,
+                dest,
+                &tcp_segment_at(4000, 80, sequence, flags, data),
+            )
+        };
+
+        // SYN, then the request split in 3 with the middle segment reordered, a retransmission
+        // and the FIN.
+        let capture = vec![
+            frame(100, TcpFlags::SYN, b""),
+            frame(101, TcpFlags::ACK, b"GET /"),
+            frame(113, TcpFlags::ACK | TcpFlags::PSH, b"\r\n\r\n"),
+            frame(106, TcpFlags::ACK, b" HTTP/1"),
+            frame(101, TcpFlags::ACK, b"GET /"),
+            frame(117, TcpFlags::ACK | TcpFlags::FIN, b""),
+        ];
+
+        let mut packets = capture
+            .into_iter()
+            .map(|frame| get_tcp_packet(frame).unwrap().1);
+
+        let syn = packets.next().unwrap();
+        assert!(is_new_connection(syn.flags));
+        let mut client_stream = TcpReassembler::new(syn.sequence);
+
+        let stream: Vec<u8> = packets
+            .flat_map(|packet| {
+                client_stream.push(packet.sequence, &packet.bytes, is_fin(packet.flags))
+            })
+            .collect();
+
+        assert_eq!(stream, b"GET / HTTP/1\r\n\r\n");
+        assert!(client_stream.is_finished());
+    }
+
+    /// The remote service's bytes are reordered and deduplicated like the peer's.
+    #[test]
+    fn reassemble_captured_responses() {
+        let frame = |sequence, flags, data: &[u8]| {
+            ipv4_frame(
+                Ipv4Addr::new(10, 0, 0, 2),
+                Ipv4Addr::new(10, 0, 0, 1),
+                &tcp_segment_at(80, 4000, sequence, flags, data),
+            )
+        };
+
+        let capture = vec![
+            frame(500, TcpFlags::SYN | TcpFlags::ACK, b""),
+            frame(510, TcpFlags::ACK | TcpFlags::PSH, b"200 OK"),
+            frame(501, TcpFlags::ACK, b"HTTP/1.1 "),
+            frame(516, TcpFlags::ACK | TcpFlags::FIN, b""),
+        ];
+
+        let mut packets = capture
+            .into_iter()
+            .map(|frame| get_tcp_packet(frame).unwrap().1);
+
+        let syn_ack = packets.next().unwrap();
+        assert!(is_syn_ack(syn_ack.flags));
+        assert!(!is_new_connection(syn_ack.flags));
+        let mut server_stream = TcpReassembler::new(syn_ack.sequence);
+
+        let stream: Vec<u8> = packets
+            .flat_map(|packet| {
+                server_stream.push(packet.sequence, &packet.bytes, is_fin(packet.flags))
+            })
+            .collect();
+
+        assert_eq!(stream, b"HTTP/1.1 200 OK");
+        assert!(server_stream.is_finished());
+    }
+
+    /// A sniffer without interfaces, with client `1` subscribed to `port`. Frames are fed to it
+    /// with [`TCPConnectionSniffer::handle_packet`].
+    async fn sniffer(port: Port) -> (TCPConnectionSniffer, Receiver<DaemonTcp>) {
+        let (_, receiver) = mpsc::channel(1);
+        let mut sniffer =
+            TCPConnectionSniffer::from_stream(receiver, futures::stream::select_all(vec![]), None);
+
+        let (tcp_sender, mut tcp_receiver) = mpsc::channel(16);
+        let (udp_sender, _) = mpsc::channel(1);
+        sniffer.handle_new_client(1, tcp_sender, udp_sender);
+        sniffer.handle_subscribe(1, port, vec![]).await.unwrap();
+        assert_eq!(tcp_receiver.recv().await, Some(DaemonTcp::Subscribed));
+
+        (sniffer, tcp_receiver)
+    }
+
+    async fn handle_frames(sniffer: &mut TCPConnectionSniffer, frames: Vec<Vec<u8>>) {
+        for bytes in frames {
+            sniffer
+                .handle_packet(SniffedFrame {
+                    timestamp_us: 0,
+                    bytes,
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    /// The remote service closing its side doesn't end the session, the connecting peer may keep
+    /// sending.
+    #[tokio::test]
+    async fn remote_half_close() {
+        let (mut sniffer, mut messages) = sniffer(80).await;
+        let peer = Ipv4Addr::new(10, 0, 0, 1);
+        let service = Ipv4Addr::new(10, 0, 0, 2);
+
+        handle_frames(
+            &mut sniffer,
+            vec![
+                ipv4_frame(
+                    peer,
+                    service,
+                    &tcp_segment_at(4000, 80, 100, TcpFlags::SYN, b""),
+                ),
+                ipv4_frame(
+                    service,
+                    peer,
+                    &tcp_segment_at(80, 4000, 500, TcpFlags::SYN | TcpFlags::ACK, b""),
+                ),
+                ipv4_frame(
+                    service,
+                    peer,
+                    &tcp_segment_at(80, 4000, 501, TcpFlags::ACK | TcpFlags::FIN, b""),
+                ),
+                ipv4_frame(
+                    peer,
+                    service,
+                    &tcp_segment_at(4000, 80, 101, TcpFlags::ACK | TcpFlags::PSH, b"late"),
+                ),
+                ipv4_frame(
+                    peer,
+                    service,
+                    &tcp_segment_at(4000, 80, 105, TcpFlags::ACK | TcpFlags::FIN, b""),
+                ),
+            ],
+        )
+        .await;
+
+        let connection_id = match messages.recv().await {
+            Some(DaemonTcp::NewConnection(connection)) => connection.connection_id,
+            other => panic!("expected a new connection, got {other:?}"),
+        };
+        assert_eq!(
+            messages.recv().await,
+            Some(DaemonTcp::Data(TcpData {
+                connection_id,
+                bytes: b"late".to_vec()
+            }))
+        );
+        assert_eq!(
+            messages.recv().await,
+            Some(DaemonTcp::Close(TcpClose { connection_id }))
+        );
+        assert!(sniffer.sessions.is_empty());
+    }
+
+    /// With shadow compare, the remote service's side starts at its SYN-ACK and is sent as
+    /// [`DaemonTcp::RemoteData`]. The peer's FIN doesn't end the session before the response.
+    #[tokio::test]
+    async fn shadow_compare_remote_data() {
+        let (mut sniffer, mut messages) = sniffer(80).await;
+        sniffer
+            .handle_session(
+                1,
+                MirrorSession {
+                    shadow_compare: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let peer = Ipv4Addr::new(10, 0, 0, 1);
+        let service = Ipv4Addr::new(10, 0, 0, 2);
+
+        handle_frames(
+            &mut sniffer,
+            vec![
+                ipv4_frame(
+                    peer,
+                    service,
+                    &tcp_segment_at(4000, 80, 100, TcpFlags::SYN, b""),
+                ),
+                ipv4_frame(
+                    service,
+                    peer,
+                    &tcp_segment_at(80, 4000, 500, TcpFlags::SYN | TcpFlags::ACK, b""),
+                ),
+                ipv4_frame(
+                    peer,
+                    service,
+                    &tcp_segment_at(4000, 80, 101, TcpFlags::ACK | TcpFlags::PSH, b"GET /"),
+                ),
+                ipv4_frame(
+                    peer,
+                    service,
+                    &tcp_segment_at(4000, 80, 106, TcpFlags::ACK | TcpFlags::FIN, b""),
+                ),
+                ipv4_frame(
+                    service,
+                    peer,
+                    &tcp_segment_at(80, 4000, 501, TcpFlags::ACK | TcpFlags::PSH, b"200 OK"),
+                ),
+                ipv4_frame(
+                    service,
+                    peer,
+                    &tcp_segment_at(80, 4000, 507, TcpFlags::ACK | TcpFlags::FIN, b""),
+                ),
+            ],
+        )
+        .await;
+
+        let connection_id = match messages.recv().await {
+            Some(DaemonTcp::NewConnection(connection)) => connection.connection_id,
+            other => panic!("expected a new connection, got {other:?}"),
+        };
+        assert_eq!(
+            messages.recv().await,
+            Some(DaemonTcp::Data(TcpData {
+                connection_id,
+                bytes: b"GET /".to_vec()
+            }))
+        );
+        assert_eq!(
+            messages.recv().await,
+            Some(DaemonTcp::RemoteData(TcpData {
+                connection_id,
+                bytes: b"200 OK".to_vec()
+            }))
+        );
+        assert_eq!(
+            messages.recv().await,
+            Some(DaemonTcp::Close(TcpClose { connection_id }))
+        );
+        assert!(sniffer.sessions.is_empty());
+    }
+
+    #[test]
+    fn bpf_captures_ipv6_extension_headers() {
+        assert_eq!(
+            format_bpf(&[80, 443], &[], &[]),
+            "tcp port (80 or 443) or (ip6 protochain 6 and not ip6 proto 6)"
+        );
+        assert_eq!(
+            format_bpf(&[80], &[], &[8125]),
+            "tcp port (80) or (ip6 protochain 6 and not ip6 proto 6) or udp dst port (8125) or \
+             (ip6 protochain 17 and not ip6 proto 17)"
+        );
+    }
+
+    /// Loopback and link-local only interfaces are skipped, every other one is sniffed.
+    #[test]
+    fn routable_interfaces_only() {
+        let devices = vec![
+            ("lo".to_string(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]),
+            (
+                "eth0".to_string(),
+                vec![
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)),
+                    IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)),
+                ],
+            ),
+            (
+                "net1".to_string(),
+                vec![IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 7))],
+            ),
+            (
+                "veth".to_string(),
+                vec![IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2))],
+            ),
+            ("any".to_string(), vec![]),
+        ];
+
+        assert_eq!(routable_interfaces(devices), vec!["eth0", "net1"]);
+    }
+
+    /// Each port is only mirrored from the sources it was subscribed with.
+    #[tokio::test]
+    async fn port_source_filters() {
+        let (mut sniffer, mut messages) = sniffer(80).await;
+        sniffer
+            .handle_subscribe(1, 8080, vec![Cidr::new("10.0.0.0".parse().unwrap(), 8)])
+            .await
+            .unwrap();
+        assert_eq!(messages.recv().await, Some(DaemonTcp::Subscribed));
+        let inside = Ipv4Addr::new(10, 0, 0, 1);
+        let outside = Ipv4Addr::new(172, 16, 0, 1);
+        let service = Ipv4Addr::new(192, 168, 0, 2);
+
+        handle_frames(
+            &mut sniffer,
+            vec![
+                ipv4_frame(
+                    outside,
+                    service,
+                    &tcp_segment(4000, 8080, TcpFlags::SYN, b""),
+                ),
+                ipv4_frame(outside, service, &tcp_segment(4001, 80, TcpFlags::SYN, b"")),
+                ipv4_frame(
+                    inside,
+                    service,
+                    &tcp_segment(4002, 8080, TcpFlags::SYN, b""),
+                ),
+            ],
+        )
+        .await;
+
+        for (source_port, destination_port) in [(4001, 80), (4002, 8080)] {
+            match messages.recv().await {
+                Some(DaemonTcp::NewConnection(connection)) => {
+                    assert_eq!(connection.source_port, source_port);
+                    assert_eq!(connection.destination_port, destination_port);
+                }
+                other => panic!("expected a new connection, got {other:?}"),
+            }
+        }
+        assert!(messages.try_recv().is_err());
+    }
+
+    #[test]
+    fn bpf_source_filters() {
+        let sources = vec![
+            Cidr::new("10.1.2.3".parse().unwrap(), 8),
+            Cidr::new("fd00::".parse().unwrap(), 16),
+        ];
+
+        assert_eq!(
+            format_bpf(&[80], &[(8080, sources)], &[]),
+            "tcp port (80) or (tcp port 8080 and (net 10.0.0.0/8 or net fd00::/16)) or (ip6 \
+             protochain 6 and not ip6 proto 6)"
+        );
+        assert_eq!(
+            format_bpf(
+                &[],
+                &[(8080, vec![Cidr::new("10.0.0.1".parse().unwrap(), 32)])],
+                &[]
+            ),
+            "(tcp port 8080 and (net 10.0.0.1/32)) or (ip6 protochain 6 and not ip6 proto 6)"
+        );
+    }
+
+    #[test]
+    fn mirror_budget_limits() {
+        let start = Instant::now();
+        let mut budget = MirrorBudget::new(
+            MirrorLimits {
+                sample_percent: Some(50),
+                max_connections: Some(2),
+                bytes_per_second: Some(100),
+            },
+            start,
+        );
+
+        // Only rolls below the sample percent are mirrored.
+        assert!(!budget.admit(start, 50));
+        assert!(budget.admit(start, 49));
+        assert!(budget.admit(start, 0));
+
+        // At the connection limit until one closes.
+        assert!(!budget.admit(start, 0));
+        budget.release();
+
+        // Over the byte budget until the window rolls over.
+        budget.record(start, 100);
+        assert!(!budget.admit(start, 0));
+        assert!(budget.admit(start + Duration::from_secs(1), 0));
+    }
+}
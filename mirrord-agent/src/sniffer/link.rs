@@ -0,0 +1,199 @@
+//! Link layer of the captured frames.
+//!
+//! Captures on `eth0` give Ethernet frames, but the `any` pseudo-interface gives Linux cooked
+//! frames (SLL/SLL2) and tun/WireGuard devices give bare IP packets. [`LinkType::to_ethernet`]
+//! turns all of them into Ethernet frames, which is what the rest of the sniffer (and the pcap
+//! files clients write, see [`SniffedFrame`]) works with.
+//!
+//! [`SniffedFrame`]: mirrord_protocol::tcp::SniffedFrame
+
+use pnet::packet::ethernet::{EtherType, EtherTypes};
+
+/// Length of an Ethernet header without VLAN tags.
+const ETHERNET_HEADER_LENGTH: usize = 14;
+
+/// VLAN tag (802.1Q), QinQ (802.1ad) and the old QinQ ethertype, all followed by a 4 bytes tag
+/// whose last 2 bytes are the next ethertype.
+const VLAN_ETHERTYPES: [EtherType; 3] = [EtherTypes::Vlan, EtherTypes::PBridge, EtherTypes::QinQ];
+
+/// Datalink types we can parse, values from <https://www.tcpdump.org/linktypes.html>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum LinkType {
+    Ethernet,
+    /// `LINKTYPE_LINUX_SLL`, 16 bytes header with the ethertype at the end.
+    LinuxSll,
+    /// `LINKTYPE_LINUX_SLL2`, 20 bytes header starting with the ethertype.
+    LinuxSll2,
+    /// Bare IPv4/IPv6 packets, the version tells which.
+    RawIp,
+    Ipv4,
+    Ipv6,
+}
+
+impl LinkType {
+    pub(super) fn from_datalink(datalink: i32) -> Option<Self> {
+        match datalink {
+            1 => Some(LinkType::Ethernet),
+            113 => Some(LinkType::LinuxSll),
+            276 => Some(LinkType::LinuxSll2),
+            // `DLT_RAW` is 12 or 14 depending on the platform, the capture file value is 101.
+            12 | 14 | 101 => Some(LinkType::RawIp),
+            228 => Some(LinkType::Ipv4),
+            229 => Some(LinkType::Ipv6),
+            _ => None,
+        }
+    }
+
+    /// Ethertype and payload of the network layer in `frame`, looking through VLAN tags.
+    pub(super) fn network_layer(self, frame: &[u8]) -> Option<(EtherType, &[u8])> {
+        let (ethertype, payload) = match self {
+            LinkType::Ethernet => (
+                ethertype_at(frame, 12)?,
+                frame.get(ETHERNET_HEADER_LENGTH..)?,
+            ),
+            LinkType::LinuxSll => (ethertype_at(frame, 14)?, frame.get(16..)?),
+            LinkType::LinuxSll2 => (ethertype_at(frame, 0)?, frame.get(20..)?),
+            LinkType::RawIp => match frame.first()? >> 4 {
+                4 => (EtherTypes::Ipv4, frame),
+                6 => (EtherTypes::Ipv6, frame),
+                _ => return None,
+            },
+            LinkType::Ipv4 => (EtherTypes::Ipv4, frame),
+            LinkType::Ipv6 => (EtherTypes::Ipv6, frame),
+        };
+
+        strip_vlan_tags(ethertype, payload)
+    }
+
+    /// `frame` as an Ethernet frame, the ones that weren't get zeroed MAC addresses.
+    pub(super) fn to_ethernet(self, frame: &[u8]) -> Option<Vec<u8>> {
+        if self == LinkType::Ethernet {
+            return Some(frame.to_vec());
+        }
+
+        let (ethertype, payload) = self.network_layer(frame)?;
+
+        let mut ethernet = Vec::with_capacity(ETHERNET_HEADER_LENGTH + payload.len());
+        ethernet.extend_from_slice(&[0; 12]);
+        ethernet.extend_from_slice(&ethertype.0.to_be_bytes());
+        ethernet.extend_from_slice(payload);
+        Some(ethernet)
+    }
+}
+
+fn ethertype_at(frame: &[u8], offset: usize) -> Option<EtherType> {
+    Some(EtherType(u16::from_be_bytes([
+        *frame.get(offset)?,
+        *frame.get(offset + 1)?,
+    ])))
+}
+
+fn strip_vlan_tags(mut ethertype: EtherType, mut payload: &[u8]) -> Option<(EtherType, &[u8])> {
+    while VLAN_ETHERTYPES.contains(&ethertype) {
+        ethertype = ethertype_at(payload, 2)?;
+        payload = payload.get(4..)?;
+    }
+
+    Some((ethertype, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start of an IPv4 header, enough for the link layer.
+    const IPV4: &[u8] = &[0x45, 0, 0, 20];
+    const IPV6: &[u8] = &[0x60, 0, 0, 0];
+
+    fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0xaa; 12];
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn zeroed_ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = ethernet(ethertype, payload);
+        frame[..12].fill(0);
+        frame
+    }
+
+    #[test]
+    fn ethernet_is_kept() {
+        let frame = ethernet(0x0800, IPV4);
+
+        assert_eq!(
+            LinkType::Ethernet.network_layer(&frame),
+            Some((EtherTypes::Ipv4, IPV4))
+        );
+        assert_eq!(LinkType::Ethernet.to_ethernet(&frame), Some(frame));
+    }
+
+    #[test]
+    fn vlan_tags() {
+        // 802.1ad outer tag, then 802.1Q.
+        let mut payload = vec![0x00, 0x64, 0x81, 0x00, 0x00, 0x0a, 0x86, 0xdd];
+        payload.extend_from_slice(IPV6);
+        let frame = ethernet(0x88a8, &payload);
+
+        assert_eq!(
+            LinkType::Ethernet.network_layer(&frame),
+            Some((EtherTypes::Ipv6, IPV6))
+        );
+        assert_eq!(LinkType::Ethernet.network_layer(&frame[..20]), None);
+    }
+
+    #[test]
+    fn linux_sll() {
+        // Packet type, ARPHRD_ETHER, address length, address, protocol.
+        let mut frame = vec![0, 0, 0, 1, 0, 6, 1, 2, 3, 4, 5, 6, 0, 0, 0x08, 0x00];
+        frame.extend_from_slice(IPV4);
+
+        assert_eq!(LinkType::from_datalink(113), Some(LinkType::LinuxSll));
+        assert_eq!(
+            LinkType::LinuxSll.to_ethernet(&frame),
+            Some(zeroed_ethernet(0x0800, IPV4))
+        );
+    }
+
+    #[test]
+    fn linux_sll2() {
+        // Protocol, reserved, interface index, ARPHRD_ETHER, packet type, address length,
+        // address.
+        let mut frame = vec![
+            0x86, 0xdd, 0, 0, 0, 0, 0, 2, 0, 1, 4, 6, 1, 2, 3, 4, 5, 6, 0, 0,
+        ];
+        frame.extend_from_slice(IPV6);
+
+        assert_eq!(LinkType::from_datalink(276), Some(LinkType::LinuxSll2));
+        assert_eq!(
+            LinkType::LinuxSll2.to_ethernet(&frame),
+            Some(zeroed_ethernet(0x86dd, IPV6))
+        );
+    }
+
+    #[test]
+    fn raw_ip() {
+        assert_eq!(LinkType::from_datalink(101), Some(LinkType::RawIp));
+        assert_eq!(
+            LinkType::RawIp.to_ethernet(IPV4),
+            Some(zeroed_ethernet(0x0800, IPV4))
+        );
+        assert_eq!(
+            LinkType::RawIp.to_ethernet(IPV6),
+            Some(zeroed_ethernet(0x86dd, IPV6))
+        );
+        assert_eq!(LinkType::RawIp.to_ethernet(&[0x10, 0, 0, 0]), None);
+
+        assert_eq!(
+            LinkType::Ipv6.to_ethernet(IPV6),
+            Some(zeroed_ethernet(0x86dd, IPV6))
+        );
+    }
+
+    #[test]
+    fn unsupported_datalink() {
+        // `LINKTYPE_IEEE802_11`
+        assert_eq!(LinkType::from_datalink(105), None);
+    }
+}
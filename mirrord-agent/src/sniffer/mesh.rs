@@ -0,0 +1,90 @@
+//! Service mesh detection, so mirroring keeps working when a sidecar encrypts the pod's traffic.
+//!
+//! With a mesh sidecar (Istio, Linkerd) traffic on `eth0` is mTLS between proxies, the app only
+//! ever sees plaintext on the loopback hop from its sidecar. The sidecar connects to the same
+//! port the client asked for, so sniffing `lo` keeps both the direction (client -> subscribed
+//! port) and the port mapping, the only difference being that the source is now the proxy.
+
+use std::{fmt, process::Command};
+
+use tracing::{debug, warn};
+
+/// Interface the sidecar talks to the app on.
+pub(super) const LOOPBACK_INTERFACE: &str = "lo";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum MeshVendor {
+    Istio,
+    Linkerd,
+}
+
+impl fmt::Display for MeshVendor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MeshVendor::Istio => write!(f, "istio"),
+            MeshVendor::Linkerd => write!(f, "linkerd"),
+        }
+    }
+}
+
+impl MeshVendor {
+    /// Looks for the nat chains the mesh's init container (or CNI plugin) creates.
+    fn from_chains<'a>(mut chains: impl Iterator<Item = &'a str>) -> Option<Self> {
+        chains.find_map(|chain| {
+            if chain.contains("ISTIO_INBOUND") || chain.contains("ISTIO_REDIRECT") {
+                Some(MeshVendor::Istio)
+            } else if chain.contains("PROXY_INIT_REDIRECT") || chain.contains("PROXY_INIT_OUTPUT") {
+                Some(MeshVendor::Linkerd)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Detects the mesh of the network namespace we're in, checking both iptables (legacy) and
+    /// nftables (where iptables-nft puts its chains).
+    pub(super) fn detect() -> Option<Self> {
+        let mut chains = match iptables::new(false).and_then(|ipt| ipt.list_chains("nat")) {
+            Ok(chains) => chains,
+            Err(err) => {
+                warn!("failed listing iptables chains for mesh detection: {err}");
+                Vec::new()
+            }
+        };
+
+        match Command::new("nft").args(["list", "chains"]).output() {
+            Ok(output) if output.status.success() => chains.extend(
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .map(From::from),
+            ),
+            Ok(output) => debug!(
+                "nft list chains failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(err) => debug!("nft unavailable for mesh detection: {err}"),
+        }
+
+        Self::from_chains(chains.iter().map(String::as_str))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_from_chains() {
+        let detect = |chains: &[&str]| MeshVendor::from_chains(chains.iter().copied());
+
+        assert_eq!(detect(&["PREROUTING", "OUTPUT"]), None);
+        assert_eq!(
+            detect(&["-N ISTIO_INBOUND", "-N ISTIO_OUTPUT"]),
+            Some(MeshVendor::Istio)
+        );
+        assert_eq!(
+            detect(&["table ip nat {", "\tchain PROXY_INIT_REDIRECT {"]),
+            Some(MeshVendor::Linkerd)
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Puts the captured segments of a TCP stream back in order.
+//!
+//! Packets are captured as they cross the interface, so retransmissions, duplicates and
+//! reordered segments show up as well. [`TcpReassembler`] tracks the sequence number of the next
+//! byte the stream expects and only releases bytes once everything before them arrived.
+
+use std::fmt;
+
+use tracing::warn;
+
+/// Out of order bytes we keep per stream while waiting for a gap to be filled. Once exceeded we
+/// give up on the gap and skip it, so a lost segment can't make the stream stall forever.
+const MAX_PENDING_BYTES: usize = 1024 * 1024;
+
+/// Signed distance between two sequence numbers, handling wraparound.
+fn sequence_distance(from: u32, to: u32) -> i32 {
+    to.wrapping_sub(from) as i32
+}
+
+pub(super) struct TcpReassembler {
+    /// Sequence number of the next byte we expect.
+    next_sequence: u32,
+    /// Segments that arrived ahead of `next_sequence`.
+    pending: Vec<(u32, Vec<u8>)>,
+    pending_bytes: usize,
+    /// Sequence number the FIN occupies, once we saw it.
+    fin_sequence: Option<u32>,
+    finished: bool,
+}
+
+impl fmt::Debug for TcpReassembler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TcpReassembler")
+            .field("next_sequence", &self.next_sequence)
+            .field("pending_segments", &self.pending.len())
+            .field("pending_bytes", &self.pending_bytes)
+            .field("fin_sequence", &self.fin_sequence)
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+impl TcpReassembler {
+    /// Starts tracking a stream from the sequence number of its SYN, which consumes one sequence
+    /// number by itself.
+    pub(super) fn new(syn_sequence: u32) -> Self {
+        Self {
+            next_sequence: syn_sequence.wrapping_add(1),
+            pending: Vec::new(),
+            pending_bytes: 0,
+            fin_sequence: None,
+            finished: false,
+        }
+    }
+
+    /// The FIN was reached in order, the stream has no more bytes to give.
+    pub(super) fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Adds a captured segment, returning the bytes that became contiguous because of it (might
+    /// be empty).
+    pub(super) fn push(&mut self, sequence: u32, payload: &[u8], fin: bool) -> Vec<u8> {
+        let mut ready = Vec::new();
+
+        if self.finished {
+            return ready;
+        }
+
+        if fin {
+            self.fin_sequence = Some(sequence.wrapping_add(payload.len() as u32));
+        }
+
+        if !self.accept(sequence, payload, &mut ready) && !payload.is_empty() {
+            self.pending_bytes += payload.len();
+            self.pending.push((sequence, payload.to_vec()));
+
+            if self.pending_bytes > MAX_PENDING_BYTES {
+                self.skip_gap();
+            }
+        }
+
+        while let Some(index) = self
+            .pending
+            .iter()
+            .position(|(sequence, _)| sequence_distance(*sequence, self.next_sequence) >= 0)
+        {
+            let (sequence, payload) = self.pending.swap_remove(index);
+            self.pending_bytes -= payload.len();
+            self.accept(sequence, &payload, &mut ready);
+        }
+
+        if self.fin_sequence == Some(self.next_sequence) {
+            self.next_sequence = self.next_sequence.wrapping_add(1);
+            self.finished = true;
+        }
+
+        ready
+    }
+
+    /// Appends the part of the segment we haven't seen yet to `ready`. Returns `false` when the
+    /// segment starts after `next_sequence` and has to wait.
+    fn accept(&mut self, sequence: u32, payload: &[u8], ready: &mut Vec<u8>) -> bool {
+        let seen = sequence_distance(sequence, self.next_sequence);
+        if seen < 0 {
+            return false;
+        }
+
+        if let Some(new_bytes) = payload.get(seen as usize..) {
+            ready.extend_from_slice(new_bytes);
+            self.next_sequence = self.next_sequence.wrapping_add(new_bytes.len() as u32);
+        }
+
+        true
+    }
+
+    /// Moves `next_sequence` to the earliest pending segment, dropping whatever is missing.
+    fn skip_gap(&mut self) {
+        let next_sequence = self.next_sequence;
+        if let Some(earliest) = self
+            .pending
+            .iter()
+            .map(|(sequence, _)| *sequence)
+            .min_by_key(|sequence| sequence_distance(next_sequence, *sequence))
+        {
+            warn!(
+                "TcpReassembler::skip_gap -> dropping {} missing bytes",
+                sequence_distance(next_sequence, earliest)
+            );
+            self.next_sequence = earliest;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYN: u32 = 1000;
+
+    /// Replays `(sequence, payload, fin)` segments, returning the reassembled stream.
+    fn replay(reassembler: &mut TcpReassembler, segments: &[(u32, &[u8], bool)]) -> Vec<u8> {
+        segments
+            .iter()
+            .flat_map(|(sequence, payload, fin)| reassembler.push(*sequence, payload, *fin))
+            .collect()
+    }
+
+    #[test]
+    fn in_order() {
+        let mut reassembler = TcpReassembler::new(SYN);
+
+        let stream = replay(
+            &mut reassembler,
+            &[
+                (SYN + 1, b"GET / ", false),
+                (SYN + 7, b"HTTP/1.1\r\n", false),
+                (SYN + 17, b"", true),
+            ],
+        );
+
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+        assert!(reassembler.is_finished());
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut reassembler = TcpReassembler::new(SYN);
+
+        assert!(reassembler.push(SYN + 7, b"HTTP/1.1\r\n", false).is_empty());
+        assert!(reassembler.push(SYN + 4, b" / ", false).is_empty());
+        assert_eq!(
+            reassembler.push(SYN + 1, b"GET", false),
+            b"GET / HTTP/1.1\r\n"
+        );
+    }
+
+    #[test]
+    fn retransmissions_and_overlaps() {
+        let mut reassembler = TcpReassembler::new(SYN);
+
+        let stream = replay(
+            &mut reassembler,
+            &[
+                (SYN, b"", false),
+                (SYN + 1, b"hello", false),
+                (SYN + 1, b"hello", false),
+                (SYN + 3, b"llo wor", false),
+                (SYN + 6, b" world", false),
+                (SYN + 1, b"h", false),
+            ],
+        );
+
+        assert_eq!(stream, b"hello world");
+    }
+
+    #[test]
+    fn fin_waits_for_missing_data() {
+        let mut reassembler = TcpReassembler::new(SYN);
+
+        assert!(reassembler.push(SYN + 6, b"world", true).is_empty());
+        assert!(!reassembler.is_finished());
+
+        assert_eq!(reassembler.push(SYN + 1, b"hello", false), b"helloworld");
+        assert!(reassembler.is_finished());
+
+        // Retransmitted FIN after the stream finished.
+        assert!(reassembler.push(SYN + 6, b"world", true).is_empty());
+    }
+
+    #[test]
+    fn sequence_wraparound() {
+        let syn = u32::MAX - 2;
+        let mut reassembler = TcpReassembler::new(syn);
+
+        let stream = replay(
+            &mut reassembler,
+            &[
+                (1, b"def", false),
+                (syn.wrapping_add(1), b"abc", false),
+                (4, b"", true),
+            ],
+        );
+
+        assert_eq!(stream, b"abcdef");
+        assert!(reassembler.is_finished());
+    }
+
+    #[test]
+    fn skips_gap_when_pending_is_full() {
+        let mut reassembler = TcpReassembler::new(SYN);
+        let chunk = vec![b'x'; MAX_PENDING_BYTES];
+
+        // Bytes `SYN + 1..SYN + 11` never arrive.
+        assert!(reassembler.push(SYN + 11, &chunk, false).is_empty());
+        let stream = reassembler.push(SYN + 11 + chunk.len() as u32, b"tail", false);
+
+        assert_eq!(stream.len(), chunk.len() + 4);
+        assert!(stream.ends_with(b"tail"));
+    }
+}
@@ -1,30 +1,109 @@
 use std::{
     collections::{HashMap, HashSet},
-    net::SocketAddr,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::unix::io::{AsRawFd, RawFd},
     path::PathBuf,
+    str::FromStr,
+    time::Duration,
 };
 
+use futures::stream::FuturesUnordered;
 use mirrord_protocol::{
-    tcp::{DaemonTcp, LayerTcpSteal, NewTcpConnection, TcpClose, TcpData},
-    ConnectionId, Port,
+    cidr::Cidr,
+    tcp::{
+        DaemonTcp, LayerTcpSteal, NewTcpConnection, StealFallback, StealSession, StealType,
+        TcpClose, TcpData,
+    },
+    ConnectionId, Port, RemoteResult, ResponseError,
 };
 use rand::distributions::{Alphanumeric, DistString};
 use streammap_ext::StreamMap;
 use tokio::{
-    io::{AsyncWriteExt, ReadHalf, WriteHalf},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
     net::{TcpListener, TcpStream},
     select,
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
+    time::{sleep_until, Instant},
 };
 use tokio_stream::StreamExt;
-use tokio_util::io::ReaderStream;
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
 use tracing::{debug, error, info, log::warn};
 
+use self::{
+    http::{
+        reconnect_original, FilteredHttpConnection, HttpFilter, StolenHttpConnection, BYPASS_MARK,
+    },
+    nftables::NfTables,
+    tls::{OriginalTls, TerminatedConnection, TlsHandshake, TlsTerminator},
+};
 use crate::{
     error::{AgentError, Result},
     runtime::set_namespace,
+    util::ClientID,
 };
 
+mod http;
+mod http2;
+mod nftables;
+mod tls;
+
+/// Stolen connections are either the [`TcpStream`] itself (TLS terminated or not), or the layer
+/// side of a connection stolen with an HTTP filter.
+trait StolenStream: AsyncRead + AsyncWrite + Send + Unpin {
+    /// The socket under the stream, if there's one to reset.
+    fn socket(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl StolenStream for TcpStream {
+    fn socket(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl StolenStream for tokio_rustls::server::TlsStream<TcpStream> {
+    fn socket(&self) -> Option<RawFd> {
+        Some(self.get_ref().0.as_raw_fd())
+    }
+}
+
+impl StolenStream for tokio_rustls::client::TlsStream<TcpStream> {
+    fn socket(&self) -> Option<RawFd> {
+        Some(self.get_ref().0.as_raw_fd())
+    }
+}
+
+impl StolenStream for DuplexStream {}
+
+/// Makes closing `socket` reset the connection (RST) instead of the usual FIN.
+fn reset_on_close(socket: RawFd) -> io::Result<()> {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+
+    let result = unsafe {
+        libc::setsockopt(
+            socket,
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&linger) as libc::socklen_t,
+        )
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
 #[cfg_attr(test, mockall::automock)]
 trait IPTables {
     fn create_chain(&self, name: &str) -> Result<()>;
@@ -33,6 +112,7 @@ trait IPTables {
     fn add_rule(&self, chain: &str, rule: &str) -> Result<()>;
     fn insert_rule(&self, chain: &str, rule: &str, index: i32) -> Result<()>;
     fn list_rules(&self, chain: &str) -> Result<Vec<String>>;
+    fn list_chains(&self) -> Result<Vec<String>>;
     fn remove_rule(&self, chain: &str, rule: &str) -> Result<()>;
 }
 
@@ -40,8 +120,14 @@ impl IPTables for iptables::IPTables {
     fn create_chain(&self, name: &str) -> Result<()> {
         self.new_chain(IPTABLES_TABLE_NAME, name)
             .map_err(|e| AgentError::IPTablesError(e.to_string()))?;
-        self.append(IPTABLES_TABLE_NAME, name, "-j RETURN")
-            .map_err(|e| AgentError::IPTablesError(e.to_string()))?;
+
+        if let Err(err) = self.append(IPTABLES_TABLE_NAME, name, "-j RETURN") {
+            if let Err(err) = self.delete_chain(IPTABLES_TABLE_NAME, name) {
+                error!("Failed removing chain {name}: {err:?}");
+            }
+
+            return Err(AgentError::IPTablesError(err.to_string()));
+        }
 
         Ok(())
     }
@@ -70,12 +156,89 @@ impl IPTables for iptables::IPTables {
             .map_err(|e| AgentError::IPTablesError(e.to_string()))
     }
 
+    fn list_chains(&self) -> Result<Vec<String>> {
+        self.list_chains(IPTABLES_TABLE_NAME)
+            .map_err(|e| AgentError::IPTablesError(e.to_string()))
+    }
+
     fn remove_rule(&self, chain: &str, rule: &str) -> Result<()> {
         self.delete(IPTABLES_TABLE_NAME, chain, rule)
             .map_err(|e| AgentError::IPTablesError(e.to_string()))
     }
 }
 
+impl IPTables for Box<dyn IPTables + Send> {
+    fn create_chain(&self, name: &str) -> Result<()> {
+        self.as_ref().create_chain(name)
+    }
+
+    fn remove_chain(&self, name: &str) -> Result<()> {
+        self.as_ref().remove_chain(name)
+    }
+
+    fn add_rule(&self, chain: &str, rule: &str) -> Result<()> {
+        self.as_ref().add_rule(chain, rule)
+    }
+
+    fn insert_rule(&self, chain: &str, rule: &str, index: i32) -> Result<()> {
+        self.as_ref().insert_rule(chain, rule, index)
+    }
+
+    fn list_rules(&self, chain: &str) -> Result<Vec<String>> {
+        self.as_ref().list_rules(chain)
+    }
+
+    fn list_chains(&self) -> Result<Vec<String>> {
+        self.as_ref().list_chains()
+    }
+
+    fn remove_rule(&self, chain: &str, rule: &str) -> Result<()> {
+        self.as_ref().remove_rule(chain, rule)
+    }
+}
+
+/// Which firewall we use to redirect stolen ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StealBackend {
+    IPTables,
+    NfTables,
+}
+
+impl FromStr for StealBackend {
+    type Err = String;
+
+    fn from_str(val: &str) -> std::result::Result<Self, Self::Err> {
+        match val {
+            "iptables" => Ok(StealBackend::IPTables),
+            "nftables" => Ok(StealBackend::NfTables),
+            other => Err(format!(
+                "unknown steal backend `{other}`, expected iptables/nftables"
+            )),
+        }
+    }
+}
+
+impl StealBackend {
+    /// Uses nftables when the target already does (natively or through iptables-nft), so our
+    /// rules end up next to the ones that are actually in effect.
+    fn detect() -> Self {
+        if nftables::has_tables() {
+            StealBackend::NfTables
+        } else {
+            StealBackend::IPTables
+        }
+    }
+
+    fn create(self, ipv6: bool) -> Result<Box<dyn IPTables + Send>> {
+        match self {
+            StealBackend::IPTables => Ok(Box::new(
+                iptables::new(ipv6).map_err(|e| AgentError::IPTablesError(e.to_string()))?,
+            )),
+            StealBackend::NfTables => Ok(Box::new(NfTables::new(ipv6)?)),
+        }
+    }
+}
+
 /// Wrapper struct for IPTables so it flushes on drop.
 struct SafeIpTables<IPT: IPTables> {
     inner: IPT,
@@ -84,6 +247,8 @@ struct SafeIpTables<IPT: IPTables> {
 }
 
 const IPTABLES_TABLE_NAME: &str = "nat";
+/// Every chain we create starts with this, so we can find the ones a crashed agent left behind.
+const CHAIN_PREFIX: &str = "MIRRORD_REDIRECT_";
 /// Wrapper for using iptables. This creates a a new chain on creation and deletes it on drop.
 /// The way it works is that it adds a chain, then adds a rule to the chain that returns to the
 /// original chain (fallback) and adds a rule in the "PREROUTING" table that jumps to the new chain.
@@ -97,11 +262,18 @@ where
         let formatter = IPTableFormatter::detect(&ipt)?;
 
         let random_string = Alphanumeric.sample_string(&mut rand::thread_rng(), 5);
-        let chain_name = format!("MIRRORD_REDIRECT_{}", random_string);
+        let chain_name = format!("{CHAIN_PREFIX}{random_string}");
 
         ipt.create_chain(&chain_name)?;
 
-        ipt.add_rule(formatter.entrypoint(), &format!("-j {}", chain_name))?;
+        // Nothing we added stays behind when we fail halfway.
+        if let Err(err) = Self::add_entrypoint_rules(&ipt, &formatter, &chain_name) {
+            if let Err(err) = ipt.remove_chain(&chain_name) {
+                error!("Failed removing chain {chain_name}: {err:?}");
+            }
+
+            return Err(err);
+        }
 
         Ok(Self {
             inner: ipt,
@@ -110,19 +282,78 @@ where
         })
     }
 
-    pub fn add_redirect(&self, redirected_port: Port, target_port: Port) -> Result<()> {
-        self.inner.insert_rule(
-            &self.chain_name,
-            &self.formatter.redirect_rule(redirected_port, target_port),
-            1,
-        )
+    /// Sends the traffic of the entrypoint through our chain.
+    fn add_entrypoint_rules(
+        ipt: &IPT,
+        formatter: &IPTableFormatter,
+        chain_name: &str,
+    ) -> Result<()> {
+        let bypass_rule = formatter.bypass_rule();
+        if let Some(bypass_rule) = &bypass_rule {
+            ipt.insert_rule(formatter.entrypoint(), bypass_rule, 1)?;
+        }
+
+        if let Err(err) = ipt.add_rule(formatter.entrypoint(), &format!("-j {}", chain_name)) {
+            if let Some(bypass_rule) = &bypass_rule {
+                if let Err(err) = ipt.remove_rule(formatter.entrypoint(), bypass_rule) {
+                    error!("Failed removing bypass rule: {err:?}");
+                }
+            }
+
+            return Err(err);
+        }
+
+        Ok(())
     }
 
-    pub fn remove_redirect(&self, redirected_port: Port, target_port: Port) -> Result<()> {
-        self.inner.remove_rule(
-            &self.chain_name,
-            &self.formatter.redirect_rule(redirected_port, target_port),
-        )
+    /// Redirects connections from every source when `sources` is empty, otherwise adds a rule
+    /// per source. Either all the rules are added, or none of them.
+    pub fn add_redirect(
+        &self,
+        redirected_port: Port,
+        target_port: Port,
+        sources: &[Cidr],
+    ) -> Result<()> {
+        if matches!(self.formatter, IPTableFormatter::Linkerd) && !sources.is_empty() {
+            warn!(
+                "Port {redirected_port} is stolen behind linkerd, where connections come from its \
+                 proxy, so the source filter only matches the proxy's address"
+            );
+        }
+
+        let rules = self
+            .formatter
+            .redirect_rules(redirected_port, target_port, sources);
+
+        for (added, rule) in rules.iter().enumerate() {
+            if let Err(err) = self.inner.insert_rule(&self.chain_name, rule, 1) {
+                for rule in rules[..added].iter().rev() {
+                    if let Err(err) = self.inner.remove_rule(&self.chain_name, rule) {
+                        error!("Failed removing redirect rule {rule}: {err:?}");
+                    }
+                }
+
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_redirect(
+        &self,
+        redirected_port: Port,
+        target_port: Port,
+        sources: &[Cidr],
+    ) -> Result<()> {
+        for rule in self
+            .formatter
+            .redirect_rules(redirected_port, target_port, sources)
+        {
+            self.inner.remove_rule(&self.chain_name, &rule)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -130,16 +361,232 @@ impl<IPT> Drop for SafeIpTables<IPT>
 where
     IPT: IPTables,
 {
+    /// Keeps going on failures, so one missing rule doesn't leave the rest behind (whatever is left
+    /// gets swept by the next agent, see [`clean_stale_rules`]).
     fn drop(&mut self) {
-        self.inner
-            .remove_rule(
-                self.formatter.entrypoint(),
-                &format!("-j {}", self.chain_name),
-            )
-            .unwrap();
+        if let Err(err) = self.inner.remove_rule(
+            self.formatter.entrypoint(),
+            &format!("-j {}", self.chain_name),
+        ) {
+            error!("Failed removing jump to {}: {err:?}", self.chain_name);
+        }
+
+        if let Some(bypass_rule) = self.formatter.bypass_rule() {
+            if let Err(err) = self
+                .inner
+                .remove_rule(self.formatter.entrypoint(), &bypass_rule)
+            {
+                error!("Failed removing bypass rule: {err:?}");
+            }
+        }
+
+        if let Err(err) = self.inner.remove_chain(&self.chain_name) {
+            error!("Failed removing chain {}: {err:?}", self.chain_name);
+        }
+    }
+}
+
+/// Whether something in this network namespace listens on `port`, i.e. the steal listener a
+/// redirect points to still belongs to a live agent.
+fn is_listening(port: Port, ipv6: bool) -> bool {
+    let address: IpAddr = if ipv6 {
+        Ipv6Addr::UNSPECIFIED.into()
+    } else {
+        Ipv4Addr::UNSPECIFIED.into()
+    };
+
+    matches!(
+        std::net::TcpListener::bind((address, port)),
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse
+    )
+}
+
+/// Port a `-j REDIRECT --to-ports <port>` rule sends traffic to.
+fn redirect_target(rule: &str) -> Option<Port> {
+    rule.split_once("--to-ports ")
+        .and_then(|(_, port)| port.split_whitespace().next())
+        .and_then(|port| port.parse().ok())
+}
+
+/// `iptables -S` lists marks in hex.
+fn is_bypass_rule(rule: &str) -> bool {
+    rule.ends_with("-j RETURN")
+        && (rule.contains(&format!("--mark {BYPASS_MARK} "))
+            || rule.contains(&format!("--mark {BYPASS_MARK:#x} ")))
+}
+
+/// Removes the chains (and the jumps to them) of agents that are gone without cleaning up, which we
+/// tell by nothing listening on the ports they redirect to anymore.
+///
+/// A chain without redirects may belong to an agent that's still starting, so it's only removed
+/// when `force`d, which removes every chain of ours.
+fn remove_stale_chains<IPT: IPTables>(ipt: &IPT, ipv6: bool, force: bool) -> Result<()> {
+    let mut stale_chains = Vec::new();
+    let mut live_chains = 0;
+
+    for chain in ipt
+        .list_chains()?
+        .into_iter()
+        .filter(|chain| chain.starts_with(CHAIN_PREFIX))
+    {
+        let targets = ipt
+            .list_rules(&chain)?
+            .iter()
+            .filter_map(|rule| redirect_target(rule))
+            .collect::<Vec<_>>();
+
+        if force || (!targets.is_empty() && !targets.iter().any(|port| is_listening(*port, ipv6))) {
+            stale_chains.push(chain);
+        } else {
+            live_chains += 1;
+        }
+    }
+
+    for entrypoint in ["PREROUTING", "OUTPUT"] {
+        let prefix = format!("-A {entrypoint} ");
+
+        for rule in ipt.list_rules(entrypoint)? {
+            if let Some(rule) = rule.strip_prefix(&prefix) {
+                let is_stale_jump = stale_chains
+                    .iter()
+                    .any(|chain| rule == format!("-j {chain}"));
+
+                // The bypass rule is shared, it stays while any agent still needs it.
+                if is_stale_jump || (live_chains == 0 && is_bypass_rule(rule)) {
+                    ipt.remove_rule(entrypoint, rule)?;
+                }
+            }
+        }
+    }
+
+    for chain in stale_chains {
+        info!("Removing stale chain {chain}");
+        ipt.remove_chain(&chain)?;
+    }
+
+    Ok(())
+}
 
-        self.inner.remove_chain(&self.chain_name).unwrap();
+/// Sweeps the steal rules left behind by agents that crashed (or were killed) in the network
+/// namespace of `pid`, for both IP families and backends. With `force`, the rules of live agents go
+/// as well.
+///
+/// Enters the namespace, so it should run on a thread of its own.
+pub fn clean_stale_rules(pid: Option<u64>, force: bool) -> Result<()> {
+    if let Some(pid) = pid {
+        let namespace = PathBuf::from("/proc")
+            .join(PathBuf::from(pid.to_string()))
+            .join(PathBuf::from("ns/net"));
+
+        set_namespace(namespace)?;
     }
+
+    for ipv6 in [false, true] {
+        let swept = iptables::new(ipv6)
+            .map_err(|e| AgentError::IPTablesError(e.to_string()))
+            .and_then(|ipt| remove_stale_chains(&ipt, ipv6, force));
+
+        if let Err(err) = swept {
+            warn!("Failed sweeping stale chains (ipv6: {ipv6}): {err:?}");
+        }
+    }
+
+    if nftables::has_tables() {
+        if let Err(err) = nftables::remove_stale_tables(force) {
+            warn!("Failed sweeping stale nftables tables: {err:?}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Redirects stolen ports of both IP families, each one with its own chain and steal listener.
+///
+/// IPv6 is optional, as it may be disabled in the pod or unsupported by the node.
+struct DualStackIpTables<IPT: IPTables> {
+    ipv4: SafeIpTables<IPT>,
+    ipv4_listen_port: Port,
+    ipv6: Option<(SafeIpTables<IPT>, Port)>,
+}
+
+impl<IPT> DualStackIpTables<IPT>
+where
+    IPT: IPTables,
+{
+    pub fn new(ipv4: IPT, ipv4_listen_port: Port, ipv6: Option<(IPT, Port)>) -> Result<Self> {
+        let ipv4 = SafeIpTables::new(ipv4)?;
+
+        let ipv6 = ipv6.and_then(|(ipt, listen_port)| match SafeIpTables::new(ipt) {
+            Ok(ipt) => Some((ipt, listen_port)),
+            Err(err) => {
+                warn!("Failed preparing ip6tables, IPv6 connections won't be stolen: {err:?}");
+                None
+            }
+        });
+
+        Ok(Self {
+            ipv4,
+            ipv4_listen_port,
+            ipv6,
+        })
+    }
+
+    /// Each family only gets the `sources` of its own, and is skipped when `sources` are all of
+    /// the other family. When IPv6 fails, the IPv4 redirect is removed again.
+    pub fn add_redirect(&self, redirected_port: Port, sources: &[Cidr]) -> Result<()> {
+        let ipv4_sources = family_sources(sources, false);
+        if let Some(ipv4_sources) = &ipv4_sources {
+            self.ipv4
+                .add_redirect(redirected_port, self.ipv4_listen_port, ipv4_sources)?;
+        }
+
+        if let (Some((ipv6, listen_port)), Some(ipv6_sources)) =
+            (&self.ipv6, family_sources(sources, true))
+        {
+            if let Err(err) = ipv6.add_redirect(redirected_port, *listen_port, &ipv6_sources) {
+                if let Some(ipv4_sources) = &ipv4_sources {
+                    if let Err(err) = self.ipv4.remove_redirect(
+                        redirected_port,
+                        self.ipv4_listen_port,
+                        ipv4_sources,
+                    ) {
+                        error!("Failed removing IPv4 redirect of port {redirected_port}: {err:?}");
+                    }
+                }
+
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_redirect(&self, redirected_port: Port, sources: &[Cidr]) -> Result<()> {
+        if let Some(ipv4_sources) = family_sources(sources, false) {
+            self.ipv4
+                .remove_redirect(redirected_port, self.ipv4_listen_port, &ipv4_sources)?;
+        }
+
+        if let (Some((ipv6, listen_port)), Some(ipv6_sources)) =
+            (&self.ipv6, family_sources(sources, true))
+        {
+            ipv6.remove_redirect(redirected_port, *listen_port, &ipv6_sources)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The `sources` of one IP family, `None` when there are sources but none of this family (so
+/// nothing of this family should be redirected).
+fn family_sources(sources: &[Cidr], ipv6: bool) -> Option<Vec<Cidr>> {
+    let family = sources
+        .iter()
+        .filter(|source| source.address.is_ipv6() == ipv6)
+        .copied()
+        .collect::<Vec<_>>();
+
+    (sources.is_empty() || !family.is_empty()).then_some(family)
 }
 
 enum IPTableFormatter {
@@ -151,10 +598,10 @@ impl IPTableFormatter {
     fn detect<IPT: IPTables>(ipt: &IPT) -> Result<Self> {
         let output = ipt.list_rules("OUTPUT")?;
 
-        if output
-            .iter()
-            .any(|rule| rule.contains("-j PROXY_INIT_OUTPUT"))
-        {
+        // `jump` is how nftables lists it.
+        if output.iter().any(|rule| {
+            rule.contains("-j PROXY_INIT_OUTPUT") || rule.contains("jump PROXY_INIT_OUTPUT")
+        }) {
             Ok(IPTableFormatter::Linkerd)
         } else {
             Ok(IPTableFormatter::Normal)
@@ -168,6 +615,16 @@ impl IPTableFormatter {
         }
     }
 
+    /// Rule that lets the connections we make to the original destination skip our chain.
+    ///
+    /// Only needed when we redirect locally generated traffic, `PREROUTING` never sees it.
+    fn bypass_rule(&self) -> Option<String> {
+        match self {
+            IPTableFormatter::Normal => None,
+            IPTableFormatter::Linkerd => Some(format!("-m mark --mark {BYPASS_MARK} -j RETURN")),
+        }
+    }
+
     fn redirect_rule(&self, redirected_port: Port, target_port: Port) -> String {
         let redirect_rule = format!(
             "-m tcp -p tcp --dport {} -j REDIRECT --to-ports {}",
@@ -179,50 +636,303 @@ impl IPTableFormatter {
             IPTableFormatter::Linkerd => format!("-o lo {}", redirect_rule),
         }
     }
+
+    /// [`Self::redirect_rule`] for each source (`-s`), or a single one for every source.
+    fn redirect_rules(
+        &self,
+        redirected_port: Port,
+        target_port: Port,
+        sources: &[Cidr],
+    ) -> Vec<String> {
+        let redirect_rule = self.redirect_rule(redirected_port, target_port);
+
+        if sources.is_empty() {
+            vec![redirect_rule]
+        } else {
+            sources
+                .iter()
+                .map(|source| format!("-s {source} {redirect_rule}"))
+                .collect()
+        }
+    }
 }
 
+/// A stolen connection the layer hasn't accepted yet. We don't read from it until then, so it can
+/// still fall back to its original destination untouched.
+struct PendingConnection {
+    stream: Box<dyn StolenStream>,
+    original_destination: SocketAddr,
+    original_tls: Option<OriginalTls>,
+    deadline: Instant,
+}
+
+/// A stolen connection on its way back to its original destination, see [`StealFallback`].
+struct FallbackConnection {
+    stream: Box<dyn StolenStream>,
+    original_destination: SocketAddr,
+    /// Set when the agent terminated TLS on `stream`.
+    original_tls: Option<OriginalTls>,
+}
+
+impl FallbackConnection {
+    async fn run(self) {
+        let original_destination = self.original_destination;
+
+        if let Err(err) = self.forward().await {
+            warn!("fallback connection to {original_destination:?} failed with {err:?}");
+        }
+    }
+
+    async fn forward(self) -> io::Result<()> {
+        let FallbackConnection {
+            mut stream,
+            original_destination,
+            original_tls,
+        } = self;
+
+        let mut original = reconnect_original(original_destination, original_tls.as_ref()).await?;
+        tokio::io::copy_bidirectional(&mut stream, &mut original).await?;
+        Ok(())
+    }
+}
+
+/// Commands from the clients to the agent-wide [`StealWorker`].
+#[derive(Debug)]
+enum StealerCommands {
+    /// The receiver completes when the client is gone, see [`TcpStealerAPI`].
+    NewClient(Sender<DaemonTcp>, oneshot::Receiver<()>),
+    LayerMessage(LayerTcpSteal),
+}
+
+#[derive(Debug)]
+pub struct StealerCommand {
+    client_id: ClientID,
+    command: StealerCommands,
+}
+
+/// A client's end of the [`StealWorker`], which is shared by every client of the agent.
+pub struct TcpStealerAPI {
+    client_id: ClientID,
+    sender: Sender<StealerCommand>,
+    receiver: Receiver<DaemonTcp>,
+    /// Dropped with us, which tells the [`StealWorker`] to release our ports even when its command
+    /// channel is full.
+    _closed: oneshot::Sender<()>,
+}
+
+impl TcpStealerAPI {
+    pub async fn new(
+        client_id: ClientID,
+        stealer_sender: Sender<StealerCommand>,
+        receiver: Receiver<DaemonTcp>,
+        sender: Sender<DaemonTcp>,
+    ) -> Result<Self> {
+        let (closed, closed_receiver) = oneshot::channel();
+        stealer_sender
+            .send(StealerCommand {
+                client_id,
+                command: StealerCommands::NewClient(sender, closed_receiver),
+            })
+            .await?;
+
+        Ok(Self {
+            client_id,
+            sender: stealer_sender,
+            receiver,
+            _closed: closed,
+        })
+    }
+
+    pub async fn handle_client_message(&mut self, message: LayerTcpSteal) -> Result<()> {
+        self.sender
+            .send(StealerCommand {
+                client_id: self.client_id,
+                command: StealerCommands::LayerMessage(message),
+            })
+            .await
+            .map_err(From::from)
+    }
+
+    pub async fn recv(&mut self) -> Option<DaemonTcp> {
+        self.receiver.recv().await
+    }
+}
+
+/// Subscribed ports, each one owned by the client that stole it first, with the HTTP filter for
+/// ports that aren't stolen entirely and the sources it's stolen from (every source when empty).
+#[derive(Default)]
+struct StolenPorts {
+    ports: HashMap<Port, (ClientID, Option<HttpFilter>, Vec<Cidr>)>,
+}
+
+impl StolenPorts {
+    /// Returns whether `port` wasn't stolen before, i.e. it has to be redirected now.
+    fn claim(
+        &mut self,
+        client_id: ClientID,
+        port: Port,
+        filter: Option<HttpFilter>,
+        sources: Vec<Cidr>,
+    ) -> RemoteResult<bool> {
+        match self.ports.get(&port) {
+            Some((owner, ..)) if *owner == client_id => Ok(false),
+            Some(_) => Err(ResponseError::PortAlreadyStolen(port)),
+            None => {
+                self.ports.insert(port, (client_id, filter, sources));
+                Ok(true)
+            }
+        }
+    }
+
+    /// Returns the sources `port` was stolen from if `client_id` stole it, i.e. it's no longer
+    /// redirected.
+    fn release(&mut self, client_id: ClientID, port: Port) -> Option<Vec<Cidr>> {
+        match self.ports.get(&port) {
+            Some((owner, ..)) if *owner == client_id => {
+                self.ports.remove(&port).map(|(_, _, sources)| sources)
+            }
+            _ => None,
+        }
+    }
+
+    /// Releases every port `client_id` stole, returning them with their sources.
+    fn release_client(&mut self, client_id: ClientID) -> Vec<(Port, Vec<Cidr>)> {
+        let ports = self
+            .ports
+            .iter()
+            .filter_map(|(port, (owner, ..))| (*owner == client_id).then_some(*port))
+            .collect::<Vec<_>>();
+
+        ports
+            .into_iter()
+            .filter_map(|port| {
+                self.ports
+                    .remove(&port)
+                    .map(|(_, _, sources)| (port, sources))
+            })
+            .collect()
+    }
+
+    fn get(&self, port: Port) -> Option<&(ClientID, Option<HttpFilter>, Vec<Cidr>)> {
+        self.ports.get(&port)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ports.is_empty()
+    }
+}
+
+/// Steals ports for every client of the agent, like [`TCPConnectionSniffer`] does for mirroring.
+///
+/// [`TCPConnectionSniffer`]: crate::sniffer::TCPConnectionSniffer
 pub struct StealWorker {
-    pub sender: Sender<DaemonTcp>,
-    iptables: SafeIpTables<iptables::IPTables>,
-    ports: HashSet<Port>,
+    clients: HashMap<ClientID, Sender<DaemonTcp>>,
+    backend: StealBackend,
     listen_port: Port,
-    write_streams: HashMap<ConnectionId, WriteHalf<TcpStream>>,
-    read_streams: StreamMap<ConnectionId, ReaderStream<ReadHalf<TcpStream>>>,
+    ipv6_listen_port: Option<Port>,
+    /// Created with the first stolen port and removed with the last one, so nothing gets
+    /// redirected while no one is stealing.
+    iptables: Option<DualStackIpTables<Box<dyn IPTables + Send>>>,
+    ports: StolenPorts,
+    /// The client each connection was stolen for.
+    connection_clients: HashMap<ConnectionId, ClientID>,
+    write_streams: HashMap<ConnectionId, WriteHalf<Box<dyn StolenStream>>>,
+    read_streams: StreamMap<ConnectionId, ReaderStream<ReadHalf<Box<dyn StolenStream>>>>,
+    /// Socket of each connection, see [`StolenStream::socket`].
+    sockets: HashMap<ConnectionId, RawFd>,
+    /// Connections whose remote peer is done sending, we told the client with
+    /// [`DaemonTcp::Shutdown`].
+    read_closed: HashSet<ConnectionId>,
+    /// Connections the client is done sending on ([`LayerTcpSteal::Shutdown`]).
+    write_closed: HashSet<ConnectionId>,
     connection_index: u64,
+    http_connection_sender: Sender<StolenHttpConnection>,
+    /// Clients that asked for [`StealSession::fallback`].
+    accept_timeouts: HashMap<ClientID, Duration>,
+    pending: HashMap<ConnectionId, PendingConnection>,
+    /// Connections to be driven by [`Self::handle_loop`].
+    fallbacks: Vec<FallbackConnection>,
+    /// Where [`TlsCertificate::ContainerPath`]s are, the root of the target's filesystem.
+    ///
+    /// [`TlsCertificate::ContainerPath`]: mirrord_protocol::tcp::TlsCertificate::ContainerPath
+    container_root: PathBuf,
+    /// Clients that asked for [`StealSession::tls_termination`], or the reason their certificate
+    /// couldn't be loaded.
+    tls_terminations: HashMap<ClientID, RemoteResult<TlsTerminator>>,
+    /// Handshakes to be driven by [`Self::handle_loop`].
+    handshakes: Vec<TlsHandshake>,
+    /// Close signals of new clients, to be watched by [`Self::handle_loop`].
+    new_clients: Vec<(ClientID, oneshot::Receiver<()>)>,
 }
 
 impl StealWorker {
-    pub fn new(sender: Sender<DaemonTcp>, listen_port: Port) -> Result<Self> {
-        Ok(Self {
-            sender,
-            iptables: SafeIpTables::new(iptables::new(false).unwrap())?,
-            ports: HashSet::default(),
+    pub fn new(
+        listen_port: Port,
+        ipv6_listen_port: Option<Port>,
+        http_connection_sender: Sender<StolenHttpConnection>,
+        backend: StealBackend,
+        container_root: PathBuf,
+    ) -> Self {
+        Self {
+            clients: HashMap::default(),
+            backend,
             listen_port,
+            ipv6_listen_port,
+            iptables: None,
+            ports: StolenPorts::default(),
+            connection_clients: HashMap::default(),
             write_streams: HashMap::default(),
             read_streams: StreamMap::default(),
+            sockets: HashMap::default(),
+            read_closed: HashSet::default(),
+            write_closed: HashSet::default(),
             connection_index: 0,
-        })
+            http_connection_sender,
+            accept_timeouts: HashMap::default(),
+            pending: HashMap::default(),
+            fallbacks: Vec::new(),
+            container_root,
+            tls_terminations: HashMap::default(),
+            handshakes: Vec::new(),
+            new_clients: Vec::new(),
+        }
     }
 
     pub async fn handle_loop(
         &mut self,
-        mut rx: Receiver<LayerTcpSteal>,
+        mut receiver: Receiver<StealerCommand>,
         listener: TcpListener,
+        ipv6_listener: Option<TcpListener>,
+        mut http_connection_receiver: Receiver<StolenHttpConnection>,
+        cancel_token: CancellationToken,
     ) -> Result<()> {
+        // Filtered connections are driven here and not spawned, as they connect to the original
+        // destination and have to stay on the thread that is in the target's namespace.
+        let mut http_connections = FuturesUnordered::new();
+        let mut fallback_connections = FuturesUnordered::new();
+        let mut tls_handshakes = FuturesUnordered::new();
+        let mut closed_clients = FuturesUnordered::new();
+
         loop {
+            let accept_deadline = self.pending.values().map(|pending| pending.deadline).min();
+
             select! {
-                msg = rx.recv() => {
-                    if let Some(msg) = msg {
-                        self.handle_client_message(msg).await?;
+                command = receiver.recv() => {
+                    if let Some(command) = command {
+                        self.handle_command(command).await?;
                     } else {
-                        debug!("rx closed, breaking");
+                        debug!("receiver closed, breaking");
                         break;
                     }
                 },
-                accept = listener.accept() => {
+                accept = accept_any(&listener, ipv6_listener.as_ref()) => {
                     match accept {
                         Ok((stream, address)) => {
-                            self.handle_incoming_connection(stream, address).await?;
+                            if let Some(http_connection) =
+                                self.handle_incoming_connection(stream, address).await?
+                            {
+                                http_connections.push(http_connection.run());
+                            }
                         },
                         Err(err) => {
                             error!("accept error {err:?}");
@@ -230,51 +940,194 @@ impl StealWorker {
                         }
                     }
                 },
+                Some(stolen) = http_connection_receiver.recv() => {
+                    self.handle_stolen_http_connection(stolen).await?;
+                },
+                Some(()) = http_connections.next() => {},
+                _ = sleep_until(accept_deadline.unwrap_or_else(Instant::now)),
+                    if accept_deadline.is_some() => {
+                    self.expire_pending().await?;
+                },
+                Some(()) = fallback_connections.next() => {},
+                Some(client_id) = closed_clients.next() => {
+                    self.handle_client_closed(client_id);
+                },
+                Some(terminated) = tls_handshakes.next() => {
+                    if let Some(TerminatedConnection {
+                        stream,
+                        address,
+                        original_destination,
+                        original_tls,
+                    }) = terminated
+                    {
+                        if let Some(http_connection) = self
+                            .route_connection(
+                                Box::new(stream),
+                                address,
+                                original_destination,
+                                Some(original_tls),
+                            )
+                            .await?
+                        {
+                            http_connections.push(http_connection.run());
+                        }
+                    }
+                },
                 message = self.next() => {
-                    if let Some(message) = message {
-                        self.sender.send(message).await?;
+                    if let Some((client_id, message)) = message {
+                        self.send_message_to_client(client_id, message).await?;
                     }
+                },
+                _ = cancel_token.cancelled() => {
+                    break;
                 }
             }
+
+            fallback_connections.extend(self.fallbacks.drain(..).map(FallbackConnection::run));
+            tls_handshakes.extend(self.handshakes.drain(..).map(TlsHandshake::run));
+            closed_clients.extend(self.new_clients.drain(..).map(
+                |(client_id, closed)| async move {
+                    let _ = closed.await;
+                    client_id
+                },
+            ));
         }
         debug!("TCP Stealer exiting");
         Ok(())
     }
 
-    pub async fn handle_client_message(&mut self, message: LayerTcpSteal) -> Result<()> {
+    async fn handle_command(&mut self, command: StealerCommand) -> Result<()> {
+        let StealerCommand { client_id, command } = command;
+
+        match command {
+            StealerCommands::NewClient(sender, closed) => {
+                self.clients.insert(client_id, sender);
+                self.new_clients.push((client_id, closed));
+                Ok(())
+            }
+            // Messages still queued when the client closed would claim ports for no one.
+            StealerCommands::LayerMessage(_) if !self.clients.contains_key(&client_id) => {
+                debug!("Dropping message of closed client {client_id}");
+                Ok(())
+            }
+            StealerCommands::LayerMessage(message) => {
+                self.handle_client_message(client_id, message).await
+            }
+        }
+    }
+
+    pub async fn handle_client_message(
+        &mut self,
+        client_id: ClientID,
+        message: LayerTcpSteal,
+    ) -> Result<()> {
         use LayerTcpSteal::*;
         match message {
-            PortSubscribe(port) => {
-                if self.ports.contains(&port) {
-                    warn!("Port {port:?} is already subscribed");
-                    Ok(())
-                } else {
-                    debug!("adding redirect rule");
-                    self.iptables.add_redirect(port, self.listen_port)?;
-                    self.ports.insert(port);
-                    self.sender.send(DaemonTcp::Subscribed).await?;
-                    debug!("sent subscribed");
-                    Ok(())
+            PortSubscribe(steal_type, sources) => {
+                let port = steal_type.port();
+
+                let filter = match steal_type {
+                    StealType::All(_) => None,
+                    StealType::FilteredHttp(_, filter) => match HttpFilter::new(filter) {
+                        Ok(filter) => Some(filter),
+                        Err(err) => {
+                            error!("Invalid HTTP filter for port {port:?}: {err}");
+                            let err = ResponseError::InvalidHttpFilter(port, err.to_string());
+                            return self
+                                .send_message_to_client(client_id, DaemonTcp::SubscribeFailed(err))
+                                .await;
+                        }
+                    },
+                };
+
+                if let Some(Err(err)) = self.tls_terminations.get(&client_id) {
+                    let err = err.clone();
+                    warn!("Client {client_id} can't steal port {port:?}: {err}");
+                    return self
+                        .send_message_to_client(client_id, DaemonTcp::SubscribeFailed(err))
+                        .await;
+                }
+
+                match self.ports.claim(client_id, port, filter, sources.clone()) {
+                    Ok(true) => {
+                        debug!("adding redirect rule");
+                        if let Err(err) = self.add_redirect(port, &sources) {
+                            error!("Failed redirecting port {port:?}: {err:?}");
+                            self.ports.release(client_id, port);
+                            if self.ports.is_empty() {
+                                self.iptables = None;
+                            }
+                            return self
+                                .send_message_to_client(
+                                    client_id,
+                                    DaemonTcp::SubscribeFailed(ResponseError::RedirectFailed(
+                                        port,
+                                        err.to_string(),
+                                    )),
+                                )
+                                .await;
+                        }
+
+                        self.send_message_to_client(client_id, DaemonTcp::Subscribed)
+                            .await?;
+                        debug!("sent subscribed");
+                        Ok(())
+                    }
+                    Ok(false) => {
+                        warn!("Port {port:?} is already subscribed");
+                        Ok(())
+                    }
+                    Err(err) => {
+                        warn!("Client {client_id} can't steal port {port:?}: {err}");
+                        self.send_message_to_client(client_id, DaemonTcp::SubscribeFailed(err))
+                            .await
+                    }
                 }
             }
+            ConnectionUnsubscribe(connection_id)
+            | Data(TcpData { connection_id, .. })
+            | ConnectionAccepted(connection_id)
+            | Shutdown(connection_id)
+            | Reset(connection_id)
+                if self.connection_clients.get(&connection_id) != Some(&client_id) =>
+            {
+                warn!("Client {client_id} doesn't own connection {connection_id:?}");
+                Ok(())
+            }
+            ConnectionUnsubscribe(connection_id) if self.pending.contains_key(&connection_id) => {
+                info!("Layer refused connection {connection_id:?}, falling back");
+                self.fall_back(connection_id);
+                Ok(())
+            }
             ConnectionUnsubscribe(connection_id) => {
                 info!("Closing connection {connection_id:?}");
-                self.write_streams.remove(&connection_id);
-                self.read_streams.remove(&connection_id);
+                self.remove_connection(connection_id);
                 Ok(())
             }
             PortUnsubscribe(port) => {
-                if self.ports.remove(&port) {
-                    self.iptables.remove_redirect(port, self.listen_port)
-                } else {
-                    warn!("removing unsubscribed port {port:?}");
-                    Ok(())
+                match self.ports.release(client_id, port) {
+                    Some(sources) => self.remove_redirect(port, &sources),
+                    None => warn!("removing unsubscribed port {port:?}"),
                 }
+                Ok(())
             }
 
             Data(data) => {
                 if let Some(stream) = self.write_streams.get_mut(&data.connection_id) {
-                    stream.write_all(&data.bytes[..]).await?;
+                    if let Err(err) = stream.write_all(&data.bytes[..]).await {
+                        warn!(
+                            "Failed writing to connection {:?} with {err:?}, resetting",
+                            data.connection_id
+                        );
+                        self.remove_connection(data.connection_id);
+                        self.send_message_to_client(
+                            client_id,
+                            DaemonTcp::Reset(TcpClose {
+                                connection_id: data.connection_id,
+                            }),
+                        )
+                        .await?;
+                    }
                     Ok(())
                 } else {
                     warn!(
@@ -284,58 +1137,437 @@ impl StealWorker {
                     Ok(())
                 }
             }
+            ConnectionAccepted(connection_id) => {
+                if let Some(pending) = self.pending.remove(&connection_id) {
+                    self.add_streams(connection_id, pending.stream);
+                }
+                Ok(())
+            }
+            Shutdown(connection_id) if self.read_closed.contains(&connection_id) => {
+                debug!("Connection {connection_id:?} closed on both sides");
+                self.remove_connection(connection_id);
+                Ok(())
+            }
+            Shutdown(connection_id) => {
+                if let Some(stream) = self.write_streams.get_mut(&connection_id) {
+                    if let Err(err) = stream.shutdown().await {
+                        warn!("Failed shutting down connection {connection_id:?} with {err:?}");
+                    }
+                    self.write_closed.insert(connection_id);
+                }
+                Ok(())
+            }
+            Reset(connection_id) => {
+                if let Some(socket) = self.sockets.get(&connection_id) {
+                    if let Err(err) = reset_on_close(*socket) {
+                        warn!("Failed resetting connection {connection_id:?} with {err:?}");
+                    }
+                }
+                self.remove_connection(connection_id);
+                Ok(())
+            }
+            Session(session) => {
+                self.handle_session(client_id, session);
+                Ok(())
+            }
         }
     }
 
+    fn handle_session(&mut self, client_id: ClientID, session: StealSession) {
+        let StealSession {
+            fallback,
+            tls_termination,
+        } = session;
+
+        match fallback {
+            Some(StealFallback { accept_timeout_ms }) => {
+                self.accept_timeouts
+                    .insert(client_id, Duration::from_millis(accept_timeout_ms));
+            }
+            None => {
+                self.accept_timeouts.remove(&client_id);
+            }
+        }
+
+        match tls_termination {
+            Some(certificate) => {
+                let terminator =
+                    TlsTerminator::new(&certificate, &self.container_root).map_err(|err| {
+                        error!("Failed loading TLS certificate {certificate:?}: {err:?}");
+                        ResponseError::InvalidTlsCertificate(err.to_string())
+                    });
+                self.tls_terminations.insert(client_id, terminator);
+            }
+            None => {
+                self.tls_terminations.remove(&client_id);
+            }
+        }
+    }
+
+    /// Releases everything `client_id` stole, its pending connections go to their original
+    /// destination.
+    fn handle_client_closed(&mut self, client_id: ClientID) {
+        self.clients.remove(&client_id);
+        self.accept_timeouts.remove(&client_id);
+        self.tls_terminations.remove(&client_id);
+
+        for (port, sources) in self.ports.release_client(client_id) {
+            self.remove_redirect(port, &sources);
+        }
+
+        let connections = self
+            .connection_clients
+            .iter()
+            .filter_map(|(connection_id, owner)| (*owner == client_id).then_some(*connection_id))
+            .collect::<Vec<_>>();
+
+        for connection_id in connections {
+            self.fall_back(connection_id);
+            self.remove_connection(connection_id);
+        }
+    }
+
+    async fn send_message_to_client(
+        &mut self,
+        client_id: ClientID,
+        message: DaemonTcp,
+    ) -> Result<()> {
+        if let Some(sender) = self.clients.get(&client_id) {
+            if sender.send(message).await.is_err() {
+                warn!("failed to send message to client {client_id}");
+                self.handle_client_closed(client_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Redirects `port` from `sources` (every source when empty).
+    fn add_redirect(&mut self, port: Port, sources: &[Cidr]) -> Result<()> {
+        let iptables = match self.iptables.take() {
+            Some(iptables) => iptables,
+            None => {
+                let backend = self.backend;
+                let ip6tables =
+                    self.ipv6_listen_port
+                        .and_then(|listen_port| match backend.create(true) {
+                            Ok(ipt) => Some((ipt, listen_port)),
+                            Err(err) => {
+                                warn!(
+                                    "{backend:?} unavailable for IPv6, IPv6 connections won't be \
+                                     stolen: {err:?}"
+                                );
+                                None
+                            }
+                        });
+
+                DualStackIpTables::new(backend.create(false)?, self.listen_port, ip6tables)?
+            }
+        };
+
+        let added = iptables.add_redirect(port, sources);
+        self.iptables = Some(iptables);
+        added
+    }
+
+    /// Removes the redirect of a port that was just released from `sources`, and our chains with
+    /// the last one.
+    fn remove_redirect(&mut self, port: Port, sources: &[Cidr]) {
+        if let Some(iptables) = &self.iptables {
+            if let Err(err) = iptables.remove_redirect(port, sources) {
+                error!("Failed removing redirect of port {port:?}: {err:?}");
+            }
+        }
+
+        if self.ports.is_empty() {
+            self.iptables = None;
+        }
+    }
+
+    /// Handles a redirected connection. Connections to ports with an HTTP filter are returned, so
+    /// the caller can drive them.
+    ///
+    /// Connections of clients that asked for [`StealSession::tls_termination`] go through their
+    /// handshake first (in [`Self::handle_loop`]), and are handled like this afterwards.
     pub async fn handle_incoming_connection(
         &mut self,
         stream: TcpStream,
         address: SocketAddr,
-    ) -> Result<()> {
+    ) -> Result<Option<FilteredHttpConnection>> {
         let real_addr = orig_dst::orig_dst_addr(&stream)?;
-        if !self.ports.contains(&real_addr.port()) {
-            return Err(AgentError::UnexpectedConnection(real_addr.port()));
+
+        let terminator = self
+            .ports
+            .get(real_addr.port())
+            .and_then(|(client_id, ..)| self.tls_terminations.get(client_id))
+            .and_then(|terminator| terminator.as_ref().ok())
+            .cloned();
+
+        match terminator {
+            Some(terminator) => {
+                self.handshakes.push(TlsHandshake {
+                    stream,
+                    address,
+                    original_destination: real_addr,
+                    terminator,
+                });
+                Ok(None)
+            }
+            None => {
+                self.route_connection(Box::new(stream), address, real_addr, None)
+                    .await
+            }
         }
+    }
+
+    /// Sends a stolen connection to the client stealing its port, see
+    /// [`Self::handle_incoming_connection`].
+    async fn route_connection(
+        &mut self,
+        stream: Box<dyn StolenStream>,
+        address: SocketAddr,
+        original_destination: SocketAddr,
+        original_tls: Option<OriginalTls>,
+    ) -> Result<Option<FilteredHttpConnection>> {
+        match self.ports.get(original_destination.port()) {
+            // Connections that were redirected right before the port got released.
+            None => {
+                info!("No client stealing {original_destination:?}, falling back");
+                self.fallbacks.push(FallbackConnection {
+                    stream,
+                    original_destination,
+                    original_tls,
+                });
+                Ok(None)
+            }
+            Some((_, Some(filter), _)) => Ok(Some(FilteredHttpConnection {
+                client: stream,
+                address,
+                original_destination,
+                original_tls,
+                filter: filter.clone(),
+                layer_sender: self.http_connection_sender.clone(),
+            })),
+            Some((client_id, None, _)) => {
+                let client_id = *client_id;
+                self.new_connection(
+                    client_id,
+                    stream,
+                    address,
+                    original_destination.port(),
+                    Some(original_destination),
+                    original_tls,
+                )
+                .await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Handles the layer side of a filtered connection that got its first matching request.
+    async fn handle_stolen_http_connection(&mut self, stolen: StolenHttpConnection) -> Result<()> {
+        let StolenHttpConnection {
+            stream,
+            address,
+            original_destination,
+            original_tls,
+        } = stolen;
+
+        match self.ports.get(original_destination.port()) {
+            Some((client_id, ..)) => {
+                let client_id = *client_id;
+                self.new_connection(
+                    client_id,
+                    Box::new(stream),
+                    address,
+                    original_destination.port(),
+                    Some(original_destination),
+                    original_tls,
+                )
+                .await
+            }
+            None => {
+                info!("No client stealing {original_destination:?}, falling back");
+                self.fallbacks.push(FallbackConnection {
+                    stream: Box::new(stream),
+                    original_destination,
+                    original_tls,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Notifies `client_id` of a new connection. Connections that can fall back to their
+    /// `original_destination` wait for the layer to accept them.
+    async fn new_connection(
+        &mut self,
+        client_id: ClientID,
+        stream: Box<dyn StolenStream>,
+        address: SocketAddr,
+        destination_port: Port,
+        original_destination: Option<SocketAddr>,
+        original_tls: Option<OriginalTls>,
+    ) -> Result<()> {
         let connection_id = self.connection_index;
         self.connection_index += 1;
-
-        let (read_half, write_half) = tokio::io::split(stream);
-        self.write_streams.insert(connection_id, write_half);
-        self.read_streams
-            .insert(connection_id, ReaderStream::new(read_half));
+        self.connection_clients.insert(connection_id, client_id);
+        let alpn_protocol = original_tls
+            .as_ref()
+            .and_then(|tls| tls.alpn_protocol().map(ToOwned::to_owned));
+
+        match (
+            self.accept_timeouts.get(&client_id).copied(),
+            original_destination,
+        ) {
+            (Some(accept_timeout), Some(original_destination)) => {
+                self.pending.insert(
+                    connection_id,
+                    PendingConnection {
+                        stream,
+                        original_destination,
+                        original_tls,
+                        deadline: Instant::now() + accept_timeout,
+                    },
+                );
+            }
+            _ => self.add_streams(connection_id, stream),
+        }
 
         let new_connection = DaemonTcp::NewConnection(NewTcpConnection {
             connection_id,
-            destination_port: real_addr.port(),
+            destination_port,
             source_port: address.port(),
             address: address.ip(),
+            alpn_protocol,
         });
-        self.sender.send(new_connection).await?;
+        self.send_message_to_client(client_id, new_connection)
+            .await?;
         debug!("sent new connection");
         Ok(())
     }
 
-    pub async fn next(&mut self) -> Option<DaemonTcp> {
+    fn add_streams(&mut self, connection_id: ConnectionId, stream: Box<dyn StolenStream>) {
+        if let Some(socket) = stream.socket() {
+            self.sockets.insert(connection_id, socket);
+        }
+
+        let (read_half, write_half) = tokio::io::split(stream);
+        self.write_streams.insert(connection_id, write_half);
+        self.read_streams
+            .insert(connection_id, ReaderStream::new(read_half));
+    }
+
+    fn remove_connection(&mut self, connection_id: ConnectionId) {
+        self.connection_clients.remove(&connection_id);
+        self.write_streams.remove(&connection_id);
+        self.read_streams.remove(&connection_id);
+        self.sockets.remove(&connection_id);
+        self.read_closed.remove(&connection_id);
+        self.write_closed.remove(&connection_id);
+    }
+
+    /// Sends a pending connection to its original destination.
+    fn fall_back(&mut self, connection_id: ConnectionId) {
+        if let Some(PendingConnection {
+            stream,
+            original_destination,
+            original_tls,
+            ..
+        }) = self.pending.remove(&connection_id)
+        {
+            self.connection_clients.remove(&connection_id);
+            self.fallbacks.push(FallbackConnection {
+                stream,
+                original_destination,
+                original_tls,
+            });
+        }
+    }
+
+    /// Falls back the connections the layer didn't accept in time.
+    async fn expire_pending(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let expired = self
+            .pending
+            .iter()
+            .filter_map(|(connection_id, pending)| {
+                (pending.deadline <= now).then_some(*connection_id)
+            })
+            .collect::<Vec<_>>();
+
+        for connection_id in expired {
+            warn!("Layer didn't accept connection {connection_id:?} in time, falling back");
+            if let Some(client_id) = self.connection_clients.get(&connection_id).copied() {
+                self.fall_back(connection_id);
+                self.send_message_to_client(
+                    client_id,
+                    DaemonTcp::Close(TcpClose { connection_id }),
+                )
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Next message for the client that owns a stolen connection.
+    pub async fn next(&mut self) -> Option<(ClientID, DaemonTcp)> {
         let (connection_id, value) = self.read_streams.next().await?;
+        let client_id = *self.connection_clients.get(&connection_id)?;
+
         match value {
-            Some(Ok(bytes)) => Some(DaemonTcp::Data(TcpData {
-                connection_id,
-                bytes: bytes.to_vec(),
-            })),
+            Some(Ok(bytes)) => Some((
+                client_id,
+                DaemonTcp::Data(TcpData {
+                    connection_id,
+                    bytes: bytes.to_vec(),
+                }),
+            )),
             Some(Err(err)) => {
-                error!("connection id {connection_id:?} read error: {err:?}");
-                None
+                warn!("connection id {connection_id:?} read error: {err:?}, resetting");
+                self.remove_connection(connection_id);
+                Some((client_id, DaemonTcp::Reset(TcpClose { connection_id })))
+            }
+            // Both sides are done.
+            None if self.write_closed.contains(&connection_id) => {
+                self.remove_connection(connection_id);
+                Some((client_id, DaemonTcp::Close(TcpClose { connection_id })))
+            }
+            // The remote peer may still be reading, only the client's reads are done.
+            None => {
+                self.read_closed.insert(connection_id);
+                Some((client_id, DaemonTcp::Shutdown(TcpClose { connection_id })))
             }
-            None => Some(DaemonTcp::Close(TcpClose { connection_id })),
         }
     }
 }
 
+/// Accepts a connection from whichever listener gets one first.
+async fn accept_any(
+    listener: &TcpListener,
+    ipv6_listener: Option<&TcpListener>,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    match ipv6_listener {
+        Some(ipv6_listener) => select! {
+            accept = listener.accept() => accept,
+            accept = ipv6_listener.accept() => accept,
+        },
+        None => listener.accept().await,
+    }
+}
+
 pub async fn steal_worker(
-    rx: Receiver<LayerTcpSteal>,
-    tx: Sender<DaemonTcp>,
+    receiver: Receiver<StealerCommand>,
     pid: Option<u64>,
+    backend: Option<StealBackend>,
+    cancel_token: CancellationToken,
 ) -> Result<()> {
+    let container_root = match pid {
+        Some(pid) => PathBuf::from("/proc").join(pid.to_string()).join("root"),
+        None => PathBuf::from("/"),
+    };
+
     if let Some(pid) = pid {
         let namespace = PathBuf::from("/proc")
             .join(PathBuf::from(pid.to_string()))
@@ -343,12 +1575,40 @@ pub async fn steal_worker(
 
         set_namespace(namespace)?;
     }
-    debug!("preparing steal");
+    let backend = backend.unwrap_or_else(StealBackend::detect);
+    debug!("preparing steal with {backend:?}");
     let listener = TcpListener::bind("0.0.0.0:0").await?;
     let listen_port = listener.local_addr()?.port();
-    let mut worker = StealWorker::new(tx, listen_port)?;
+    let ipv6_listener = match TcpListener::bind("[::]:0").await {
+        Ok(listener) => Some(listener),
+        Err(err) => {
+            warn!("Failed listening on IPv6, IPv6 connections won't be stolen: {err:?}");
+            None
+        }
+    };
+    let ipv6_listen_port = ipv6_listener
+        .as_ref()
+        .map(TcpListener::local_addr)
+        .transpose()?
+        .map(|address| address.port());
+    let (http_connection_sender, http_connection_receiver) = mpsc::channel(1000);
+    let mut worker = StealWorker::new(
+        listen_port,
+        ipv6_listen_port,
+        http_connection_sender,
+        backend,
+        container_root,
+    );
     debug!("finished preparing steal");
-    worker.handle_loop(rx, listener).await?;
+    worker
+        .handle_loop(
+            receiver,
+            listener,
+            ipv6_listener,
+            http_connection_receiver,
+            cancel_token,
+        )
+        .await?;
     debug!("steal exiting");
 
     Ok(())
@@ -367,7 +1627,8 @@ mod orig_dst {
     pub fn orig_dst_addr(sock: &TcpStream) -> io::Result<SocketAddr> {
         use std::os::unix::io::AsRawFd;
         let fd = sock.as_raw_fd();
-        unsafe { linux::so_original_dst(fd) }
+        let ipv6 = sock.local_addr()?.is_ipv6();
+        unsafe { linux::so_original_dst(fd, ipv6) }
     }
 
     #[cfg(not(target_os = "linux"))]
@@ -389,14 +1650,20 @@ mod orig_dst {
 
         use tracing::warn;
 
-        pub unsafe fn so_original_dst(fd: RawFd) -> io::Result<SocketAddr> {
+        pub unsafe fn so_original_dst(fd: RawFd, ipv6: bool) -> io::Result<SocketAddr> {
             let mut sockaddr: libc::sockaddr_storage = mem::zeroed();
             let mut socklen: libc::socklen_t = mem::size_of::<libc::sockaddr_storage>() as u32;
 
+            let (level, name) = if ipv6 {
+                (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+            } else {
+                (libc::SOL_IP, libc::SO_ORIGINAL_DST)
+            };
+
             let ret = libc::getsockopt(
                 fd,
-                libc::SOL_IP,
-                libc::SO_ORIGINAL_DST,
+                level,
+                name,
                 &mut sockaddr as *mut _ as *mut _,
                 &mut socklen as *mut _ as *mut _,
             );
@@ -486,6 +1753,7 @@ mod orig_dst {
 #[cfg(test)]
 mod tests {
     use mockall::predicate::*;
+    use tokio::io::AsyncReadExt;
 
     use super::*;
 
@@ -536,9 +1804,9 @@ mod tests {
 
         let ipt = SafeIpTables::new(mock).expect("Create Failed");
 
-        assert!(ipt.add_redirect(69, 420).is_ok());
+        assert!(ipt.add_redirect(69, 420, &[]).is_ok());
 
-        assert!(ipt.remove_redirect(69, 420).is_ok());
+        assert!(ipt.remove_redirect(69, 420, &[]).is_ok());
     }
 
     #[test]
@@ -559,6 +1827,15 @@ mod tests {
             .times(1)
             .returning(|_| Ok(()));
 
+        mock.expect_insert_rule()
+            .with(
+                eq("OUTPUT"),
+                eq(format!("-m mark --mark {BYPASS_MARK} -j RETURN")),
+                eq(1),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
         mock.expect_add_rule()
             .with(eq("OUTPUT"), str::starts_with("-j MIRRORD_REDIRECT_"))
             .times(1)
@@ -578,6 +1855,14 @@ mod tests {
             .times(1)
             .returning(|_, _| Ok(()));
 
+        mock.expect_remove_rule()
+            .with(
+                eq("OUTPUT"),
+                eq(format!("-m mark --mark {BYPASS_MARK} -j RETURN")),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+
         mock.expect_remove_rule()
             .with(
                 str::starts_with("MIRRORD_REDIRECT_"),
@@ -588,8 +1873,583 @@ mod tests {
 
         let ipt = SafeIpTables::new(mock).expect("Create Failed");
 
-        assert!(ipt.add_redirect(69, 420).is_ok());
+        assert!(ipt.add_redirect(69, 420, &[]).is_ok());
+
+        assert!(ipt.remove_redirect(69, 420, &[]).is_ok());
+    }
+
+    /// When jumping to the new chain fails, the chain and the bypass rule are removed.
+    #[test]
+    fn failed_setup_is_rolled_back() {
+        let mut mock = MockIPTables::new();
+
+        mock.expect_list_rules()
+            .with(eq("OUTPUT"))
+            .returning(|_| Ok(vec!["-j PROXY_INIT_OUTPUT".to_owned()]));
+
+        mock.expect_create_chain()
+            .with(str::starts_with("MIRRORD_REDIRECT_"))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        mock.expect_insert_rule()
+            .with(
+                eq("OUTPUT"),
+                eq(format!("-m mark --mark {BYPASS_MARK} -j RETURN")),
+                eq(1),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        mock.expect_add_rule()
+            .with(eq("OUTPUT"), str::starts_with("-j MIRRORD_REDIRECT_"))
+            .times(1)
+            .returning(|_, _| Err(AgentError::IPTablesError("no jump".to_owned())));
+
+        mock.expect_remove_rule()
+            .with(
+                eq("OUTPUT"),
+                eq(format!("-m mark --mark {BYPASS_MARK} -j RETURN")),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        mock.expect_remove_chain()
+            .with(str::starts_with("MIRRORD_REDIRECT_"))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        assert!(SafeIpTables::new(mock).is_err());
+    }
+
+    /// Expects the chain of a single family, with port 69 redirected to `listen_port`.
+    fn mock_family(listen_port: Port) -> MockIPTables {
+        mock_family_rules(vec![format!(
+            "-m tcp -p tcp --dport 69 -j REDIRECT --to-ports {listen_port}"
+        )])
+    }
+
+    /// Expects the chain of a single family, with each of `redirect_rules` added and removed.
+    fn mock_family_rules(redirect_rules: Vec<String>) -> MockIPTables {
+        let mut mock = MockIPTables::new();
+
+        mock.expect_list_rules()
+            .with(eq("OUTPUT"))
+            .returning(|_| Ok(vec![]));
+
+        mock.expect_create_chain()
+            .with(str::starts_with("MIRRORD_REDIRECT_"))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        mock.expect_remove_chain()
+            .with(str::starts_with("MIRRORD_REDIRECT_"))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        mock.expect_add_rule()
+            .with(eq("PREROUTING"), str::starts_with("-j MIRRORD_REDIRECT_"))
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        mock.expect_remove_rule()
+            .with(eq("PREROUTING"), str::starts_with("-j MIRRORD_REDIRECT_"))
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        for redirect_rule in redirect_rules {
+            mock.expect_insert_rule()
+                .with(
+                    str::starts_with("MIRRORD_REDIRECT_"),
+                    eq(redirect_rule.clone()),
+                    eq(1),
+                )
+                .times(1)
+                .returning(|_, _, _| Ok(()));
+
+            mock.expect_remove_rule()
+                .with(str::starts_with("MIRRORD_REDIRECT_"), eq(redirect_rule))
+                .times(1)
+                .returning(|_, _| Ok(()));
+        }
+
+        mock
+    }
+
+    /// A rule that fails midway takes the rules added before it out again.
+    #[test]
+    fn failed_redirect_is_rolled_back() {
+        let mut mock = mock_family_rules(vec![]);
+        let added = "-s 10.0.0.0/8 -m tcp -p tcp --dport 69 -j REDIRECT --to-ports 420";
+        let failed = "-s 192.168.1.7/32 -m tcp -p tcp --dport 69 -j REDIRECT --to-ports 420";
+
+        mock.expect_insert_rule()
+            .with(str::starts_with("MIRRORD_REDIRECT_"), eq(added), eq(1))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        mock.expect_insert_rule()
+            .with(str::starts_with("MIRRORD_REDIRECT_"), eq(failed), eq(1))
+            .times(1)
+            .returning(|_, _, _| Err(AgentError::IPTablesError("no rule".to_owned())));
+
+        mock.expect_remove_rule()
+            .with(str::starts_with("MIRRORD_REDIRECT_"), eq(added))
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let ipt = SafeIpTables::new(mock).expect("Create Failed");
+        let sources = [
+            Cidr::new("10.1.2.3".parse().unwrap(), 8),
+            Cidr::new("192.168.1.7".parse().unwrap(), 32),
+        ];
+
+        assert!(ipt.add_redirect(69, 420, &sources).is_err());
+    }
+
+    /// When IPv6 fails, the IPv4 redirect doesn't stay behind.
+    #[test]
+    fn dual_stack_failed_ipv6_is_rolled_back() {
+        let mut ipv6 = mock_family_rules(vec![]);
+        ipv6.expect_insert_rule()
+            .with(
+                str::starts_with("MIRRORD_REDIRECT_"),
+                eq("-m tcp -p tcp --dport 69 -j REDIRECT --to-ports 421"),
+                eq(1),
+            )
+            .times(1)
+            .returning(|_, _, _| Err(AgentError::IPTablesError("no rule".to_owned())));
+
+        let ipt = DualStackIpTables::new(mock_family(420), 420, Some((ipv6, 421)))
+            .expect("Create Failed");
+
+        assert!(ipt.add_redirect(69, &[]).is_err());
+    }
+
+    #[test]
+    fn dual_stack() {
+        let ipt = DualStackIpTables::new(mock_family(420), 420, Some((mock_family(421), 421)))
+            .expect("Create Failed");
+
+        assert!(ipt.add_redirect(69, &[]).is_ok());
+
+        assert!(ipt.remove_redirect(69, &[]).is_ok());
+    }
+
+    #[test]
+    fn dual_stack_source_filter() {
+        let ipv4 = mock_family_rules(vec![
+            "-s 10.0.0.0/8 -m tcp -p tcp --dport 69 -j REDIRECT --to-ports 420".to_owned(),
+            "-s 192.168.1.7/32 -m tcp -p tcp --dport 69 -j REDIRECT --to-ports 420".to_owned(),
+        ]);
+        // No IPv6 sources, so nothing gets redirected there.
+        let ipv6 = mock_family_rules(vec![]);
+
+        let ipt = DualStackIpTables::new(ipv4, 420, Some((ipv6, 421))).expect("Create Failed");
+
+        let sources = [
+            Cidr::new("10.1.2.3".parse().unwrap(), 8),
+            Cidr::new("192.168.1.7".parse().unwrap(), 32),
+        ];
+
+        assert!(ipt.add_redirect(69, &sources).is_ok());
+
+        assert!(ipt.remove_redirect(69, &sources).is_ok());
+    }
+
+    #[test]
+    fn dual_stack_without_ipv6() {
+        let mut ipv6 = MockIPTables::new();
+
+        ipv6.expect_list_rules()
+            .with(eq("OUTPUT"))
+            .returning(|_| Err(AgentError::IPTablesError("nat table missing".to_owned())));
+
+        let ipt = DualStackIpTables::new(mock_family(420), 420, Some((ipv6, 421)))
+            .expect("Create Failed");
+
+        assert!(ipt.ipv6.is_none());
+
+        assert!(ipt.add_redirect(69, &[]).is_ok());
+
+        assert!(ipt.remove_redirect(69, &[]).is_ok());
+    }
+
+    /// Chain listing as `iptables -S` prints it, redirecting port 80 to `target_port`.
+    fn chain_rules(chain: &'static str, target_port: Option<Port>) -> Vec<String> {
+        let mut rules = vec![format!("-N {chain}")];
+        if let Some(target_port) = target_port {
+            rules.push(format!(
+                "-A {chain} -p tcp -m tcp --dport 80 -j REDIRECT --to-ports {target_port}"
+            ));
+        }
+        rules.push(format!("-A {chain} -j RETURN"));
+        rules
+    }
+
+    #[test]
+    fn remove_stale_chain() {
+        let live_listener = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+        let live_port = live_listener.local_addr().unwrap().port();
+        let stale_port = std::net::TcpListener::bind("0.0.0.0:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+
+        let mut mock = MockIPTables::new();
+
+        mock.expect_list_chains().returning(|| {
+            Ok(vec![
+                "PREROUTING".to_owned(),
+                "MIRRORD_REDIRECT_stale".to_owned(),
+                "MIRRORD_REDIRECT_alive".to_owned(),
+            ])
+        });
+
+        mock.expect_list_rules()
+            .with(eq("MIRRORD_REDIRECT_stale"))
+            .returning(move |_| Ok(chain_rules("MIRRORD_REDIRECT_stale", Some(stale_port))));
+
+        mock.expect_list_rules()
+            .with(eq("MIRRORD_REDIRECT_alive"))
+            .returning(move |_| Ok(chain_rules("MIRRORD_REDIRECT_alive", Some(live_port))));
+
+        mock.expect_list_rules()
+            .with(eq("PREROUTING"))
+            .returning(|_| {
+                Ok(vec![
+                    "-P PREROUTING ACCEPT".to_owned(),
+                    "-A PREROUTING -j MIRRORD_REDIRECT_stale".to_owned(),
+                    "-A PREROUTING -j MIRRORD_REDIRECT_alive".to_owned(),
+                ])
+            });
+
+        mock.expect_list_rules()
+            .with(eq("OUTPUT"))
+            .returning(|_| Ok(vec!["-P OUTPUT ACCEPT".to_owned()]));
+
+        mock.expect_remove_rule()
+            .with(eq("PREROUTING"), eq("-j MIRRORD_REDIRECT_stale"))
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        mock.expect_remove_chain()
+            .with(eq("MIRRORD_REDIRECT_stale"))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        assert!(remove_stale_chains(&mock, false, false).is_ok());
+    }
+
+    #[test]
+    fn force_remove_chains() {
+        let mut mock = MockIPTables::new();
+
+        mock.expect_list_chains()
+            .returning(|| Ok(vec!["MIRRORD_REDIRECT_abcde".to_owned()]));
+
+        // No redirects yet, so it's only removed because of `force`.
+        mock.expect_list_rules()
+            .with(eq("MIRRORD_REDIRECT_abcde"))
+            .returning(|_| Ok(chain_rules("MIRRORD_REDIRECT_abcde", None)));
+
+        mock.expect_list_rules()
+            .with(eq("PREROUTING"))
+            .returning(|_| Ok(vec!["-P PREROUTING ACCEPT".to_owned()]));
+
+        mock.expect_list_rules().with(eq("OUTPUT")).returning(|_| {
+            Ok(vec![
+                "-P OUTPUT ACCEPT".to_owned(),
+                format!("-A OUTPUT -m mark --mark {BYPASS_MARK:#x} -j RETURN"),
+                "-A OUTPUT -j PROXY_INIT_OUTPUT".to_owned(),
+                "-A OUTPUT -j MIRRORD_REDIRECT_abcde".to_owned(),
+            ])
+        });
+
+        mock.expect_remove_rule()
+            .with(
+                eq("OUTPUT"),
+                eq(format!("-m mark --mark {BYPASS_MARK:#x} -j RETURN")),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        mock.expect_remove_rule()
+            .with(eq("OUTPUT"), eq("-j MIRRORD_REDIRECT_abcde"))
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        mock.expect_remove_chain()
+            .with(eq("MIRRORD_REDIRECT_abcde"))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        assert!(remove_stale_chains(&mock, false, true).is_ok());
+    }
+
+    #[test]
+    fn port_ownership() {
+        let mut ports = StolenPorts::default();
+
+        let sources = vec![Cidr::new("10.0.0.0".parse().unwrap(), 8)];
+
+        assert_eq!(ports.claim(1, 80, None, sources.clone()), Ok(true));
+        assert_eq!(ports.claim(1, 80, None, vec![]), Ok(false));
+        assert_eq!(
+            ports.claim(2, 80, None, vec![]),
+            Err(ResponseError::PortAlreadyStolen(80))
+        );
+        assert_eq!(ports.claim(2, 8080, None, vec![]), Ok(true));
+
+        assert_eq!(ports.release(2, 80), None);
+        assert_eq!(ports.release_client(1), vec![(80, sources.clone())]);
+
+        assert_eq!(ports.claim(2, 80, None, sources.clone()), Ok(true));
+        assert_eq!(ports.release(2, 80), Some(sources));
+        assert_eq!(ports.release(2, 8080), Some(vec![]));
+        assert!(ports.is_empty());
+    }
+
+    /// The client is told, instead of waiting for traffic that never comes.
+    #[tokio::test]
+    async fn invalid_http_filter() {
+        let (http_connection_sender, _) = mpsc::channel(1);
+        let mut worker = StealWorker::new(
+            0,
+            None,
+            http_connection_sender,
+            StealBackend::IPTables,
+            PathBuf::from("/"),
+        );
+        let (client_sender, mut client_receiver) = mpsc::channel(1);
+        worker.clients.insert(1, client_sender);
+
+        let filter = mirrord_protocol::tcp::HttpFilter {
+            header: Some("x-user: (alice".to_owned()),
+            path: None,
+        };
+        worker
+            .handle_client_message(
+                1,
+                LayerTcpSteal::PortSubscribe(StealType::FilteredHttp(80, filter), vec![]),
+            )
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            client_receiver.recv().await,
+            Some(DaemonTcp::SubscribeFailed(
+                ResponseError::InvalidHttpFilter(80, _)
+            ))
+        ));
+        assert!(worker.ports.is_empty());
+    }
+
+    /// Dropping a client's API releases its ports, without going through the command channel.
+    #[tokio::test]
+    async fn closed_client_releases_ports() {
+        let (http_connection_sender, http_connection_receiver) = mpsc::channel(1);
+        let mut worker = StealWorker::new(
+            0,
+            None,
+            http_connection_sender,
+            StealBackend::IPTables,
+            PathBuf::from("/"),
+        );
+        worker.ports.claim(1, 80, None, vec![]).unwrap();
+
+        let (command_sender, command_receiver) = mpsc::channel(1);
+        let (client_sender, client_receiver) = mpsc::channel(1);
+        let api = TcpStealerAPI::new(1, command_sender.clone(), client_receiver, client_sender)
+            .await
+            .unwrap();
+
+        let cancel_token = CancellationToken::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let worker = tokio::spawn({
+            let cancel_token = cancel_token.clone();
+            async move {
+                worker
+                    .handle_loop(
+                        command_receiver,
+                        listener,
+                        None,
+                        http_connection_receiver,
+                        cancel_token,
+                    )
+                    .await
+                    .unwrap();
+                worker
+            }
+        });
+
+        drop(api);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        cancel_token.cancel();
+
+        let worker = worker.await.unwrap();
+        assert!(worker.clients.is_empty());
+        assert!(worker.ports.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fallback_forwards_to_original() {
+        let original = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, stolen) = tokio::io::duplex(1024);
+
+        let fallback = FallbackConnection {
+            stream: Box::new(stolen),
+            original_destination: original.local_addr().unwrap(),
+            original_tls: None,
+        };
+        tokio::spawn(fallback.run());
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nhost: pod\r\n\r\n")
+            .await
+            .unwrap();
+
+        let (mut server, _) = original.accept().await.unwrap();
+        let mut request = [0; 29];
+        server.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"GET / HTTP/1.1\r\nhost: pod\r\n\r\n");
+
+        server.write_all(b"HTTP/1.1 200 OK\r\n").await.unwrap();
+        let mut response = [0; 17];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 OK\r\n");
+    }
+
+    /// The matching requests of a filtered connection the layer doesn't accept in time go to the
+    /// original destination.
+    #[tokio::test]
+    async fn filtered_connection_falls_back() {
+        let original = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let original_destination = original.local_addr().unwrap();
+
+        let (http_connection_sender, _) = mpsc::channel(1);
+        let mut worker = StealWorker::new(
+            0,
+            None,
+            http_connection_sender,
+            StealBackend::IPTables,
+            PathBuf::from("/"),
+        );
+        let (client_sender, mut client_receiver) = mpsc::channel(2);
+        worker.clients.insert(1, client_sender);
+        worker.accept_timeouts.insert(1, Duration::ZERO);
+        worker
+            .ports
+            .claim(1, original_destination.port(), None, vec![])
+            .unwrap();
+
+        let (mut client, stolen) = tokio::io::duplex(1024);
+        worker
+            .handle_stolen_http_connection(StolenHttpConnection {
+                stream: stolen,
+                address: "10.0.0.2:1234".parse().unwrap(),
+                original_destination,
+                original_tls: None,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            client_receiver.recv().await,
+            Some(DaemonTcp::NewConnection(_))
+        ));
+
+        worker.expire_pending().await.unwrap();
+        assert_eq!(
+            client_receiver.recv().await,
+            Some(DaemonTcp::Close(TcpClose { connection_id: 0 }))
+        );
+        tokio::spawn(worker.fallbacks.pop().unwrap().run());
+
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let (mut server, _) = original.accept().await.unwrap();
+        let mut request = [0; 16];
+        server.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"GET / HTTP/1.1\r\n");
+    }
+
+    /// A stolen connection with its remote peer, owned by client `1`.
+    async fn stolen_connection() -> (StealWorker, TcpStream) {
+        let (http_connection_sender, _) = mpsc::channel(1);
+        let mut worker = StealWorker::new(
+            0,
+            None,
+            http_connection_sender,
+            StealBackend::IPTables,
+            PathBuf::from("/"),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stolen, _) = listener.accept().await.unwrap();
+
+        worker.connection_clients.insert(0, 1);
+        worker.add_streams(0, Box::new(stolen));
+
+        (worker, remote)
+    }
+
+    /// The remote peer's FIN only ends what the client reads, the client can still answer before
+    /// shutting down its side too.
+    #[tokio::test]
+    async fn half_close() {
+        let (mut worker, mut remote) = stolen_connection().await;
+
+        remote.write_all(b"request").await.unwrap();
+        remote.shutdown().await.unwrap();
+
+        assert_eq!(
+            worker.next().await,
+            Some((
+                1,
+                DaemonTcp::Data(TcpData {
+                    connection_id: 0,
+                    bytes: b"request".to_vec()
+                })
+            ))
+        );
+        assert_eq!(
+            worker.next().await,
+            Some((1, DaemonTcp::Shutdown(TcpClose { connection_id: 0 })))
+        );
+
+        worker
+            .handle_client_message(
+                1,
+                LayerTcpSteal::Data(TcpData {
+                    connection_id: 0,
+                    bytes: b"response".to_vec(),
+                }),
+            )
+            .await
+            .unwrap();
+        worker
+            .handle_client_message(1, LayerTcpSteal::Shutdown(0))
+            .await
+            .unwrap();
+        assert!(worker.write_streams.is_empty());
+
+        let mut response = Vec::new();
+        remote.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+    }
+
+    #[tokio::test]
+    async fn reset_by_client() {
+        let (mut worker, mut remote) = stolen_connection().await;
+
+        worker
+            .handle_client_message(1, LayerTcpSteal::Reset(0))
+            .await
+            .unwrap();
 
-        assert!(ipt.remove_redirect(69, 420).is_ok());
+        let err = remote.read(&mut [0; 8]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
     }
 }
@@ -0,0 +1,780 @@
+//! HTTP aware stealing.
+//!
+//! Connections to ports subscribed with [`StealType::FilteredHttp`] are parsed request by
+//! request. Requests matching the client's [`HttpFilter`] are sent to the layer, everything else
+//! is forwarded to the original destination, so the remote application keeps serving other users.
+//! Connections that start with the HTTP/2 preface are handed over to [`http2`].
+//!
+//! [`StealType::FilteredHttp`]: mirrord_protocol::tcp::StealType::FilteredHttp
+
+use std::{io, net::SocketAddr, ops::Range, os::unix::io::AsRawFd};
+
+use bytes::BytesMut;
+use httparse::Status;
+use mirrord_protocol::tcp;
+use regex::Regex;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::{TcpSocket, TcpStream},
+    sync::mpsc::Sender,
+};
+use tracing::{debug, trace, warn};
+
+use super::{
+    http2::{self, Rewind, PREFACE},
+    tls::OriginalTls,
+    StolenStream,
+};
+
+/// Mark set on connections the agent makes to the original destination, so the redirect rules
+/// can let them through instead of stealing them again.
+pub(super) const BYPASS_MARK: u32 = 0x6d6972;
+
+/// Size of the in-memory pipe between a filtered connection and the layer.
+const LAYER_PIPE_SIZE: usize = 64 * 1024;
+
+/// Maximum amount of headers we parse in a single HTTP message.
+const MAX_HEADERS: usize = 64;
+
+/// Sent to clients that wait for it before sending the body of a request.
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// Compiled version of [`tcp::HttpFilter`].
+#[derive(Debug, Clone)]
+pub(super) struct HttpFilter {
+    header: Option<Regex>,
+    path: Option<Regex>,
+}
+
+impl HttpFilter {
+    pub(super) fn new(filter: tcp::HttpFilter) -> Result<Self, regex::Error> {
+        Ok(Self {
+            header: filter.header.as_deref().map(Regex::new).transpose()?,
+            path: filter.path.as_deref().map(Regex::new).transpose()?,
+        })
+    }
+
+    /// A request matches when it passes every filter that is set.
+    pub(super) fn matches<'a>(
+        &self,
+        path: &str,
+        mut headers: impl Iterator<Item = (&'a str, &'a str)>,
+    ) -> bool {
+        let path_matches = self
+            .path
+            .as_ref()
+            .map_or(true, |filter| filter.is_match(path));
+
+        let header_matches = self.header.as_ref().map_or(true, |filter| {
+            headers.any(|(name, value)| filter.is_match(&format!("{name}: {value}")))
+        });
+
+        path_matches && header_matches
+    }
+}
+
+/// The layer side of a filtered connection, sent to the [`StealWorker`](super::StealWorker) when
+/// the first matching request arrives, so it can be handled like any other stolen connection.
+#[derive(Debug)]
+pub(crate) struct StolenHttpConnection {
+    pub stream: DuplexStream,
+    pub address: SocketAddr,
+    /// Where the connection falls back to if the layer doesn't accept it in time.
+    pub(super) original_destination: SocketAddr,
+    pub(super) original_tls: Option<OriginalTls>,
+}
+
+#[derive(Debug)]
+struct RequestHead {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    /// Where the `Expect: 100-continue` line is in the head, the client doesn't send the body
+    /// until it gets a `100 Continue`.
+    expect_continue: Option<Range<usize>>,
+    length: usize,
+}
+
+#[derive(Debug)]
+struct ResponseHead {
+    status: u16,
+    headers: Vec<(String, String)>,
+    length: usize,
+}
+
+/// A full HTTP message as it was read from the stream.
+#[derive(Debug)]
+struct HttpMessage<H> {
+    head: H,
+    raw: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum BodyLength {
+    Fixed(usize),
+    Chunked,
+    UntilClose,
+}
+
+fn owned_headers(headers: &[httparse::Header]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|header| {
+            (
+                header.name.to_owned(),
+                String::from_utf8_lossy(header.value).into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// Iterates over the values of every header called `name`.
+fn header_values<'a>(
+    headers: &'a [(String, String)],
+    name: &'a str,
+) -> impl Iterator<Item = &'a str> + 'a {
+    headers
+        .iter()
+        .filter(move |(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn has_token(headers: &[(String, String)], name: &str, token: &str) -> bool {
+    header_values(headers, name)
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+fn content_length(headers: &[(String, String)]) -> io::Result<Option<usize>> {
+    header_values(headers, "content-length")
+        .next()
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid content-length"))
+        })
+        .transpose()
+}
+
+fn invalid_data(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// Where the line of `header` (with its line break) is in `buffer`, which it was parsed from.
+fn header_line(buffer: &[u8], header: &httparse::Header) -> Range<usize> {
+    let start = header.name.as_ptr() as usize - buffer.as_ptr() as usize;
+    let value_end = header.value.as_ptr() as usize - buffer.as_ptr() as usize + header.value.len();
+    let end = buffer[value_end..]
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(value_end, |position| value_end + position + 1);
+
+    start..end
+}
+
+fn parse_request(buffer: &[u8]) -> io::Result<Option<RequestHead>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+
+    match request.parse(buffer).map_err(invalid_data)? {
+        Status::Complete(length) => Ok(Some(RequestHead {
+            method: request.method.unwrap_or_default().to_owned(),
+            path: request.path.unwrap_or_default().to_owned(),
+            headers: owned_headers(request.headers),
+            expect_continue: request
+                .headers
+                .iter()
+                .find(|header| {
+                    header.name.eq_ignore_ascii_case("expect")
+                        && header.value.eq_ignore_ascii_case(b"100-continue")
+                })
+                // HTTP/1.0 servers ignore `Expect`.
+                .filter(|_| request.version == Some(1))
+                .map(|header| header_line(buffer, header)),
+            length,
+        })),
+        Status::Partial => Ok(None),
+    }
+}
+
+fn parse_response(buffer: &[u8]) -> io::Result<Option<ResponseHead>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+
+    match response.parse(buffer).map_err(invalid_data)? {
+        Status::Complete(length) => Ok(Some(ResponseHead {
+            status: response.code.unwrap_or_default(),
+            headers: owned_headers(response.headers),
+            length,
+        })),
+        Status::Partial => Ok(None),
+    }
+}
+
+impl RequestHead {
+    fn matches(&self, filter: &HttpFilter) -> bool {
+        filter.matches(
+            &self.path,
+            self.headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        )
+    }
+
+    fn body_length(&self) -> io::Result<BodyLength> {
+        if has_token(&self.headers, "transfer-encoding", "chunked") {
+            Ok(BodyLength::Chunked)
+        } else {
+            Ok(BodyLength::Fixed(
+                content_length(&self.headers)?.unwrap_or(0),
+            ))
+        }
+    }
+
+    fn closes_connection(&self) -> bool {
+        has_token(&self.headers, "connection", "close")
+    }
+}
+
+impl ResponseHead {
+    /// Informational responses (other than `101 Switching Protocols`) are followed by the final
+    /// response to the same request.
+    fn is_informational(&self) -> bool {
+        (100..200).contains(&self.status) && self.status != 101
+    }
+
+    fn is_upgrade(&self, request: &RequestHead) -> bool {
+        self.status == 101 || (request.method == "CONNECT" && (200..300).contains(&self.status))
+    }
+
+    fn body_length(&self, request: &RequestHead) -> io::Result<BodyLength> {
+        if request.method == "HEAD"
+            || self.is_informational()
+            || self.is_upgrade(request)
+            || self.status == 204
+            || self.status == 304
+        {
+            Ok(BodyLength::Fixed(0))
+        } else if has_token(&self.headers, "transfer-encoding", "chunked") {
+            Ok(BodyLength::Chunked)
+        } else {
+            Ok(content_length(&self.headers)?
+                .map(BodyLength::Fixed)
+                .unwrap_or(BodyLength::UntilClose))
+        }
+    }
+
+    fn closes_connection(&self) -> bool {
+        has_token(&self.headers, "connection", "close")
+    }
+}
+
+/// Reads whole HTTP/1.x messages (head and body) out of a stream.
+struct MessageReader<R> {
+    stream: R,
+    buffer: BytesMut,
+}
+
+impl<R> MessageReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn new(stream: R) -> Self {
+        Self {
+            stream,
+            buffer: BytesMut::with_capacity(4096),
+        }
+    }
+
+    /// Reads more bytes into the buffer, returns `false` on EOF.
+    async fn fill(&mut self) -> io::Result<bool> {
+        Ok(self.stream.read_buf(&mut self.buffer).await? != 0)
+    }
+
+    async fn fill_to(&mut self, length: usize) -> io::Result<()> {
+        while self.buffer.len() < length {
+            if !self.fill().await? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads until `parse` gives back a message head. Returns `None` if the stream was closed
+    /// cleanly between messages.
+    async fn read_head<H>(
+        &mut self,
+        parse: fn(&[u8]) -> io::Result<Option<H>>,
+    ) -> io::Result<Option<H>> {
+        loop {
+            if let Some(head) = parse(&self.buffer)? {
+                return Ok(Some(head));
+            }
+
+            if !self.fill().await? {
+                return if self.buffer.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::ErrorKind::UnexpectedEof.into())
+                };
+            }
+        }
+    }
+
+    /// Finds where a message that starts at the beginning of the buffer ends, reading its body.
+    async fn message_end(&mut self, head_length: usize, body: BodyLength) -> io::Result<usize> {
+        match body {
+            BodyLength::Fixed(length) => {
+                self.fill_to(head_length + length).await?;
+                Ok(head_length + length)
+            }
+            BodyLength::Chunked => {
+                let mut end = head_length;
+
+                loop {
+                    let (consumed, size) = loop {
+                        match httparse::parse_chunk_size(&self.buffer[end..])
+                            .map_err(|_| invalid_data("invalid chunk size"))?
+                        {
+                            Status::Complete(chunk) => break chunk,
+                            Status::Partial => self.fill_to(self.buffer.len() + 1).await?,
+                        }
+                    };
+                    end += consumed;
+
+                    if size == 0 {
+                        break;
+                    }
+
+                    // Chunk data is followed by CRLF.
+                    end += size as usize + 2;
+                    self.fill_to(end).await?;
+                }
+
+                // Trailer fields, until an empty line.
+                loop {
+                    let line_length = loop {
+                        match self.buffer[end..]
+                            .windows(2)
+                            .position(|window| window == b"\r\n")
+                        {
+                            Some(position) => break position,
+                            None => self.fill_to(self.buffer.len() + 1).await?,
+                        }
+                    };
+                    end += line_length + 2;
+
+                    if line_length == 0 {
+                        break Ok(end);
+                    }
+                }
+            }
+            BodyLength::UntilClose => {
+                while self.fill().await? {}
+                Ok(self.buffer.len())
+            }
+        }
+    }
+
+    /// Reads a request, answering `Expect: 100-continue` on `client` before reading the body, as
+    /// we need the whole request to send it anywhere. The `Expect` line is removed from the
+    /// request, so the upstream doesn't send another `100 Continue`.
+    async fn read_request<W>(
+        &mut self,
+        client: &mut W,
+    ) -> io::Result<Option<HttpMessage<RequestHead>>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        match self.read_head(parse_request).await? {
+            Some(mut head) => {
+                if let Some(line) = head.expect_continue.take() {
+                    client.write_all(CONTINUE).await?;
+
+                    let rest = self.buffer.split_off(line.end);
+                    self.buffer.truncate(line.start);
+                    self.buffer.unsplit(rest);
+                    head.length -= line.len();
+                }
+
+                let end = self.message_end(head.length, head.body_length()?).await?;
+                let raw = self.buffer.split_to(end).to_vec();
+
+                Ok(Some(HttpMessage { head, raw }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the response to `request`, including any informational responses that precede it.
+    async fn read_response(
+        &mut self,
+        request: &RequestHead,
+    ) -> io::Result<Option<HttpMessage<ResponseHead>>> {
+        let mut raw = Vec::new();
+
+        loop {
+            let head = match self.read_head(parse_response).await? {
+                Some(head) => head,
+                None if raw.is_empty() => return Ok(None),
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+
+            let end = self
+                .message_end(head.length, head.body_length(request)?)
+                .await?;
+            raw.extend_from_slice(&self.buffer.split_to(end));
+
+            if !head.is_informational() {
+                return Ok(Some(HttpMessage { head, raw }));
+            }
+        }
+    }
+}
+
+/// A connection requests get sent to, either the original destination or the layer.
+struct Upstream {
+    reader: MessageReader<Box<dyn AsyncRead + Send + Unpin>>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+}
+
+impl Upstream {
+    fn new<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read_half, write_half) = tokio::io::split(stream);
+
+        Self {
+            reader: MessageReader::new(Box::new(read_half)),
+            writer: Box::new(write_half),
+        }
+    }
+}
+
+/// Connects to the original destination of a stolen connection, bypassing our redirect rules.
+pub(super) async fn connect_original(address: SocketAddr) -> io::Result<TcpStream> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    let mark = BYPASS_MARK;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&mark) as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        warn!(
+            "failed setting SO_MARK on connection to {address:?}: {:?}",
+            io::Error::last_os_error()
+        );
+    }
+
+    socket.connect(address).await
+}
+
+/// [`connect_original`], encrypted again if the agent terminated TLS on the stolen connection.
+pub(super) async fn reconnect_original(
+    address: SocketAddr,
+    tls: Option<&OriginalTls>,
+) -> io::Result<Box<dyn StolenStream>> {
+    let stream = connect_original(address).await?;
+
+    match tls {
+        Some(tls) => Ok(Box::new(tls.connect(stream).await?)),
+        None => Ok(Box::new(stream)),
+    }
+}
+
+/// Creates the layer side of a filtered connection and hands it over to the steal worker,
+/// returning our side of it.
+pub(super) async fn steal_pipe(
+    layer_sender: &Sender<StolenHttpConnection>,
+    address: SocketAddr,
+    original_destination: SocketAddr,
+    original_tls: Option<&OriginalTls>,
+) -> io::Result<DuplexStream> {
+    let (ours, theirs) = tokio::io::duplex(LAYER_PIPE_SIZE);
+
+    layer_sender
+        .send(StolenHttpConnection {
+            stream: theirs,
+            address,
+            original_destination,
+            original_tls: original_tls.cloned(),
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "steal worker closed"))?;
+
+    Ok(ours)
+}
+
+/// Reads enough of `stream` to tell whether it starts with the HTTP/2 connection preface.
+async fn read_preface<S>(stream: &mut S, buffer: &mut BytesMut) -> io::Result<bool>
+where
+    S: AsyncRead + Unpin,
+{
+    while buffer.len() < PREFACE.len() && PREFACE.starts_with(buffer) {
+        if stream.read_buf(buffer).await? == 0 {
+            break;
+        }
+    }
+
+    Ok(buffer.starts_with(PREFACE))
+}
+
+/// A connection to a port stolen with an HTTP filter.
+pub(crate) struct FilteredHttpConnection {
+    pub(super) client: Box<dyn StolenStream>,
+    pub(super) address: SocketAddr,
+    pub(super) original_destination: SocketAddr,
+    /// Set when the agent terminated TLS on `client`.
+    pub(super) original_tls: Option<OriginalTls>,
+    pub(super) filter: HttpFilter,
+    pub(super) layer_sender: Sender<StolenHttpConnection>,
+}
+
+impl FilteredHttpConnection {
+    pub(super) async fn run(self) {
+        let address = self.address;
+
+        if let Err(err) = self.filter_requests().await {
+            warn!("filtered http connection from {address:?} failed with {err:?}");
+        }
+    }
+
+    async fn filter_requests(self) -> io::Result<()> {
+        let FilteredHttpConnection {
+            mut client,
+            address,
+            original_destination,
+            original_tls,
+            filter,
+            layer_sender,
+        } = self;
+
+        let mut buffer = BytesMut::with_capacity(4096);
+        if read_preface(&mut client, &mut buffer).await? {
+            return http2::filter_streams(
+                Rewind::new(buffer.freeze(), client),
+                address,
+                original_destination,
+                original_tls,
+                filter,
+                layer_sender,
+            )
+            .await;
+        }
+
+        let (client_read, mut client_write) = tokio::io::split(client);
+        let mut client = MessageReader {
+            stream: client_read,
+            buffer,
+        };
+
+        let mut original = None;
+        let mut layer = None;
+
+        while let Some(request) = client.read_request(&mut client_write).await? {
+            let stolen = request.head.matches(&filter);
+            trace!(
+                "{} {} {} from {:?}",
+                if stolen { "stealing" } else { "forwarding" },
+                request.head.method,
+                request.head.path,
+                address
+            );
+
+            let upstream = if stolen {
+                match &mut layer {
+                    Some(upstream) => upstream,
+                    None => layer.insert(Upstream::new(
+                        steal_pipe(
+                            &layer_sender,
+                            address,
+                            original_destination,
+                            original_tls.as_ref(),
+                        )
+                        .await?,
+                    )),
+                }
+            } else {
+                match &mut original {
+                    Some(upstream) => upstream,
+                    None => original.insert(Upstream::new(
+                        reconnect_original(original_destination, original_tls.as_ref()).await?,
+                    )),
+                }
+            };
+
+            upstream.writer.write_all(&request.raw).await?;
+
+            let response = match upstream.reader.read_response(&request.head).await? {
+                Some(response) => response,
+                None => {
+                    debug!("upstream closed before responding, closing connection");
+                    break;
+                }
+            };
+            client_write.write_all(&response.raw).await?;
+
+            if response.head.is_upgrade(&request.head) {
+                // The connection isn't HTTP anymore, so it stays where the upgrade went.
+                client_write.write_all(&upstream.reader.buffer).await?;
+                upstream.writer.write_all(&client.buffer).await?;
+
+                futures::try_join!(
+                    tokio::io::copy(&mut client.stream, &mut upstream.writer),
+                    tokio::io::copy(&mut upstream.reader.stream, &mut client_write),
+                )?;
+                break;
+            }
+
+            if request.head.closes_connection()
+                || response.head.closes_connection()
+                || response.head.body_length(&request.head)? == BodyLength::UntilClose
+            {
+                break;
+            }
+        }
+
+        client_write.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(header: Option<&str>, path: Option<&str>) -> HttpFilter {
+        HttpFilter::new(tcp::HttpFilter {
+            header: header.map(ToOwned::to_owned),
+            path: path.map(ToOwned::to_owned),
+        })
+        .unwrap()
+    }
+
+    fn request(raw: &[u8]) -> RequestHead {
+        parse_request(raw).unwrap().unwrap()
+    }
+
+    #[test]
+    fn filter_matches() {
+        let alice = request(b"GET /api/users HTTP/1.1\r\nHost: a\r\nx-mirrord-user: alice\r\n\r\n");
+        let bob = request(b"GET /health HTTP/1.1\r\nHost: a\r\nx-mirrord-user: bob\r\n\r\n");
+
+        let by_header = filter(Some("x-mirrord-user: alice"), None);
+        assert!(alice.matches(&by_header));
+        assert!(!bob.matches(&by_header));
+
+        let by_path = filter(None, Some("^/api/"));
+        assert!(alice.matches(&by_path));
+        assert!(!bob.matches(&by_path));
+
+        let both = filter(Some("(?i)X-MIRRORD-USER: bob"), Some("^/api/"));
+        assert!(!alice.matches(&both));
+        assert!(!bob.matches(&both));
+    }
+
+    #[tokio::test]
+    async fn read_pipelined_requests() {
+        let raw: &[u8] = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\n\r\nPOST /c HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n0\r\nTrailer: yes\r\n\r\n";
+        let mut reader = MessageReader::new(raw);
+
+        let first = reader
+            .read_request(&mut tokio::io::sink())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.head.path, "/a");
+        assert!(first.raw.ends_with(b"\r\n\r\nhello"));
+
+        let second = reader
+            .read_request(&mut tokio::io::sink())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.head.path, "/b");
+        assert_eq!(second.raw, b"GET /b HTTP/1.1\r\n\r\n");
+
+        let third = reader
+            .read_request(&mut tokio::io::sink())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(third.head.path, "/c");
+        assert!(third.raw.ends_with(b"0\r\nTrailer: yes\r\n\r\n"));
+
+        assert!(reader
+            .read_request(&mut tokio::io::sink())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn read_response_after_continue() {
+        let head = request(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 1\r\n\r\n");
+        let raw: &[u8] =
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nokHTTP/1.1";
+        let mut reader = MessageReader::new(raw);
+
+        let response = reader.read_response(&head).await.unwrap().unwrap();
+        assert_eq!(response.head.status, 200);
+        assert!(response.raw.starts_with(b"HTTP/1.1 100 Continue"));
+        assert!(response.raw.ends_with(b"\r\n\r\nok"));
+        assert_eq!(&reader.buffer[..], b"HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn read_request_after_continue() {
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let (stream_read, mut stream_write) = tokio::io::split(stream);
+        client
+            .write_all(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n")
+            .await
+            .unwrap();
+
+        let reader = tokio::spawn(async move {
+            MessageReader::new(stream_read)
+                .read_request(&mut stream_write)
+                .await
+        });
+
+        // The body only goes out after the `100 Continue`.
+        let mut interim = [0; CONTINUE.len()];
+        client.read_exact(&mut interim).await.unwrap();
+        assert_eq!(interim, CONTINUE);
+        client.write_all(b"hello").await.unwrap();
+
+        let request = reader.await.unwrap().unwrap().unwrap();
+        assert_eq!(request.head.length, request.raw.len() - 5);
+        assert_eq!(
+            request.raw,
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello"
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_request_fails() {
+        let raw: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort";
+        let mut reader = MessageReader::new(raw);
+
+        assert_eq!(
+            reader
+                .read_request(&mut tokio::io::sink())
+                .await
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
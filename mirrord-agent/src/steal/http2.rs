@@ -0,0 +1,530 @@
+//! HTTP/2 (h2c) aware stealing.
+//!
+//! A single HTTP/2 connection carries many requests, so instead of stealing the connection we
+//! terminate it here and route each stream on its own. Matching streams go through an HTTP/2
+//! connection to the layer (which sees a regular stolen TCP connection), the rest are
+//! re-originated to the original destination.
+//!
+//! Nothing here is spawned: the streams and upstream connections are driven by
+//! [`filter_streams`], so the connections to the original destination are made from the steal
+//! worker's thread, which is in the target's network namespace.
+
+use std::{
+    future::{poll_fn, Future},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::{stream::FuturesUnordered, StreamExt};
+use h2::{client::SendRequest, server::SendResponse, Reason, RecvStream, SendStream};
+use http::{Request, Response};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    select,
+    sync::{
+        mpsc::{self, Sender, UnboundedSender},
+        OnceCell,
+    },
+};
+use tracing::{debug, trace};
+
+use super::{
+    http::{reconnect_original, steal_pipe, HttpFilter, StolenHttpConnection},
+    tls::OriginalTls,
+};
+
+/// The client connection preface every HTTP/2 connection starts with.
+pub(super) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Replays bytes that were already read from `inner` before reading from it again.
+pub(super) struct Rewind<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub(super) fn new(prefix: Bytes, inner: S) -> Self {
+        Self { prefix, inner }
+    }
+}
+
+impl<S> AsyncRead for Rewind<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let amount = self.prefix.len().min(buf.remaining());
+            let prefix = self.prefix.split_to(amount);
+            buf.put_slice(&prefix);
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for Rewind<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn h2_to_io(err: h2::Error) -> io::Error {
+    if err.is_io() {
+        err.into_io().expect("checked by is_io")
+    } else {
+        io::Error::new(io::ErrorKind::Other, err)
+    }
+}
+
+/// Starts an HTTP/2 client connection over `io`, the connection itself is returned as a task
+/// that has to be driven alongside the streams.
+async fn client_handshake<S>(io: S) -> io::Result<(SendRequest<Bytes>, Task)>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (send_request, connection) = h2::client::handshake(io).await.map_err(h2_to_io)?;
+
+    let task = Box::pin(async move {
+        if let Err(err) = connection.await {
+            debug!("http2 upstream connection closed with {err:?}");
+        }
+    });
+
+    Ok((send_request, task))
+}
+
+/// Copies a body (and its trailers) from one stream to the other.
+///
+/// Data is only sent as the receiving peer's window allows, and the sending peer's window only
+/// reopens once its data was sent on, so a slow receiver slows the sender down instead of having
+/// us buffer the body.
+async fn forward_body(from: &mut RecvStream, to: &mut SendStream<Bytes>) -> Result<(), h2::Error> {
+    while let Some(data) = from.data().await {
+        let mut data = data?;
+        let received = data.len();
+
+        while !data.is_empty() {
+            to.reserve_capacity(data.len());
+
+            let capacity = poll_fn(|cx| to.poll_capacity(cx))
+                .await
+                .ok_or(Reason::STREAM_CLOSED)??;
+            to.send_data(data.split_to(capacity.min(data.len())), false)?;
+        }
+
+        from.flow_control().release_capacity(received)?;
+    }
+
+    match from.trailers().await? {
+        Some(trailers) => to.send_trailers(trailers),
+        None => to.send_data(Bytes::new(), true),
+    }
+}
+
+/// Where the streams of a terminated connection go. Each upstream connection is made by the first
+/// stream routed to it, and shared by the following ones.
+struct Upstreams {
+    address: SocketAddr,
+    original_destination: SocketAddr,
+    original_tls: Option<OriginalTls>,
+    layer_sender: Sender<StolenHttpConnection>,
+    /// Hands the upstream connections over to [`filter_streams`], which drives them.
+    connections: UnboundedSender<Task>,
+    original: OnceCell<SendRequest<Bytes>>,
+    layer: OnceCell<SendRequest<Bytes>>,
+}
+
+impl Upstreams {
+    /// The connection to the layer if the stream is `stolen`, to the original destination
+    /// otherwise.
+    async fn get(&self, stolen: bool) -> io::Result<SendRequest<Bytes>> {
+        let upstream = if stolen { &self.layer } else { &self.original };
+
+        upstream
+            .get_or_try_init(|| async {
+                let (send_request, connection) = if stolen {
+                    client_handshake(
+                        steal_pipe(
+                            &self.layer_sender,
+                            self.address,
+                            self.original_destination,
+                            self.original_tls.as_ref(),
+                        )
+                        .await?,
+                    )
+                    .await?
+                } else {
+                    client_handshake(
+                        reconnect_original(self.original_destination, self.original_tls.as_ref())
+                            .await?,
+                    )
+                    .await?
+                };
+
+                // Closes once every stream is done with it.
+                let _ = self.connections.send(connection);
+                Ok(send_request)
+            })
+            .await
+            .cloned()
+    }
+}
+
+/// Sends a single request stream to its upstream and the response back to the client.
+async fn proxy_stream(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    upstreams: Arc<Upstreams>,
+    stolen: bool,
+) {
+    let upstream = match upstreams.get(stolen).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            debug!("http2 upstream connection failed with {err:?}");
+            respond.send_reset(Reason::REFUSED_STREAM);
+            return;
+        }
+    };
+
+    let result: Result<(), h2::Error> = async {
+        let (parts, mut request_body) = request.into_parts();
+        let request_ended = request_body.is_end_stream();
+
+        let mut upstream = upstream.ready().await?;
+        let (response, mut upstream_body) =
+            upstream.send_request(Request::from_parts(parts, ()), request_ended)?;
+
+        let forward_request = async {
+            if request_ended {
+                Ok(())
+            } else {
+                forward_body(&mut request_body, &mut upstream_body).await
+            }
+        };
+
+        let forward_response = async {
+            let (parts, mut response_body) = response.await?.into_parts();
+            let response_ended = response_body.is_end_stream();
+
+            let mut send_stream =
+                respond.send_response(Response::from_parts(parts, ()), response_ended)?;
+
+            if response_ended {
+                Ok(())
+            } else {
+                forward_body(&mut response_body, &mut send_stream).await
+            }
+        };
+
+        futures::try_join!(forward_request, forward_response).map(|_| ())
+    }
+    .await;
+
+    if let Err(err) = result {
+        debug!("http2 stream failed with {err:?}");
+        respond.send_reset(err.reason().unwrap_or(Reason::INTERNAL_ERROR));
+    }
+}
+
+/// Terminates an h2c connection from `client`, routing each request stream by `filter`.
+pub(super) async fn filter_streams<S>(
+    client: S,
+    address: SocketAddr,
+    original_destination: SocketAddr,
+    original_tls: Option<OriginalTls>,
+    filter: HttpFilter,
+    layer_sender: Sender<StolenHttpConnection>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut connection = h2::server::handshake(client).await.map_err(h2_to_io)?;
+
+    let (connections_sender, mut connections) = mpsc::unbounded_channel();
+    let upstreams = Arc::new(Upstreams {
+        address,
+        original_destination,
+        original_tls,
+        layer_sender,
+        connections: connections_sender,
+        original: OnceCell::new(),
+        layer: OnceCell::new(),
+    });
+    // The streams and the upstream connections, polled concurrently so a slow upstream or body
+    // doesn't hold the others back.
+    let mut tasks = FuturesUnordered::<Task>::new();
+
+    loop {
+        select! {
+            request = connection.accept() => {
+                let (request, respond) = match request {
+                    Some(request) => request.map_err(h2_to_io)?,
+                    None => break,
+                };
+
+                let path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+                let stolen = filter.matches(
+                    path,
+                    request
+                        .headers()
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.to_str().unwrap_or_default())),
+                );
+                trace!(
+                    "{} http2 {} {} from {:?}",
+                    if stolen { "stealing" } else { "forwarding" },
+                    request.method(),
+                    path,
+                    address
+                );
+
+                tasks.push(Box::pin(proxy_stream(request, respond, upstreams.clone(), stolen)));
+            },
+            Some(connection) = connections.recv() => tasks.push(connection),
+            Some(()) = tasks.next() => {},
+        }
+    }
+
+    // The upstream connections close once the last stream drops `upstreams`.
+    drop(upstreams);
+    loop {
+        select! {
+            Some(connection) = connections.recv() => tasks.push(connection),
+            task = tasks.next() => if task.is_none() {
+                break;
+            },
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::StatusCode;
+    use mirrord_protocol::tcp;
+    use tokio::{io::AsyncReadExt, net::TcpListener, sync::mpsc};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rewind_replays_prefix() {
+        let data: &[u8] = b" world";
+        let mut rewind = Rewind::new(Bytes::from_static(b"hello"), data);
+
+        let mut read = String::new();
+        rewind.read_to_string(&mut read).await.unwrap();
+
+        assert_eq!(read, "hello world");
+    }
+
+    /// Serves every request with a 200 response whose body is `name`.
+    async fn serve(listener: TcpListener, name: &'static str) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut connection = h2::server::handshake(stream).await.unwrap();
+
+        while let Some(Ok((_, mut respond))) = connection.accept().await {
+            let mut body = respond.send_response(Response::new(()), false).unwrap();
+            body.send_data(Bytes::from_static(name.as_bytes()), true)
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_streams_by_header() {
+        let original = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let original_destination = original.local_addr().unwrap();
+        tokio::spawn(serve(original, "original"));
+
+        let (layer_sender, mut layer_receiver) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let stolen: StolenHttpConnection = layer_receiver.recv().await.unwrap();
+            let mut connection = h2::server::handshake(stolen.stream).await.unwrap();
+
+            while let Some(Ok((_, mut respond))) = connection.accept().await {
+                let mut body = respond.send_response(Response::new(()), false).unwrap();
+                body.send_data(Bytes::from_static(b"layer"), true).unwrap();
+            }
+        });
+
+        let (client, agent) = tokio::io::duplex(64 * 1024);
+        let filter = HttpFilter::new(tcp::HttpFilter {
+            header: Some("x-mirrord-user: alice".to_owned()),
+            path: None,
+        })
+        .unwrap();
+        tokio::spawn(filter_streams(
+            agent,
+            "10.0.0.1:1234".parse().unwrap(),
+            original_destination,
+            None,
+            filter,
+            layer_sender,
+        ));
+
+        let (send_request, connection) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(connection);
+
+        for (user, expected) in [("alice", "layer"), ("bob", "original"), ("alice", "layer")] {
+            let received = send(&send_request, user, Bytes::new()).await;
+            assert_eq!(received, expected.as_bytes());
+        }
+    }
+
+    /// Sends a request from `user` with `body`, returns the response body.
+    async fn send(send_request: &SendRequest<Bytes>, user: &str, body: Bytes) -> Vec<u8> {
+        let request = Request::builder()
+            .uri("http://service/echo.Echo/Echo")
+            .header("x-mirrord-user", user)
+            .body(())
+            .unwrap();
+
+        let (response, mut request_body) = send_request
+            .clone()
+            .ready()
+            .await
+            .unwrap()
+            .send_request(request, body.is_empty())
+            .unwrap();
+        if !body.is_empty() {
+            request_body.send_data(body, true).unwrap();
+        }
+
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut body = response.into_body();
+        let mut received = Vec::new();
+        while let Some(data) = body.data().await {
+            let data = data.unwrap();
+            body.flow_control().release_capacity(data.len()).unwrap();
+            received.extend_from_slice(&data);
+        }
+
+        received
+    }
+
+    /// Starts [`filter_streams`] for a client connection, stealing alice's requests.
+    async fn filtered_client(
+        original_destination: SocketAddr,
+        layer_sender: Sender<StolenHttpConnection>,
+    ) -> SendRequest<Bytes> {
+        let (client, agent) = tokio::io::duplex(64 * 1024);
+        let filter = HttpFilter::new(tcp::HttpFilter {
+            header: Some("x-mirrord-user: alice".to_owned()),
+            path: None,
+        })
+        .unwrap();
+        tokio::spawn(filter_streams(
+            agent,
+            "10.0.0.1:1234".parse().unwrap(),
+            original_destination,
+            None,
+            filter,
+            layer_sender,
+        ));
+
+        let (send_request, connection) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(connection);
+
+        send_request
+    }
+
+    /// Answers every request with its own body.
+    async fn echo(listener: TcpListener) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut connection = h2::server::handshake(stream).await.unwrap();
+
+        while let Some(Ok((request, mut respond))) = connection.accept().await {
+            tokio::spawn(async move {
+                let mut body = request.into_body();
+                let mut received = Vec::new();
+                while let Some(data) = body.data().await {
+                    let data = data.unwrap();
+                    body.flow_control().release_capacity(data.len()).unwrap();
+                    received.extend_from_slice(&data);
+                }
+
+                let mut response = respond.send_response(Response::new(()), false).unwrap();
+                response.send_data(received.into(), true).unwrap();
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_bodies_larger_than_the_window() {
+        let original = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let original_destination = original.local_addr().unwrap();
+        tokio::spawn(echo(original));
+
+        let (layer_sender, _layer_receiver) = mpsc::channel(1);
+        let send_request = filtered_client(original_destination, layer_sender).await;
+
+        let body = Bytes::from(vec![7; 1024 * 1024]);
+        let received = send(&send_request, "bob", body.clone()).await;
+        assert_eq!(received, body);
+    }
+
+    /// A stream waiting for its upstream doesn't hold back the other streams of the connection.
+    #[tokio::test]
+    async fn streams_run_independently() {
+        let original = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let original_destination = original.local_addr().unwrap();
+        tokio::spawn(serve(original, "original"));
+
+        // The steal worker never takes the layer connection, so alice's stream waits forever.
+        let (layer_sender, _layer_receiver) = mpsc::channel(1);
+        let (busy, _) = tokio::io::duplex(1);
+        layer_sender
+            .send(StolenHttpConnection {
+                stream: busy,
+                address: "10.0.0.2:1234".parse().unwrap(),
+                original_destination,
+                original_tls: None,
+            })
+            .await
+            .unwrap();
+        let send_request = filtered_client(original_destination, layer_sender).await;
+
+        let stolen = send_request.clone();
+        tokio::spawn(async move { send(&stolen, "alice", Bytes::new()).await });
+
+        let received = tokio::time::timeout(
+            Duration::from_secs(5),
+            send(&send_request, "bob", Bytes::new()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(received, b"original");
+    }
+}
@@ -0,0 +1,360 @@
+use std::{collections::HashMap, process::Command, sync::Mutex};
+
+use mirrord_protocol::Port;
+use rand::distributions::{Alphanumeric, DistString};
+use tracing::{info, trace, warn};
+
+use super::{is_listening, IPTables};
+use crate::error::{AgentError, Result};
+
+/// Priority of our base chains, right before the default `dstnat` (-100) so our redirects win.
+const NAT_PRIORITY: i32 = -101;
+
+/// Every table we create starts with this, so we can find the ones a crashed agent left behind.
+const TABLE_PREFIX: &str = "mirrord_";
+
+/// Runs `nft` with the given command, returning its output.
+fn nft(options: &[&str], command: &str) -> Result<String> {
+    trace!("nft {options:?} {command}");
+
+    let output = Command::new("nft")
+        .args(options)
+        .arg(command)
+        .output()
+        .map_err(|e| AgentError::NfTablesError(e.to_string()))?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(AgentError::NfTablesError(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ))
+    }
+}
+
+/// Whether the network namespace we're in has any nftables tables, either native ones or the ones
+/// created by iptables-nft.
+pub(super) fn has_tables() -> bool {
+    Command::new("nft")
+        .args(["list", "tables"])
+        .output()
+        .map(|output| output.status.success() && !output.stdout.is_empty())
+        .unwrap_or(false)
+}
+
+/// nftables backend, everything lives in a table of its own, which is deleted on drop.
+///
+/// The entrypoints (`PREROUTING`/`OUTPUT`) are base chains in our table, hooked right before the
+/// system ones. Rules are given in iptables syntax (see [`nft_rule`]).
+pub(super) struct NfTables {
+    family: &'static str,
+    table: String,
+    /// nftables deletes rules by handle, these are the ones we added per `(chain, rule)`.
+    handles: Mutex<HashMap<(String, String), Vec<u64>>>,
+}
+
+impl NfTables {
+    pub(super) fn new(ipv6: bool) -> Result<Self> {
+        let family = if ipv6 { "ip6" } else { "ip" };
+        let table = format!(
+            "{TABLE_PREFIX}{}",
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 5)
+        );
+
+        nft(&[], &format!("add table {family} {table}"))?;
+
+        let nftables = Self {
+            family,
+            table,
+            handles: Mutex::default(),
+        };
+
+        for (chain, hook) in [("PREROUTING", "prerouting"), ("OUTPUT", "output")] {
+            nft(
+                &[],
+                &format!(
+                    "add chain {family} {} {chain} {{ type nat hook {hook} priority {NAT_PRIORITY}; }}",
+                    nftables.table
+                ),
+            )?;
+        }
+
+        Ok(nftables)
+    }
+
+    /// Adds (or inserts) `rule` at `position`, keeping its handle around for [`remove_rule`].
+    ///
+    /// [`remove_rule`]: IPTables::remove_rule
+    fn add(&self, verb: &str, chain: &str, position: &str, rule: &str) -> Result<()> {
+        let output = nft(
+            &["--echo", "--handle"],
+            &format!(
+                "{verb} rule {} {} {chain} {position} {}",
+                self.family,
+                self.table,
+                nft_rule(rule)?
+            ),
+        )?;
+
+        let handle = parse_handle(&output).ok_or_else(|| {
+            AgentError::NfTablesError(format!("no handle for `{rule}` in `{output}`"))
+        })?;
+
+        self.handles
+            .lock()
+            .unwrap()
+            .entry((chain.to_owned(), rule.to_owned()))
+            .or_default()
+            .push(handle);
+
+        Ok(())
+    }
+}
+
+impl IPTables for NfTables {
+    fn create_chain(&self, name: &str) -> Result<()> {
+        nft(
+            &[],
+            &format!("add chain {} {} {name}", self.family, self.table),
+        )
+        .map(|_| ())
+    }
+
+    fn remove_chain(&self, name: &str) -> Result<()> {
+        nft(
+            &[],
+            &format!("flush chain {} {} {name}", self.family, self.table),
+        )?;
+        nft(
+            &[],
+            &format!("delete chain {} {} {name}", self.family, self.table),
+        )?;
+
+        self.handles
+            .lock()
+            .unwrap()
+            .retain(|(chain, _), _| chain != name);
+
+        Ok(())
+    }
+
+    fn add_rule(&self, chain: &str, rule: &str) -> Result<()> {
+        self.add("add", chain, "", rule)
+    }
+
+    fn insert_rule(&self, chain: &str, rule: &str, index: i32) -> Result<()> {
+        // nftables indexes rules from 0, and needs the chain to have a rule at `index` already.
+        if index == 1 {
+            self.add("insert", chain, "", rule)
+        } else {
+            self.add("insert", chain, &format!("index {}", index - 1), rule)
+        }
+    }
+
+    /// Lists the rules of `chain` in the system `nat` table, so we can tell what else is
+    /// redirecting traffic (e.g. linkerd).
+    fn list_rules(&self, chain: &str) -> Result<Vec<String>> {
+        match nft(&[], &format!("list chain {} nat {chain}", self.family)) {
+            Ok(output) => Ok(output.lines().map(|line| line.trim().to_owned()).collect()),
+            // No `nat` table, or no such chain in it.
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
+    fn list_chains(&self) -> Result<Vec<String>> {
+        let output = nft(&[], &format!("list table {} {}", self.family, self.table))?;
+
+        Ok(output
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("chain "))
+            .filter_map(|chain| chain.split_whitespace().next())
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn remove_rule(&self, chain: &str, rule: &str) -> Result<()> {
+        let handle = self
+            .handles
+            .lock()
+            .unwrap()
+            .get_mut(&(chain.to_owned(), rule.to_owned()))
+            .and_then(Vec::pop)
+            .ok_or_else(|| AgentError::NfTablesError(format!("`{rule}` not found in {chain}")))?;
+
+        nft(
+            &[],
+            &format!(
+                "delete rule {} {} {chain} handle {handle}",
+                self.family, self.table
+            ),
+        )
+        .map(|_| ())
+    }
+}
+
+impl Drop for NfTables {
+    fn drop(&mut self) {
+        if let Err(err) = nft(&[], &format!("delete table {} {}", self.family, self.table)) {
+            warn!("Failed deleting nftables table {}: {err:?}", self.table);
+        }
+    }
+}
+
+/// Deletes the tables of agents that are gone, which we tell by nothing listening on the ports they
+/// redirect to anymore, or all of ours when `force`d. See [`super::clean_stale_rules`].
+pub(super) fn remove_stale_tables(force: bool) -> Result<()> {
+    for line in nft(&[], "list tables")?.lines() {
+        // `table <family> <name>`
+        let (family, table) = match line.trim().strip_prefix("table ") {
+            Some(family_table) => match family_table.split_once(' ') {
+                Some(family_table) => family_table,
+                None => continue,
+            },
+            None => continue,
+        };
+
+        if !table.starts_with(TABLE_PREFIX) {
+            continue;
+        }
+
+        let targets = nft(&[], &format!("list table {family} {table}"))?
+            .lines()
+            .filter_map(redirect_target)
+            .collect::<Vec<_>>();
+
+        if force
+            || (!targets.is_empty()
+                && !targets
+                    .iter()
+                    .any(|port| is_listening(*port, family == "ip6")))
+        {
+            info!("Removing stale nftables table {family} {table}");
+            nft(&[], &format!("delete table {family} {table}"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Port a `redirect to :<port>` rule sends traffic to.
+fn redirect_target(rule: &str) -> Option<Port> {
+    rule.split_once("redirect to :")
+        .and_then(|(_, port)| port.split_whitespace().next())
+        .and_then(|port| port.parse().ok())
+}
+
+/// Extracts the handle `nft --echo --handle` prints after a new rule.
+fn parse_handle(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .find_map(|line| line.rsplit_once("# handle "))
+        .and_then(|(_, handle)| handle.trim().parse().ok())
+}
+
+/// Translates the iptables rules we generate into nftables syntax.
+fn nft_rule(rule: &str) -> Result<String> {
+    let unsupported = || AgentError::NfTablesError(format!("unsupported rule `{rule}`"));
+
+    let mut tokens = rule.split_whitespace();
+    let mut protocol = "tcp";
+    let mut nft_rule = Vec::new();
+
+    while let Some(token) = tokens.next() {
+        match token {
+            // The match modules are implied by the matches themselves.
+            "-m" => {
+                tokens.next().ok_or_else(unsupported)?;
+            }
+            "-p" => protocol = tokens.next().ok_or_else(unsupported)?,
+            "--dport" => nft_rule.push(format!(
+                "{protocol} dport {}",
+                tokens.next().ok_or_else(unsupported)?
+            )),
+            "--mark" => nft_rule.push(format!(
+                "meta mark {}",
+                tokens.next().ok_or_else(unsupported)?
+            )),
+            "-s" => {
+                let source = tokens.next().ok_or_else(unsupported)?;
+                let family = if source.contains(':') { "ip6" } else { "ip" };
+                nft_rule.push(format!("{family} saddr {source}"))
+            }
+            "-o" => nft_rule.push(format!(
+                "oifname \"{}\"",
+                tokens.next().ok_or_else(unsupported)?
+            )),
+            "-j" => match tokens.next().ok_or_else(unsupported)? {
+                "RETURN" => nft_rule.push("return".to_owned()),
+                "REDIRECT" => match (tokens.next(), tokens.next()) {
+                    (Some("--to-ports"), Some(port)) => {
+                        nft_rule.push(format!("redirect to :{port}"))
+                    }
+                    _ => return Err(unsupported()),
+                },
+                chain => nft_rule.push(format!("jump {chain}")),
+            },
+            _ => return Err(unsupported()),
+        }
+    }
+
+    Ok(nft_rule.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_rules() {
+        assert_eq!(
+            nft_rule("-m tcp -p tcp --dport 69 -j REDIRECT --to-ports 420").unwrap(),
+            "tcp dport 69 redirect to :420"
+        );
+        assert_eq!(
+            nft_rule("-o lo -m tcp -p tcp --dport 69 -j REDIRECT --to-ports 420").unwrap(),
+            "oifname \"lo\" tcp dport 69 redirect to :420"
+        );
+        assert_eq!(
+            nft_rule("-s 10.0.0.0/8 -m tcp -p tcp --dport 69 -j REDIRECT --to-ports 420").unwrap(),
+            "ip saddr 10.0.0.0/8 tcp dport 69 redirect to :420"
+        );
+        assert_eq!(
+            nft_rule("-s fd00::/16 -m tcp -p tcp --dport 69 -j REDIRECT --to-ports 421").unwrap(),
+            "ip6 saddr fd00::/16 tcp dport 69 redirect to :421"
+        );
+        assert_eq!(
+            nft_rule("-m mark --mark 7170418 -j RETURN").unwrap(),
+            "meta mark 7170418 return"
+        );
+        assert_eq!(
+            nft_rule("-j MIRRORD_REDIRECT_abcde").unwrap(),
+            "jump MIRRORD_REDIRECT_abcde"
+        );
+    }
+
+    #[test]
+    fn translate_unsupported_rule() {
+        assert!(nft_rule("-m tcp -p tcp --sport 69 -j ACCEPT").is_err());
+        assert!(nft_rule("-j REDIRECT").is_err());
+    }
+
+    #[test]
+    fn handle_from_echo() {
+        assert_eq!(
+            parse_handle(
+                "add rule ip mirrord_abcde PREROUTING jump MIRRORD_REDIRECT_abcde # handle 4\n"
+            ),
+            Some(4)
+        );
+        assert_eq!(parse_handle("add table ip mirrord_abcde\n"), None);
+    }
+
+    #[test]
+    fn redirect_targets() {
+        assert_eq!(
+            redirect_target("tcp dport 69 redirect to :420 # handle 7"),
+            Some(420)
+        );
+        assert_eq!(redirect_target("jump MIRRORD_REDIRECT_abcde"), None);
+    }
+}
@@ -1,18 +1,15 @@
-use std::{
-    clone::Clone,
-    collections::{HashMap, HashSet},
-    future::Future,
-    hash::Hash,
-    thread::JoinHandle,
-};
+use std::{clone::Clone, collections::HashMap, future::Future, hash::Hash, thread::JoinHandle};
 
 use num_traits::{zero, CheckedAdd, Num};
 
+use crate::error::AgentError;
+
 /// Struct that helps you manage topic -> subscribers
 /// When a topip has no subscribers, it is removed.
+/// Each subscription can carry a value `V`, like the settings the client subscribed with.
 #[derive(Debug)]
-pub struct Subscriptions<T, C> {
-    _inner: HashMap<T, HashSet<C>>,
+pub struct Subscriptions<T, C, V = ()> {
+    _inner: HashMap<T, HashMap<C, V>>,
 }
 
 pub type ClientID = u32;
@@ -22,26 +19,38 @@ where
     T: Eq + Hash + Clone + Copy,
     C: Eq + Hash + Clone + Copy,
 {
-    pub fn new() -> Subscriptions<T, C> {
+    /// Add a new subscription to a topic for a given client.
+    pub fn subscribe(&mut self, client: C, topic: T) {
+        self.subscribe_with(client, topic, ())
+    }
+}
+
+impl<T, C, V> Subscriptions<T, C, V>
+where
+    T: Eq + Hash + Clone + Copy,
+    C: Eq + Hash + Clone + Copy,
+{
+    pub fn new() -> Subscriptions<T, C, V> {
         Subscriptions {
             _inner: HashMap::new(),
         }
     }
 
-    /// Add a new subscription to a topic for a given client.
-    pub fn subscribe(&mut self, client: C, topic: T) {
+    /// Add a new subscription to a topic for a given client, replacing the value of an existing
+    /// one.
+    pub fn subscribe_with(&mut self, client: C, topic: T, value: V) {
         self._inner
             .entry(topic)
-            .or_insert_with(HashSet::new)
-            .insert(client);
+            .or_insert_with(HashMap::new)
+            .insert(client, value);
     }
 
     /// Remove a subscription of given client from the topic.
     /// topic is removed if no subscribers left.
     pub fn unsubscribe(&mut self, client: C, topic: T) {
-        if let Some(set) = self._inner.get_mut(&topic) {
-            set.remove(&client);
-            if set.is_empty() {
+        if let Some(clients) = self._inner.get_mut(&topic) {
+            clients.remove(&client);
+            if clients.is_empty() {
                 self._inner.remove(&topic);
             }
         }
@@ -50,11 +59,16 @@ where
     /// Get a vector of clients subscribed to a specific topic
     pub fn get_topic_subscribers(&self, topic: T) -> Vec<C> {
         match self._inner.get(&topic) {
-            Some(clients_set) => clients_set.iter().cloned().collect(),
+            Some(clients) => clients.keys().cloned().collect(),
             None => Vec::new(),
         }
     }
 
+    /// The value the client subscribed to the topic with.
+    pub fn get_subscription(&self, topic: T, client: C) -> Option<&V> {
+        self._inner.get(&topic)?.get(&client)
+    }
+
     /// Get subscribed topics
     pub fn get_subscribed_topics(&self) -> Vec<T> {
         self._inner.keys().cloned().collect()
@@ -63,8 +77,8 @@ where
     /// Get topics subscribed by a client
     pub fn get_client_topics(&self, client: C) -> Vec<T> {
         let mut result = Vec::new();
-        for (topic, client_set) in self._inner.iter() {
-            if client_set.contains(&client) {
+        for (topic, clients) in self._inner.iter() {
+            if clients.contains_key(&client) {
                 result.push(*topic)
             }
         }
@@ -146,6 +160,16 @@ where
     })
 }
 
+/// Waits for a thread (like the ones [`run_thread`] starts) without blocking the runtime.
+pub async fn join_thread<T>(handle: JoinHandle<T>) -> Result<T, AgentError>
+where
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || handle.join())
+        .await?
+        .map_err(|_| AgentError::JoinTask)
+}
+
 #[cfg(test)]
 mod subscription_tests {
     use mirrord_protocol::Port;
@@ -183,6 +207,21 @@ mod subscription_tests {
         subscriptions.remove_topic(1);
         assert_eq!(subscriptions.get_subscribed_topics(), Vec::<u16>::new());
     }
+
+    #[test]
+    fn subscription_values() {
+        let mut subscriptions = Subscriptions::<Port, u32, &str>::new();
+        subscriptions.subscribe_with(1, 80, "first");
+        subscriptions.subscribe_with(1, 80, "second");
+        subscriptions.subscribe_with(2, 80, "other");
+
+        assert_eq!(subscriptions.get_subscription(80, 1), Some(&"second"));
+        assert_eq!(subscriptions.get_subscription(80, 2), Some(&"other"));
+        assert_eq!(subscriptions.get_subscription(8080, 1), None);
+
+        subscriptions.unsubscribe(1, 80);
+        assert_eq!(subscriptions.get_subscription(80, 1), None);
+    }
 }
 
 #[cfg(test)]
@@ -65,7 +65,7 @@ mod tests {
         let mut codec = Framed::new(stream, ClientCodec::new());
 
         codec
-            .send(ClientMessage::Tcp(LayerTcp::PortSubscribe(1337)))
+            .send(ClientMessage::Tcp(LayerTcp::PortSubscribe(1337, vec![])))
             .await
             .expect("port subscribe failed");
         assert!(matches!(
@@ -107,7 +107,8 @@ mod tests {
                 connection_id: 0,
                 address: IpAddr::V4("127.0.0.1".parse().unwrap()),
                 destination_port: 1337,
-                source_port: port
+                source_port: port,
+                alpn_protocol: None,
             }))
         );
 
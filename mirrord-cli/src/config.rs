@@ -16,6 +16,10 @@ pub(super) enum Commands {
         #[clap(value_parser)]
         path: String,
     },
+    /// Remove the traffic stealing rules mirrord left in a target (e.g. after a crash).
+    Cleanup(Box<CleanupArgs>),
+    /// Run a binary, feeding it the incoming connections recorded with `exec --record`.
+    Replay(Box<ReplayArgs>),
     // Login(LoginArgs),
 }
 
@@ -119,6 +123,14 @@ pub(super) struct ExecArgs {
     #[clap(long = "steal", value_parser)]
     pub tcp_steal: bool,
 
+    /// Steal only HTTP requests with a header matching this regex (implies `--steal`)
+    #[clap(long, value_parser)]
+    pub http_header_filter: Option<String>,
+
+    /// Steal only HTTP requests with a path matching this regex (implies `--steal`)
+    #[clap(long, value_parser)]
+    pub http_path_filter: Option<String>,
+
     /// Disable tcp/udp outgoing traffic
     #[clap(long, value_parser)]
     pub no_outgoing: bool,
@@ -134,6 +146,69 @@ pub(super) struct ExecArgs {
     /// Load config from config file
     #[clap(short = 'f', conflicts_with_all = &["target", "pod-name"], long, value_parser)]
     pub config_file: Option<PathBuf>,
+
+    /// Record the incoming connections to this file, for `mirrord replay`.
+    #[clap(long, value_parser)]
+    pub record: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub(super) struct CleanupArgs {
+    /// Target to clean up, in the same formats as `exec --target`.
+    #[clap(short, long, value_parser)]
+    pub target: String,
+
+    /// Namespace of the target. Defaults to "default".
+    #[clap(long, value_parser)]
+    pub target_namespace: Option<String>,
+
+    /// Namespace to place agent in.
+    #[clap(short = 'a', long, value_parser)]
+    pub agent_namespace: Option<String>,
+
+    /// Agent log level
+    #[clap(short = 'l', long, value_parser)]
+    pub agent_log_level: Option<String>,
+
+    /// Agent image
+    #[clap(short = 'i', long, value_parser)]
+    pub agent_image: Option<String>,
+
+    /// Use an Ephemeral Container to clean up.
+    #[clap(short, long, value_parser)]
+    pub ephemeral_container: bool,
+
+    /// Accept/reject invalid certificates.
+    #[clap(short = 'c', long, value_parser)]
+    pub accept_invalid_certificates: bool,
+}
+
+#[derive(Args, Debug)]
+pub(super) struct ReplayArgs {
+    /// Recording to replay, made with `exec --record`.
+    #[clap(short, long, value_parser)]
+    pub file: PathBuf,
+
+    /// Replay speed relative to the recording, e.g. 2 is twice as fast. 0 doesn't wait between
+    /// messages at all.
+    #[clap(long, default_value = "1", value_parser)]
+    pub speed: f64,
+
+    /// The most connections to replay at the same time.
+    #[clap(long, value_parser)]
+    pub concurrency: Option<u32>,
+
+    /// Binary to execute and replay traffic into.
+    #[clap(value_parser)]
+    pub binary: String,
+
+    /// Arguments to pass to the binary.
+    #[clap(value_parser)]
+    pub(super) binary_args: Vec<String>,
+
+    /// Where to extract the library to. Default is temp dir.
+    #[clap(long, value_parser)]
+    pub extract_path: Option<String>,
 }
 
 #[derive(Args, Debug)]
@@ -10,7 +10,12 @@ use clap::Parser;
 use config::*;
 use exec::execvp;
 use mirrord_auth::AuthConfig;
-use rand::distributions::{Alphanumeric, DistString};
+use mirrord_config::{config::MirrordConfig, LayerFileConfig};
+use mirrord_kube::pod_api;
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    Rng,
+};
 use semver::Version;
 use tracing::{debug, error, info, warn};
 use tracing_subscriber::{fmt, prelude::*, registry, EnvFilter};
@@ -185,6 +190,16 @@ fn exec(args: &ExecArgs) -> Result<()> {
         std::env::set_var("MIRRORD_AGENT_TCP_STEAL_TRAFFIC", "true");
     };
 
+    if let Some(http_header_filter) = &args.http_header_filter {
+        std::env::set_var("MIRRORD_AGENT_TCP_STEAL_TRAFFIC", "true");
+        std::env::set_var("MIRRORD_HTTP_HEADER_FILTER", http_header_filter);
+    }
+
+    if let Some(http_path_filter) = &args.http_path_filter {
+        std::env::set_var("MIRRORD_AGENT_TCP_STEAL_TRAFFIC", "true");
+        std::env::set_var("MIRRORD_HTTP_PATH_FILTER", http_path_filter);
+    }
+
     if args.no_outgoing || args.no_tcp_outgoing {
         std::env::set_var("MIRRORD_TCP_OUTGOING", "false");
     }
@@ -197,6 +212,85 @@ fn exec(args: &ExecArgs) -> Result<()> {
         std::env::set_var("MIRRORD_CONFIG_FILE", config_file.clone());
     }
 
+    if let Some(record) = &args.record {
+        std::env::set_var("MIRRORD_RECORD_FILE", record);
+    }
+
+    let library_path = extract_library(args.extract_path.clone())?;
+    add_to_preload(library_path.to_str().unwrap()).unwrap();
+
+    let mut binary_args = args.binary_args.clone();
+    binary_args.insert(0, args.binary.clone());
+
+    let err = execvp(args.binary.clone(), binary_args);
+    error!("Couldn't execute {:?}", err);
+    Err(anyhow!("Failed to execute binary"))
+}
+
+/// Runs a short-lived agent in the target (`agent.cleanup`) that removes the steal rules mirrord
+/// left there, the rest of the config comes from the environment like with `exec`.
+fn cleanup(args: &CleanupArgs) -> Result<()> {
+    std::env::set_var("MIRRORD_IMPERSONATED_TARGET", &args.target);
+
+    if let Some(namespace) = &args.target_namespace {
+        std::env::set_var("MIRRORD_TARGET_NAMESPACE", namespace.clone());
+    }
+
+    if let Some(namespace) = &args.agent_namespace {
+        std::env::set_var("MIRRORD_AGENT_NAMESPACE", namespace.clone());
+    }
+
+    if let Some(log_level) = &args.agent_log_level {
+        std::env::set_var("MIRRORD_AGENT_RUST_LOG", log_level.clone());
+    }
+
+    if let Some(image) = &args.agent_image {
+        std::env::set_var("MIRRORD_AGENT_IMAGE", image.clone());
+    }
+
+    if args.accept_invalid_certificates {
+        std::env::set_var("MIRRORD_ACCEPT_INVALID_CERTIFICATES", "true");
+    }
+
+    if args.ephemeral_container {
+        std::env::set_var("MIRRORD_EPHEMERAL_CONTAINER", "true");
+    };
+
+    let mut config = LayerFileConfig::default().generate_config()?;
+    config.agent.cleanup = true;
+
+    let connection_port: u16 = rand::thread_rng().gen_range(30000..=65535);
+    tokio::runtime::Runtime::new()?
+        .block_on(pod_api::cleanup_agent(config, connection_port))
+        .context("Failed cleaning up the target")?;
+
+    println!("mirrord removed the steal rules left in the target");
+    Ok(())
+}
+
+/// The layer plays the agent when `MIRRORD_REPLAY_FILE` is set, so everything that would reach
+/// the cluster (files, DNS, outgoing traffic) stays local.
+fn replay(args: &ReplayArgs) -> Result<()> {
+    if args.speed < 0.0 {
+        return Err(anyhow!("--speed can't be negative"));
+    }
+
+    std::env::set_var("MIRRORD_REPLAY_FILE", &args.file);
+    std::env::set_var(
+        "MIRRORD_REPLAY_SPEED",
+        ((args.speed * 100.0).round() as u32).to_string(),
+    );
+
+    if let Some(concurrency) = args.concurrency {
+        std::env::set_var("MIRRORD_REPLAY_CONCURRENCY", concurrency.to_string());
+    }
+
+    std::env::set_var("MIRRORD_FILE_OPS", "false");
+    std::env::set_var("MIRRORD_FILE_RO_OPS", "false");
+    std::env::set_var("MIRRORD_REMOTE_DNS", "false");
+    std::env::set_var("MIRRORD_TCP_OUTGOING", "false");
+    std::env::set_var("MIRRORD_UDP_OUTGOING", "false");
+
     let library_path = extract_library(args.extract_path.clone())?;
     add_to_preload(library_path.to_str().unwrap()).unwrap();
 
@@ -238,7 +332,10 @@ fn main() -> Result<()> {
         Commands::Exec(args) => exec(&args)?,
         Commands::Extract { path } => {
             extract_library(Some(path))?;
-        } // Commands::Login(args) => login(args)?,
+        }
+        Commands::Cleanup(args) => cleanup(&args)?,
+        Commands::Replay(args) => replay(&args)?,
+        // Commands::Login(args) => login(args)?,
     }
     Ok(())
 }
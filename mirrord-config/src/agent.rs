@@ -27,6 +27,14 @@ pub struct AgentFileConfig {
 
     #[config(env = "MIRRORD_AGENT_COMMUNICATION_TIMEOUT")]
     pub communication_timeout: Option<u16>,
+
+    /// Instead of running the app, only remove the steal rules mirrord left in the target.
+    #[config(env = "MIRRORD_AGENT_CLEANUP", default = "false")]
+    pub cleanup: Option<bool>,
+
+    /// Interfaces the agent sniffs on, comma separated. Detected by the agent when not set.
+    #[config(env = "MIRRORD_AGENT_NETWORK_INTERFACE")]
+    pub network_interface: Option<String>,
 }
 
 #[cfg(test)]
@@ -51,6 +59,11 @@ mod tests {
             Option<&str>,
             Option<u16>,
         ),
+        #[values((None, false), (Some("true"), true))] cleanup: (Option<&str>, bool),
+        #[values((None, None), (Some("eth0,net1"), Some("eth0,net1")))] network_interface: (
+            Option<&str>,
+            Option<&str>,
+        ),
     ) {
         with_env_vars(
             vec![
@@ -64,6 +77,8 @@ mod tests {
                     "MIRRORD_AGENT_COMMUNICATION_TIMEOUT",
                     communication_timeout.0,
                 ),
+                ("MIRRORD_AGENT_CLEANUP", cleanup.0),
+                ("MIRRORD_AGENT_NETWORK_INTERFACE", network_interface.0),
             ],
             || {
                 let agent = AgentFileConfig::default().generate_config().unwrap();
@@ -75,6 +90,8 @@ mod tests {
                 assert_eq!(agent.ttl, ttl.1);
                 assert_eq!(agent.ephemeral, ephemeral.1);
                 assert_eq!(agent.communication_timeout, communication_timeout.1);
+                assert_eq!(agent.cleanup, cleanup.1);
+                assert_eq!(agent.network_interface.as_deref(), network_interface.1);
             },
         );
     }
@@ -6,6 +6,9 @@ pub mod source;
 pub enum ConfigError {
     #[error("value for {1:?} not provided in {0:?} (env override {2:?})")]
     ValueNotProvided(&'static str, &'static str, Option<&'static str>),
+
+    #[error("value {2:?} for {1:?} in {0:?} is invalid, {3}")]
+    InvalidValue(&'static str, &'static str, String, &'static str),
 }
 
 use thiserror::Error;
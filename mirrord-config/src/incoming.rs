@@ -1,43 +1,585 @@
-use std::str::FromStr;
+use std::{path::PathBuf, str::FromStr};
 
+use mirrord_config_derive::MirrordConfig;
+use mirrord_protocol::cidr::Cidr;
 use serde::Deserialize;
 use thiserror::Error;
 
-#[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
+use crate::{
+    config::{source::MirrordConfigSource, ConfigError, MirrordConfig},
+    util::VecOrSingle,
+};
+
+/// Incoming traffic can either be mirrored (the remote pod keeps handling it) or stolen (only the
+/// local process handles it).
+#[derive(Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
 #[serde(rename_all = "lowercase")]
-pub enum IncomingConfig {
+pub enum IncomingMode {
     Mirror,
     Steal,
 }
 
-impl Default for IncomingConfig {
+impl Default for IncomingMode {
     fn default() -> Self {
-        IncomingConfig::Mirror
+        IncomingMode::Mirror
     }
 }
 
 #[derive(Error, Debug)]
-#[error("could not parse IncomingConfig from string, values must be bool or mirror/steal")]
-pub struct IncomingConfigParseError;
+#[error("could not parse IncomingMode from string, values must be bool or mirror/steal")]
+pub struct IncomingModeParseError;
 
-impl FromStr for IncomingConfig {
-    type Err = IncomingConfigParseError;
+impl FromStr for IncomingMode {
+    type Err = IncomingModeParseError;
 
     fn from_str(val: &str) -> Result<Self, Self::Err> {
         match val.parse::<bool>() {
-            Ok(true) => Ok(IncomingConfig::Steal),
-            Ok(false) => Ok(IncomingConfig::Mirror),
+            Ok(true) => Ok(IncomingMode::Steal),
+            Ok(false) => Ok(IncomingMode::Mirror),
             Err(_) => match val {
-                "steal" => Ok(IncomingConfig::Steal),
-                "mirror" => Ok(IncomingConfig::Mirror),
-                _ => Err(IncomingConfigParseError),
+                "steal" => Ok(IncomingMode::Steal),
+                "mirror" => Ok(IncomingMode::Mirror),
+                _ => Err(IncomingModeParseError),
             },
         }
     }
 }
 
+impl IncomingMode {
+    pub fn is_steal(&self) -> bool {
+        self == &IncomingMode::Steal
+    }
+}
+
+/// Configuration for incoming traffic, either just the mode (`incoming = "steal"`) or the full
+/// configuration.
+#[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(untagged)]
+pub enum IncomingFileConfig {
+    Mode(IncomingMode),
+    Advanced(AdvancedIncomingFileConfig),
+}
+
+impl Default for IncomingFileConfig {
+    fn default() -> Self {
+        IncomingFileConfig::Advanced(AdvancedIncomingFileConfig::default())
+    }
+}
+
+impl MirrordConfig for IncomingFileConfig {
+    type Generated = IncomingConfig;
+
+    fn generate_config(self) -> Result<Self::Generated, ConfigError> {
+        match self {
+            IncomingFileConfig::Mode(mode) => AdvancedIncomingFileConfig {
+                mode: Some(mode),
+                ..Default::default()
+            }
+            .generate_config(),
+            IncomingFileConfig::Advanced(advanced) => advanced.generate_config(),
+        }
+        .and_then(|config| match config.mirror_sample_percent {
+            Some(percent) if percent > 100 => Err(ConfigError::InvalidValue(
+                "AdvancedIncomingFileConfig",
+                "mirror_sample_percent",
+                percent.to_string(),
+                "must be a percentage between 0 and 100",
+            )),
+            _ => Ok(config),
+        })
+    }
+}
+
+#[derive(MirrordConfig, Deserialize, Default, PartialEq, Eq, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+#[config(map_to = IncomingConfig)]
+pub struct AdvancedIncomingFileConfig {
+    #[config(env = "MIRRORD_AGENT_TCP_STEAL_TRAFFIC", default = "mirror")]
+    pub mode: Option<IncomingMode>,
+
+    /// Steal only HTTP requests that have a header matching this regex, for example
+    /// `x-mirrord-user: alice`. Other requests keep going to the remote pod.
+    #[config(env = "MIRRORD_HTTP_HEADER_FILTER")]
+    pub http_header_filter: Option<String>,
+
+    /// Steal only HTTP requests with a path matching this regex (`:path` for HTTP/2 and gRPC).
+    #[config(env = "MIRRORD_HTTP_PATH_FILTER")]
+    pub http_path_filter: Option<String>,
+
+    /// Maps local ports to the remote ones, `[[8080, 80]]` makes a local app listening on 8080
+    /// receive the traffic of port 80 in the pod.
+    pub port_mapping: Option<Vec<(u16, u16)>>,
+
+    /// Overrides `mode` for specific remote ports, `[[80, "steal"], [50051, "mirror"]]` steals
+    /// the traffic of port 80 while only mirroring port 50051.
+    pub port_modes: Option<Vec<(u16, IncomingMode)>>,
+
+    /// In steal mode, connections that the local app doesn't accept (it isn't listening, or
+    /// it's too slow) go to their original destination in the pod instead of being reset.
+    #[config(env = "MIRRORD_STEAL_FALLBACK", default = "false")]
+    pub fallback: Option<bool>,
+
+    /// How long (in milliseconds) the local app has to accept a stolen connection before it
+    /// falls back to the original destination.
+    #[config(env = "MIRRORD_STEAL_FALLBACK_TIMEOUT", default = "1000")]
+    pub fallback_timeout: Option<u64>,
+
+    /// In mirror mode, mirror only this percentage (0-100) of the new connections.
+    #[config(env = "MIRRORD_MIRROR_SAMPLE_PERCENT")]
+    pub mirror_sample_percent: Option<u8>,
+
+    /// In mirror mode, the most connections that are mirrored at the same time.
+    #[config(env = "MIRRORD_MIRROR_MAX_CONNECTIONS")]
+    pub mirror_max_connections: Option<u32>,
+
+    /// In mirror mode, new connections aren't mirrored while the traffic mirrored in the last
+    /// second is over this many bytes.
+    #[config(env = "MIRRORD_MIRROR_BYTES_PER_SECOND")]
+    pub mirror_bytes_per_second: Option<u64>,
+
+    /// Only mirror or steal connections coming from these addresses, CIDR ranges or single
+    /// addresses separated by `;` in the environment variable, for example
+    /// `10.8.0.0/16;10.9.1.4`.
+    #[config(env = "MIRRORD_INCOMING_SOURCE_FILTER")]
+    pub source_filter: Option<VecOrSingle<Cidr>>,
+
+    /// Overrides `source_filter` for specific remote ports, `[[80, ["10.8.0.0/16"]], [8080, []]]`
+    /// only takes the traffic of port 80 from `10.8.0.0/16` and the traffic of port 8080 from
+    /// every source.
+    pub port_source_filters: Option<Vec<(u16, Vec<Cidr>)>>,
+
+    /// In mirror mode, saves the packets captured for the subscribed ports to this pcap file.
+    #[config(env = "MIRRORD_CAPTURE_FILE")]
+    pub capture_file: Option<PathBuf>,
+
+    /// Once the capture file reaches this many bytes, capturing goes on in a new file
+    /// (`capture.1.pcap`, `capture.2.pcap`...). Defaults to 100MiB.
+    #[config(env = "MIRRORD_CAPTURE_MAX_BYTES", default = "104857600")]
+    pub capture_max_bytes: Option<u64>,
+
+    /// Records the incoming connections (mirrored or stolen) to this file, to be replayed with
+    /// `mirrord replay`.
+    #[config(env = "MIRRORD_RECORD_FILE")]
+    pub record_file: Option<PathBuf>,
+
+    /// Feeds the connections recorded in this file to the local app, instead of connecting to
+    /// the cluster.
+    #[config(env = "MIRRORD_REPLAY_FILE")]
+    pub replay_file: Option<PathBuf>,
+
+    /// Replay pace in percent of the recorded one, `200` replays twice as fast and `0` doesn't
+    /// wait between messages at all.
+    #[config(env = "MIRRORD_REPLAY_SPEED", default = "100")]
+    pub replay_speed: Option<u32>,
+
+    /// The most recorded connections that are replayed at the same time.
+    #[config(env = "MIRRORD_REPLAY_CONCURRENCY")]
+    pub replay_concurrency: Option<u32>,
+
+    /// In mirror mode, compares the local app's HTTP/1.1 responses with the remote service's
+    /// ones and reports the differences (status, headers, body) of each request to this file.
+    #[config(env = "MIRRORD_SHADOW_COMPARE_FILE")]
+    pub shadow_compare_file: Option<PathBuf>,
+
+    /// In steal mode, the stolen traffic is TLS that the agent terminates with this certificate
+    /// chain (PEM), a path in the target container. Requires `tls_key`.
+    #[config(env = "MIRRORD_STEAL_TLS_CERTIFICATE")]
+    pub tls_certificate: Option<String>,
+
+    /// Private key (PEM) of `tls_certificate`, a path in the target container.
+    #[config(env = "MIRRORD_STEAL_TLS_KEY")]
+    pub tls_key: Option<String>,
+
+    /// Like `tls_certificate`, but the certificate and key are in this Kubernetes Secret (of type
+    /// `kubernetes.io/tls`) in the target's namespace.
+    #[config(env = "MIRRORD_STEAL_TLS_SECRET")]
+    pub tls_secret: Option<String>,
+
+    /// When the agent terminates TLS, the stolen connections are encrypted again toward the
+    /// local app, for apps that only speak TLS.
+    #[config(env = "MIRRORD_STEAL_TLS_LOCAL", default = "false")]
+    pub tls_local: Option<bool>,
+}
+
 impl IncomingConfig {
     pub fn is_steal(&self) -> bool {
-        self == &IncomingConfig::Steal
+        self.mode.is_steal()
+    }
+
+    /// Whether any port is stolen, with `mode` or in `port_modes`.
+    pub fn steals_any_port(&self) -> bool {
+        self.is_steal()
+            || self
+                .port_modes
+                .iter()
+                .flatten()
+                .any(|(_, mode)| mode.is_steal())
+    }
+
+    /// The mode of the given remote port, falls back to `mode` when it's not in `port_modes`.
+    pub fn mode_for(&self, port: u16) -> IncomingMode {
+        self.port_modes
+            .iter()
+            .flatten()
+            .find_map(|(mode_port, mode)| (*mode_port == port).then_some(*mode))
+            .unwrap_or(self.mode)
+    }
+
+    /// Mirroring is limited when any of the `mirror_*` limits is set.
+    pub fn is_mirror_limited(&self) -> bool {
+        self.mirror_sample_percent.is_some()
+            || self.mirror_max_connections.is_some()
+            || self.mirror_bytes_per_second.is_some()
+    }
+
+    /// Steal is HTTP aware when any of the HTTP filters is set.
+    pub fn is_http_filtered(&self) -> bool {
+        self.http_header_filter.is_some() || self.http_path_filter.is_some()
+    }
+
+    /// The agent terminates TLS when there's a certificate, from a file or a secret.
+    pub fn is_tls_terminated(&self) -> bool {
+        self.tls_certificate.is_some() || self.tls_secret.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::util::testing::with_env_vars;
+
+    #[rstest]
+    fn default(
+        #[values((None, IncomingMode::Mirror), (Some("true"), IncomingMode::Steal), (Some("steal"), IncomingMode::Steal))]
+        mode: (Option<&str>, IncomingMode),
+        #[values((None, None), (Some("x-mirrord-user: alice"), Some("x-mirrord-user: alice")))]
+        header: (Option<&str>, Option<&str>),
+    ) {
+        with_env_vars(
+            vec![
+                ("MIRRORD_AGENT_TCP_STEAL_TRAFFIC", mode.0),
+                ("MIRRORD_HTTP_HEADER_FILTER", header.0),
+                ("MIRRORD_HTTP_PATH_FILTER", None),
+            ],
+            || {
+                let incoming = IncomingFileConfig::default().generate_config().unwrap();
+
+                assert_eq!(incoming.mode, mode.1);
+                assert_eq!(incoming.http_header_filter.as_deref(), header.1);
+                assert_eq!(incoming.http_path_filter, None);
+                assert_eq!(incoming.is_http_filtered(), header.1.is_some());
+            },
+        );
+    }
+
+    #[rstest]
+    fn steal_fallback(
+        #[values((None, false), (Some("true"), true))] fallback: (Option<&str>, bool),
+        #[values((None, 1000), (Some("250"), 250))] timeout: (Option<&str>, u64),
+    ) {
+        with_env_vars(
+            vec![
+                ("MIRRORD_STEAL_FALLBACK", fallback.0),
+                ("MIRRORD_STEAL_FALLBACK_TIMEOUT", timeout.0),
+            ],
+            || {
+                let incoming = IncomingFileConfig::default().generate_config().unwrap();
+
+                assert_eq!(incoming.fallback, fallback.1);
+                assert_eq!(incoming.fallback_timeout, timeout.1);
+            },
+        );
+    }
+
+    #[rstest]
+    fn mirror_limits(
+        #[values((None, None), (Some("10"), Some(10)))] sample: (Option<&str>, Option<u8>),
+        #[values((None, None), (Some("1048576"), Some(1048576)))] bytes: (
+            Option<&str>,
+            Option<u64>,
+        ),
+    ) {
+        with_env_vars(
+            vec![
+                ("MIRRORD_MIRROR_SAMPLE_PERCENT", sample.0),
+                ("MIRRORD_MIRROR_MAX_CONNECTIONS", None),
+                ("MIRRORD_MIRROR_BYTES_PER_SECOND", bytes.0),
+            ],
+            || {
+                let incoming = IncomingFileConfig::default().generate_config().unwrap();
+
+                assert_eq!(incoming.mirror_sample_percent, sample.1);
+                assert_eq!(incoming.mirror_max_connections, None);
+                assert_eq!(incoming.mirror_bytes_per_second, bytes.1);
+                assert_eq!(
+                    incoming.is_mirror_limited(),
+                    sample.1.is_some() || bytes.1.is_some()
+                );
+            },
+        );
+    }
+
+    #[rstest]
+    #[case("0", Some(0))]
+    #[case("100", Some(100))]
+    #[case("101", None)]
+    fn mirror_sample_percent(#[case] sample: &str, #[case] expected: Option<u8>) {
+        with_env_vars(
+            vec![("MIRRORD_MIRROR_SAMPLE_PERCENT", Some(sample))],
+            || {
+                let incoming = IncomingFileConfig::default().generate_config();
+
+                match expected {
+                    Some(percent) => {
+                        assert_eq!(incoming.unwrap().mirror_sample_percent, Some(percent))
+                    }
+                    None => assert!(matches!(
+                        incoming,
+                        Err(ConfigError::InvalidValue(_, "mirror_sample_percent", _, _))
+                    )),
+                }
+            },
+        );
+    }
+
+    #[rstest]
+    fn source_filter(
+        #[values(
+            (None, vec![]),
+            (Some("10.8.0.0/16"), vec![("10.8.0.0", 16)]),
+            (Some("10.8.0.0/16;10.9.1.4;fd00::/8"), vec![("10.8.0.0", 16), ("10.9.1.4", 32), ("fd00::", 8)]),
+        )]
+        filter: (Option<&str>, Vec<(&str, u8)>),
+    ) {
+        with_env_vars(vec![("MIRRORD_INCOMING_SOURCE_FILTER", filter.0)], || {
+            let incoming = IncomingFileConfig::default().generate_config().unwrap();
+
+            let expect = filter
+                .1
+                .iter()
+                .map(|(address, prefix_len)| Cidr {
+                    address: address.parse().unwrap(),
+                    prefix_len: *prefix_len,
+                })
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                incoming
+                    .source_filter
+                    .map(VecOrSingle::to_vec)
+                    .unwrap_or_default(),
+                expect
+            );
+        });
+    }
+
+    #[rstest]
+    fn capture(
+        #[values((None, None), (Some("/tmp/capture.pcap"), Some("/tmp/capture.pcap")))] file: (
+            Option<&str>,
+            Option<&str>,
+        ),
+        #[values((None, 104857600), (Some("1024"), 1024))] max_bytes: (Option<&str>, u64),
+    ) {
+        with_env_vars(
+            vec![
+                ("MIRRORD_CAPTURE_FILE", file.0),
+                ("MIRRORD_CAPTURE_MAX_BYTES", max_bytes.0),
+            ],
+            || {
+                let incoming = IncomingFileConfig::default().generate_config().unwrap();
+
+                assert_eq!(incoming.capture_file, file.1.map(PathBuf::from));
+                assert_eq!(incoming.capture_max_bytes, max_bytes.1);
+            },
+        );
+    }
+
+    #[rstest]
+    fn replay(
+        #[values((None, None), (Some("/tmp/traffic.rec"), Some("/tmp/traffic.rec")))] file: (
+            Option<&str>,
+            Option<&str>,
+        ),
+        #[values((None, 100), (Some("250"), 250))] speed: (Option<&str>, u32),
+    ) {
+        with_env_vars(
+            vec![
+                ("MIRRORD_RECORD_FILE", None),
+                ("MIRRORD_REPLAY_FILE", file.0),
+                ("MIRRORD_REPLAY_SPEED", speed.0),
+                ("MIRRORD_REPLAY_CONCURRENCY", Some("4")),
+            ],
+            || {
+                let incoming = IncomingFileConfig::default().generate_config().unwrap();
+
+                assert_eq!(incoming.record_file, None);
+                assert_eq!(incoming.replay_file, file.1.map(PathBuf::from));
+                assert_eq!(incoming.replay_speed, speed.1);
+                assert_eq!(incoming.replay_concurrency, Some(4));
+            },
+        );
+    }
+
+    #[rstest]
+    fn shadow_compare(
+        #[values((None, None), (Some("/tmp/shadow.jsonl"), Some("/tmp/shadow.jsonl")))] file: (
+            Option<&str>,
+            Option<&str>,
+        ),
+    ) {
+        with_env_vars(vec![("MIRRORD_SHADOW_COMPARE_FILE", file.0)], || {
+            let incoming = IncomingFileConfig::default().generate_config().unwrap();
+
+            assert_eq!(incoming.shadow_compare_file, file.1.map(PathBuf::from));
+        });
+    }
+
+    #[rstest]
+    fn steal_tls(
+        #[values(
+            (None, None, false),
+            (Some("/etc/tls/tls.crt"), None, true),
+            (None, Some("api-tls"), true),
+        )]
+        certificate: (Option<&str>, Option<&str>, bool),
+        #[values((None, false), (Some("true"), true))] local: (Option<&str>, bool),
+    ) {
+        with_env_vars(
+            vec![
+                ("MIRRORD_STEAL_TLS_CERTIFICATE", certificate.0),
+                (
+                    "MIRRORD_STEAL_TLS_KEY",
+                    certificate.0.map(|_| "/etc/tls/tls.key"),
+                ),
+                ("MIRRORD_STEAL_TLS_SECRET", certificate.1),
+                ("MIRRORD_STEAL_TLS_LOCAL", local.0),
+            ],
+            || {
+                let incoming = IncomingFileConfig::default().generate_config().unwrap();
+
+                assert_eq!(incoming.tls_certificate.as_deref(), certificate.0);
+                assert_eq!(
+                    incoming.tls_key.as_deref(),
+                    certificate.0.map(|_| "/etc/tls/tls.key")
+                );
+                assert_eq!(incoming.tls_secret.as_deref(), certificate.1);
+                assert_eq!(incoming.is_tls_terminated(), certificate.2);
+                assert_eq!(incoming.tls_local, local.1);
+            },
+        );
+    }
+
+    #[rstest]
+    #[case(r#""steal""#, IncomingFileConfig::Mode(IncomingMode::Steal))]
+    #[case(
+        r#"{ "mode": "steal", "http_header_filter": "x-mirrord-user: alice" }"#,
+        IncomingFileConfig::Advanced(AdvancedIncomingFileConfig {
+            mode: Some(IncomingMode::Steal),
+            http_header_filter: Some("x-mirrord-user: alice".to_owned()),
+            http_path_filter: None,
+            port_mapping: None,
+            port_modes: None,
+            fallback: None,
+            fallback_timeout: None,
+            mirror_sample_percent: None,
+            mirror_max_connections: None,
+            mirror_bytes_per_second: None,
+            source_filter: None,
+            port_source_filters: None,
+            capture_file: None,
+            capture_max_bytes: None,
+            record_file: None,
+            replay_file: None,
+            replay_speed: None,
+            replay_concurrency: None,
+            shadow_compare_file: None,
+            tls_certificate: None,
+            tls_key: None,
+            tls_secret: None,
+            tls_local: None,
+        })
+    )]
+    #[case(
+        r#"{ "port_mapping": [[8080, 80], [3000, 3001]] }"#,
+        IncomingFileConfig::Advanced(AdvancedIncomingFileConfig {
+            port_mapping: Some(vec![(8080, 80), (3000, 3001)]),
+            ..Default::default()
+        })
+    )]
+    #[case(
+        r#"{ "source_filter": ["10.8.0.0/16", "10.9.1.4"] }"#,
+        IncomingFileConfig::Advanced(AdvancedIncomingFileConfig {
+            source_filter: Some(VecOrSingle::Multiple(vec![
+                Cidr { address: "10.8.0.0".parse().unwrap(), prefix_len: 16 },
+                Cidr { address: "10.9.1.4".parse().unwrap(), prefix_len: 32 },
+            ])),
+            ..Default::default()
+        })
+    )]
+    #[case(
+        r#"{ "port_source_filters": [[80, ["10.8.0.0/16"]], [8080, []]] }"#,
+        IncomingFileConfig::Advanced(AdvancedIncomingFileConfig {
+            port_source_filters: Some(vec![
+                (80, vec![Cidr { address: "10.8.0.0".parse().unwrap(), prefix_len: 16 }]),
+                (8080, vec![]),
+            ]),
+            ..Default::default()
+        })
+    )]
+    #[case(
+        r#"{ "mode": "mirror", "port_modes": [[80, "steal"], [50051, "mirror"]] }"#,
+        IncomingFileConfig::Advanced(AdvancedIncomingFileConfig {
+            mode: Some(IncomingMode::Mirror),
+            port_modes: Some(vec![(80, IncomingMode::Steal), (50051, IncomingMode::Mirror)]),
+            ..Default::default()
+        })
+    )]
+    fn parse(#[case] input: &str, #[case] expect: IncomingFileConfig) {
+        let incoming: IncomingFileConfig = serde_json::from_str(input).unwrap();
+
+        assert_eq!(incoming, expect);
+    }
+
+    #[rstest]
+    #[case(80, IncomingMode::Steal)]
+    #[case(50051, IncomingMode::Mirror)]
+    #[case(8080, IncomingMode::Steal)]
+    fn mode_for_port(#[case] port: u16, #[case] expect: IncomingMode) {
+        let incoming = AdvancedIncomingFileConfig {
+            mode: Some(IncomingMode::Steal),
+            port_modes: Some(vec![
+                (80, IncomingMode::Steal),
+                (50051, IncomingMode::Mirror),
+            ]),
+            ..Default::default()
+        }
+        .generate_config()
+        .unwrap();
+
+        assert_eq!(incoming.mode_for(port), expect);
+    }
+
+    #[rstest]
+    #[case(IncomingMode::Mirror, None, false)]
+    #[case(IncomingMode::Steal, None, true)]
+    #[case(IncomingMode::Mirror, Some(IncomingMode::Mirror), false)]
+    #[case(IncomingMode::Mirror, Some(IncomingMode::Steal), true)]
+    fn steals_any_port(
+        #[case] mode: IncomingMode,
+        #[case] port_mode: Option<IncomingMode>,
+        #[case] expect: bool,
+    ) {
+        let incoming = AdvancedIncomingFileConfig {
+            mode: Some(mode),
+            port_modes: port_mode.map(|port_mode| vec![(80, port_mode)]),
+            ..Default::default()
+        }
+        .generate_config()
+        .unwrap();
+
+        assert_eq!(incoming.steals_any_port(), expect);
     }
 }
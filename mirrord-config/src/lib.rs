@@ -73,8 +73,11 @@ mod tests {
 
     use super::*;
     use crate::{
-        fs::FsConfig, incoming::IncomingConfig, network::NetworkFileConfig,
-        outgoing::OutgoingFileConfig, util::ToggleableConfig,
+        fs::FsConfig,
+        incoming::{IncomingFileConfig, IncomingMode},
+        network::NetworkFileConfig,
+        outgoing::OutgoingFileConfig,
+        util::{ToggleableConfig, VecOrSingle},
     };
 
     #[derive(Debug)]
@@ -117,7 +120,8 @@ mod tests {
                                 "incoming": "mirror",
                                 "outgoing": {
                                     "tcp": true,
-                                    "udp": false
+                                    "udp": false,
+                                    "filter": ["local 127.0.0.0/8", "remote db.internal:5432"]
                                 }
                             }
                         },
@@ -154,6 +158,7 @@ mod tests {
                     [feature.network.outgoing]
                     tcp = true
                     udp = false
+                    filter = ["local 127.0.0.0/8", "remote db.internal:5432"]
 
                     [pod]
                     name = "test-service-abcdefg-abcd"
@@ -184,6 +189,9 @@ mod tests {
                             outgoing:
                                 tcp: true
                                 udp: false
+                                filter:
+                                    - "local 127.0.0.0/8"
+                                    - "remote db.internal:5432"
                     pod:
                         name: "test-service-abcdefg-abcd"
                         namespace: "default"
@@ -238,17 +246,25 @@ mod tests {
                 ttl: Some(60),
                 ephemeral: Some(false),
                 communication_timeout: None,
+                cleanup: None,
+                network_interface: None,
             },
             feature: FeatureFileConfig {
                 env: ToggleableConfig::Enabled(true),
                 fs: ToggleableConfig::Config(FsConfig::Write),
                 network: ToggleableConfig::Config(NetworkFileConfig {
                     dns: Some(false),
-                    incoming: Some(IncomingConfig::Mirror),
+                    incoming: IncomingFileConfig::Mode(IncomingMode::Mirror),
                     outgoing: ToggleableConfig::Config(OutgoingFileConfig {
                         tcp: Some(true),
                         udp: Some(false),
+                        filter: Some(VecOrSingle::Multiple(vec![
+                            "local 127.0.0.0/8".parse().unwrap(),
+                            "remote db.internal:5432".parse().unwrap(),
+                        ])),
                     }),
+                    ignore_ports: None,
+                    allow_ports: None,
                 }),
             },
             pod: PodFileConfig {
@@ -1,21 +1,98 @@
+use std::{fmt, ops::RangeInclusive, str::FromStr};
+
 use mirrord_config_derive::MirrordConfig;
 use serde::Deserialize;
+use thiserror::Error;
 
 use crate::{
     config::{
         default_value::DefaultValue, from_env::FromEnv, source::MirrordConfigSource, ConfigError,
+        MirrordConfig,
     },
-    incoming::IncomingConfig,
+    incoming::IncomingFileConfig,
     outgoing::OutgoingFileConfig,
-    util::{MirrordToggleableConfig, ToggleableConfig},
+    util::{MirrordToggleableConfig, ToggleableConfig, VecOrSingle},
 };
 
+/// A single port (`8080`) or an inclusive range of ports (`"50000-60000"`).
+#[derive(Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(try_from = "PortRangeFileConfig")]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRangeFileConfig {
+    Port(u16),
+    Range(String),
+}
+
+#[derive(Error, Debug)]
+#[error("could not parse port range `{0}`, values must be a port or `start-end`")]
+pub struct PortRangeParseError(String);
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        RangeInclusive::new(self.start, self.end).contains(&port)
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = PortRangeParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| PortRangeParseError(val.to_owned()))
+        };
+
+        let (start, end) = match val.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => (parse(val)?, parse(val)?),
+        };
+
+        if start > end {
+            return Err(PortRangeParseError(val.to_owned()));
+        }
+
+        Ok(PortRange { start, end })
+    }
+}
+
+impl TryFrom<PortRangeFileConfig> for PortRange {
+    type Error = PortRangeParseError;
+
+    fn try_from(config: PortRangeFileConfig) -> Result<Self, Self::Error> {
+        match config {
+            PortRangeFileConfig::Port(port) => Ok(PortRange {
+                start: port,
+                end: port,
+            }),
+            PortRangeFileConfig::Range(range) => range.parse(),
+        }
+    }
+}
+
 #[derive(MirrordConfig, Deserialize, Default, PartialEq, Eq, Clone, Debug)]
 #[serde(deny_unknown_fields)]
 #[config(map_to = NetworkConfig)]
 pub struct NetworkFileConfig {
-    #[config(env = "MIRRORD_AGENT_TCP_STEAL_TRAFFIC", default = "mirror")]
-    pub incoming: Option<IncomingConfig>,
+    #[serde(default)]
+    #[config(nested)]
+    pub incoming: IncomingFileConfig,
 
     #[serde(default)]
     #[config(nested)]
@@ -23,21 +100,53 @@ pub struct NetworkFileConfig {
 
     #[config(env = "MIRRORD_REMOTE_DNS", default = "true")]
     pub dns: Option<bool>,
+
+    /// Local ports we never subscribe to for incoming traffic, ports or `"start-end"` ranges
+    /// separated by `;` in the environment variable. Defaults to `0` and `50001-59999`.
+    #[config(env = "MIRRORD_IGNORE_PORTS", default = "0;50001-59999")]
+    pub ignore_ports: Option<VecOrSingle<PortRange>>,
+
+    /// When set, only these local ports are subscribed to for incoming traffic (unless they're
+    /// also ignored).
+    #[config(env = "MIRRORD_ALLOW_PORTS")]
+    pub allow_ports: Option<VecOrSingle<PortRange>>,
+}
+
+impl NetworkConfig {
+    /// The local ports we never subscribe to for incoming traffic, see [`IgnoredPorts`].
+    pub fn ignored_ports(&self) -> IgnoredPorts {
+        IgnoredPorts {
+            ignore: self.ignore_ports.iter().copied().collect(),
+            allow: self
+                .allow_ports
+                .as_ref()
+                .map(|allow| allow.iter().copied().collect()),
+        }
+    }
+}
+
+/// Built from `ignore_ports` and `allow_ports`.
+#[derive(Clone, Debug, Default)]
+pub struct IgnoredPorts {
+    ignore: Vec<PortRange>,
+    allow: Option<Vec<PortRange>>,
+}
+
+impl IgnoredPorts {
+    /// Port `0` is always ignored, it means the OS picks a port for us.
+    pub fn contains(&self, port: u16) -> bool {
+        port == 0
+            || self.ignore.iter().any(|range| range.contains(port))
+            || self.allow.as_ref().map_or(false, |allow| {
+                !allow.iter().any(|range| range.contains(port))
+            })
+    }
 }
 
 impl MirrordToggleableConfig for NetworkFileConfig {
     fn disabled_config() -> Result<Self::Generated, ConfigError> {
         Ok(NetworkConfig {
-            incoming: (
-                FromEnv::new("MIRRORD_AGENT_TCP_STEAL_TRAFFIC"),
-                DefaultValue::new("mirror"),
-            )
-                .source_value()
-                .ok_or(ConfigError::ValueNotProvided(
-                    "NetworkFileConfig",
-                    "incoming",
-                    Some("MIRRORD_AGENT_TCP_STEAL_TRAFFIC"),
-                ))?,
+            incoming: IncomingFileConfig::default().generate_config()?,
             dns: (
                 FromEnv::new("MIRRORD_REMOTE_DNS"),
                 DefaultValue::new("false"),
@@ -49,6 +158,17 @@ impl MirrordToggleableConfig for NetworkFileConfig {
                     Some("MIRRORD_REMOTE_DNS"),
                 ))?,
             outgoing: OutgoingFileConfig::disabled_config()?,
+            ignore_ports: (
+                FromEnv::new("MIRRORD_IGNORE_PORTS"),
+                DefaultValue::new("0;50001-59999"),
+            )
+                .source_value()
+                .ok_or(ConfigError::ValueNotProvided(
+                    "NetworkFileConfig",
+                    "ignore_ports",
+                    Some("MIRRORD_IGNORE_PORTS"),
+                ))?,
+            allow_ports: FromEnv::new("MIRRORD_ALLOW_PORTS").source_value(),
         })
     }
 }
@@ -58,12 +178,93 @@ mod tests {
     use rstest::rstest;
 
     use super::*;
-    use crate::{config::MirrordConfig, util::testing::with_env_vars};
+    use crate::{incoming::IncomingMode, util::testing::with_env_vars};
+
+    #[rstest]
+    #[case("8080", 8080, 8080)]
+    #[case("50000-60000", 50000, 60000)]
+    #[case(" 1 - 2 ", 1, 2)]
+    fn parse_port_range(#[case] input: &str, #[case] start: u16, #[case] end: u16) {
+        assert_eq!(
+            input.parse::<PortRange>().unwrap(),
+            PortRange { start, end }
+        );
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("http")]
+    #[case("60000-50000")]
+    #[case("1-2-3")]
+    #[case("70000")]
+    fn parse_port_range_fails(#[case] input: &str) {
+        assert!(input.parse::<PortRange>().is_err());
+    }
+
+    #[rstest]
+    #[case(None, None, &[0, 50001, 59999], &[80, 50000, 60000])]
+    #[case(Some("5005;9000-9100"), None, &[0, 5005, 9050], &[80, 50001])]
+    #[case(None, Some("80;8000-8999"), &[0, 81, 50001], &[80, 8080])]
+    #[case(Some("8080"), Some("8000-8999"), &[8080, 443], &[8000, 8081])]
+    fn ignored_ports(
+        #[case] ignore: Option<&str>,
+        #[case] allow: Option<&str>,
+        #[case] ignored: &[u16],
+        #[case] subscribed: &[u16],
+    ) {
+        with_env_vars(
+            vec![
+                ("MIRRORD_IGNORE_PORTS", ignore),
+                ("MIRRORD_ALLOW_PORTS", allow),
+            ],
+            || {
+                let ignored_ports = NetworkFileConfig::default()
+                    .generate_config()
+                    .unwrap()
+                    .ignored_ports();
+
+                for port in ignored {
+                    assert!(ignored_ports.contains(*port), "{port} should be ignored");
+                }
+                for port in subscribed {
+                    assert!(
+                        !ignored_ports.contains(*port),
+                        "{port} should be subscribed"
+                    );
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn ignore_ports_from_file() {
+        let network: NetworkFileConfig =
+            serde_json::from_str(r#"{ "ignore_ports": [5005, "9000-9100"], "allow_ports": 80 }"#)
+                .unwrap();
+
+        assert_eq!(
+            network.ignore_ports,
+            Some(VecOrSingle::Multiple(vec![
+                PortRange {
+                    start: 5005,
+                    end: 5005
+                },
+                PortRange {
+                    start: 9000,
+                    end: 9100
+                },
+            ]))
+        );
+        assert_eq!(
+            network.allow_ports,
+            Some(VecOrSingle::Single(PortRange { start: 80, end: 80 }))
+        );
+    }
 
     #[rstest]
     fn default(
-        #[values((None, IncomingConfig::Mirror), (Some("false"), IncomingConfig::Mirror), (Some("true"), IncomingConfig::Steal))]
-        incoming: (Option<&str>, IncomingConfig),
+        #[values((None, IncomingMode::Mirror), (Some("false"), IncomingMode::Mirror), (Some("true"), IncomingMode::Steal))]
+        incoming: (Option<&str>, IncomingMode),
         #[values((None, true), (Some("false"), false))] dns: (Option<&str>, bool),
     ) {
         with_env_vars(
@@ -74,7 +275,7 @@ mod tests {
             || {
                 let env = NetworkFileConfig::default().generate_config().unwrap();
 
-                assert_eq!(env.incoming, incoming.1);
+                assert_eq!(env.incoming.mode, incoming.1);
                 assert_eq!(env.dns, dns.1);
             },
         );
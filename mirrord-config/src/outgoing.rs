@@ -1,13 +1,175 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
 use mirrord_config_derive::MirrordConfig;
+use mirrord_protocol::cidr::Cidr;
 use serde::Deserialize;
+use thiserror::Error;
 
 use crate::{
     config::{
         default_value::DefaultValue, from_env::FromEnv, source::MirrordConfigSource, ConfigError,
     },
-    util::MirrordToggleableConfig,
+    network::PortRange,
+    util::{MirrordToggleableConfig, VecOrSingle},
 };
 
+/// Where the connections to the destinations of an [`OutgoingRule`] are made.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum OutgoingAction {
+    /// Through the agent, from the remote pod.
+    Remote,
+    /// From the local machine, as without mirrord.
+    Local,
+}
+
+/// The addresses an [`OutgoingRule`] matches.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum DestinationAddress {
+    Any,
+    Cidr(Cidr),
+    /// Matched against the names the address was resolved from (with remote DNS), `*.example.com`
+    /// matches its subdomains. Only names resolved through `getaddrinfo` are known, so Go binaries
+    /// using the pure Go resolver never match these.
+    Host(String),
+}
+
+/// A rule of `feature.network.outgoing.filter`, `"<remote|local> <destination>"`.
+///
+/// The destination is an address, CIDR or hostname with an optional port or port range after a
+/// `:`, for example `10.0.0.0/8`, `db.internal:5432`, `:5432` (any address), or
+/// `[fd00::/8]:8000-8999` (IPv6 with a port goes in brackets).
+#[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(try_from = "String")]
+pub struct OutgoingRule {
+    pub action: OutgoingAction,
+    pub address: DestinationAddress,
+    /// Any port when not set.
+    pub port: Option<PortRange>,
+}
+
+#[derive(Error, Debug)]
+#[error(
+    "could not parse outgoing rule `{0}`, values must be `remote` or `local` followed by an \
+     address, CIDR or hostname, with an optional `:port`"
+)]
+pub struct OutgoingRuleParseError(String);
+
+impl OutgoingRule {
+    /// Whether the rule applies to connections to `destination`, which was resolved from `hosts`.
+    pub fn matches(&self, destination: SocketAddr, hosts: &[String]) -> bool {
+        // Dual-stack sockets connect to IPv4 destinations through mapped addresses.
+        let address = match destination.ip() {
+            IpAddr::V6(address) => address
+                .to_ipv4_mapped()
+                .map_or(IpAddr::V6(address), IpAddr::V4),
+            address => address,
+        };
+
+        let address_matches = match &self.address {
+            DestinationAddress::Any => true,
+            DestinationAddress::Cidr(cidr) => cidr.contains(address),
+            DestinationAddress::Host(host) => hosts.iter().any(|name| host_matches(host, name)),
+        };
+
+        address_matches
+            && self
+                .port
+                .map_or(true, |port| port.contains(destination.port()))
+    }
+}
+
+fn host_matches(pattern: &str, name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    match pattern.strip_prefix("*.") {
+        Some(domain) => name
+            .strip_suffix(domain)
+            .map_or(false, |subdomain| subdomain.ends_with('.')),
+        None => name == pattern,
+    }
+}
+
+fn parse_destination(val: &str) -> Option<(DestinationAddress, Option<PortRange>)> {
+    // `[fd00::/8]:443`, IPv6 addresses have `:`s of their own.
+    if let Some(bracketed) = val.strip_prefix('[') {
+        let (address, port) = bracketed.split_once(']')?;
+        let port = match port {
+            "" => None,
+            port => Some(port.strip_prefix(':')?.parse().ok()?),
+        };
+
+        return Some((DestinationAddress::Cidr(address.parse().ok()?), port));
+    }
+
+    if let Ok(cidr) = val.parse::<Cidr>() {
+        return Some((DestinationAddress::Cidr(cidr), None));
+    }
+
+    let (address, port) = match val.rsplit_once(':') {
+        Some((address, port)) => (address, Some(port.parse().ok()?)),
+        None => (val, None),
+    };
+
+    let address = match address {
+        "" | "*" => DestinationAddress::Any,
+        address => match address.parse::<Cidr>() {
+            Ok(cidr) => DestinationAddress::Cidr(cidr),
+            Err(_) if is_host_pattern(address) => {
+                DestinationAddress::Host(address.to_ascii_lowercase())
+            }
+            Err(_) => return None,
+        },
+    };
+
+    Some((address, port))
+}
+
+/// Hostnames, with a leading `*.` for their subdomains.
+fn is_host_pattern(host: &str) -> bool {
+    let host = host.strip_prefix("*.").unwrap_or(host);
+
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+}
+
+impl FromStr for OutgoingRule {
+    type Err = OutgoingRuleParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let error = || OutgoingRuleParseError(val.to_owned());
+
+        let (action, destination) = val
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(error)?;
+        let action = match action {
+            "remote" => OutgoingAction::Remote,
+            "local" => OutgoingAction::Local,
+            _ => return Err(error()),
+        };
+        let (address, port) = parse_destination(destination.trim()).ok_or_else(error)?;
+
+        Ok(OutgoingRule {
+            action,
+            address,
+            port,
+        })
+    }
+}
+
+impl TryFrom<String> for OutgoingRule {
+    type Error = OutgoingRuleParseError;
+
+    fn try_from(val: String) -> Result<Self, Self::Error> {
+        val.parse()
+    }
+}
+
 #[derive(MirrordConfig, Default, Deserialize, PartialEq, Eq, Clone, Debug)]
 #[serde(deny_unknown_fields)]
 #[config(map_to = OutgoingConfig)]
@@ -17,6 +179,34 @@ pub struct OutgoingFileConfig {
 
     #[config(env = "MIRRORD_UDP_OUTGOING", default = "true")]
     pub udp: Option<bool>,
+
+    /// Decides per destination whether outgoing connections are made from the remote pod or
+    /// locally, the first matching rule wins and connections no rule matches are remote. Rules
+    /// are separated by `;` in the environment variable, for example
+    /// `local 127.0.0.0/8;local db.internal:5432;remote *.svc.cluster.local`. Hostnames only
+    /// match with `feature.network.dns` enabled, see [`DestinationAddress::Host`] and
+    /// [`OutgoingRule`].
+    #[config(env = "MIRRORD_OUTGOING_FILTER")]
+    pub filter: Option<VecOrSingle<OutgoingRule>>,
+}
+
+impl OutgoingConfig {
+    /// Where a connection to `destination` is made, `hosts` being the names it was resolved from.
+    pub fn action_for(&self, destination: SocketAddr, hosts: &[String]) -> OutgoingAction {
+        self.filter
+            .iter()
+            .flat_map(VecOrSingle::iter)
+            .find(|rule| rule.matches(destination, hosts))
+            .map_or(OutgoingAction::Remote, |rule| rule.action)
+    }
+
+    /// Whether any rule matches by hostname, see [`DestinationAddress::Host`].
+    pub fn has_host_rules(&self) -> bool {
+        self.filter
+            .iter()
+            .flat_map(VecOrSingle::iter)
+            .any(|rule| matches!(rule.address, DestinationAddress::Host(_)))
+    }
 }
 
 impl MirrordToggleableConfig for OutgoingFileConfig {
@@ -42,6 +232,7 @@ impl MirrordToggleableConfig for OutgoingFileConfig {
                     "udp",
                     Some("MIRRORD_TCP_OUTGOING"),
                 ))?,
+            filter: FromEnv::new("MIRRORD_OUTGOING_FILTER").source_value(),
         })
     }
 }
@@ -107,4 +298,127 @@ mod tests {
             },
         );
     }
+
+    #[rstest]
+    #[case("local 127.0.0.0/8", OutgoingAction::Local, "127.0.0.0/8", None)]
+    #[case("remote 10.0.0.1", OutgoingAction::Remote, "10.0.0.1/32", None)]
+    #[case("local fd00::/8", OutgoingAction::Local, "fd00::/8", None)]
+    #[case(
+        "local 10.0.0.0/8:5432",
+        OutgoingAction::Local,
+        "10.0.0.0/8",
+        Some("5432")
+    )]
+    #[case(
+        "remote [fd00::/8]:8000-8999",
+        OutgoingAction::Remote,
+        "fd00::/8",
+        Some("8000-8999")
+    )]
+    fn cidr_rule(
+        #[case] input: &str,
+        #[case] action: OutgoingAction,
+        #[case] cidr: &str,
+        #[case] port: Option<&str>,
+    ) {
+        assert_eq!(
+            input.parse::<OutgoingRule>().unwrap(),
+            OutgoingRule {
+                action,
+                address: DestinationAddress::Cidr(cidr.parse().unwrap()),
+                port: port.map(|port| port.parse().unwrap()),
+            }
+        );
+    }
+
+    #[rstest]
+    #[case("local :5432", DestinationAddress::Any, Some("5432"))]
+    #[case("local *:6379", DestinationAddress::Any, Some("6379"))]
+    #[case("remote DB.internal", DestinationAddress::Host("db.internal".to_owned()), None)]
+    #[case(
+        "local *.svc.cluster.local:80-90",
+        DestinationAddress::Host("*.svc.cluster.local".to_owned()),
+        Some("80-90")
+    )]
+    fn other_rule(
+        #[case] input: &str,
+        #[case] address: DestinationAddress,
+        #[case] port: Option<&str>,
+    ) {
+        let rule = input.parse::<OutgoingRule>().unwrap();
+
+        assert_eq!(rule.address, address);
+        assert_eq!(rule.port, port.map(|port| port.parse().unwrap()));
+    }
+
+    #[rstest]
+    #[case("local")]
+    #[case("forward 10.0.0.0/8")]
+    #[case("local 10.0.0.0/33")]
+    #[case("local db.internal:http")]
+    #[case("local [fd00::/8")]
+    #[case("remote *.")]
+    #[case("remote db/internal")]
+    fn invalid_rule(#[case] input: &str) {
+        assert!(input.parse::<OutgoingRule>().is_err());
+    }
+
+    #[rstest]
+    #[case("10.0.0.1:5432", &[], OutgoingAction::Local)]
+    #[case("[::ffff:10.0.0.1]:5432", &[], OutgoingAction::Local)]
+    #[case("10.0.0.1:80", &[], OutgoingAction::Remote)]
+    #[case("127.0.0.1:80", &[], OutgoingAction::Local)]
+    #[case("192.168.0.1:6379", &["cache.internal"], OutgoingAction::Local)]
+    #[case("192.168.0.1:6379", &["api.svc.cluster.local."], OutgoingAction::Remote)]
+    #[case("192.168.0.2:443", &["API.example.com"], OutgoingAction::Local)]
+    #[case("192.168.0.2:443", &["example.com"], OutgoingAction::Remote)]
+    #[case("192.168.0.2:443", &["badexample.com"], OutgoingAction::Remote)]
+    fn action_for(
+        #[case] destination: &str,
+        #[case] hosts: &[&str],
+        #[case] action: OutgoingAction,
+    ) {
+        with_env_vars(
+            vec![(
+                "MIRRORD_OUTGOING_FILTER",
+                Some(
+                    "local 10.0.0.0/8:5432;local 127.0.0.0/8;remote *.svc.cluster.local;\
+                     local :6379;local *.example.com",
+                ),
+            )],
+            || {
+                let outgoing = OutgoingFileConfig::default().generate_config().unwrap();
+                let hosts = hosts.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+                assert_eq!(
+                    outgoing.action_for(destination.parse().unwrap(), &hosts),
+                    action
+                );
+            },
+        );
+    }
+
+    #[rstest]
+    #[case(Some("local 10.0.0.0/8;local :6379"), false)]
+    #[case(Some("local 10.0.0.0/8;remote *.svc.cluster.local"), true)]
+    #[case(None, false)]
+    fn has_host_rules(#[case] filter: Option<&str>, #[case] expected: bool) {
+        with_env_vars(vec![("MIRRORD_OUTGOING_FILTER", filter)], || {
+            let outgoing = OutgoingFileConfig::default().generate_config().unwrap();
+
+            assert_eq!(outgoing.has_host_rules(), expected);
+        });
+    }
+
+    #[test]
+    fn no_filter() {
+        with_env_vars(vec![("MIRRORD_OUTGOING_FILTER", None)], || {
+            let outgoing = OutgoingFileConfig::default().generate_config().unwrap();
+
+            assert_eq!(
+                outgoing.action_for("127.0.0.1:80".parse().unwrap(), &[]),
+                OutgoingAction::Remote
+            );
+        });
+    }
 }
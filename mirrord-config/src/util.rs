@@ -58,6 +58,13 @@ impl<T> VecOrSingle<T> {
         }
     }
 
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        match self {
+            VecOrSingle::Single(val) => std::slice::from_ref(val).iter(),
+            VecOrSingle::Multiple(vals) => vals.iter(),
+        }
+    }
+
     pub fn to_vec(self) -> Vec<T> {
         match self {
             VecOrSingle::Single(val) => vec![val],
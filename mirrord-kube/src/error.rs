@@ -0,0 +1,43 @@
+use kube::config::InferConfigError;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum KubeApiError {
+    #[error("mirrord-kube: Failed to get `KubeConfig`!")]
+    KubeConfigError(#[from] InferConfigError),
+
+    #[error("mirrord-kube: Failed to get `Spec` for Pod `{0}`!")]
+    PodSpecNotFound(String),
+
+    #[error("mirrord-kube: Failed to get Pod for Job `{0}`!")]
+    JobPodNotFound(String),
+
+    #[error("mirrord-kube: Kube failed with error `{0}`!")]
+    KubeError(#[from] kube::Error),
+
+    #[error("mirrord-kube: JSON convert error")]
+    JSONConvertError(#[from] serde_json::Error),
+
+    #[error("mirrord-kube: Container not found: `{0}`")]
+    ContainerNotFound(String),
+
+    #[error("mirrord-kube: Node not found for: `{0}`")]
+    NodeNotFound(String),
+
+    #[error("mirrord-kube: Deployment: `{0} not found!`")]
+    DeploymentNotFound(String),
+
+    #[error("mirrord-kube: Invalid target proivded `{0:#?}`!")]
+    InvalidTarget(String),
+
+    #[error("mirrord-kube: Failed to get Container runtime data for `{0}`!")]
+    ContainerRuntimeParseError(String),
+
+    #[error("mirrord-kube: Secret `{0}` has no `{1}`!")]
+    TlsSecretKeyNotFound(String, &'static str),
+
+    #[error("mirrord-kube: `tls_certificate` and `tls_key` have to be set together!")]
+    IncompleteTlsCertificate,
+}
+
+pub type Result<T> = std::result::Result<T, KubeApiError>;
@@ -0,0 +1,5 @@
+//! Creates the mirrord-agent in the cluster, shared by mirrord-layer and mirrord-cli.
+#![feature(let_chains)]
+
+pub mod error;
+pub mod pod_api;
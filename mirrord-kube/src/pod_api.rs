@@ -5,7 +5,7 @@ use futures::{StreamExt, TryStreamExt};
 use k8s_openapi::api::{
     apps::v1::Deployment,
     batch::v1::Job,
-    core::v1::{EphemeralContainer, Pod},
+    core::v1::{EphemeralContainer, Pod, Secret},
 };
 use kube::{
     api::{Api, ListParams, LogParams, Portforwarder, PostParams},
@@ -13,12 +13,13 @@ use kube::{
     Client, Config,
 };
 use mirrord_config::LayerConfig;
+use mirrord_protocol::tcp::TlsCertificate;
 use rand::distributions::{Alphanumeric, DistString};
 use serde_json::{json, to_vec};
 use tokio::pin;
 use tracing::{debug, info, warn};
 
-use crate::error::{LayerError, Result};
+use crate::error::{KubeApiError, Result};
 
 struct EnvVarGuard {
     library: String,
@@ -43,10 +44,77 @@ impl Drop for EnvVarGuard {
     }
 }
 
-pub(crate) async fn create_agent(
+pub async fn create_agent(
     config: LayerConfig,
     connection_port: u16,
 ) -> Result<Portforwarder> {
+    let (pod_api, pod_name) = spawn_agent(config, connection_port).await?;
+
+    pod_api
+        .portforward(&pod_name, &[connection_port])
+        .await
+        .map_err(KubeApiError::KubeError)
+}
+
+/// Runs a short-lived agent (`agent.cleanup`) that removes the steal rules mirrord left in the
+/// target, returning once it's done.
+pub async fn cleanup_agent(config: LayerConfig, connection_port: u16) -> Result<()> {
+    spawn_agent(config, connection_port).await.map(|_| ())
+}
+
+/// The certificate the agent terminates stolen TLS with (`feature.network.incoming.tls_*`), read
+/// from its Kubernetes Secret when it's in one.
+pub async fn steal_tls_certificate(config: &LayerConfig) -> Result<Option<TlsCertificate>> {
+    let incoming = &config.feature.network.incoming;
+
+    match (&incoming.tls_secret, &incoming.tls_certificate, &incoming.tls_key) {
+        (Some(secret_name), ..) => {
+            let _guard = EnvVarGuard::new();
+            let client = kube_client(config.accept_invalid_certificates).await?;
+
+            // START | DEPRECATED: - Scheduled for removal on [28/10/2022]
+            let namespace = if config.target.is_none() && config.pod.name.is_some() {
+                &config.pod.namespace
+            } else {
+                &config.target_namespace
+            };
+            // END
+
+            let secret_api: Api<Secret> = Api::namespaced(client, namespace);
+            let mut data = secret_api.get(secret_name).await?.data.unwrap_or_default();
+            let mut take = |key: &'static str| {
+                data.remove(key)
+                    .map(|value| value.0)
+                    .ok_or_else(|| KubeApiError::TlsSecretKeyNotFound(secret_name.clone(), key))
+            };
+
+            Ok(Some(TlsCertificate::Pem {
+                certificate: take("tls.crt")?,
+                key: take("tls.key")?,
+            }))
+        }
+        (None, Some(certificate), Some(key)) => Ok(Some(TlsCertificate::ContainerPath {
+            certificate: certificate.clone(),
+            key: key.clone(),
+        })),
+        (None, None, None) => Ok(None),
+        (None, ..) => Err(KubeApiError::IncompleteTlsCertificate),
+    }
+}
+
+async fn kube_client(accept_invalid_certificates: bool) -> Result<Client> {
+    if accept_invalid_certificates {
+        let mut config = Config::infer().await?;
+        config.accept_invalid_certs = true;
+        warn!("Accepting invalid certificates");
+        Client::try_from(config).map_err(KubeApiError::KubeError)
+    } else {
+        Client::try_default().await.map_err(KubeApiError::KubeError)
+    }
+}
+
+/// Creates the agent and waits for it to start, returning the name of the pod it runs in.
+async fn spawn_agent(config: LayerConfig, connection_port: u16) -> Result<(Api<Pod>, String)> {
     let _guard = EnvVarGuard::new();
     let LayerConfig {
         target,
@@ -57,14 +125,7 @@ pub(crate) async fn create_agent(
         ..
     } = config.clone();
 
-    let client = if accept_invalid_certificates {
-        let mut config = Config::infer().await?;
-        config.accept_invalid_certs = true;
-        warn!("Accepting invalid certificates");
-        Client::try_from(config).map_err(LayerError::KubeError)?
-    } else {
-        Client::try_default().await.map_err(LayerError::KubeError)?
-    };
+    let client = kube_client(accept_invalid_certificates).await?;
 
     let agent_image = agent.image.unwrap_or_else(|| {
         concat!("ghcr.io/metalbear-co/mirrord:", env!("CARGO_PKG_VERSION")).to_string()
@@ -131,10 +192,8 @@ pub(crate) async fn create_agent(
         )
         .await?
     };
-    pod_api
-        .portforward(&pod_name, &[connection_port])
-        .await
-        .map_err(LayerError::KubeError)
+
+    Ok((pod_api, pod_name))
 }
 
 fn get_agent_name() -> String {
@@ -165,10 +224,20 @@ fn is_ephemeral_container_running(pod: Pod, container_name: &String) -> bool {
         .unwrap_or(false)
 }
 
+/// What the agent logs once it's ready, or done when it's only cleaning up.
+fn agent_startup_line(config: &LayerConfig) -> &'static str {
+    if config.agent.cleanup {
+        "cleanup done"
+    } else {
+        "agent ready"
+    }
+}
+
 async fn wait_for_agent_startup(
     pod_api: &Api<Pod>,
     pod_name: &str,
     container_name: String,
+    startup_line: &str,
 ) -> Result<()> {
     let mut logs = pod_api
         .log_stream(
@@ -183,7 +252,7 @@ async fn wait_for_agent_startup(
 
     while let Some(line) = logs.try_next().await? {
         let line = String::from_utf8_lossy(&line);
-        if line.contains("agent ready") {
+        if line.contains(startup_line) {
             break;
         }
     }
@@ -212,6 +281,13 @@ async fn create_ephemeral_container_agent(
         agent_command_line.push("-t".to_string());
         agent_command_line.push(timeout.to_string());
     }
+    if config.agent.cleanup {
+        agent_command_line.push("--cleanup".to_string());
+    }
+    if let Some(network_interface) = &config.agent.network_interface {
+        agent_command_line.push("-i".to_string());
+        agent_command_line.push(network_interface.clone());
+    }
 
     let ephemeral_container: EphemeralContainer = serde_json::from_value(json!({
         "name": mirrord_agent_name,
@@ -232,12 +308,12 @@ async fn create_ephemeral_container_agent(
     let mut ephemeral_containers_subresource = pod_api
         .get_subresource("ephemeralcontainers", &runtime_data.pod_name)
         .await
-        .map_err(LayerError::KubeError)?;
+        .map_err(KubeApiError::KubeError)?;
 
     let mut spec = ephemeral_containers_subresource
         .spec
         .as_mut()
-        .ok_or_else(|| LayerError::PodSpecNotFound(runtime_data.pod_name.clone()))?;
+        .ok_or_else(|| KubeApiError::PodSpecNotFound(runtime_data.pod_name.clone()))?;
 
     spec.ephemeral_containers = match spec.ephemeral_containers.clone() {
         Some(mut ephemeral_containers) => {
@@ -252,10 +328,10 @@ async fn create_ephemeral_container_agent(
             "ephemeralcontainers",
             &runtime_data.pod_name,
             &PostParams::default(),
-            to_vec(&ephemeral_containers_subresource).map_err(LayerError::from)?,
+            to_vec(&ephemeral_containers_subresource).map_err(KubeApiError::from)?,
         )
         .await
-        .map_err(LayerError::KubeError)?;
+        .map_err(KubeApiError::KubeError)?;
 
     let params = ListParams::default()
         .fields(&format!("metadata.name={}", &runtime_data.pod_name))
@@ -273,7 +349,13 @@ async fn create_ephemeral_container_agent(
         }
     }
 
-    wait_for_agent_startup(pod_api, &runtime_data.pod_name, mirrord_agent_name).await?;
+    wait_for_agent_startup(
+        pod_api,
+        &runtime_data.pod_name,
+        mirrord_agent_name,
+        agent_startup_line(config),
+    )
+    .await?;
 
     debug!("container is ready");
     Ok(runtime_data.pod_name.to_string())
@@ -302,6 +384,13 @@ async fn create_job_pod_agent(
         agent_command_line.push("-t".to_string());
         agent_command_line.push(timeout.to_string());
     }
+    if config.agent.cleanup {
+        agent_command_line.push("--cleanup".to_string());
+    }
+    if let Some(network_interface) = &config.agent.network_interface {
+        agent_command_line.push("-i".to_string());
+        agent_command_line.push(network_interface.clone());
+    }
 
     let agent_pod: Job =
         serde_json::from_value(json!({ // Only Jobs support self deletion after completion
@@ -353,7 +442,7 @@ async fn create_job_pod_agent(
     job_api
         .create(&PostParams::default(), &agent_pod)
         .await
-        .map_err(LayerError::KubeError)?;
+        .map_err(KubeApiError::KubeError)?;
 
     let params = ListParams::default()
         .labels(&format!("job-name={}", mirrord_agent_job_name))
@@ -365,7 +454,8 @@ async fn create_job_pod_agent(
     while let Some(Ok(pod)) = stream.next().await {
         if let Some(status) = &pod.status && let Some(phase) = &status.phase {
                     debug!("Pod Phase = {phase:?}");
-                if phase == "Running" {
+                // A cleanup agent may be done before we get to see it running.
+                if phase == "Running" || (config.agent.cleanup && phase == "Succeeded") {
                     break;
                 }
             }
@@ -374,19 +464,25 @@ async fn create_job_pod_agent(
     let pods = pod_api
         .list(&ListParams::default().labels(&format!("job-name={}", mirrord_agent_job_name)))
         .await
-        .map_err(LayerError::KubeError)?;
+        .map_err(KubeApiError::KubeError)?;
 
     let pod_name = pods
         .items
         .first()
         .and_then(|pod| pod.metadata.name.clone())
-        .ok_or(LayerError::JobPodNotFound(mirrord_agent_job_name))?;
-
-    wait_for_agent_startup(pod_api, &pod_name, "mirrord-agent".to_string()).await?;
+        .ok_or(KubeApiError::JobPodNotFound(mirrord_agent_job_name))?;
+
+    wait_for_agent_startup(
+        pod_api,
+        &pod_name,
+        "mirrord-agent".to_string(),
+        agent_startup_line(config),
+    )
+    .await?;
     Ok(pod_name)
 }
 
-pub(crate) struct RuntimeData {
+pub struct RuntimeData {
     pod_name: String,
     node_name: String,
     container_id: String,
@@ -411,7 +507,7 @@ impl RuntimeData {
             &container_statuses
                 .iter()
                 .find(|&status| &status.name == container_name)
-                .ok_or_else(|| LayerError::ContainerNotFound(container_name.clone()))?
+                .ok_or_else(|| KubeApiError::ContainerNotFound(container_name.clone()))?
                 .container_id
         } else {
             info!("No container name specified, defaulting to first container found");
@@ -449,7 +545,7 @@ impl RuntimeData {
 // END
 
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) struct DeploymentData {
+pub struct DeploymentData {
     pub deployment: String,
 }
 
@@ -460,7 +556,7 @@ impl RuntimeDataProvider for DeploymentData {
         let deployment = deployment_api
             .get(&self.deployment)
             .await
-            .map_err(LayerError::KubeError)?;
+            .map_err(KubeApiError::KubeError)?;
 
         let pod_label = deployment
             .spec
@@ -468,7 +564,7 @@ impl RuntimeDataProvider for DeploymentData {
             .and_then(|metadata| metadata.labels)
             .and_then(|labels| labels.get("app").cloned())
             .ok_or_else(|| {
-                LayerError::DeploymentNotFound(format!(
+                KubeApiError::DeploymentNotFound(format!(
                     "Label for deployment: {}, not found!",
                     self.deployment.clone()
                 ))
@@ -478,17 +574,17 @@ impl RuntimeDataProvider for DeploymentData {
         let deployment_pods = pod_api
             .list(&ListParams::default().labels(&format!("app={}", pod_label)))
             .await
-            .map_err(LayerError::KubeError)?;
+            .map_err(KubeApiError::KubeError)?;
 
         let first_pod = deployment_pods.items.first().ok_or_else(|| {
-            LayerError::DeploymentNotFound(format!(
+            KubeApiError::DeploymentNotFound(format!(
                 "Failed to fetch the default(first pod) from ObjectList<Pod> for {}",
                 self.deployment.clone()
             ))
         })?;
 
         let pod_name = first_pod.clone().metadata.name.ok_or_else(|| {
-            LayerError::DeploymentNotFound(format!(
+            KubeApiError::DeploymentNotFound(format!(
                 "Failed to fetch the name of the default pod in deployment {}, pod {:?}",
                 self.deployment.clone(),
                 first_pod.clone()
@@ -520,7 +616,7 @@ impl RuntimeDataProvider for DeploymentData {
             Some((container_name, container_runtime_and_id))
         }()
         .ok_or_else(|| {
-            LayerError::ContainerNotFound(format!(
+            KubeApiError::ContainerNotFound(format!(
                 "Failed to fetch container for pod {:?}, deployment {}",
                 first_pod.clone(),
                 self.deployment.clone()
@@ -538,7 +634,7 @@ impl RuntimeDataProvider for DeploymentData {
             .spec
             .and_then(|spec| spec.node_name)
             .ok_or_else(|| {
-                LayerError::NodeNotFound(format!(
+                KubeApiError::NodeNotFound(format!(
                     "Target: {:?} | Pod: {:?} | Container: {}",
                     self.clone(),
                     first_pod.clone(),
@@ -558,7 +654,7 @@ impl RuntimeDataProvider for DeploymentData {
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) enum Target {
+pub enum Target {
     Deployment(DeploymentData),
     Pod(PodData),
 }
@@ -578,7 +674,7 @@ impl Target {
 }
 
 impl FromStr for DeploymentData {
-    type Err = LayerError;
+    type Err = KubeApiError;
 
     fn from_str(input: &str) -> Result<Self> {
         let target_data = input.split('/').collect::<Vec<&str>>();
@@ -586,7 +682,7 @@ impl FromStr for DeploymentData {
             Some(&"deployment") if target_data.len() == 2 => Ok(DeploymentData {
                 deployment: target_data[1].to_string(),
             }),
-            _ => Err(LayerError::InvalidTarget(format!(
+            _ => Err(KubeApiError::InvalidTarget(format!(
                 "Provided target: {:?} is not a deployment.",
                 input
             ))),
@@ -595,13 +691,13 @@ impl FromStr for DeploymentData {
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) struct PodData {
+pub struct PodData {
     pub pod_name: String,
     pub container_name: Option<String>,
 }
 
 impl FromStr for PodData {
-    type Err = LayerError;
+    type Err = KubeApiError;
 
     fn from_str(input: &str) -> Result<Self> {
         let target_data = input.split('/').collect::<Vec<&str>>();
@@ -616,7 +712,7 @@ impl FromStr for PodData {
                     container_name: Some(target_data[3].to_string()),
                 })
             }
-            _ => Err(LayerError::InvalidTarget(format!(
+            _ => Err(KubeApiError::InvalidTarget(format!(
                 "Provided target: {:?} is neither a pod or a deployment.",
                 input
             ))),
@@ -655,7 +751,7 @@ impl RuntimeDataProvider for PodData {
             Some((container_name, container_runtime_and_id))
         }()
         .ok_or_else(|| {
-            LayerError::ContainerNotFound(format!(
+            KubeApiError::ContainerNotFound(format!(
                 "Failed to fetch container for pod {:#?}",
                 self.clone(),
             ))
@@ -670,7 +766,7 @@ impl RuntimeDataProvider for PodData {
         let node_name = pod
             .spec
             .and_then(|spec| spec.node_name)
-            .ok_or_else(|| LayerError::NodeNotFound(format!("{:?}", self.clone())))?;
+            .ok_or_else(|| KubeApiError::NodeNotFound(format!("{:?}", self.clone())))?;
 
         Ok(RuntimeData {
             container_id,
@@ -684,7 +780,7 @@ impl RuntimeDataProvider for PodData {
 }
 
 impl FromStr for Target {
-    type Err = LayerError;
+    type Err = KubeApiError;
 
     fn from_str(target: &str) -> Result<Self> {
         target
@@ -694,28 +790,28 @@ impl FromStr for Target {
     }
 }
 
-pub(crate) struct ContainerData {
+pub struct ContainerData {
     container_runtime: String,
     socket_path: String,
     container_id: String,
 }
 
 impl FromStr for ContainerData {
-    type Err = LayerError;
+    type Err = KubeApiError;
     fn from_str(input: &str) -> Result<Self> {
         let container_runtime_and_id = input.split("://").collect::<Vec<&str>>();
         let (container_runtime, socket_path) = match container_runtime_and_id.first() {
             Some(&"docker") => ("docker", "/var/run/docker.sock"),
             Some(&"containerd") => ("containerd", "/run/containerd/containerd.sock"),
             _ => {
-                return Err(LayerError::ContainerRuntimeParseError(
+                return Err(KubeApiError::ContainerRuntimeParseError(
                     "unsupported container runtime".to_owned(),
                 ))
             }
         };
 
         let container_id = container_runtime_and_id.last().ok_or_else(|| {
-            LayerError::ContainerRuntimeParseError(format!(
+            KubeApiError::ContainerRuntimeParseError(format!(
                 "Failed while parsing container_id for {}",
                 input
             ))
@@ -728,3 +824,35 @@ impl FromStr for ContainerData {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("pod/foobaz", Target::Pod(PodData {pod_name: "foobaz".to_string(), container_name: None}))]
+    #[case("deployment/foobaz", Target::Deployment(DeploymentData {deployment: "foobaz".to_string()}))]
+    #[case("deployment/nginx-deployment", Target::Deployment(DeploymentData {deployment: "nginx-deployment".to_string()}))]
+    #[case("pod/foo/container/baz", Target::Pod(PodData { pod_name: "foo".to_string(), container_name: Some("baz".to_string()) }))]
+    fn test_target_parses(#[case] target: &str, #[case] expected: Target) {
+        let target = target.parse::<Target>().unwrap();
+        assert_eq!(target, expected)
+    }
+
+    #[rstest]
+    #[should_panic(expected = "InvalidTarget")]
+    #[case::panic("deployment/foobaz/blah")]
+    #[should_panic(expected = "InvalidTarget")]
+    #[case::panic("pod/foo/baz")]
+    fn test_target_parse_fails(#[case] target: &str) {
+        let target = target.parse::<Target>().unwrap();
+        assert_eq!(
+            target,
+            Target::Deployment(DeploymentData {
+                deployment: "foobaz".to_string()
+            })
+        )
+    }
+}
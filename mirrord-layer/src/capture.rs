@@ -0,0 +1,166 @@
+//! Saves the frames the agent sniffs for our ports (see [`LayerTcp::CaptureFrames`]) as pcap
+//! files.
+//!
+//! [`LayerTcp::CaptureFrames`]: mirrord_protocol::tcp::LayerTcp::CaptureFrames
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use mirrord_protocol::tcp::SniffedFrame;
+
+/// pcap magic number for microsecond timestamps, readers tell our byte order by it.
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_VERSION: (u16, u16) = (2, 4);
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+const PCAP_HEADER_LENGTH: u64 = 24;
+const PCAP_RECORD_HEADER_LENGTH: u64 = 16;
+
+/// Writes frames to `path`, moving on to `capture.1.pcap`, `capture.2.pcap`... (for a `path` of
+/// `capture.pcap`) whenever the current file would go over `max_bytes`.
+///
+/// Files are only created once there's something to write to them.
+#[derive(Debug)]
+pub(crate) struct PcapWriter {
+    path: PathBuf,
+    max_bytes: u64,
+    /// Index of the current file, `0` is `path` itself.
+    index: usize,
+    file: Option<File>,
+    written: u64,
+}
+
+impl PcapWriter {
+    pub(crate) fn new(path: PathBuf, max_bytes: u64) -> Self {
+        PcapWriter {
+            path,
+            max_bytes,
+            index: 0,
+            file: None,
+            written: 0,
+        }
+    }
+
+    pub(crate) fn write(&mut self, frame: &SniffedFrame) -> io::Result<()> {
+        let record_length = PCAP_RECORD_HEADER_LENGTH + frame.bytes.len() as u64;
+
+        // A file always gets at least one frame, even when it's bigger than `max_bytes`.
+        if self.file.is_some() && self.written + record_length > self.max_bytes {
+            self.file = None;
+            self.index += 1;
+        }
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let mut file = File::create(rotated_path(&self.path, self.index))?;
+                file.write_all(&pcap_header())?;
+                self.written = PCAP_HEADER_LENGTH;
+                self.file.insert(file)
+            }
+        };
+
+        file.write_all(&pcap_record(frame))?;
+        self.written += record_length;
+
+        Ok(())
+    }
+}
+
+/// `capture.pcap` for index 0, then `capture.1.pcap`, `capture.2.pcap`...
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        return path.to_path_buf();
+    }
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{stem}.{index}.{}", extension.to_string_lossy()),
+        None => format!("{stem}.{index}"),
+    };
+
+    path.with_file_name(file_name)
+}
+
+fn pcap_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(PCAP_HEADER_LENGTH as usize);
+    header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+    header.extend_from_slice(&PCAP_VERSION.0.to_le_bytes());
+    header.extend_from_slice(&PCAP_VERSION.1.to_le_bytes());
+    // Timezone offset and timestamp accuracy, always 0.
+    header.extend_from_slice(&0i32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    header
+}
+
+fn pcap_record(frame: &SniffedFrame) -> Vec<u8> {
+    let length = frame.bytes.len() as u32;
+
+    let mut record = Vec::with_capacity(PCAP_RECORD_HEADER_LENGTH as usize + frame.bytes.len());
+    record.extend_from_slice(&((frame.timestamp_us / 1_000_000) as u32).to_le_bytes());
+    record.extend_from_slice(&((frame.timestamp_us % 1_000_000) as u32).to_le_bytes());
+    // Captured and original length, we get the frames whole.
+    record.extend_from_slice(&length.to_le_bytes());
+    record.extend_from_slice(&length.to_le_bytes());
+    record.extend_from_slice(&frame.bytes);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn frame(timestamp_us: u64, length: usize) -> SniffedFrame {
+        SniffedFrame {
+            timestamp_us,
+            bytes: vec![0xab; length],
+        }
+    }
+
+    #[test]
+    fn rotated_paths() {
+        let path = Path::new("/tmp/capture.pcap");
+
+        assert_eq!(rotated_path(path, 0), path);
+        assert_eq!(rotated_path(path, 2), Path::new("/tmp/capture.2.pcap"));
+        assert_eq!(
+            rotated_path(Path::new("capture"), 1),
+            Path::new("capture.1")
+        );
+    }
+
+    #[test]
+    fn write_and_rotate() {
+        let dir = std::env::temp_dir().join(format!("mirrord-capture-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("capture.pcap");
+
+        // Room for the header and two 34 byte frames.
+        let mut writer = PcapWriter::new(path.clone(), 24 + 2 * (16 + 34));
+        writer.write(&frame(1_500_000, 34)).unwrap();
+        writer.write(&frame(1_600_000, 34)).unwrap();
+        writer.write(&frame(1_700_000, 34)).unwrap();
+
+        let first = fs::read(&path).unwrap();
+        assert_eq!(first.len(), 24 + 2 * (16 + 34));
+        assert_eq!(first[..4], PCAP_MAGIC.to_le_bytes());
+        assert_eq!(first[20..24], LINKTYPE_ETHERNET.to_le_bytes());
+        // First record: 1 second, 500000 microseconds, 34 bytes captured out of 34.
+        assert_eq!(first[24..28], 1u32.to_le_bytes());
+        assert_eq!(first[28..32], 500_000u32.to_le_bytes());
+        assert_eq!(first[32..36], 34u32.to_le_bytes());
+        assert_eq!(first[36..40], 34u32.to_le_bytes());
+
+        let second = fs::read(dir.join("capture.1.pcap")).unwrap();
+        assert_eq!(second.len(), 24 + 16 + 34);
+        assert_eq!(second[..24], first[..24]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -7,13 +7,20 @@ use crate::{
     error::{HookError, HookResult},
     file::HookMessageFile,
     outgoing::{tcp::TcpOutgoing, udp::UdpOutgoing},
+    session::{self, is_session_owner},
     tcp::HookMessageTcp,
+    udp_mirror::HookMessageUdp,
     HOOK_SENDER,
 };
 
 pub(crate) type ResponseDeque<T> = VecDeque<ResponseChannel<T>>;
 
 pub(crate) fn blocking_send_hook_message(message: HookMessage) -> HookResult<()> {
+    // The layer thread didn't survive the fork, forked workers go through the session owner.
+    if !is_session_owner() {
+        return session::forward(message);
+    }
+
     unsafe {
         HOOK_SENDER
             .as_ref()
@@ -36,6 +43,7 @@ pub struct GetAddrInfoHook {
 #[derive(Debug)]
 pub(crate) enum HookMessage {
     Tcp(HookMessageTcp),
+    Udp(HookMessageUdp),
     TcpOutgoing(TcpOutgoing),
     UdpOutgoing(UdpOutgoing),
     File(HookMessageFile),
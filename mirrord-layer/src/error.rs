@@ -1,7 +1,6 @@
 use std::{env::VarError, os::unix::io::RawFd, ptr, str::ParseBoolError};
 
 use errno::set_errno;
-use kube::config::InferConfigError;
 use libc::FILE;
 use mirrord_protocol::{tcp::LayerTcp, ConnectionId, ResponseError};
 use thiserror::Error;
@@ -9,7 +8,6 @@ use tokio::sync::{mpsc::error::SendError, oneshot::error::RecvError};
 use tracing::{error, warn};
 
 use super::HookMessage;
-// use crate::pod_api::Target;
 
 #[derive(Error, Debug)]
 pub(crate) enum HookError {
@@ -49,6 +47,9 @@ pub(crate) enum HookError {
     #[error("mirrord-layer: HOOK_SENDER is `None`!")]
     EmptyHookSender,
 
+    #[error("mirrord-layer: Only the process that loaded the layer talks to the agent, not forked workers!")]
+    NotSessionOwner,
+
     #[error("mirrord-layer: Failed converting `sockaddr`!")]
     AddressConversion,
 
@@ -120,36 +121,6 @@ pub(crate) enum LayerError {
 
     #[error("mirrord-layer: Unmatched pong!")]
     UnmatchedPong,
-
-    #[error("mirrord-layer: Failed to get `KubeConfig`!")]
-    KubeConfigError(#[from] InferConfigError),
-
-    #[error("mirrord-layer: Failed to get `Spec` for Pod `{0}`!")]
-    PodSpecNotFound(String),
-
-    #[error("mirrord-layer: Failed to get Pod for Job `{0}`!")]
-    JobPodNotFound(String),
-
-    #[error("mirrord-layer: Kube failed with error `{0}`!")]
-    KubeError(#[from] kube::Error),
-
-    #[error("mirrord-layer: JSON convert error")]
-    JSONConvertError(#[from] serde_json::Error),
-
-    #[error("mirrord-layer: Container not found: `{0}`")]
-    ContainerNotFound(String),
-
-    #[error("mirrord-layer: Node not found for: `{0}`")]
-    NodeNotFound(String),
-
-    #[error("mirrord-layer: Deployment: `{0} not found!`")]
-    DeploymentNotFound(String),
-
-    #[error("mirrord-layer: Invalid target proivded `{0:#?}`!")]
-    InvalidTarget(String),
-
-    #[error("mirrord-layer: Failed to get Container runtime data for `{0}`!")]
-    ContainerRuntimeParseError(String),
 }
 
 // Cannot have a generic From<T> implementation for this error, so explicitly implemented here.
@@ -175,7 +146,8 @@ impl From<HookError> for i64 {
             | HookError::LocalFDNotFound(_)
             | HookError::BypassedType(_)
             | HookError::BypassedDomain(_)
-            | HookError::BypassedPort(_) => {
+            | HookError::BypassedPort(_)
+            | HookError::NotSessionOwner => {
                 warn!("Recoverable issue >> {:#?}", fail)
             }
             HookError::ResponseError(ResponseError::DnsFailure(code)) => {
@@ -207,6 +179,7 @@ impl From<HookError> for i64 {
             HookError::TryFromInt(_) => libc::EINVAL,
             HookError::LocalFDNotFound(..) => libc::EBADF,
             HookError::EmptyHookSender => libc::EINVAL,
+            HookError::NotSessionOwner => libc::ENOTSUP,
             HookError::IO(io_fail) => io_fail.raw_os_error().unwrap_or(libc::EIO),
             HookError::LockError => libc::EINVAL,
             HookError::ResponseError(response_fail) => match response_fail {
@@ -221,6 +194,10 @@ impl From<HookError> for i64 {
                     mirrord_protocol::RemoteError::ConnectTimedOut(_) => libc::ENETUNREACH,
                     _ => libc::EINVAL,
                 },
+                ResponseError::PortAlreadyStolen(_) => libc::EADDRINUSE,
+                ResponseError::InvalidTlsCertificate(_) => libc::EINVAL,
+                ResponseError::InvalidHttpFilter(..) => libc::EINVAL,
+                ResponseError::RedirectFailed(..) => libc::EIO,
             },
             HookError::DNSNoName => libc::EFAULT,
             HookError::Utf8(_) => libc::EINVAL,
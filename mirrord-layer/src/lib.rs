@@ -9,7 +9,7 @@
 #![feature(async_closure)]
 
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     path::PathBuf,
     sync::{LazyLock, OnceLock},
 };
@@ -20,33 +20,45 @@ use error::{LayerError, Result};
 use file::OPEN_FILES;
 use frida_gum::{interceptor::Interceptor, Gum};
 use futures::{SinkExt, StreamExt};
-use kube::api::Portforwarder;
 use libc::c_int;
 use mirrord_config::{
-    config::MirrordConfig, pod::PodConfig, util::VecOrSingle, LayerConfig, LayerFileConfig,
+    config::MirrordConfig, incoming::IncomingConfig, network::IgnoredPorts,
+    outgoing::OutgoingConfig, pod::PodConfig, util::VecOrSingle, LayerConfig, LayerFileConfig,
 };
+use mirrord_kube::{error::KubeApiError, pod_api};
 use mirrord_macro::hook_guard_fn;
 use mirrord_protocol::{
+    tcp::{DaemonTcp, HttpFilter, MirrorLimits, StealFallback, TcpRecordOrigin, TlsCertificate},
     AddrInfoInternal, ClientCodec, ClientMessage, DaemonMessage, EnvVars, GetAddrInfoRequest,
-    GetEnvVarsRequest,
+    GetEnvVarsRequest, Port,
 };
 use outgoing::{tcp::TcpOutgoingHandler, udp::UdpOutgoingHandler};
 use rand::Rng;
-use socket::SOCKETS;
-use tcp::TcpHandler;
+use session::Forwarded;
+use socket::{CONNECTION_SOURCES, SOCKETS};
+use tcp::{HookMessageTcp, Listen, SourceFilters, TcpHandler};
 use tcp_mirror::TcpMirrorHandler;
 use tcp_steal::TcpStealHandler;
+use udp_mirror::UdpMirrorHandler;
 use tokio::{
+    net::UnixDatagram,
     runtime::Runtime,
     select,
     sync::mpsc::{channel, Receiver, Sender},
     time::{sleep, Duration},
 };
-use tracing::{error, info, trace};
+use tracing::{debug, error, info, trace, warn};
 use tracing_subscriber::{fmt::format::FmtSpan, prelude::*};
 
-use crate::{common::HookMessage, file::FileHandler};
+use crate::{
+    capture::PcapWriter,
+    common::HookMessage,
+    file::FileHandler,
+    replay::{ReplayPace, TcpRecorder},
+    shadow::ShadowReport,
+};
 
+mod capture;
 mod common;
 mod detour;
 mod error;
@@ -54,11 +66,14 @@ mod file;
 mod go_env;
 mod macros;
 mod outgoing;
-mod pod_api;
+mod replay;
+mod session;
+mod shadow;
 mod socket;
 mod tcp;
 mod tcp_mirror;
 mod tcp_steal;
+mod udp_mirror;
 
 #[cfg(target_os = "linux")]
 #[cfg(target_arch = "x86_64")]
@@ -82,6 +97,15 @@ pub(crate) static ENABLED_FILE_RO_OPS: OnceLock<bool> = OnceLock::new();
 pub(crate) static ENABLED_TCP_OUTGOING: OnceLock<bool> = OnceLock::new();
 pub(crate) static ENABLED_UDP_OUTGOING: OnceLock<bool> = OnceLock::new();
 
+/// Local port -> remote port, from `feature.network.incoming.port_mapping`.
+pub(crate) static INCOMING_PORT_MAPPING: OnceLock<HashMap<Port, Port>> = OnceLock::new();
+
+/// Ports `bind` leaves local, from `feature.network.{ignore,allow}_ports`.
+pub(crate) static IGNORED_INCOMING_PORTS: OnceLock<IgnoredPorts> = OnceLock::new();
+
+/// Decides which destinations `connect` reaches locally, from `feature.network.outgoing.filter`.
+pub(crate) static OUTGOING_FILTER: OnceLock<OutgoingConfig> = OnceLock::new();
+
 #[ctor]
 fn before_init() {
     if !cfg!(test) {
@@ -100,7 +124,10 @@ fn before_init() {
                 let skip_processes = config.skip_processes.clone().map(VecOrSingle::to_vec);
 
                 if should_load(given_process, skip_processes) {
-                    deprecation_check(&config);
+                    // Replaying doesn't need a target.
+                    if config.feature.network.incoming.replay_file.is_none() {
+                        deprecation_check(&config);
+                    }
                     init(config);
                 }
             }
@@ -150,25 +177,50 @@ fn init(config: LayerConfig) {
 
     info!("Using port `{connection_port:?}` for communication");
 
-    let port_forwarder = RUNTIME
-        .block_on(pod_api::create_agent(config.clone(), connection_port))
-        .unwrap_or_else(|err| match err {
-            LayerError::KubeError(kube::Error::HyperError(err)) => {
-                eprintln!("\nmirrord encountered an error accessing the Kubernetes API. Consider passing --accept-invalid-certificates.\n");
+    let replay_file = config.feature.network.incoming.replay_file.clone();
+
+    // The agent only needs a certificate when it terminates the TLS of stolen connections.
+    let incoming = &config.feature.network.incoming;
+    let tls_certificate = if replay_file.is_none()
+        && incoming.steals_any_port()
+        && incoming.is_tls_terminated()
+    {
+        RUNTIME
+            .block_on(pod_api::steal_tls_certificate(&config))
+            .unwrap_or_else(|err| {
+                panic!("failed loading the TLS certificate to steal with: {}", err)
+            })
+    } else {
+        None
+    };
 
-                match err.into_cause() {
-                    Some(cause) => panic!("{}", cause),
-                    None => panic!("mirrord got KubeError::HyperError"),
+    // No agent when replaying, `replay::replay_agent` answers the layer instead.
+    let port_forwarder = replay_file.is_none().then(|| {
+        RUNTIME
+            .block_on(pod_api::create_agent(config.clone(), connection_port))
+            .unwrap_or_else(|err| match err {
+                KubeApiError::KubeError(kube::Error::HyperError(err)) => {
+                    eprintln!("\nmirrord encountered an error accessing the Kubernetes API. Consider passing --accept-invalid-certificates.\n");
+
+                    match err.into_cause() {
+                        Some(cause) => panic!("{}", cause),
+                        None => panic!("mirrord got KubeError::HyperError"),
+                    }
                 }
-            }
-            _ => panic!("failed to create agent: {}", err),
-        });
+                _ => panic!("failed to create agent: {}", err),
+            })
+    });
 
     let (sender, receiver) = channel::<HookMessage>(1000);
     unsafe {
         HOOK_SENDER = Some(sender);
     };
 
+    // Both are shared with the processes the user forks, so they're created before any can be.
+    LazyLock::force(&CONNECTION_SOURCES);
+    let forwarded_listens =
+        session::start().expect("Creating the socket forked workers listen through failed!");
+
     let enabled_file_ops = ENABLED_FILE_OPS
         .get_or_init(|| config.feature.fs.is_read() || config.feature.fs.is_write());
     ENABLED_FILE_RO_OPS
@@ -180,15 +232,76 @@ fn init(config: LayerConfig) {
     ENABLED_UDP_OUTGOING
         .set(config.feature.network.outgoing.udp)
         .expect("Setting ENABLED_UDP_OUTGOING singleton");
+    INCOMING_PORT_MAPPING
+        .set(
+            config
+                .feature
+                .network
+                .incoming
+                .port_mapping
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .collect(),
+        )
+        .expect("Setting INCOMING_PORT_MAPPING singleton");
+    IGNORED_INCOMING_PORTS
+        .set(config.feature.network.ignored_ports())
+        .expect("Setting IGNORED_INCOMING_PORTS singleton");
+    if config.feature.network.outgoing.has_host_rules() {
+        if config.feature.network.dns {
+            warn!(
+                "feature.network.outgoing.filter hostnames only match names resolved through \
+                 getaddrinfo, connections of Go binaries using the pure Go resolver only match \
+                 address rules"
+            );
+        } else {
+            warn!(
+                "feature.network.outgoing.filter hostnames never match with feature.network.dns \
+                 disabled, only address rules apply"
+            );
+        }
+    }
+    OUTGOING_FILTER
+        .set(config.feature.network.outgoing.clone())
+        .expect("Setting OUTGOING_FILTER singleton");
 
     enable_hooks(*enabled_file_ops, config.feature.network.dns);
 
-    RUNTIME.block_on(start_layer_thread(
-        port_forwarder,
-        receiver,
-        config,
-        connection_port,
-    ));
+    match (port_forwarder, replay_file) {
+        (Some(mut port_forwarder), _) => {
+            // TODO: Make port configurable
+            let stream = port_forwarder.take_stream(connection_port).unwrap();
+            RUNTIME.block_on(start_layer_thread(
+                stream,
+                receiver,
+                forwarded_listens,
+                config,
+                tls_certificate,
+            ));
+        }
+        (None, Some(replay_file)) => {
+            let pace = ReplayPace {
+                speed: config.feature.network.incoming.replay_speed,
+                concurrency: config.feature.network.incoming.replay_concurrency,
+            };
+            let (stream, agent_stream) = tokio::io::duplex(1024 * 1024);
+
+            RUNTIME.spawn(async move {
+                if let Err(err) = replay::replay_agent(agent_stream, &replay_file, pace).await {
+                    error!("Failed replaying {:?}: {}", replay_file, err);
+                }
+            });
+            RUNTIME.block_on(start_layer_thread(
+                stream,
+                receiver,
+                forwarded_listens,
+                config,
+                tls_certificate,
+            ));
+        }
+        (None, None) => unreachable!(),
+    }
 }
 
 fn should_load(given_process: &str, skip_processes: Option<Vec<String>>) -> bool {
@@ -206,6 +319,7 @@ where
     pub codec: actix_codec::Framed<T, ClientCodec>,
     ping: bool,
     tcp_mirror_handler: TcpMirrorHandler,
+    udp_mirror_handler: UdpMirrorHandler,
     tcp_outgoing_handler: TcpOutgoingHandler,
     udp_outgoing_handler: UdpOutgoingHandler,
     // TODO: Starting to think about a better abstraction over this whole mess. File operations are
@@ -222,24 +336,108 @@ where
 
     pub tcp_steal_handler: TcpStealHandler,
 
-    steal: bool,
+    /// Decides if a `Listen` goes to the mirror or the steal handler.
+    incoming: IncomingConfig,
+
+    /// Saves the incoming connections, from `feature.network.incoming.record_file`.
+    recorder: Option<TcpRecorder>,
 }
 
 impl<T> Layer<T>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send,
 {
-    fn new(codec: actix_codec::Framed<T, ClientCodec>, steal: bool) -> Layer<T> {
+    fn new(
+        codec: actix_codec::Framed<T, ClientCodec>,
+        incoming: IncomingConfig,
+        tls_certificate: Option<TlsCertificate>,
+    ) -> Layer<T> {
+        let http_filter = incoming.is_http_filtered().then(|| HttpFilter {
+            header: incoming.http_header_filter.clone(),
+            path: incoming.http_path_filter.clone(),
+        });
+        let fallback = incoming.fallback.then_some(StealFallback {
+            accept_timeout_ms: incoming.fallback_timeout,
+        });
+        let mirror_limits = incoming.is_mirror_limited().then_some(MirrorLimits {
+            sample_percent: incoming.mirror_sample_percent,
+            max_connections: incoming.mirror_max_connections,
+            bytes_per_second: incoming.mirror_bytes_per_second,
+        });
+        let source_filters = SourceFilters::new(
+            incoming
+                .source_filter
+                .iter()
+                .flat_map(VecOrSingle::iter)
+                .copied()
+                .collect(),
+            incoming.port_source_filters.iter().flatten().cloned().collect(),
+        );
+        let capture = incoming
+            .capture_file
+            .clone()
+            .map(|path| PcapWriter::new(path, incoming.capture_max_bytes));
+        let shadow = incoming
+            .shadow_compare_file
+            .as_ref()
+            .and_then(|path| match ShadowReport::new(path) {
+                Ok(report) => Some(report),
+                Err(fail) => {
+                    error!("Failed creating shadow compare report {:?}: {}", path, fail);
+                    None
+                }
+            });
+        let recorder = incoming
+            .record_file
+            .as_ref()
+            .and_then(|path| match TcpRecorder::new(path) {
+                Ok(recorder) => Some(recorder),
+                Err(fail) => {
+                    error!("Failed creating recording {:?}: {}", path, fail);
+                    None
+                }
+            });
+
         Self {
             codec,
             ping: false,
-            tcp_mirror_handler: TcpMirrorHandler::default(),
+            tcp_mirror_handler: TcpMirrorHandler::new(
+                mirror_limits,
+                source_filters.clone(),
+                capture,
+                shadow,
+            ),
+            udp_mirror_handler: UdpMirrorHandler::default(),
             tcp_outgoing_handler: TcpOutgoingHandler::default(),
             udp_outgoing_handler: Default::default(),
             file_handler: FileHandler::default(),
             getaddrinfo_handler_queue: VecDeque::new(),
-            tcp_steal_handler: TcpStealHandler::default(),
-            steal,
+            tcp_steal_handler: TcpStealHandler::new(
+                http_filter,
+                fallback,
+                source_filters,
+                tls_certificate,
+                incoming.tls_local,
+            ),
+            incoming,
+            recorder,
+        }
+    }
+
+    /// Sends the incoming settings that apply to every port, once before any `Listen`.
+    async fn start_session(&mut self) -> Result<()> {
+        self.tcp_mirror_handler
+            .start_session(&mut self.codec)
+            .await?;
+        self.tcp_steal_handler.start_session(&mut self.codec).await
+    }
+
+    /// Stops recording when it fails, rather than leaving a recording with holes in it.
+    fn record(&mut self, origin: TcpRecordOrigin, message: &DaemonTcp) {
+        if let Some(recorder) = &mut self.recorder
+            && let Err(fail) = recorder.record(origin, message) {
+            error!("Failed recording, stopping: {}", fail);
+            self.recorder = None;
         }
     }
 
@@ -247,7 +445,14 @@ where
     async fn handle_hook_message(&mut self, hook_message: HookMessage) {
         match hook_message {
             HookMessage::Tcp(message) => {
-                if self.steal {
+                let HookMessageTcp::Listen(Listen { requested_port, .. }) = &message;
+                let mode = self.incoming.mode_for(*requested_port);
+                debug!(
+                    "handle_hook_message -> port {} is in {:?} mode",
+                    requested_port, mode
+                );
+
+                if mode.is_steal() {
                     self.tcp_steal_handler
                         .handle_hook_message(message, &mut self.codec)
                         .await
@@ -259,6 +464,12 @@ where
                         .unwrap();
                 }
             }
+            HookMessage::Udp(message) => {
+                self.udp_mirror_handler
+                    .handle_hook_message(message, &mut self.codec)
+                    .await
+                    .unwrap();
+            }
             HookMessage::File(message) => {
                 self.file_handler
                     .handle_hook_message(message, &mut self.codec)
@@ -298,9 +509,14 @@ where
     async fn handle_daemon_message(&mut self, daemon_message: DaemonMessage) -> Result<()> {
         match daemon_message {
             DaemonMessage::Tcp(message) => {
+                self.record(TcpRecordOrigin::Mirror, &message);
                 self.tcp_mirror_handler.handle_daemon_message(message).await
             }
+            DaemonMessage::Udp(message) => {
+                self.udp_mirror_handler.handle_daemon_message(message).await
+            }
             DaemonMessage::TcpSteal(message) => {
+                self.record(TcpRecordOrigin::Steal, &message);
                 self.tcp_steal_handler.handle_daemon_message(message).await
             }
             DaemonMessage::File(message) => self.file_handler.handle_daemon_message(message).await,
@@ -341,18 +557,43 @@ where
 
 async fn thread_loop(
     mut receiver: Receiver<HookMessage>,
+    forwarded_listens: UnixDatagram,
     codec: actix_codec::Framed<
         impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send,
         ClientCodec,
     >,
-    steal: bool,
+    incoming: IncomingConfig,
+    tls_certificate: Option<TlsCertificate>,
 ) {
-    let mut layer = Layer::new(codec, steal);
+    let mut layer = Layer::new(codec, incoming, tls_certificate);
+    if let Err(fail) = layer.start_session().await {
+        error!("Failed sending session settings: {:#?}", fail);
+    }
+    // Forked workers' requests go through the hooks channel, like the session owner's.
+    let hook_sender = unsafe { HOOK_SENDER.clone() }
+        .expect("HOOK_SENDER is set before the layer thread starts");
     loop {
         select! {
             hook_message = receiver.recv() => {
                 layer.handle_hook_message(hook_message.unwrap()).await;
             }
+            Ok(forwarded) = session::recv_forwarded(&forwarded_listens) => {
+                match forwarded {
+                    Forwarded::Listen(listen) => {
+                        debug!("forked worker listens with {:?}", listen);
+                        layer
+                            .handle_hook_message(HookMessage::Tcp(HookMessageTcp::Listen(listen)))
+                            .await;
+                    }
+                    Forwarded::Worker(worker) => {
+                        debug!("forked worker connected");
+                        tokio::spawn(session::serve_worker(worker, hook_sender.clone()));
+                    }
+                    Forwarded::Invalid(bytes) => {
+                        error!("forked worker sent an invalid listen {:?}", bytes)
+                    }
+                }
+            }
             Some(tcp_outgoing_message) = layer.tcp_outgoing_handler.recv() => {
                 if let Err(fail) =
                     layer.codec.send(ClientMessage::TcpOutgoing(tcp_outgoing_message)).await {
@@ -402,21 +643,25 @@ async fn thread_loop(
         }
     }
 
+    if let Some(recorder) = &mut layer.recorder
+        && let Err(fail) = recorder.flush() {
+        error!("Failed flushing recording: {}", fail);
+    }
+
     graceful_exit!();
 }
 
-#[tracing::instrument(level = "trace", skip(pf, receiver))]
+#[tracing::instrument(level = "trace", skip(stream, receiver, forwarded_listens))]
 async fn start_layer_thread(
-    mut pf: Portforwarder,
+    stream: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
     receiver: Receiver<HookMessage>,
+    forwarded_listens: std::os::unix::net::UnixDatagram,
     config: LayerConfig,
-    connection_port: u16,
+    tls_certificate: Option<TlsCertificate>,
 ) {
-    let port = pf.take_stream(connection_port).unwrap(); // TODO: Make port configurable
-
     // `codec` is used to retrieve messages from the daemon (messages that are sent from -agent to
     // -layer)
-    let mut codec = actix_codec::Framed::new(port, ClientCodec::new());
+    let mut codec = actix_codec::Framed::new(stream, ClientCodec::new());
 
     let (env_vars_filter, env_vars_select) = match (
         config.feature.env.exclude.map(|exclude| exclude.join(";")),
@@ -468,10 +713,15 @@ async fn start_layer_thread(
         }
     };
 
+    let forwarded_listens = UnixDatagram::from_std(forwarded_listens)
+        .expect("Registering the socket forked workers listen through failed!");
+
     let _ = tokio::spawn(thread_loop(
         receiver,
+        forwarded_listens,
         codec,
-        config.feature.network.incoming.is_steal(),
+        config.feature.network.incoming,
+        tls_certificate,
     ));
 }
 
@@ -516,7 +766,13 @@ unsafe extern "C" fn close_detour(fd: c_int) -> c_int {
         .get()
         .expect("Should be set during initialization!");
 
-    if SOCKETS.lock().unwrap().remove(&fd).is_some() {
+    let socket = SOCKETS.lock().unwrap().remove(&fd);
+
+    if let Some(socket) = socket {
+        if let Err(fail) = socket::ops::close(fd, socket) {
+            warn!("close_detour -> failed closing socket with {:#?}", fail);
+        }
+
         FN_CLOSE(fd)
     } else if *enabled_file_ops
         && let Some(remote_fd) = OPEN_FILES.lock().unwrap().remove(&fd) {
@@ -538,7 +794,6 @@ mod tests {
     use rstest::rstest;
 
     use super::*;
-    use crate::pod_api::*;
 
     #[rstest]
     #[case("test", Some(vec!["foo".to_string()]))]
@@ -560,29 +815,4 @@ mod tests {
     ) {
         assert!(!should_load(given_process, skip_processes));
     }
-
-    #[rstest]
-    #[case("pod/foobaz", Target::Pod(PodData {pod_name: "foobaz".to_string(), container_name: None}))]
-    #[case("deployment/foobaz", Target::Deployment(DeploymentData {deployment: "foobaz".to_string()}))]
-    #[case("deployment/nginx-deployment", Target::Deployment(DeploymentData {deployment: "nginx-deployment".to_string()}))]
-    #[case("pod/foo/container/baz", Target::Pod(PodData { pod_name: "foo".to_string(), container_name: Some("baz".to_string()) }))]
-    fn test_target_parses(#[case] target: &str, #[case] expected: Target) {
-        let target = target.parse::<Target>().unwrap();
-        assert_eq!(target, expected)
-    }
-
-    #[rstest]
-    #[should_panic(expected = "InvalidTarget")]
-    #[case::panic("deployment/foobaz/blah")]
-    #[should_panic(expected = "InvalidTarget")]
-    #[case::panic("pod/foo/baz")]
-    fn test_target_parse_fails(#[case] target: &str) {
-        let target = target.parse::<pod_api::Target>().unwrap();
-        assert_eq!(
-            target,
-            Target::Deployment(DeploymentData {
-                deployment: "foobaz".to_string()
-            })
-        )
-    }
 }
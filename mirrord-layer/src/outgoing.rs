@@ -38,15 +38,26 @@ impl fmt::Debug for Write {
     }
 }
 
+/// What happened on the remote stream, passed from the `agent` to our interceptor socket.
+#[derive(Debug)]
+pub(crate) enum RemoteEvent {
+    /// Data to be written back to the user's socket.
+    Data(Vec<u8>),
+    /// The remote host is done sending, but it may still read.
+    Shutdown,
+    /// The remote host reset the connection.
+    Reset,
+}
+
 /// Wrapper type around `tokio::Sender`, used to send messages from the `agent` to our interceptor
 /// socket, where they'll be written back to the user's socket.
 ///
 /// (agent) -> (layer) -> (user)
 #[derive(Debug)]
-pub(crate) struct ConnectionMirror(tokio::sync::mpsc::Sender<Vec<u8>>);
+pub(crate) struct ConnectionMirror(tokio::sync::mpsc::Sender<RemoteEvent>);
 
 impl Deref for ConnectionMirror {
-    type Target = tokio::sync::mpsc::Sender<Vec<u8>>;
+    type Target = tokio::sync::mpsc::Sender<RemoteEvent>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -1,7 +1,9 @@
 use std::{
     collections::HashMap,
     future::Future,
+    io::ErrorKind,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
 };
 
 use futures::{SinkExt, TryFutureExt};
@@ -20,7 +22,12 @@ use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tracing::{error, info, trace, warn};
 
 use super::*;
-use crate::{common::ResponseDeque, detour::DetourGuard, error::LayerError};
+use crate::{
+    common::ResponseDeque,
+    detour::DetourGuard,
+    error::LayerError,
+    socket::{take_user_shutdown, user_connection},
+};
 
 /// Hook messages handled by `TcpOutgoingHandler`.
 #[derive(Debug)]
@@ -67,7 +74,7 @@ impl TcpOutgoingHandler {
         layer_tx: Sender<LayerTcpOutgoing>,
         connection_id: ConnectionId,
         mirror_listener: TcpListener,
-        remote_rx: Receiver<Vec<u8>>,
+        remote_rx: Receiver<RemoteEvent>,
     ) {
         // Accepts the user's socket connection, and finally becomes the interceptor socket.
         let (mut mirror_stream, _) = mirror_listener.accept().await.unwrap();
@@ -89,23 +96,53 @@ impl TcpOutgoingHandler {
             }
         };
 
+        // Either side may shut down its half of the connection (`shutdown(SHUT_WR)`) and keep
+        // reading, we're done once both did.
+        let mut user_shut_down = false;
+        let mut remote_shut_down = false;
+
         loop {
             select! {
                 biased; // To allow local socket to be read before being closed
 
                 // Reads data that the user is sending from their socket to mirrord's interceptor
                 // socket.
-                read = mirror_stream.read(&mut buffer) => {
+                read = mirror_stream.read(&mut buffer), if !user_shut_down => {
                     match read {
-                        Err(fail) if fail.kind() == std::io::ErrorKind::WouldBlock => {
+                        Err(fail) if fail.kind() == ErrorKind::WouldBlock => {
                             continue;
                         },
+                        Err(fail) if fail.kind() == ErrorKind::ConnectionReset => {
+                            info!("interceptor_task -> Stream {:#?} was reset by the user!", connection_id);
+                            let reset = LayerTcpOutgoing::Reset(LayerClose { connection_id });
+
+                            if let Err(fail) = layer_tx.send(reset).await {
+                                error!("Failed sending reset message with {:#?}!", fail);
+                            }
+
+                            break;
+                        }
                         Err(fail) => {
                             info!("Failed reading mirror_stream with {:#?}", fail);
                             close_remote_stream(layer_tx.clone()).await;
 
                             break;
                         }
+                        Ok(read_amount) if read_amount == 0 && take_user_shutdown(user_connection(&mirror_stream)) => {
+                            info!("interceptor_task -> Stream {:#?} was shut down by the user!", connection_id);
+                            let shutdown = LayerTcpOutgoing::Shutdown(LayerClose { connection_id });
+
+                            if let Err(fail) = layer_tx.send(shutdown).await {
+                                error!("Failed sending shutdown message with {:#?}!", fail);
+
+                                break;
+                            }
+
+                            if remote_shut_down {
+                                break;
+                            }
+                            user_shut_down = true;
+                        },
                         Ok(read_amount) if read_amount == 0 => {
                             info!("interceptor_task -> Stream {:#?} has no more data, closing!", connection_id);
                             close_remote_stream(layer_tx.clone()).await;
@@ -126,9 +163,9 @@ impl TcpOutgoingHandler {
                         }
                     }
                 },
-                bytes = remote_stream.next() => {
-                    match bytes {
-                        Some(bytes) => {
+                event = remote_stream.next(), if !remote_shut_down => {
+                    match event {
+                        Some(RemoteEvent::Data(bytes)) => {
                             // Writes the data sent by `agent` (that came from the actual remote
                             // stream) to our interceptor socket. When the user tries to read the
                             // remote data, this'll be what they receive.
@@ -137,6 +174,25 @@ impl TcpOutgoingHandler {
                                 break;
                             }
                         },
+                        Some(RemoteEvent::Shutdown) => {
+                            if let Err(fail) = mirror_stream.shutdown().await {
+                                error!("Failed shutting down mirror_stream with {:#?}!", fail);
+                                break;
+                            }
+
+                            if user_shut_down {
+                                break;
+                            }
+                            remote_shut_down = true;
+                        },
+                        Some(RemoteEvent::Reset) => {
+                            // Dropping the stream with a zero linger sends a RST to the user.
+                            if let Err(fail) = mirror_stream.set_linger(Some(Duration::ZERO)) {
+                                error!("Failed setting linger on mirror_stream with {:#?}!", fail);
+                            }
+
+                            break;
+                        },
                         None => {
                             warn!("interceptor_task -> exiting due to remote stream closed!");
                             break;
@@ -196,6 +252,9 @@ impl TcpOutgoingHandler {
     ///
     /// - `TcpOutgoingResponse::Write`: (agent) sent some data to the remote host, currently this
     ///   response is only significant to handle errors when this send failed.
+    ///
+    /// - `TcpOutgoingResponse::Shutdown` and `TcpOutgoingResponse::Reset`: the remote host shut
+    ///   down or reset the connection, which our interceptor socket does to the user socket.
     #[tracing::instrument(level = "trace", skip(self))]
     pub(crate) async fn handle_daemon_message(
         &mut self,
@@ -244,7 +303,7 @@ impl TcpOutgoingHandler {
                         // mirror_stream.
                         // Agent ----> layer --> remote_tx=====remote_rx --> interceptor -->
                         // mirror_stream
-                        let (remote_tx, remote_rx) = channel::<Vec<u8>>(1000);
+                        let (remote_tx, remote_rx) = channel::<RemoteEvent>(1000);
 
                         let _ = DetourGuard::new();
                         let mirror_address = MirrorAddress(listener.local_addr()?);
@@ -283,12 +342,34 @@ impl TcpOutgoingHandler {
                     .get_mut(&connection_id)
                     .ok_or(LayerError::NoConnectionId(connection_id))?;
 
-                sender.send(bytes).await.unwrap_or_else(|_| {
-                    warn!(
-                        "Got new data from agent after application closed socket. connection_id: \
-                    connection_id: {connection_id}"
-                    );
-                });
+                sender
+                    .send(RemoteEvent::Data(bytes))
+                    .await
+                    .unwrap_or_else(|_| {
+                        warn!(
+                            "Got new data from agent after application closed socket. \
+                            connection_id: {connection_id}"
+                        );
+                    });
+                Ok(())
+            }
+            DaemonTcpOutgoing::Shutdown(connection_id) => {
+                trace!("Shutdown -> connection_id {:?}", connection_id);
+
+                // No more data comes from the remote host, the interceptor is told once.
+                if let Some(sender) = self.mirrors.remove(&connection_id) {
+                    let _ = sender.send(RemoteEvent::Shutdown).await;
+                }
+
+                Ok(())
+            }
+            DaemonTcpOutgoing::Reset(connection_id) => {
+                trace!("Reset -> connection_id {:?}", connection_id);
+
+                if let Some(sender) = self.mirrors.remove(&connection_id) {
+                    let _ = sender.send(RemoteEvent::Reset).await;
+                }
+
                 Ok(())
             }
             DaemonTcpOutgoing::Close(connection_id) => {
@@ -307,3 +388,90 @@ impl TcpOutgoingHandler {
         self.layer_rx.recv()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::socket::half_close::record_user_shutdown;
+
+    /// Runs the interceptor of connection 1, returning the user's socket connected to it.
+    async fn intercept() -> (
+        TcpStream,
+        Receiver<LayerTcpOutgoing>,
+        Sender<RemoteEvent>,
+        task::JoinHandle<()>,
+    ) {
+        let mirror_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mirror_address = mirror_listener.local_addr().unwrap();
+        let (layer_tx, layer_rx) = channel(8);
+        let (remote_tx, remote_rx) = channel(8);
+
+        let interceptor = task::spawn(TcpOutgoingHandler::interceptor_task(
+            layer_tx,
+            1,
+            mirror_listener,
+            remote_rx,
+        ));
+        let user = TcpStream::connect(mirror_address).await.unwrap();
+
+        (user, layer_rx, remote_tx, interceptor)
+    }
+
+    /// The user shutting down its side still gets what the remote host sends.
+    #[tokio::test]
+    async fn user_half_close() {
+        let (mut user, mut layer_rx, remote_tx, interceptor) = intercept().await;
+
+        // What `shutdown_detour` does.
+        record_user_shutdown(user.as_raw_fd()).unwrap();
+        user.shutdown().await.unwrap();
+
+        assert!(matches!(
+            layer_rx.recv().await,
+            Some(LayerTcpOutgoing::Shutdown(LayerClose { connection_id: 1 }))
+        ));
+
+        remote_tx
+            .send(RemoteEvent::Data(b"response".to_vec()))
+            .await
+            .unwrap();
+        remote_tx.send(RemoteEvent::Shutdown).await.unwrap();
+
+        let mut response = Vec::new();
+        user.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+        interceptor.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn user_reset() {
+        let (user, mut layer_rx, _remote_tx, interceptor) = intercept().await;
+
+        user.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(user);
+
+        assert!(matches!(
+            layer_rx.recv().await,
+            Some(LayerTcpOutgoing::Reset(LayerClose { connection_id: 1 }))
+        ));
+        interceptor.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn remote_reset() {
+        let (mut user, _layer_rx, remote_tx, interceptor) = intercept().await;
+
+        remote_tx.send(RemoteEvent::Reset).await.unwrap();
+        interceptor.await.unwrap();
+
+        let mut buffer = [0; 8];
+        assert_eq!(
+            user.read(&mut buffer).await.unwrap_err().kind(),
+            ErrorKind::ConnectionReset
+        );
+    }
+}
@@ -0,0 +1,552 @@
+//! Recording incoming connections (`feature.network.incoming.record_file`) and replaying them to
+//! the local app without a cluster (`mirrord replay`).
+//!
+//! When replaying, [`replay_agent`] stands in for the agent, so the recorded connections reach
+//! the app the same way live ones do (through [`TcpHandler::create_local_stream`]).
+//!
+//! [`TcpHandler::create_local_stream`]: crate::tcp::TcpHandler::create_local_stream
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use actix_codec::{Decoder, Encoder};
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use mirrord_protocol::{
+    tcp::{
+        DaemonTcp, LayerTcp, LayerTcpSteal, NewTcpConnection, TcpClose, TcpData, TcpRecord,
+        TcpRecordOrigin,
+    },
+    ClientMessage, ConnectionId, DaemonCodec, DaemonMessage, Port, RecordingCodec,
+};
+use tokio::{
+    select,
+    sync::{
+        mpsc::{channel, Sender},
+        OwnedSemaphorePermit, Semaphore,
+    },
+    time::{sleep_until, Duration},
+};
+use tracing::{debug, trace, warn};
+
+use crate::error::LayerError;
+
+/// Appends the connection messages the layer gets to a recording file.
+///
+/// Writes are buffered, and flushed whenever a connection ends (and when dropped).
+pub(crate) struct TcpRecorder {
+    file: BufWriter<File>,
+    start: Instant,
+    codec: RecordingCodec,
+}
+
+impl TcpRecorder {
+    pub(crate) fn new(path: &Path) -> io::Result<Self> {
+        Ok(TcpRecorder {
+            file: BufWriter::new(File::create(path)?),
+            start: Instant::now(),
+            codec: RecordingCodec::new(),
+        })
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        origin: TcpRecordOrigin,
+        message: &DaemonTcp,
+    ) -> io::Result<()> {
+        if !matches!(
+            message,
+            DaemonTcp::NewConnection(_)
+                | DaemonTcp::Data(_)
+                | DaemonTcp::Close(_)
+                | DaemonTcp::Shutdown(_)
+                | DaemonTcp::Reset(_)
+        ) {
+            return Ok(());
+        }
+
+        let mut buffer = BytesMut::new();
+        self.codec.encode(
+            TcpRecord {
+                elapsed_us: self.start.elapsed().as_micros() as u64,
+                origin,
+                message: message.clone(),
+            },
+            &mut buffer,
+        )?;
+
+        self.file.write_all(&buffer)?;
+
+        if matches!(message, DaemonTcp::Close(_) | DaemonTcp::Reset(_)) {
+            self.file.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A recorded connection, its messages are timed from its start.
+#[derive(Debug, PartialEq, Eq)]
+struct RecordedConnection {
+    start_us: u64,
+    messages: Vec<(u64, DaemonTcp)>,
+}
+
+/// How the recorded timing is replayed, see `feature.network.incoming.replay_speed`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ReplayPace {
+    /// Percent of the recorded pace, `0` doesn't wait at all.
+    pub speed: u32,
+    /// The most connections replayed at the same time.
+    pub concurrency: Option<u32>,
+}
+
+impl ReplayPace {
+    fn scale(&self, elapsed_us: u64) -> Duration {
+        if self.speed == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_micros(elapsed_us * 100 / u64::from(self.speed))
+        }
+    }
+}
+
+/// Groups the recorded messages by connection, and the connections by port (in the order they
+/// started). Connections the recording ended in the middle of get closed.
+///
+/// Mirrored and stolen connections are told apart by their [`TcpRecordOrigin`], as their ids
+/// overlap.
+fn group_connections(records: Vec<TcpRecord>) -> HashMap<Port, Vec<RecordedConnection>> {
+    let mut ports: HashMap<Port, Vec<RecordedConnection>> = HashMap::new();
+    let mut open: HashMap<(TcpRecordOrigin, ConnectionId), (Port, usize)> = HashMap::new();
+
+    for TcpRecord {
+        elapsed_us,
+        origin,
+        message,
+    } in records
+    {
+        let (connection_id, closed) = match &message {
+            DaemonTcp::NewConnection(NewTcpConnection {
+                connection_id,
+                destination_port,
+                ..
+            }) => {
+                let connections = ports.entry(*destination_port).or_default();
+                open.insert(
+                    (origin, *connection_id),
+                    (*destination_port, connections.len()),
+                );
+                connections.push(RecordedConnection {
+                    start_us: elapsed_us,
+                    messages: Vec::new(),
+                });
+
+                (*connection_id, false)
+            }
+            DaemonTcp::Data(TcpData { connection_id, .. }) => (*connection_id, false),
+            DaemonTcp::Shutdown(TcpClose { connection_id }) => (*connection_id, false),
+            DaemonTcp::Close(TcpClose { connection_id })
+            | DaemonTcp::Reset(TcpClose { connection_id }) => (*connection_id, true),
+            _ => continue,
+        };
+
+        if let Some((port, index)) = open.get(&(origin, connection_id)) {
+            let connection = &mut ports.get_mut(port).unwrap()[*index];
+            connection
+                .messages
+                .push((elapsed_us.saturating_sub(connection.start_us), message));
+        }
+
+        if closed {
+            open.remove(&(origin, connection_id));
+        }
+    }
+
+    for ((_, connection_id), (port, index)) in open {
+        let connection = &mut ports.get_mut(&port).unwrap()[index];
+        let last = connection
+            .messages
+            .last()
+            .map_or(0, |(elapsed_us, _)| *elapsed_us);
+        connection
+            .messages
+            .push((last, DaemonTcp::Close(TcpClose { connection_id })));
+    }
+
+    ports
+}
+
+async fn read_recording(path: &Path) -> Result<HashMap<Port, Vec<RecordedConnection>>, LayerError> {
+    let mut buffer = BytesMut::from(&tokio::fs::read(path).await?[..]);
+    let mut codec = RecordingCodec::new();
+
+    let mut records = Vec::new();
+    while let Some(record) = codec.decode(&mut buffer)? {
+        records.push(record);
+    }
+
+    if !buffer.is_empty() {
+        warn!(
+            "read_recording -> ignoring {} bytes at the end of {:?}",
+            buffer.len(),
+            path
+        );
+    }
+
+    Ok(group_connections(records))
+}
+
+/// The recorded message, for the replayed connection `connection_id`.
+fn with_connection_id(message: DaemonTcp, connection_id: ConnectionId) -> DaemonTcp {
+    match message {
+        DaemonTcp::NewConnection(connection) => DaemonTcp::NewConnection(NewTcpConnection {
+            connection_id,
+            ..connection
+        }),
+        DaemonTcp::Data(data) => DaemonTcp::Data(TcpData {
+            connection_id,
+            ..data
+        }),
+        DaemonTcp::Close(_) => DaemonTcp::Close(TcpClose { connection_id }),
+        DaemonTcp::Shutdown(_) => DaemonTcp::Shutdown(TcpClose { connection_id }),
+        DaemonTcp::Reset(_) => DaemonTcp::Reset(TcpClose { connection_id }),
+        other => other,
+    }
+}
+
+async fn replay_connection(
+    connection: RecordedConnection,
+    connection_id: ConnectionId,
+    pace: ReplayPace,
+    wrap: fn(DaemonTcp) -> DaemonMessage,
+    sender: Sender<DaemonMessage>,
+    _permit: OwnedSemaphorePermit,
+) {
+    let start = tokio::time::Instant::now();
+
+    for (elapsed_us, message) in connection.messages {
+        sleep_until(start + pace.scale(elapsed_us)).await;
+
+        if sender
+            .send(wrap(with_connection_id(message, connection_id)))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Starts the connections of a port at their recorded times, as long as there's room for them
+/// (see [`ReplayPace::concurrency`]).
+async fn replay_port(
+    connections: Vec<RecordedConnection>,
+    pace: ReplayPace,
+    semaphore: Arc<Semaphore>,
+    connection_ids: Arc<AtomicU64>,
+    wrap: fn(DaemonTcp) -> DaemonMessage,
+    sender: Sender<DaemonMessage>,
+) {
+    let start = tokio::time::Instant::now();
+    let first_us = connections
+        .first()
+        .map_or(0, |connection| connection.start_us);
+
+    for connection in connections {
+        sleep_until(start + pace.scale(connection.start_us - first_us)).await;
+
+        let permit = match semaphore.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => return,
+        };
+
+        let connection_id = connection_ids.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(replay_connection(
+            connection,
+            connection_id,
+            pace,
+            wrap,
+            sender.clone(),
+            permit,
+        ));
+    }
+}
+
+/// Plays the agent for the layer on the other end of `stream`, replaying the recording at `path`
+/// to each port it subscribes to (mirrored or stolen alike).
+pub(crate) async fn replay_agent(
+    stream: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    path: &Path,
+    pace: ReplayPace,
+) -> Result<(), LayerError> {
+    let mut connections = read_recording(path).await?;
+    let mut codec = actix_codec::Framed::new(stream, DaemonCodec::new());
+
+    let (sender, mut receiver) = channel::<DaemonMessage>(1000);
+    let semaphore = Arc::new(Semaphore::new(
+        pace.concurrency
+            .map_or(Semaphore::MAX_PERMITS, |concurrency| concurrency as usize),
+    ));
+    let connection_ids = Arc::new(AtomicU64::new(0));
+
+    loop {
+        select! {
+            message = codec.next() => {
+                let (port, wrap): (Port, fn(DaemonTcp) -> DaemonMessage) = match message {
+                    Some(Ok(ClientMessage::Tcp(LayerTcp::PortSubscribe(port, _)))) => {
+                        (port, DaemonMessage::Tcp)
+                    }
+                    Some(Ok(ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(steal_type, _)))) => {
+                        (steal_type.port(), DaemonMessage::TcpSteal)
+                    }
+                    Some(Ok(ClientMessage::Ping)) => {
+                        codec.send(DaemonMessage::Pong).await?;
+                        continue;
+                    }
+                    Some(Ok(ClientMessage::GetEnvVarsRequest(_))) => {
+                        codec
+                            .send(DaemonMessage::GetEnvVarsResponse(Ok(HashMap::new())))
+                            .await?;
+                        continue;
+                    }
+                    Some(Ok(message)) => {
+                        trace!("replay_agent -> ignoring {:?}", message);
+                        continue;
+                    }
+                    Some(Err(fail)) => return Err(fail.into()),
+                    None => return Ok(()),
+                };
+
+                codec.send(wrap(DaemonTcp::Subscribed)).await?;
+
+                let port_connections = connections.remove(&port).unwrap_or_default();
+                debug!(
+                    "replay_agent -> replaying {} connections to port {}",
+                    port_connections.len(),
+                    port
+                );
+
+                tokio::spawn(replay_port(
+                    port_connections,
+                    pace,
+                    semaphore.clone(),
+                    connection_ids.clone(),
+                    wrap,
+                    sender.clone(),
+                ));
+            }
+            Some(message) = receiver.recv() => {
+                codec.send(message).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_connection(connection_id: ConnectionId, destination_port: Port) -> DaemonTcp {
+        DaemonTcp::NewConnection(NewTcpConnection {
+            connection_id,
+            address: "10.0.0.1".parse().unwrap(),
+            destination_port,
+            source_port: 40000,
+            alpn_protocol: None,
+        })
+    }
+
+    fn data(connection_id: ConnectionId, bytes: &[u8]) -> DaemonTcp {
+        DaemonTcp::Data(TcpData {
+            connection_id,
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    fn record(elapsed_us: u64, message: DaemonTcp) -> TcpRecord {
+        TcpRecord {
+            elapsed_us,
+            origin: TcpRecordOrigin::Mirror,
+            message,
+        }
+    }
+
+    fn stolen(elapsed_us: u64, message: DaemonTcp) -> TcpRecord {
+        TcpRecord {
+            origin: TcpRecordOrigin::Steal,
+            ..record(elapsed_us, message)
+        }
+    }
+
+    #[test]
+    fn groups_connections() {
+        let records = vec![
+            record(100, new_connection(0, 80)),
+            record(150, new_connection(1, 80)),
+            record(200, data(0, b"first")),
+            record(300, DaemonTcp::Close(TcpClose { connection_id: 0 })),
+            record(400, data(1, b"second")),
+            // Reused id, it's a new connection.
+            record(500, new_connection(0, 8080)),
+            record(600, DaemonTcp::Subscribed),
+        ];
+
+        let mut ports = group_connections(records);
+
+        assert_eq!(
+            ports.remove(&80).unwrap(),
+            vec![
+                RecordedConnection {
+                    start_us: 100,
+                    messages: vec![
+                        (0, new_connection(0, 80)),
+                        (100, data(0, b"first")),
+                        (200, DaemonTcp::Close(TcpClose { connection_id: 0 })),
+                    ],
+                },
+                RecordedConnection {
+                    start_us: 150,
+                    messages: vec![
+                        (0, new_connection(1, 80)),
+                        (250, data(1, b"second")),
+                        (250, DaemonTcp::Close(TcpClose { connection_id: 1 })),
+                    ],
+                },
+            ]
+        );
+        assert_eq!(
+            ports.remove(&8080).unwrap(),
+            vec![RecordedConnection {
+                start_us: 500,
+                messages: vec![
+                    (0, new_connection(0, 8080)),
+                    (0, DaemonTcp::Close(TcpClose { connection_id: 0 })),
+                ],
+            }]
+        );
+        assert!(ports.is_empty());
+    }
+
+    /// The sniffer and the stealer both start counting at 0, their connections stay apart.
+    #[test]
+    fn mirrored_and_stolen_ids_overlap() {
+        let records = vec![
+            record(100, new_connection(0, 80)),
+            stolen(200, new_connection(0, 8080)),
+            record(300, data(0, b"mirrored")),
+            stolen(400, data(0, b"stolen")),
+            stolen(500, DaemonTcp::Close(TcpClose { connection_id: 0 })),
+            record(600, data(0, b"still mirrored")),
+        ];
+
+        let mut ports = group_connections(records);
+
+        assert_eq!(
+            ports.remove(&80).unwrap(),
+            vec![RecordedConnection {
+                start_us: 100,
+                messages: vec![
+                    (0, new_connection(0, 80)),
+                    (200, data(0, b"mirrored")),
+                    (500, data(0, b"still mirrored")),
+                    (500, DaemonTcp::Close(TcpClose { connection_id: 0 })),
+                ],
+            }]
+        );
+        assert_eq!(
+            ports.remove(&8080).unwrap(),
+            vec![RecordedConnection {
+                start_us: 200,
+                messages: vec![
+                    (0, new_connection(0, 8080)),
+                    (200, data(0, b"stolen")),
+                    (300, DaemonTcp::Close(TcpClose { connection_id: 0 })),
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn replay_pace() {
+        let pace = |speed| ReplayPace {
+            speed,
+            concurrency: None,
+        };
+
+        assert_eq!(pace(100).scale(1500), Duration::from_micros(1500));
+        assert_eq!(pace(200).scale(1500), Duration::from_micros(750));
+        assert_eq!(pace(50).scale(1500), Duration::from_micros(3000));
+        assert_eq!(pace(0).scale(1500), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let path = std::env::temp_dir().join(format!("mirrord-replay-{}", std::process::id()));
+
+        let mut recorder = TcpRecorder::new(&path).unwrap();
+        recorder
+            .record(TcpRecordOrigin::Mirror, &DaemonTcp::Subscribed)
+            .unwrap();
+        recorder
+            .record(TcpRecordOrigin::Mirror, &new_connection(5, 80))
+            .unwrap();
+        recorder
+            .record(TcpRecordOrigin::Mirror, &data(5, b"hello"))
+            .unwrap();
+        recorder.flush().unwrap();
+
+        let (layer_stream, agent_stream) = tokio::io::duplex(1024);
+        let pace = ReplayPace {
+            speed: 0,
+            concurrency: Some(1),
+        };
+        let agent_path = path.clone();
+        tokio::spawn(async move { replay_agent(agent_stream, &agent_path, pace).await });
+
+        let mut layer =
+            actix_codec::Framed::new(layer_stream, mirrord_protocol::ClientCodec::new());
+        layer
+            .send(ClientMessage::Tcp(LayerTcp::PortSubscribe(80, vec![])))
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            received.push(layer.next().await.unwrap().unwrap());
+        }
+
+        assert_eq!(
+            received,
+            vec![
+                DaemonMessage::Tcp(DaemonTcp::Subscribed),
+                DaemonMessage::Tcp(new_connection(0, 80)),
+                DaemonMessage::Tcp(data(0, b"hello")),
+                DaemonMessage::Tcp(DaemonTcp::Close(TcpClose { connection_id: 0 })),
+            ]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn replaces_connection_id() {
+        assert_eq!(with_connection_id(data(3, b"bytes"), 7), data(7, b"bytes"));
+        assert_eq!(
+            with_connection_id(new_connection(3, 80), 7),
+            new_connection(7, 80)
+        );
+    }
+}
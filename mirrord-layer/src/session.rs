@@ -0,0 +1,738 @@
+//! Forked workers share the session of the process that loaded the layer.
+//!
+//! The agent connection is served by the layer's threads, which `fork` doesn't copy, so only the
+//! session owner talks to the agent and workers make their requests through it. Pre-fork servers
+//! (gunicorn, nginx, PHP-FPM) listen in the master and accept in the workers, or have each worker
+//! listen with `SO_REUSEPORT`:
+//!
+//! - Inherited listeners need nothing, the kernel gives each connection to one of the processes
+//!   accepting on it, and the sources `accept` reports are shared with the workers (see
+//!   [`crate::socket::sources`]).
+//! - Listeners a worker creates are sent to the session owner, which adds them to the listeners of
+//!   their port, so they get their turn of its connections.
+//!
+//! A worker's other requests (its files, names, outgoing connections and their half-closes) go
+//! over a connection it makes to the session owner on the first one: it sends one end of a new
+//! socket pair over [`FORWARDED_LISTENS`], and the session owner answers each [`ForwardedRequest`]
+//! on it by sending it to its layer thread like its own hooks do. The outgoing connections are
+//! intercepted on the session owner's localhost, which the worker's sockets connect to.
+//!
+//! Only the datagrams of Udp sockets a worker binds aren't mirrored, it binds them locally.
+
+use std::{
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    net::SocketAddr,
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::{UnixDatagram, UnixStream},
+    },
+    process,
+    sync::{Mutex, OnceLock},
+};
+
+use bincode::{Decode, Encode};
+use mirrord_protocol::{
+    AccessFileRequest, AddrInfoInternal, CloseFileRequest, FileRequest, FileResponse,
+    GetAddrInfoRequest, OpenFileRequest, OpenRelativeFileRequest, ReadFileRequest, RemoteResult,
+    SeekFileRequest, WriteFileRequest,
+};
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags, UnixAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, Interest},
+    sync::{mpsc::Sender, oneshot},
+};
+use tracing::{debug, error};
+
+use crate::{
+    common::{GetAddrInfoHook, HookMessage, ResponseChannel},
+    error::{HookError, HookResult},
+    file::{
+        Access, Close, HookMessageFile, Open, OpenRelative, Read as ReadFile, Seek,
+        Write as WriteFile,
+    },
+    outgoing::{tcp::TcpOutgoing, udp::UdpOutgoing, Connect, MirrorAddress},
+    socket::half_close,
+    tcp::{HookMessageTcp, Listen},
+};
+
+static SESSION_PID: OnceLock<u32> = OnceLock::new();
+
+/// Where forked workers send their listens and connect for their other requests, the session owner
+/// reads the other end.
+static FORWARDED_LISTENS: OnceLock<UnixDatagram> = OnceLock::new();
+
+/// This process' connection to the session owner, with the process that made it: the workers a
+/// worker forks make their own.
+static OWNER_CONNECTION: Mutex<Option<(u32, UnixStream)>> = Mutex::new(None);
+
+/// Size of a forwarded listen: mirror port, requested port and whether it's IPv6.
+const LISTEN_LENGTH: usize = 5;
+
+/// Sent with the worker's end of its connection, which can't be mistaken for a listen.
+const WORKER_CONNECTION: u8 = 0;
+
+/// A request of a forked worker, the session owner answers it with a [`ForwardedResponse`].
+#[derive(Encode, Decode, Debug)]
+pub(crate) enum ForwardedRequest {
+    File(FileRequest),
+    GetAddrInfo(GetAddrInfoRequest),
+    TcpConnect(SocketAddr),
+    UdpConnect(SocketAddr),
+    /// The worker shut down its socket of the connection, see [`half_close`].
+    Shutdown((SocketAddr, SocketAddr)),
+    /// The worker closed its socket of a connection it had shut down.
+    ForgetShutdown((SocketAddr, SocketAddr)),
+}
+
+#[derive(Encode, Decode, Debug)]
+pub(crate) enum ForwardedResponse {
+    File(FileResponse),
+    GetAddrInfo(RemoteResult<Vec<AddrInfoInternal>>),
+    /// The address of the socket intercepting the connection.
+    Connect(RemoteResult<SocketAddr>),
+    Done,
+}
+
+/// What a forked worker sent on [`FORWARDED_LISTENS`].
+pub(crate) enum Forwarded {
+    Listen(Listen),
+    /// The session owner's end of the worker's connection, see [`serve_worker`].
+    Worker(tokio::net::UnixStream),
+    Invalid(Vec<u8>),
+}
+
+/// Makes this process the session owner, returning the socket forked workers' listens arrive on.
+///
+/// Has to run before the user's code, so every worker inherits the socket.
+pub(crate) fn start() -> io::Result<UnixDatagram> {
+    let (sender, receiver) = UnixDatagram::pair()?;
+    receiver.set_nonblocking(true)?;
+
+    SESSION_PID
+        .set(process::id())
+        .expect("Setting SESSION_PID singleton");
+    FORWARDED_LISTENS
+        .set(sender)
+        .expect("Setting FORWARDED_LISTENS singleton");
+
+    Ok(receiver)
+}
+
+/// Whether this process talks to the agent, which it does when the layer wasn't started (tests).
+pub(crate) fn is_session_owner() -> bool {
+    SESSION_PID
+        .get()
+        .map_or(true, |session_pid| *session_pid == process::id())
+}
+
+/// Sends a hook message of a forked worker to the session owner, answering it on its channel.
+pub(crate) fn forward(message: HookMessage) -> HookResult<()> {
+    match message {
+        HookMessage::Tcp(HookMessageTcp::Listen(listen)) => forward_listen(&listen),
+        HookMessage::File(message) => forward_file(message),
+        HookMessage::GetAddrInfoHook(GetAddrInfoHook {
+            node,
+            service,
+            hints,
+            hook_channel_tx,
+        }) => respond(
+            hook_channel_tx,
+            ForwardedRequest::GetAddrInfo(GetAddrInfoRequest {
+                node,
+                service,
+                hints,
+            }),
+            |response| match response {
+                ForwardedResponse::GetAddrInfo(addr_info) => Ok(addr_info),
+                other => Err(other),
+            },
+        ),
+        HookMessage::TcpOutgoing(TcpOutgoing::Connect(Connect {
+            remote_address,
+            channel_tx,
+        })) => respond(
+            channel_tx,
+            ForwardedRequest::TcpConnect(remote_address),
+            connect_response,
+        ),
+        HookMessage::UdpOutgoing(UdpOutgoing::Connect(Connect {
+            remote_address,
+            channel_tx,
+        })) => respond(
+            channel_tx,
+            ForwardedRequest::UdpConnect(remote_address),
+            connect_response,
+        ),
+        // Datagrams are only subscribed by the session owner, see `socket::ops::bind`.
+        HookMessage::Udp(_) => Err(HookError::NotSessionOwner),
+    }
+}
+
+/// Tells the session owner that a forked worker shut down its socket of `connection`, or closed
+/// it after that when `forget`.
+pub(crate) fn forward_shutdown(
+    connection: (SocketAddr, SocketAddr),
+    forget: bool,
+) -> HookResult<()> {
+    let request = if forget {
+        ForwardedRequest::ForgetShutdown(connection)
+    } else {
+        ForwardedRequest::Shutdown(connection)
+    };
+
+    match self::request(request)? {
+        ForwardedResponse::Done => Ok(()),
+        response => Err(unexpected_response(response).into()),
+    }
+}
+
+/// Sends a listen made in a forked worker to the session owner.
+fn forward_listen(listen: &Listen) -> HookResult<()> {
+    let forwarded_listens = FORWARDED_LISTENS.get().ok_or(HookError::EmptyHookSender)?;
+    forwarded_listens.send(&encode_listen(listen))?;
+
+    Ok(())
+}
+
+fn forward_file(message: HookMessageFile) -> HookResult<()> {
+    match message {
+        HookMessageFile::Open(Open {
+            path,
+            file_channel_tx,
+            open_options,
+        }) => respond(
+            file_channel_tx,
+            ForwardedRequest::File(FileRequest::Open(OpenFileRequest { path, open_options })),
+            |response| match response {
+                ForwardedResponse::File(FileResponse::Open(open)) => Ok(open),
+                other => Err(other),
+            },
+        ),
+        HookMessageFile::OpenRelative(OpenRelative {
+            relative_fd,
+            path,
+            file_channel_tx,
+            open_options,
+        }) => respond(
+            file_channel_tx,
+            ForwardedRequest::File(FileRequest::OpenRelative(OpenRelativeFileRequest {
+                relative_fd,
+                path,
+                open_options,
+            })),
+            |response| match response {
+                ForwardedResponse::File(FileResponse::Open(open)) => Ok(open),
+                other => Err(other),
+            },
+        ),
+        HookMessageFile::Read(ReadFile {
+            fd,
+            buffer_size,
+            file_channel_tx,
+        }) => respond(
+            file_channel_tx,
+            ForwardedRequest::File(FileRequest::Read(ReadFileRequest { fd, buffer_size })),
+            |response| match response {
+                ForwardedResponse::File(FileResponse::Read(read)) => Ok(read),
+                other => Err(other),
+            },
+        ),
+        HookMessageFile::Seek(Seek {
+            fd,
+            seek_from,
+            file_channel_tx,
+        }) => respond(
+            file_channel_tx,
+            ForwardedRequest::File(FileRequest::Seek(SeekFileRequest {
+                fd,
+                seek_from: seek_from.into(),
+            })),
+            |response| match response {
+                ForwardedResponse::File(FileResponse::Seek(seek)) => Ok(seek),
+                other => Err(other),
+            },
+        ),
+        HookMessageFile::Write(WriteFile {
+            fd,
+            write_bytes,
+            file_channel_tx,
+        }) => respond(
+            file_channel_tx,
+            ForwardedRequest::File(FileRequest::Write(WriteFileRequest { fd, write_bytes })),
+            |response| match response {
+                ForwardedResponse::File(FileResponse::Write(write)) => Ok(write),
+                other => Err(other),
+            },
+        ),
+        HookMessageFile::Close(Close {
+            fd,
+            file_channel_tx,
+        }) => respond(
+            file_channel_tx,
+            ForwardedRequest::File(FileRequest::Close(CloseFileRequest { fd })),
+            |response| match response {
+                ForwardedResponse::File(FileResponse::Close(close)) => Ok(close),
+                other => Err(other),
+            },
+        ),
+        HookMessageFile::Access(Access {
+            pathname,
+            mode,
+            file_channel_tx,
+        }) => respond(
+            file_channel_tx,
+            ForwardedRequest::File(FileRequest::Access(AccessFileRequest { pathname, mode })),
+            |response| match response {
+                ForwardedResponse::File(FileResponse::Access(access)) => Ok(access),
+                other => Err(other),
+            },
+        ),
+    }
+}
+
+fn connect_response(
+    response: ForwardedResponse,
+) -> Result<RemoteResult<MirrorAddress>, ForwardedResponse> {
+    match response {
+        ForwardedResponse::Connect(connect) => Ok(connect.map(MirrorAddress)),
+        other => Err(other),
+    }
+}
+
+/// Makes `request` through the session owner, and sends the answer `answer` takes out of its
+/// response to the hook waiting on `channel`.
+fn respond<T>(
+    channel: ResponseChannel<T>,
+    request: ForwardedRequest,
+    answer: impl FnOnce(ForwardedResponse) -> Result<RemoteResult<T>, ForwardedResponse>,
+) -> HookResult<()> {
+    let answer = answer(self::request(request)?).map_err(unexpected_response)?;
+
+    // The hook is blocked on the other end, it's only gone if it panicked.
+    let _ = channel.send(answer);
+
+    Ok(())
+}
+
+/// Makes `request` through the session owner, connecting to it on this worker's first one.
+fn request(request: ForwardedRequest) -> HookResult<ForwardedResponse> {
+    let forwarded_listens = FORWARDED_LISTENS.get().ok_or(HookError::EmptyHookSender)?;
+    let mut connection = OWNER_CONNECTION.lock()?;
+
+    let owner = match &mut *connection {
+        Some((pid, owner)) if *pid == process::id() => owner,
+        stale => {
+            &mut stale
+                .insert((process::id(), connect_to_owner(forwarded_listens)?))
+                .1
+        }
+    };
+
+    exchange(owner, &request).map_err(|fail| {
+        // What's left of the exchange would be read as the next one's, start over.
+        *connection = None;
+        fail.into()
+    })
+}
+
+/// Connects this worker to the session owner, see [`serve_worker`].
+fn connect_to_owner(forwarded_listens: &UnixDatagram) -> io::Result<UnixStream> {
+    let (worker, owner) = UnixStream::pair()?;
+
+    sendmsg::<UnixAddr>(
+        forwarded_listens.as_raw_fd(),
+        &[IoSlice::new(&[WORKER_CONNECTION])],
+        &[ControlMessage::ScmRights(&[owner.as_raw_fd()])],
+        MsgFlags::empty(),
+        None,
+    )?;
+
+    Ok(worker)
+}
+
+/// Sends `request` to the session owner and waits for its response.
+fn exchange(owner: &mut UnixStream, request: &ForwardedRequest) -> io::Result<ForwardedResponse> {
+    owner.write_all(&encode_frame(request)?)?;
+
+    let mut length = [0; 4];
+    owner.read_exact(&mut length)?;
+    let mut frame = vec![0; u32::from_be_bytes(length) as usize];
+    owner.read_exact(&mut frame)?;
+
+    decode_frame(&frame)
+}
+
+/// Receives what a forked worker sent on [`FORWARDED_LISTENS`], the session owner's end of it.
+pub(crate) async fn recv_forwarded(
+    forwarded_listens: &tokio::net::UnixDatagram,
+) -> io::Result<Forwarded> {
+    loop {
+        forwarded_listens.readable().await?;
+
+        match forwarded_listens.try_io(Interest::READABLE, || {
+            receive(forwarded_listens.as_raw_fd())
+        }) {
+            Err(fail) if fail.kind() == io::ErrorKind::WouldBlock => continue,
+            received => return received,
+        }
+    }
+}
+
+fn receive(forwarded_listens: RawFd) -> io::Result<Forwarded> {
+    let mut bytes = [0; LISTEN_LENGTH + 1];
+    let mut control = nix::cmsg_space!([RawFd; 1]);
+
+    let (amount, worker) = {
+        let mut buffers = [IoSliceMut::new(&mut bytes)];
+        let message = recvmsg::<UnixAddr>(
+            forwarded_listens,
+            &mut buffers,
+            Some(&mut control),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+
+        let worker = message.cmsgs().find_map(|control| match control {
+            ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+            _ => None,
+        });
+
+        (message.bytes, worker)
+    };
+
+    match worker {
+        Some(worker) => {
+            // Received just now, nothing else owns it.
+            let worker = unsafe { UnixStream::from_raw_fd(worker) };
+            worker.set_nonblocking(true)?;
+
+            tokio::net::UnixStream::from_std(worker).map(Forwarded::Worker)
+        }
+        None => Ok(decode_listen(&bytes[..amount]).map_or_else(
+            || Forwarded::Invalid(bytes[..amount].to_vec()),
+            Forwarded::Listen,
+        )),
+    }
+}
+
+/// Answers the requests of a forked worker until it exits, sending them to the layer thread on
+/// `hooks` like the hooks of the session owner do.
+pub(crate) async fn serve_worker(mut worker: tokio::net::UnixStream, hooks: Sender<HookMessage>) {
+    loop {
+        let request = match read_request(&mut worker).await {
+            Ok(request) => request,
+            // The worker exited.
+            Err(fail) if fail.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(fail) => {
+                error!("Failed reading the request of a forked worker: {}", fail);
+                break;
+            }
+        };
+        debug!("forked worker requests {:?}", request);
+
+        let response = match answer(request, &hooks).await {
+            Some(response) => response,
+            // The layer thread is gone, so is the session.
+            None => break,
+        };
+
+        let written = match encode_frame(&response) {
+            Ok(frame) => worker.write_all(&frame).await,
+            Err(fail) => Err(fail),
+        };
+        if let Err(fail) = written {
+            error!("Failed answering a forked worker: {}", fail);
+            break;
+        }
+    }
+}
+
+async fn read_request(worker: &mut tokio::net::UnixStream) -> io::Result<ForwardedRequest> {
+    let length = worker.read_u32().await?;
+    let mut frame = vec![0; length as usize];
+    worker.read_exact(&mut frame).await?;
+
+    decode_frame(&frame)
+}
+
+async fn answer(
+    request: ForwardedRequest,
+    hooks: &Sender<HookMessage>,
+) -> Option<ForwardedResponse> {
+    Some(match request {
+        ForwardedRequest::File(request) => {
+            ForwardedResponse::File(answer_file(request, hooks).await?)
+        }
+        ForwardedRequest::GetAddrInfo(GetAddrInfoRequest {
+            node,
+            service,
+            hints,
+        }) => ForwardedResponse::GetAddrInfo(
+            ask(hooks, |hook_channel_tx| {
+                HookMessage::GetAddrInfoHook(GetAddrInfoHook {
+                    node,
+                    service,
+                    hints,
+                    hook_channel_tx,
+                })
+            })
+            .await?,
+        ),
+        ForwardedRequest::TcpConnect(remote_address) => ForwardedResponse::Connect(
+            ask(hooks, |channel_tx| {
+                HookMessage::TcpOutgoing(TcpOutgoing::Connect(Connect {
+                    remote_address,
+                    channel_tx,
+                }))
+            })
+            .await?
+            .map(|MirrorAddress(address)| address),
+        ),
+        ForwardedRequest::UdpConnect(remote_address) => ForwardedResponse::Connect(
+            ask(hooks, |channel_tx| {
+                HookMessage::UdpOutgoing(UdpOutgoing::Connect(Connect {
+                    remote_address,
+                    channel_tx,
+                }))
+            })
+            .await?
+            .map(|MirrorAddress(address)| address),
+        ),
+        ForwardedRequest::Shutdown(connection) => {
+            half_close::record_shutdown(connection);
+            ForwardedResponse::Done
+        }
+        ForwardedRequest::ForgetShutdown(connection) => {
+            half_close::forget_shutdown(connection);
+            ForwardedResponse::Done
+        }
+    })
+}
+
+async fn answer_file(request: FileRequest, hooks: &Sender<HookMessage>) -> Option<FileResponse> {
+    Some(match request {
+        FileRequest::Open(OpenFileRequest { path, open_options }) => FileResponse::Open(
+            ask(hooks, |file_channel_tx| {
+                HookMessage::File(HookMessageFile::Open(Open {
+                    path,
+                    file_channel_tx,
+                    open_options,
+                }))
+            })
+            .await?,
+        ),
+        FileRequest::OpenRelative(OpenRelativeFileRequest {
+            relative_fd,
+            path,
+            open_options,
+        }) => FileResponse::Open(
+            ask(hooks, |file_channel_tx| {
+                HookMessage::File(HookMessageFile::OpenRelative(OpenRelative {
+                    relative_fd,
+                    path,
+                    file_channel_tx,
+                    open_options,
+                }))
+            })
+            .await?,
+        ),
+        FileRequest::Read(ReadFileRequest { fd, buffer_size }) => FileResponse::Read(
+            ask(hooks, |file_channel_tx| {
+                HookMessage::File(HookMessageFile::Read(ReadFile {
+                    fd,
+                    buffer_size,
+                    file_channel_tx,
+                }))
+            })
+            .await?,
+        ),
+        FileRequest::Seek(SeekFileRequest { fd, seek_from }) => FileResponse::Seek(
+            ask(hooks, |file_channel_tx| {
+                HookMessage::File(HookMessageFile::Seek(Seek {
+                    fd,
+                    seek_from: seek_from.into(),
+                    file_channel_tx,
+                }))
+            })
+            .await?,
+        ),
+        FileRequest::Write(WriteFileRequest { fd, write_bytes }) => FileResponse::Write(
+            ask(hooks, |file_channel_tx| {
+                HookMessage::File(HookMessageFile::Write(WriteFile {
+                    fd,
+                    write_bytes,
+                    file_channel_tx,
+                }))
+            })
+            .await?,
+        ),
+        FileRequest::Close(CloseFileRequest { fd }) => FileResponse::Close(
+            ask(hooks, |file_channel_tx| {
+                HookMessage::File(HookMessageFile::Close(Close {
+                    fd,
+                    file_channel_tx,
+                }))
+            })
+            .await?,
+        ),
+        FileRequest::Access(AccessFileRequest { pathname, mode }) => FileResponse::Access(
+            ask(hooks, |file_channel_tx| {
+                HookMessage::File(HookMessageFile::Access(Access {
+                    pathname,
+                    mode,
+                    file_channel_tx,
+                }))
+            })
+            .await?,
+        ),
+    })
+}
+
+/// Sends the hook message `message` makes with a response channel to the layer thread, and waits
+/// for the response.
+async fn ask<T>(
+    hooks: &Sender<HookMessage>,
+    message: impl FnOnce(ResponseChannel<T>) -> HookMessage,
+) -> Option<RemoteResult<T>> {
+    let (response_tx, response_rx) = oneshot::channel();
+    hooks.send(message(response_tx)).await.ok()?;
+
+    response_rx.await.ok()
+}
+
+/// Frames are the length of the encoded message as a big endian `u32`, followed by it.
+fn encode_frame(message: impl Encode) -> io::Result<Vec<u8>> {
+    let encoded = bincode::encode_to_vec(message, bincode::config::standard())
+        .map_err(|fail| invalid_data(fail.to_string()))?;
+    let length = u32::try_from(encoded.len()).map_err(|fail| invalid_data(fail.to_string()))?;
+
+    Ok([&length.to_be_bytes()[..], &encoded].concat())
+}
+
+fn decode_frame<T: Decode>(frame: &[u8]) -> io::Result<T> {
+    bincode::decode_from_slice(frame, bincode::config::standard())
+        .map(|(message, _)| message)
+        .map_err(|fail| invalid_data(fail.to_string()))
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn unexpected_response(response: ForwardedResponse) -> io::Error {
+    invalid_data(format!("unexpected forwarded response {response:?}"))
+}
+
+fn encode_listen(listen: &Listen) -> [u8; LISTEN_LENGTH] {
+    let [mirror_high, mirror_low] = listen.mirror_port.to_be_bytes();
+    let [requested_high, requested_low] = listen.requested_port.to_be_bytes();
+
+    [
+        mirror_high,
+        mirror_low,
+        requested_high,
+        requested_low,
+        listen.ipv6.into(),
+    ]
+}
+
+fn decode_listen(bytes: &[u8]) -> Option<Listen> {
+    match *bytes {
+        [mirror_high, mirror_low, requested_high, requested_low, ipv6] => Some(Listen {
+            mirror_port: u16::from_be_bytes([mirror_high, mirror_low]),
+            requested_port: u16::from_be_bytes([requested_high, requested_low]),
+            ipv6: ipv6 != 0,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use mirrord_protocol::AccessFileResponse;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn forwarded_listen() {
+        let listen = Listen {
+            mirror_port: 41234,
+            requested_port: 80,
+            ipv6: true,
+        };
+
+        let decoded = decode_listen(&encode_listen(&listen)).unwrap();
+        assert_eq!(
+            (decoded.mirror_port, decoded.requested_port, decoded.ipv6),
+            (41234, 80, true)
+        );
+        assert!(decode_listen(&encode_listen(&listen)[..4]).is_none());
+    }
+
+    /// A worker's listen arrives as before, its other requests over the connection it sends,
+    /// which the session owner answers through its hooks channel.
+    #[tokio::test]
+    async fn forwarded_requests() {
+        let (workers, forwarded_listens) = UnixDatagram::pair().unwrap();
+        forwarded_listens.set_nonblocking(true).unwrap();
+        let forwarded_listens = tokio::net::UnixDatagram::from_std(forwarded_listens).unwrap();
+
+        let connection = (
+            "127.0.0.1:41234".parse().unwrap(),
+            "127.0.0.1:80".parse().unwrap(),
+        );
+        let worker = tokio::task::spawn_blocking(move || {
+            let listen = Listen {
+                mirror_port: 41234,
+                requested_port: 80,
+                ipv6: false,
+            };
+            workers.send(&encode_listen(&listen)).unwrap();
+
+            let mut owner = connect_to_owner(&workers).unwrap();
+            let access = exchange(
+                &mut owner,
+                &ForwardedRequest::File(FileRequest::Access(AccessFileRequest {
+                    pathname: "/app/config.toml".into(),
+                    mode: 4,
+                })),
+            )
+            .unwrap();
+            let shutdown = exchange(&mut owner, &ForwardedRequest::Shutdown(connection)).unwrap();
+
+            (access, shutdown)
+        });
+
+        match recv_forwarded(&forwarded_listens).await.unwrap() {
+            Forwarded::Listen(listen) => assert_eq!(listen.requested_port, 80),
+            _ => panic!("expected the worker's listen"),
+        }
+        let (hooks, mut hook_messages) = mpsc::channel(1);
+        match recv_forwarded(&forwarded_listens).await.unwrap() {
+            Forwarded::Worker(worker) => tokio::spawn(serve_worker(worker, hooks)),
+            _ => panic!("expected the worker's connection"),
+        };
+
+        match hook_messages.recv().await.unwrap() {
+            HookMessage::File(HookMessageFile::Access(Access {
+                pathname,
+                mode,
+                file_channel_tx,
+            })) => {
+                assert_eq!((pathname, mode), (PathBuf::from("/app/config.toml"), 4));
+                file_channel_tx.send(Ok(AccessFileResponse)).unwrap();
+            }
+            other => panic!("unexpected hook message {other:?}"),
+        }
+
+        let (access, shutdown) = worker.await.unwrap();
+        assert!(matches!(
+            access,
+            ForwardedResponse::File(FileResponse::Access(Ok(AccessFileResponse)))
+        ));
+        assert!(matches!(shutdown, ForwardedResponse::Done));
+        assert!(half_close::take_user_shutdown(Some(connection)));
+    }
+}
@@ -0,0 +1,617 @@
+//! Shadow compare (`feature.network.incoming.shadow_compare_file`).
+//!
+//! Mirrored HTTP/1.1 requests get answered twice: by the remote service (which the agent sends
+//! us, see [`LayerTcp::ShadowCompare`]) and by the local app. Each pair of responses is compared
+//! and the differences are appended to the report as JSON lines.
+//!
+//! [`LayerTcp::ShadowCompare`]: mirrord_protocol::tcp::LayerTcp::ShadowCompare
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use httparse::Status;
+use mirrord_protocol::ConnectionId;
+use serde_json::{json, Map, Value};
+use tokio::sync::mpsc::Receiver;
+use tracing::{error, warn};
+
+/// Maximum amount of headers we parse in a single HTTP message.
+const MAX_HEADERS: usize = 64;
+
+/// Headers that differ without the responses being any different, timing and framing (the body
+/// comparison covers the latter).
+const IGNORED_HEADERS: [&str; 5] = [
+    "connection",
+    "content-length",
+    "date",
+    "keep-alive",
+    "transfer-encoding",
+];
+
+/// Bytes of a shadowed connection, by who sent them.
+#[derive(Debug)]
+pub(crate) enum ShadowEvent {
+    /// The mirrored request bytes.
+    Request(Vec<u8>),
+    /// The local app's response bytes.
+    Local(Vec<u8>),
+    /// The remote service's response bytes.
+    Remote(Vec<u8>),
+}
+
+/// The report file, shared by every shadowed connection.
+#[derive(Debug, Clone)]
+pub(crate) struct ShadowReport {
+    file: Arc<Mutex<File>>,
+}
+
+impl ShadowReport {
+    pub(crate) fn new(path: &Path) -> io::Result<Self> {
+        Ok(ShadowReport {
+            file: Arc::new(Mutex::new(File::create(path)?)),
+        })
+    }
+
+    fn write(&self, entry: &Value) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        self.file.lock().unwrap().write_all(&line)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Request {
+    method: String,
+    path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Without the chunked encoding, if it had one.
+    body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum BodyLength {
+    Fixed(usize),
+    Chunked,
+    UntilClose,
+}
+
+fn invalid_data(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn owned_headers(headers: &[httparse::Header]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|header| {
+            (
+                header.name.to_owned(),
+                String::from_utf8_lossy(header.value).into_owned(),
+            )
+        })
+        .collect()
+}
+
+fn is_chunked(headers: &[(String, String)]) -> bool {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("transfer-encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case("chunked"))
+}
+
+fn content_length(headers: &[(String, String)]) -> io::Result<Option<usize>> {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.trim().parse().map_err(invalid_data))
+        .transpose()
+}
+
+/// Where a body that starts at the beginning of `buffer` ends, and its bytes (de-chunked).
+/// `None` until all of it arrived.
+fn read_body(
+    buffer: &[u8],
+    length: BodyLength,
+    closed: bool,
+) -> io::Result<Option<(usize, Vec<u8>)>> {
+    match length {
+        BodyLength::Fixed(length) => {
+            Ok((buffer.len() >= length).then(|| (length, buffer[..length].to_vec())))
+        }
+        BodyLength::UntilClose => Ok(closed.then(|| (buffer.len(), buffer.to_vec()))),
+        BodyLength::Chunked => {
+            let mut end = 0;
+            let mut body = Vec::new();
+
+            loop {
+                let (consumed, size) = match httparse::parse_chunk_size(&buffer[end..])
+                    .map_err(|_| invalid_data("invalid chunk size"))?
+                {
+                    Status::Complete(chunk) => chunk,
+                    Status::Partial => return Ok(None),
+                };
+                end += consumed;
+
+                if size == 0 {
+                    break;
+                }
+
+                // Chunk data is followed by CRLF.
+                let data_end = end.saturating_add(size as usize);
+                if buffer.len() < data_end.saturating_add(2) {
+                    return Ok(None);
+                }
+                body.extend_from_slice(&buffer[end..data_end]);
+                end = data_end + 2;
+            }
+
+            // Trailer fields, until an empty line.
+            loop {
+                match buffer[end..]
+                    .windows(2)
+                    .position(|window| window == b"\r\n")
+                {
+                    Some(0) => return Ok(Some((end + 2, body))),
+                    Some(line_length) => end += line_length + 2,
+                    None => return Ok(None),
+                }
+            }
+        }
+    }
+}
+
+/// One direction of a shadowed connection, whole HTTP messages are taken out of it as they
+/// arrive.
+#[derive(Debug, Default)]
+struct HttpStream {
+    buffer: Vec<u8>,
+    closed: bool,
+}
+
+impl HttpStream {
+    /// Removes the message with a head of `head_length` from the buffer, returning its body.
+    fn take_message(
+        &mut self,
+        head_length: usize,
+        length: BodyLength,
+    ) -> io::Result<Option<Vec<u8>>> {
+        match read_body(&self.buffer[head_length..], length, self.closed)? {
+            Some((body_length, body)) => {
+                self.buffer.drain(..head_length + body_length);
+                Ok(Some(body))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_request(&mut self) -> io::Result<Option<Request>> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+
+        let head_length = match request.parse(&self.buffer).map_err(invalid_data)? {
+            Status::Complete(head_length) => head_length,
+            Status::Partial => return Ok(None),
+        };
+
+        let headers = owned_headers(request.headers);
+        let length = if is_chunked(&headers) {
+            BodyLength::Chunked
+        } else {
+            BodyLength::Fixed(content_length(&headers)?.unwrap_or(0))
+        };
+        let parsed = Request {
+            method: request.method.unwrap_or_default().to_owned(),
+            path: request.path.unwrap_or_default().to_owned(),
+        };
+
+        Ok(self.take_message(head_length, length)?.map(|_| parsed))
+    }
+
+    /// The final response to `request`, informational responses before it are skipped.
+    fn next_response(&mut self, request: &Request) -> io::Result<Option<Response>> {
+        loop {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut response = httparse::Response::new(&mut headers);
+
+            let head_length = match response.parse(&self.buffer).map_err(invalid_data)? {
+                Status::Complete(head_length) => head_length,
+                Status::Partial => return Ok(None),
+            };
+
+            let status = response.code.unwrap_or_default();
+            let headers = owned_headers(response.headers);
+            let informational = (100..200).contains(&status) && status != 101;
+
+            let length = if request.method == "HEAD"
+                || (100..200).contains(&status)
+                || status == 204
+                || status == 304
+            {
+                BodyLength::Fixed(0)
+            } else if is_chunked(&headers) {
+                BodyLength::Chunked
+            } else {
+                content_length(&headers)?
+                    .map(BodyLength::Fixed)
+                    .unwrap_or(BodyLength::UntilClose)
+            };
+
+            let body = match self.take_message(head_length, length)? {
+                Some(body) => body,
+                None => return Ok(None),
+            };
+
+            if !informational {
+                return Ok(Some(Response {
+                    status,
+                    headers,
+                    body,
+                }));
+            }
+        }
+    }
+}
+
+/// Pairs the requests of a connection with the local and remote responses to them, which come
+/// in the same order (HTTP/1.1 pipelining keeps it).
+#[derive(Debug, Default)]
+struct ShadowConnection {
+    requests: HttpStream,
+    local: HttpStream,
+    remote: HttpStream,
+    pending: VecDeque<Request>,
+    local_responses: VecDeque<Response>,
+    remote_responses: VecDeque<Response>,
+}
+
+impl ShadowConnection {
+    /// Adds the bytes of `event`, returning the requests that got both of their responses.
+    fn push(&mut self, event: ShadowEvent) -> io::Result<Vec<(Request, Response, Response)>> {
+        match event {
+            ShadowEvent::Request(bytes) => self.requests.buffer.extend(bytes),
+            ShadowEvent::Local(bytes) => self.local.buffer.extend(bytes),
+            ShadowEvent::Remote(bytes) => self.remote.buffer.extend(bytes),
+        }
+
+        self.parse()
+    }
+
+    /// Responses without a length end here.
+    fn close(&mut self) -> io::Result<Vec<(Request, Response, Response)>> {
+        self.local.closed = true;
+        self.remote.closed = true;
+
+        self.parse()
+    }
+
+    fn parse(&mut self) -> io::Result<Vec<(Request, Response, Response)>> {
+        while let Some(request) = self.requests.next_request()? {
+            self.pending.push_back(request);
+        }
+
+        for (stream, responses) in [
+            (&mut self.local, &mut self.local_responses),
+            (&mut self.remote, &mut self.remote_responses),
+        ] {
+            while let Some(request) = self.pending.get(responses.len()) {
+                match stream.next_response(request)? {
+                    Some(response) => responses.push_back(response),
+                    None => break,
+                }
+            }
+        }
+
+        let mut answered = Vec::new();
+        while !self.local_responses.is_empty() && !self.remote_responses.is_empty() {
+            answered.push((
+                self.pending.pop_front().unwrap(),
+                self.local_responses.pop_front().unwrap(),
+                self.remote_responses.pop_front().unwrap(),
+            ));
+        }
+
+        Ok(answered)
+    }
+
+    /// Requests only one side answered, with the side that didn't.
+    fn unanswered(&self) -> impl Iterator<Item = (&Request, &'static str)> {
+        let local = self.local_responses.len();
+        let remote = self.remote_responses.len();
+
+        self.pending
+            .iter()
+            .enumerate()
+            .filter_map(
+                move |(index, request)| match (index < local, index < remote) {
+                    (true, false) => Some((request, "remote")),
+                    (false, true) => Some((request, "local")),
+                    _ => None,
+                },
+            )
+    }
+}
+
+/// Headers by lowercase name, repeated ones joined with `, `.
+fn header_map(headers: &[(String, String)]) -> BTreeMap<String, String> {
+    let mut map: BTreeMap<String, String> = BTreeMap::new();
+
+    for (name, value) in headers {
+        let name = name.to_ascii_lowercase();
+        if IGNORED_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+
+        map.entry(name)
+            .and_modify(|joined| {
+                joined.push_str(", ");
+                joined.push_str(value);
+            })
+            .or_insert_with(|| value.clone());
+    }
+
+    map
+}
+
+fn header_diffs(local: &[(String, String)], remote: &[(String, String)]) -> Vec<Value> {
+    let local = header_map(local);
+    let remote = header_map(remote);
+
+    let mut names = local.keys().chain(remote.keys()).collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+
+    names
+        .into_iter()
+        .filter(|name| local.get(*name) != remote.get(*name))
+        .map(|name| json!({ "name": name, "local": local.get(name), "remote": remote.get(name) }))
+        .collect()
+}
+
+/// The report entry for `request`, `None` when both responses are the same.
+fn diff(
+    connection_id: ConnectionId,
+    request: &Request,
+    local: &Response,
+    remote: &Response,
+) -> Option<Value> {
+    let mut entry = Map::new();
+
+    if local.status != remote.status {
+        entry.insert(
+            "status".to_owned(),
+            json!({ "local": local.status, "remote": remote.status }),
+        );
+    }
+
+    let headers = header_diffs(&local.headers, &remote.headers);
+    if !headers.is_empty() {
+        entry.insert("headers".to_owned(), Value::Array(headers));
+    }
+
+    if local.body != remote.body {
+        let first_difference = local
+            .body
+            .iter()
+            .zip(&remote.body)
+            .position(|(local, remote)| local != remote)
+            .unwrap_or_else(|| local.body.len().min(remote.body.len()));
+
+        entry.insert(
+            "body".to_owned(),
+            json!({
+                "local_length": local.body.len(),
+                "remote_length": remote.body.len(),
+                "first_difference": first_difference,
+            }),
+        );
+    }
+
+    if entry.is_empty() {
+        return None;
+    }
+
+    entry.insert("connection_id".to_owned(), json!(connection_id));
+    entry.insert(
+        "request".to_owned(),
+        json!(format!("{} {}", request.method, request.path)),
+    );
+
+    Some(Value::Object(entry))
+}
+
+fn report_answered(
+    connection_id: ConnectionId,
+    answered: Vec<(Request, Response, Response)>,
+    report: &ShadowReport,
+) {
+    for (request, local, remote) in answered {
+        if let Some(entry) = diff(connection_id, &request, &local, &remote) {
+            if let Err(fail) = report.write(&entry) {
+                error!("report_answered -> failed writing report with {:#?}", fail);
+            }
+        }
+    }
+}
+
+/// Compares the responses of a mirrored connection, until every [`ShadowEvent`] sender of it is
+/// gone (the connection and its local stream are both closed).
+pub(crate) async fn compare_connection(
+    connection_id: ConnectionId,
+    mut events: Receiver<ShadowEvent>,
+    report: ShadowReport,
+) {
+    let mut connection = ShadowConnection::default();
+
+    loop {
+        let (answered, closed) = match events.recv().await {
+            Some(event) => (connection.push(event), false),
+            None => (connection.close(), true),
+        };
+
+        match answered {
+            Ok(answered) => report_answered(connection_id, answered, &report),
+            Err(fail) => {
+                warn!(
+                    "compare_connection -> not comparing connection {} further, failed parsing it with {:#?}",
+                    connection_id, fail
+                );
+                return;
+            }
+        }
+
+        if closed {
+            break;
+        }
+    }
+
+    for (request, missing) in connection.unanswered() {
+        let entry = json!({
+            "connection_id": connection_id,
+            "request": format!("{} {}", request.method, request.path),
+            "missing": missing,
+        });
+
+        if let Err(fail) = report.write(&entry) {
+            error!(
+                "compare_connection -> failed writing report with {:#?}",
+                fail
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str) -> Request {
+        Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+        }
+    }
+
+    #[test]
+    fn pairs_pipelined_responses() {
+        let mut connection = ShadowConnection::default();
+
+        let answered = connection
+            .push(ShadowEvent::Request(
+                b"GET /a HTTP/1.1\r\n\r\nHEAD /b HTTP/1.1\r\n\r\nPOST /c HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi"
+                    .to_vec(),
+            ))
+            .unwrap();
+        assert!(answered.is_empty());
+
+        // The local app chunks its bodies, the remote service sends their length.
+        let answered = connection
+            .push(ShadowEvent::Local(
+                b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n"
+                    .to_vec(),
+            ))
+            .unwrap();
+        assert!(answered.is_empty());
+
+        let answered = connection
+            .push(ShadowEvent::Remote(
+                b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nokHTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nHTTP/1.1 201 Created\r\n"
+                    .to_vec(),
+            ))
+            .unwrap();
+
+        assert_eq!(answered.len(), 2);
+        assert_eq!(answered[0].0, request("GET", "/a"));
+        assert_eq!(answered[0].1.body, b"ok");
+        assert_eq!(answered[0].1.body, answered[0].2.body);
+        assert_eq!(answered[1].0, request("HEAD", "/b"));
+        assert!(answered[1].2.body.is_empty());
+
+        // The remote service answered /c, the local app never did.
+        let answered = connection
+            .push(ShadowEvent::Remote(b"Content-Length: 0\r\n\r\n".to_vec()))
+            .unwrap();
+        assert!(answered.is_empty());
+        assert!(connection.close().unwrap().is_empty());
+        assert_eq!(
+            connection.unanswered().collect::<Vec<_>>(),
+            vec![(&request("POST", "/c"), "local")]
+        );
+    }
+
+    #[test]
+    fn body_until_close() {
+        let mut connection = ShadowConnection::default();
+
+        connection
+            .push(ShadowEvent::Request(b"GET / HTTP/1.1\r\n\r\n".to_vec()))
+            .unwrap();
+        connection
+            .push(ShadowEvent::Local(b"HTTP/1.1 200 OK\r\n\r\nlocal".to_vec()))
+            .unwrap();
+        let answered = connection
+            .push(ShadowEvent::Remote(
+                b"HTTP/1.1 200 OK\r\n\r\nremote".to_vec(),
+            ))
+            .unwrap();
+        assert!(answered.is_empty());
+
+        let answered = connection.close().unwrap();
+        assert_eq!(answered.len(), 1);
+        assert_eq!(answered[0].1.body, b"local");
+        assert_eq!(answered[0].2.body, b"remote");
+    }
+
+    #[test]
+    fn reports_differences() {
+        let headers = |headers: &[(&str, &str)]| {
+            headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+        let local = Response {
+            status: 500,
+            headers: headers(&[
+                ("Date", "Mon, 17 Oct 2022 10:00:00 GMT"),
+                ("Content-Type", "text/plain"),
+                ("X-Version", "2"),
+            ]),
+            body: b"internal error".to_vec(),
+        };
+        let remote = Response {
+            status: 200,
+            headers: headers(&[
+                ("date", "Mon, 17 Oct 2022 10:00:01 GMT"),
+                ("content-type", "text/plain"),
+                ("Set-Cookie", "a=1"),
+                ("set-cookie", "b=2"),
+            ]),
+            body: b"internal state".to_vec(),
+        };
+
+        assert_eq!(
+            diff(7, &request("GET", "/users"), &local, &remote).unwrap(),
+            json!({
+                "connection_id": 7,
+                "request": "GET /users",
+                "status": { "local": 500, "remote": 200 },
+                "headers": [
+                    { "name": "set-cookie", "local": null, "remote": "a=1, b=2" },
+                    { "name": "x-version", "local": "2", "remote": null },
+                ],
+                "body": { "local_length": 14, "remote_length": 14, "first_difference": 9 },
+            })
+        );
+        assert_eq!(diff(7, &request("GET", "/users"), &remote, &remote), None);
+    }
+}
@@ -1,7 +1,7 @@
 //! We implement each hook function in a safe function as much as possible, having the unsafe do the
 //! absolute minimum
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::HashMap,
     net::SocketAddr,
     os::unix::io::RawFd,
     sync::{Arc, LazyLock, Mutex},
@@ -11,51 +11,32 @@ use libc::{c_int, sockaddr, socklen_t};
 use mirrord_protocol::{AddrInfoHint, Port};
 use socket2::SockAddr;
 
-use crate::error::{HookError, HookResult};
+pub(crate) use self::half_close::{take_user_shutdown, user_connection};
+use self::sources::ConnectionSources;
+use crate::{
+    error::{HookError, HookResult},
+    IGNORED_INCOMING_PORTS,
+};
 
+pub(crate) mod half_close;
 pub(super) mod hooks;
 pub(crate) mod ops;
+pub(crate) mod resolved_hosts;
+pub(crate) mod sources;
 
 pub(crate) static SOCKETS: LazyLock<Mutex<HashMap<RawFd, Arc<UserSocket>>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
 
-pub static CONNECTION_QUEUE: LazyLock<Mutex<ConnectionQueue>> =
-    LazyLock::new(|| Mutex::new(ConnectionQueue::default()));
-
-/// Struct sent over the socket once created to pass metadata to the hook
-#[derive(Debug)]
-pub struct SocketInformation {
-    pub address: SocketAddr,
-}
-
-/// poll_agent loop inserts connection data into this queue, and accept reads it.
-#[derive(Debug, Default)]
-pub struct ConnectionQueue {
-    connections: HashMap<RawFd, VecDeque<SocketInformation>>,
-}
-
-impl ConnectionQueue {
-    pub fn add(&mut self, fd: &RawFd, info: SocketInformation) {
-        self.connections.entry(*fd).or_default().push_back(info);
-    }
-    pub fn get(&mut self, fd: &RawFd) -> Option<SocketInformation> {
-        let mut queue = self.connections.remove(fd)?;
-        if let Some(info) = queue.pop_front() {
-            if !queue.is_empty() {
-                self.connections.insert(*fd, queue);
-            }
-            Some(info)
-        } else {
-            None
-        }
-    }
-}
+/// Mirrored datagrams are delivered to the bound socket from a local socket per original sender,
+/// this maps the address of such local socket to the original sender address, so `recvfrom` can
+/// report it.
+pub(crate) static MIRRORED_DATAGRAM_SOURCES: LazyLock<Mutex<HashMap<SocketAddr, SocketAddr>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
 
-impl SocketInformation {
-    pub fn new(address: SocketAddr) -> Self {
-        Self { address }
-    }
-}
+/// See [`ConnectionSources`], forced when the layer starts so forked workers share it.
+pub(crate) static CONNECTION_SOURCES: LazyLock<ConnectionSources> = LazyLock::new(|| {
+    ConnectionSources::new().expect("Mapping the connection sources shared memory failed!")
+});
 
 trait GetPeerName {
     fn get_peer_name(&self) -> SocketAddr;
@@ -128,11 +109,22 @@ pub struct UserSocket {
     pub(crate) kind: SocketKind,
 }
 
+/// Outgoing connections to these ports are never intercepted.
 #[inline]
 const fn is_ignored_port(port: Port) -> bool {
     port == 0 || (port > 50000 && port < 60000)
 }
 
+/// Incoming ports are ignored according to `feature.network.ignore_ports` and
+/// `feature.network.allow_ports`.
+#[inline]
+fn is_ignored_incoming_port(port: Port) -> bool {
+    IGNORED_INCOMING_PORTS.get().map_or_else(
+        || is_ignored_port(port),
+        |ignored_ports| ignored_ports.contains(port),
+    )
+}
+
 /// Fill in the sockaddr structure for the given address.
 #[inline]
 fn fill_address(
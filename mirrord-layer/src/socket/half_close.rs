@@ -0,0 +1,127 @@
+//! Tells a connection the user shut down for writing (`shutdown(SHUT_WR)`) from one it closed, as
+//! our side of it reads EOF either way.
+
+use std::{
+    collections::HashSet,
+    io,
+    net::SocketAddr,
+    os::unix::io::RawFd,
+    sync::{LazyLock, Mutex},
+};
+
+use socket2::SockRef;
+use tracing::warn;
+
+use crate::{
+    error::HookResult,
+    session::{self, is_session_owner},
+};
+
+/// Connections the user shut down for writing, by the local and peer address of the user's
+/// socket.
+static SHUT_DOWN_CONNECTIONS: LazyLock<Mutex<HashSet<(SocketAddr, SocketAddr)>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// The local and peer address of the user's `sockfd`. The real ones, as we're in a detour these
+/// don't go through our `getsockname`.
+fn fd_connection(sockfd: RawFd) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    let socket = SockRef::from(&sockfd);
+
+    Ok(socket
+        .local_addr()?
+        .as_socket()
+        .zip(socket.peer_addr()?.as_socket()))
+}
+
+/// The local and peer address of the user's socket at the other end of `stream` (our side of the
+/// connection), the key of [`SHUT_DOWN_CONNECTIONS`].
+pub(crate) fn user_connection(stream: &tokio::net::TcpStream) -> Option<(SocketAddr, SocketAddr)> {
+    Some((stream.peer_addr().ok()?, stream.local_addr().ok()?))
+}
+
+/// The user is done writing on `sockfd`, recorded before the actual `shutdown` happens.
+///
+/// Our side of the connections of forked workers is in the session owner, which records them.
+pub(crate) fn record_user_shutdown(sockfd: RawFd) -> HookResult<()> {
+    if let Some(connection) = fd_connection(sockfd)? {
+        record_shutdown(connection);
+
+        if !is_session_owner() {
+            session::forward_shutdown(connection, false)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The user closed `sockfd`, so a shutdown our side didn't see (it stopped reading before the
+/// EOF) won't be taken anymore.
+pub(crate) fn forget_user_shutdown(sockfd: RawFd) {
+    if let Ok(Some(connection)) = fd_connection(sockfd) {
+        if forget_shutdown(connection) && !is_session_owner() {
+            if let Err(fail) = session::forward_shutdown(connection, true) {
+                warn!(
+                    "Failed forgetting the shutdown of {:?}: {}",
+                    connection, fail
+                );
+            }
+        }
+    }
+}
+
+/// Records the shutdown of `connection`, by this process or a forked worker.
+pub(crate) fn record_shutdown(connection: (SocketAddr, SocketAddr)) {
+    SHUT_DOWN_CONNECTIONS.lock().unwrap().insert(connection);
+}
+
+/// Returns whether `connection` was shut down.
+pub(crate) fn forget_shutdown(connection: (SocketAddr, SocketAddr)) -> bool {
+    SHUT_DOWN_CONNECTIONS.lock().unwrap().remove(&connection)
+}
+
+/// Whether the user shut down the socket of `user_connection`, see [`SHUT_DOWN_CONNECTIONS`].
+pub(crate) fn take_user_shutdown(user_connection: Option<(SocketAddr, SocketAddr)>) -> bool {
+    user_connection.map_or(false, forget_shutdown)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// The user's socket and our side of the connection.
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let user = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (ours, _) = listener.accept().await.unwrap();
+
+        (user, ours)
+    }
+
+    #[tokio::test]
+    async fn shutdown_taken_once() {
+        let (user, ours) = connection().await;
+        let (_, other) = connection().await;
+
+        record_user_shutdown(user.as_raw_fd()).unwrap();
+
+        assert!(!take_user_shutdown(user_connection(&other)));
+        assert!(take_user_shutdown(user_connection(&ours)));
+        assert!(!take_user_shutdown(user_connection(&ours)));
+    }
+
+    #[tokio::test]
+    async fn shutdown_forgotten_on_close() {
+        let (user, ours) = connection().await;
+
+        record_user_shutdown(user.as_raw_fd()).unwrap();
+        forget_user_shutdown(user.as_raw_fd());
+
+        assert!(!take_user_shutdown(user_connection(&ours)));
+    }
+}
@@ -1,7 +1,9 @@
 use std::{ffi::CStr, os::unix::io::RawFd};
 
 use frida_gum::interceptor::Interceptor;
-use libc::{c_char, c_int, sockaddr, socklen_t};
+use libc::{c_char, c_int, c_void, msghdr, size_t, sockaddr, socklen_t, ssize_t};
+#[cfg(target_os = "linux")]
+use libc::{c_uint, mmsghdr, timespec};
 use mirrord_macro::{hook_fn, hook_guard_fn};
 use mirrord_protocol::AddrInfoHint;
 use socket2::SockAddr;
@@ -51,7 +53,8 @@ pub(crate) unsafe extern "C" fn bind_detour(
                     .map_err(|fail| match fail {
                         HookError::LocalFDNotFound(_)
                         | HookError::BypassedPort(_)
-                        | HookError::AddressConversion => {
+                        | HookError::AddressConversion
+                        | HookError::NotSessionOwner => {
                             warn!("bind_detour -> bypassed with {:#?}", fail);
 
                             FN_BIND(sockfd, raw_address, address_length)
@@ -75,7 +78,15 @@ pub(crate) unsafe extern "C" fn listen_detour(sockfd: RawFd, backlog: c_int) ->
         listen(sockfd, backlog)
             .map(|()| 0)
             .map_err(|fail| match fail {
-                HookError::LocalFDNotFound(_) | HookError::SocketInvalidState(_) => {
+                HookError::LocalFDNotFound(_) => {
+                    trace!(
+                        "listen_detour -> sockfd {} is not managed by mirrord (its port may be \
+                         ignored), listening locally",
+                        sockfd
+                    );
+                    FN_LISTEN(sockfd, backlog)
+                }
+                HookError::SocketInvalidState(_) => {
                     warn!("listen_detour -> bypassed with {:#?}", fail);
                     FN_LISTEN(sockfd, backlog)
                 }
@@ -133,6 +144,21 @@ pub(super) unsafe extern "C" fn getpeername_detour(
     result
 }
 
+/// Lets the interceptor of a managed connection know it's a half-close, see [`shutdown`].
+#[hook_guard_fn]
+#[tracing::instrument(level = "trace")]
+pub(crate) unsafe extern "C" fn shutdown_detour(sockfd: RawFd, how: c_int) -> c_int {
+    match shutdown(sockfd, how) {
+        Ok(()) | Err(HookError::LocalFDNotFound(_) | HookError::SocketInvalidState(_)) => {}
+        Err(fail) => warn!(
+            "shutdown_detour -> failed recording shutdown with {:#?}",
+            fail
+        ),
+    }
+
+    FN_SHUTDOWN(sockfd, how)
+}
+
 #[hook_guard_fn]
 #[tracing::instrument(level = "trace", skip(address, address_len))]
 pub(crate) unsafe extern "C" fn getsockname_detour(
@@ -151,6 +177,88 @@ pub(crate) unsafe extern "C" fn getsockname_detour(
     result
 }
 
+/// Mirrored datagrams reach the socket from a local address of ours, we report the original sender
+/// instead.
+#[hook_guard_fn]
+#[tracing::instrument(level = "trace", skip(buffer, address, address_len))]
+pub(super) unsafe extern "C" fn recvfrom_detour(
+    sockfd: RawFd,
+    buffer: *mut c_void,
+    length: size_t,
+    flags: c_int,
+    address: *mut sockaddr,
+    address_len: *mut socklen_t,
+) -> ssize_t {
+    let recvfrom_result = FN_RECVFROM(sockfd, buffer, length, flags, address, address_len);
+
+    if recvfrom_result != -1 {
+        match recv_from(sockfd, address, address_len) {
+            Ok(()) | Err(HookError::LocalFDNotFound(_) | HookError::SocketInvalidState(_)) => {}
+            Err(fail) => warn!(
+                "recvfrom_detour -> failed replacing address with {:#?}",
+                fail
+            ),
+        }
+    }
+
+    recvfrom_result
+}
+
+/// Same as [`recvfrom_detour`], the original sender goes in `msg_name`.
+#[hook_guard_fn]
+#[tracing::instrument(level = "trace", skip(message))]
+pub(super) unsafe extern "C" fn recvmsg_detour(
+    sockfd: RawFd,
+    message: *mut msghdr,
+    flags: c_int,
+) -> ssize_t {
+    let recvmsg_result = FN_RECVMSG(sockfd, message, flags);
+
+    if recvmsg_result != -1 {
+        replace_message_source(sockfd, message);
+    }
+
+    recvmsg_result
+}
+
+/// Same as [`recvmsg_detour`], for every message received.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+#[tracing::instrument(level = "trace", skip(messages, timeout))]
+pub(super) unsafe extern "C" fn recvmmsg_detour(
+    sockfd: RawFd,
+    messages: *mut mmsghdr,
+    length: c_uint,
+    flags: c_int,
+    timeout: *mut timespec,
+) -> c_int {
+    let recvmmsg_result = FN_RECVMMSG(sockfd, messages, length, flags, timeout);
+
+    for index in 0..recvmmsg_result.max(0) as usize {
+        replace_message_source(sockfd, &mut (*messages.add(index)).msg_hdr);
+    }
+
+    recvmmsg_result
+}
+
+unsafe fn replace_message_source(sockfd: RawFd, message: *mut msghdr) {
+    if message.is_null() {
+        return;
+    }
+
+    match recv_from(
+        sockfd,
+        (*message).msg_name.cast(),
+        &mut (*message).msg_namelen,
+    ) {
+        Ok(()) | Err(HookError::LocalFDNotFound(_) | HookError::SocketInvalidState(_)) => {}
+        Err(fail) => warn!(
+            "replace_message_source -> failed replacing address with {:#?}",
+            fail
+        ),
+    }
+}
+
 #[hook_guard_fn]
 #[tracing::instrument(level = "trace", skip(address, address_len))]
 pub(crate) unsafe extern "C" fn accept_detour(
@@ -409,6 +517,30 @@ pub(crate) unsafe fn enable_socket_hooks(interceptor: &mut Interceptor, enabled_
         FN_CONNECT
     );
 
+    let _ = replace!(
+        interceptor,
+        "recvfrom",
+        recvfrom_detour,
+        FnRecvfrom,
+        FN_RECVFROM
+    );
+
+    let _ = replace!(
+        interceptor,
+        "recvmsg",
+        recvmsg_detour,
+        FnRecvmsg,
+        FN_RECVMSG
+    );
+
+    let _ = replace!(
+        interceptor,
+        "shutdown",
+        shutdown_detour,
+        FnShutdown,
+        FN_SHUTDOWN
+    );
+
     let _ = replace!(interceptor, "fcntl", fcntl_detour, FnFcntl, FN_FCNTL);
     let _ = replace!(interceptor, "dup", dup_detour, FnDup, FN_DUP);
     let _ = replace!(interceptor, "dup2", dup2_detour, FnDup2, FN_DUP2);
@@ -448,6 +580,14 @@ pub(crate) unsafe fn enable_socket_hooks(interceptor: &mut Interceptor, enabled_
         );
 
         let _ = replace!(interceptor, "dup3", dup3_detour, FnDup3, FN_DUP3);
+
+        let _ = replace!(
+            interceptor,
+            "recvmmsg",
+            recvmmsg_detour,
+            FnRecvmmsg,
+            FN_RECVMMSG
+        );
     }
 
     let _ = replace!(interceptor, "accept", accept_detour, FnAccept, FN_ACCEPT);
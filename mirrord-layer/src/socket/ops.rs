@@ -9,17 +9,20 @@ use std::{
 
 use dns_lookup::AddrInfo;
 use libc::{c_int, sockaddr, socklen_t};
-use socket2::SockAddr;
+use mirrord_config::outgoing::OutgoingAction;
+use socket2::{SockAddr, SockRef};
 use tokio::sync::oneshot;
 use tracing::{debug, error, info, trace};
 
-use super::{hooks::*, *};
+use super::{hooks::*, resolved_hosts::outgoing_action, *};
 use crate::{
     common::{blocking_send_hook_message, GetAddrInfoHook, HookMessage},
     error::HookError,
     outgoing::{tcp::TcpOutgoing, udp::UdpOutgoing, Connect, MirrorAddress},
+    session::is_session_owner,
     tcp::{HookMessageTcp, Listen},
-    ENABLED_TCP_OUTGOING, ENABLED_UDP_OUTGOING,
+    udp_mirror::{HookMessageUdp, UdpBind},
+    ENABLED_TCP_OUTGOING, ENABLED_UDP_OUTGOING, INCOMING_PORT_MAPPING, OUTGOING_FILTER,
 };
 
 /// Create the socket, add it to SOCKETS if successful and matching protocol and domain (Tcpv4/v6)
@@ -76,9 +79,20 @@ pub(super) fn bind(sockfd: c_int, address: SockAddr) -> HookResult<()> {
     };
 
     let requested_port = requested_address.port();
-    (!is_ignored_port(requested_port))
-        .then_some(())
-        .ok_or_else(|| HookError::BypassedPort(requested_address.port()))?;
+    if is_ignored_incoming_port(requested_port) {
+        debug!(
+            "bind -> port {} is ignored by `feature.network.ignore_ports`/`allow_ports`, \
+             binding it locally",
+            requested_port
+        );
+
+        return Err(HookError::BypassedPort(requested_port));
+    }
+
+    // Datagrams are only subscribed by the session owner, forked workers bind them locally.
+    if matches!(socket.kind, SocketKind::Udp(_)) && !is_session_owner() {
+        return Err(HookError::NotSessionOwner);
+    }
 
     let unbound_address = match socket.domain {
         libc::AF_INET => Ok(SockAddr::from(SocketAddr::new(
@@ -121,6 +135,14 @@ pub(super) fn bind(sockfd: c_int, address: SockAddr) -> HookResult<()> {
     .map(|(_, address)| address.as_socket())?
     .ok_or(HookError::AddressConversion)?;
 
+    // Udp sockets don't `listen`, so we start mirroring as soon as they're bound.
+    if let SocketKind::Udp(_) = socket.kind {
+        blocking_send_hook_message(HookMessage::Udp(HookMessageUdp::Bind(UdpBind {
+            mirror_address: address,
+            requested_port: remote_port(requested_port),
+        })))?;
+    }
+
     Arc::get_mut(&mut socket).unwrap().state = SocketState::Bound(Bound {
         requested_port,
         address,
@@ -131,6 +153,37 @@ pub(super) fn bind(sockfd: c_int, address: SockAddr) -> HookResult<()> {
     Ok(())
 }
 
+/// The user closed one of the fds of `socket`, when it was the last one of a bound Udp socket, we
+/// stop mirroring its port. The last one of a connection can't be shut down anymore.
+#[tracing::instrument(level = "trace")]
+pub(crate) fn close(sockfd: RawFd, socket: Arc<UserSocket>) -> HookResult<()> {
+    match (socket.kind, &socket.state) {
+        (SocketKind::Tcp(_), SocketState::Connected(_)) if Arc::strong_count(&socket) == 1 => {
+            half_close::forget_user_shutdown(sockfd);
+            Ok(())
+        }
+        (SocketKind::Udp(_), SocketState::Bound(bound))
+            if Arc::strong_count(&socket) == 1 && is_session_owner() =>
+        {
+            blocking_send_hook_message(HookMessage::Udp(HookMessageUdp::Close(UdpBind {
+                mirror_address: bound.address,
+                requested_port: remote_port(bound.requested_port),
+            })))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The remote port whose traffic goes to `local_port`, see
+/// `feature.network.incoming.port_mapping`.
+fn remote_port(local_port: Port) -> Port {
+    INCOMING_PORT_MAPPING
+        .get()
+        .and_then(|port_mapping| port_mapping.get(&local_port))
+        .copied()
+        .unwrap_or(local_port)
+}
+
 /// Subscribe to the agent on the real port. Messages received from the agent on the real port will
 /// later be routed to the fake local port.
 #[tracing::instrument(level = "trace")]
@@ -156,9 +209,8 @@ pub(super) fn listen(sockfd: RawFd, backlog: c_int) -> HookResult<()> {
 
             blocking_send_hook_message(HookMessage::Tcp(HookMessageTcp::Listen(Listen {
                 mirror_port: address.port(),
-                requested_port,
+                requested_port: remote_port(requested_port),
                 ipv6: address.is_ipv6(),
-                fd: sockfd,
             })))?;
 
             Arc::get_mut(&mut socket).unwrap().state = SocketState::Listening(Bound {
@@ -241,8 +293,9 @@ fn connect_outgoing<const TYPE: ConnectType>(
 
 /// Handles 3 different cases, depending if the outgoing traffic feature is enabled or not:
 ///
-/// 1. Outgoing traffic is **disabled**: this just becomes a normal `libc::connect` call, removing
-/// the socket from our list of managed sockets.
+/// 1. Outgoing traffic is **disabled**, or `feature.network.outgoing.filter` makes the destination
+/// local: this just becomes a normal `libc::connect` call, removing the socket from our list of
+/// managed sockets.
 ///
 /// 2. Outgoing traffic is **enabled** and `socket.state` is `Initialized`: sends a hook message
 /// that will be handled by `(Tcp|Udp)OutgoingHandler`, starting the request interception procedure.
@@ -271,6 +324,15 @@ pub(super) fn connect(sockfd: RawFd, remote_address: SocketAddr) -> HookResult<i
         .then_some(())
         .ok_or_else(|| HookError::BypassedPort(remote_address.port()))?;
 
+    let remote_outgoing =
+        outgoing_action(OUTGOING_FILTER.get(), remote_address) == OutgoingAction::Remote;
+    if !remote_outgoing {
+        debug!(
+            "connect -> {} is local by `feature.network.outgoing.filter`",
+            remote_address
+        );
+    }
+
     let raw_connect = |remote_address| {
         let rawish_remote_address = SockAddr::from(remote_address);
         let result = unsafe {
@@ -289,11 +351,11 @@ pub(super) fn connect(sockfd: RawFd, remote_address: SocketAddr) -> HookResult<i
     };
 
     match user_socket_info.kind {
-        SocketKind::Udp(_) if enabled_udp_outgoing => {
+        SocketKind::Udp(_) if enabled_udp_outgoing && remote_outgoing => {
             connect_outgoing::<UDP>(sockfd, remote_address, user_socket_info)
         }
         SocketKind::Tcp(_) => match user_socket_info.state {
-            SocketState::Initialized if enabled_tcp_outgoing => {
+            SocketState::Initialized if enabled_tcp_outgoing && remote_outgoing => {
                 connect_outgoing::<TCP>(sockfd, remote_address, user_socket_info)
             }
             SocketState::Bound(Bound { address, .. }) => {
@@ -319,6 +381,26 @@ pub(super) fn connect(sockfd: RawFd, remote_address: SocketAddr) -> HookResult<i
     }
 }
 
+/// Records that the user is done writing on a managed connection, before the actual `shutdown`
+/// happens, see [`half_close`].
+#[tracing::instrument(level = "trace")]
+pub(super) fn shutdown(sockfd: RawFd, how: c_int) -> HookResult<()> {
+    SOCKETS
+        .lock()?
+        .get(&sockfd)
+        .ok_or(HookError::LocalFDNotFound(sockfd))
+        .and_then(|socket| match &socket.state {
+            SocketState::Connected(_) => Ok(()),
+            _ => Err(HookError::SocketInvalidState(sockfd)),
+        })?;
+
+    if how == libc::SHUT_WR || how == libc::SHUT_RDWR {
+        half_close::record_user_shutdown(sockfd)?;
+    }
+
+    Ok(())
+}
+
 /// Resolve fake local address to real remote address. (IP & port of incoming traffic on the
 /// cluster)
 #[tracing::instrument(level = "trace", skip(address, address_len))]
@@ -369,9 +451,61 @@ pub(super) fn getsockname(
     fill_address(address, address_len, local_address)
 }
 
-/// When the fd is "ours", we accept and recv the first bytes that contain metadata on the
-/// connection to be set in our lock This enables us to have a safe way to get "remote" information
-/// (remote ip, port, etc).
+/// Replaces the address `recvfrom` filled in with the original sender, when the datagram was
+/// mirrored by us (see [`MIRRORED_DATAGRAM_SOURCES`]).
+#[tracing::instrument(level = "trace", skip(address, address_len))]
+pub(super) fn recv_from(
+    sockfd: RawFd,
+    address: *mut sockaddr,
+    address_len: *mut socklen_t,
+) -> HookResult<()> {
+    let domain = {
+        SOCKETS
+            .lock()?
+            .get(&sockfd)
+            .ok_or(HookError::LocalFDNotFound(sockfd))
+            .and_then(|socket| match (&socket.state, socket.kind) {
+                (SocketState::Bound(_), SocketKind::Udp(_)) => Ok(socket.domain),
+                _ => Err(HookError::SocketInvalidState(sockfd)),
+            })?
+    };
+
+    if address.is_null() || address_len.is_null() {
+        return Ok(());
+    }
+
+    let received_from = unsafe {
+        SockAddr::init(|storage, len| {
+            let copy_len = std::cmp::min(*address_len, *len);
+            storage
+                .cast::<u8>()
+                .copy_from_nonoverlapping(address.cast(), copy_len as usize);
+            *len = copy_len;
+
+            Ok(())
+        })
+    }
+    .map(|((), received_from)| received_from.as_socket())?
+    .ok_or(HookError::AddressConversion)?;
+
+    let original_source = MIRRORED_DATAGRAM_SOURCES
+        .lock()?
+        .get(&received_from)
+        .copied();
+
+    match original_source {
+        Some(SocketAddr::V4(source)) if domain == libc::AF_INET6 => fill_address(
+            address,
+            address_len,
+            SocketAddr::new(IpAddr::V6(source.ip().to_ipv6_mapped()), source.port()),
+        ),
+        Some(source) => fill_address(address, address_len, source),
+        None => Ok(()),
+    }
+}
+
+/// When the fd is "ours", the connection was made by us and [`CONNECTION_SOURCES`] has its "remote"
+/// information (remote ip, port), which is what the user gets as the peer address.
 #[tracing::instrument(level = "trace", skip(address, address_len))]
 pub(super) fn accept(
     sockfd: RawFd,
@@ -392,13 +526,16 @@ pub(super) fn accept(
             })?
     };
 
-    let remote_address = {
-        CONNECTION_QUEUE
-            .lock()?
-            .get(&sockfd)
-            .ok_or(HookError::LocalFDNotFound(sockfd))
-            .map(|socket| socket.address)?
-    };
+    // Our connection may have been accepted by a forked worker, the sources are shared with them.
+    let accepted = SockRef::from(&new_fd);
+    let remote_address = accepted
+        .peer_addr()?
+        .as_socket()
+        .zip(accepted.local_addr()?.as_socket())
+        .and_then(|(peer_address, local_address)| {
+            CONNECTION_SOURCES.take(peer_address, local_address)
+        })
+        .ok_or(HookError::LocalFDNotFound(sockfd))?;
 
     let new_socket = UserSocket {
         domain,
@@ -457,7 +594,7 @@ pub(super) fn getaddrinfo(
 ) -> HookResult<*mut libc::addrinfo> {
     let (hook_channel_tx, hook_channel_rx) = oneshot::channel();
     let hook = GetAddrInfoHook {
-        node,
+        node: node.clone(),
         service,
         hints,
         hook_channel_tx,
@@ -465,11 +602,23 @@ pub(super) fn getaddrinfo(
 
     blocking_send_hook_message(HookMessage::GetAddrInfoHook(hook))?;
 
-    let addr_info_list = hook_channel_rx.blocking_recv()??;
+    let addr_info_list = hook_channel_rx
+        .blocking_recv()??
+        .into_iter()
+        .map(AddrInfo::from)
+        .collect::<Vec<_>>();
+
+    if let Some(node) = &node {
+        resolved_hosts::record_resolution(
+            node,
+            addr_info_list
+                .iter()
+                .map(|addr_info| addr_info.sockaddr.ip()),
+        );
+    }
 
     let result = addr_info_list
         .into_iter()
-        .map(AddrInfo::from)
         .map(|addr_info| {
             let AddrInfo {
                 socktype: ai_socktype,
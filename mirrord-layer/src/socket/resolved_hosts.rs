@@ -0,0 +1,137 @@
+//! The names remote DNS resolved to each address, which the hostname rules of
+//! `feature.network.outgoing.filter` are matched against when the user connects.
+//!
+//! Only names that go through our `getaddrinfo` hook are known. Go binaries using the pure Go
+//! resolver connect to addresses we never saw resolved, so only the address rules apply to those.
+//! Forked workers resolve through the session owner and remember their own resolutions.
+
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, SocketAddr},
+    sync::{LazyLock, Mutex},
+};
+
+use mirrord_config::outgoing::{OutgoingAction, OutgoingConfig};
+
+/// How many hosts we remember, the least recently resolved ones are forgotten first.
+const MAX_RESOLVED_HOSTS: usize = 1024;
+
+static RESOLVED_HOSTS: LazyLock<Mutex<ResolvedHosts>> =
+    LazyLock::new(|| Mutex::new(ResolvedHosts::default()));
+
+#[derive(Debug, Default)]
+struct ResolvedHosts {
+    /// The addresses of the latest resolution of each host, the least recently resolved first.
+    hosts: VecDeque<(String, Vec<IpAddr>)>,
+}
+
+impl ResolvedHosts {
+    /// `host` now resolves to `addresses`, replacing the addresses of its previous resolution.
+    fn insert(&mut self, host: &str, addresses: Vec<IpAddr>) {
+        self.hosts.retain(|(resolved, _)| resolved != host);
+        self.hosts.push_back((host.to_owned(), addresses));
+
+        if self.hosts.len() > MAX_RESOLVED_HOSTS {
+            self.hosts.pop_front();
+        }
+    }
+
+    /// The hosts that last resolved to `address`.
+    fn hosts_of(&self, address: IpAddr) -> Vec<String> {
+        // Dual-stack sockets connect to IPv4 addresses through mapped ones, which were resolved
+        // as IPv4.
+        let address = match address {
+            IpAddr::V6(address) => address
+                .to_ipv4_mapped()
+                .map_or(IpAddr::V6(address), IpAddr::V4),
+            address => address,
+        };
+
+        self.hosts
+            .iter()
+            .filter(|(_, addresses)| addresses.contains(&address))
+            .map(|(host, _)| host.clone())
+            .collect()
+    }
+}
+
+/// Records that remote DNS resolved `host` to `addresses`, see [`RESOLVED_HOSTS`].
+pub(crate) fn record_resolution(host: &str, addresses: impl IntoIterator<Item = IpAddr>) {
+    RESOLVED_HOSTS
+        .lock()
+        .unwrap()
+        .insert(host, addresses.into_iter().collect());
+}
+
+/// Where `connect` makes a connection to `address`, according to `outgoing` (the
+/// `feature.network.outgoing` config) and the hosts `address` was resolved from.
+pub(crate) fn outgoing_action(
+    outgoing: Option<&OutgoingConfig>,
+    address: SocketAddr,
+) -> OutgoingAction {
+    outgoing.map_or(OutgoingAction::Remote, |outgoing| {
+        let hosts = RESOLVED_HOSTS.lock().unwrap().hosts_of(address.ip());
+
+        outgoing.action_for(address, &hosts)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use mirrord_config::util::VecOrSingle;
+
+    use super::*;
+
+    #[test]
+    fn connect_local_or_remote() {
+        let outgoing = OutgoingConfig {
+            tcp: true,
+            udp: true,
+            filter: Some(VecOrSingle::Multiple(vec![
+                "local 10.0.0.0/8:5432".parse().unwrap(),
+                "local db.internal".parse().unwrap(),
+            ])),
+        };
+        let action = |address: &str| outgoing_action(Some(&outgoing), address.parse().unwrap());
+
+        assert_eq!(
+            outgoing_action(None, "10.0.0.1:5432".parse().unwrap()),
+            OutgoingAction::Remote
+        );
+        assert_eq!(action("10.0.0.1:5432"), OutgoingAction::Local);
+        assert_eq!(action("10.0.0.1:80"), OutgoingAction::Remote);
+        assert_eq!(action("192.168.7.1:5432"), OutgoingAction::Remote);
+
+        record_resolution("db.internal", ["192.168.7.1".parse().unwrap()]);
+        assert_eq!(action("192.168.7.1:5432"), OutgoingAction::Local);
+        assert_eq!(action("[::ffff:192.168.7.1]:5432"), OutgoingAction::Local);
+
+        // The host moved, its previous address isn't it anymore.
+        record_resolution("db.internal", ["192.168.7.2".parse().unwrap()]);
+        assert_eq!(action("192.168.7.1:5432"), OutgoingAction::Remote);
+        assert_eq!(action("192.168.7.2:5432"), OutgoingAction::Local);
+    }
+
+    #[test]
+    fn resolved_hosts_bounded() {
+        let mut resolved_hosts = ResolvedHosts::default();
+        let address: IpAddr = "10.0.0.1".parse().unwrap();
+
+        resolved_hosts.insert("first.internal", vec![address]);
+        resolved_hosts.insert("second.internal", vec![address]);
+        for index in 0..MAX_RESOLVED_HOSTS - 2 {
+            resolved_hosts.insert(&format!("{index}.internal"), vec![]);
+        }
+        assert_eq!(
+            resolved_hosts.hosts_of(address),
+            ["first.internal", "second.internal"]
+        );
+
+        // Resolving a host again makes it the most recent one.
+        resolved_hosts.insert("first.internal", vec![address]);
+        resolved_hosts.insert("last.internal", vec![]);
+
+        assert_eq!(resolved_hosts.hosts.len(), MAX_RESOLVED_HOSTS);
+        assert_eq!(resolved_hosts.hosts_of(address), ["first.internal"]);
+    }
+}
@@ -0,0 +1,213 @@
+//! Where the connections we make to the user's listeners really come from.
+//!
+//! The source is keyed by the local address we connect from, which is the peer address `accept`
+//! gets for the connection, so whichever process accepts it finds the right one: the process that
+//! bound the listener, a forked worker that inherited it, or a worker with its own `SO_REUSEPORT`
+//! listener (see [`crate::session`]). The table lives in memory shared with forked workers.
+//!
+//! Accepting takes the source out, so a later connection from the same port (that we didn't make)
+//! isn't given it.
+
+use std::{
+    io, mem,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    ptr, slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Local ports per address family.
+const PORTS: usize = u16::MAX as usize + 1;
+
+/// Marks a slot that holds a source, so an empty (zeroed) one is never read as `[::]:0`.
+const OCCUPIED: u64 = 1 << 32;
+const IPV4: u64 = 1 << 16;
+/// Where the port of the listener we connected to is in the tag.
+const LISTENER_PORT_SHIFT: u64 = 40;
+
+#[repr(C)]
+struct Slot {
+    /// [`OCCUPIED`], [`IPV4`], the source port and the listener port, written last.
+    tag: AtomicU64,
+    /// The source address, IPv4 addresses are stored mapped.
+    ip: [AtomicU64; 2],
+}
+
+pub(crate) struct ConnectionSources {
+    /// A slot per local port we may connect from, IPv4 ports then IPv6 ones.
+    slots: &'static [Slot],
+}
+
+impl ConnectionSources {
+    /// Maps the table as shared anonymous memory, which forked workers only get when it's mapped
+    /// before they're forked.
+    pub(crate) fn new() -> io::Result<Self> {
+        let length = 2 * PORTS * mem::size_of::<Slot>();
+
+        let address = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                length,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if address == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Anonymous mappings are zeroed, every slot starts empty. The mapping is never unmapped.
+        let slots = unsafe { slice::from_raw_parts(address.cast::<Slot>(), 2 * PORTS) };
+
+        Ok(Self { slots })
+    }
+
+    fn slot(&self, local_address: SocketAddr) -> &Slot {
+        let family = match local_address {
+            SocketAddr::V4(_) => 0,
+            SocketAddr::V6(_) => 1,
+        };
+
+        &self.slots[family * PORTS + local_address.port() as usize]
+    }
+
+    /// Records `source` as the origin of the connection made from `local_address` to the listener
+    /// on `listener_port`, before it's made.
+    pub(crate) fn insert(&self, local_address: SocketAddr, listener_port: u16, source: SocketAddr) {
+        let (ip, family) = match source.ip() {
+            IpAddr::V4(ip) => (ip.to_ipv6_mapped(), IPV4),
+            IpAddr::V6(ip) => (ip, 0),
+        };
+        let ip = u128::from(ip);
+
+        let slot = self.slot(local_address);
+        slot.ip[0].store((ip >> 64) as u64, Ordering::Relaxed);
+        slot.ip[1].store(ip as u64, Ordering::Relaxed);
+        slot.tag.store(
+            OCCUPIED
+                | u64::from(listener_port) << LISTENER_PORT_SHIFT
+                | family
+                | u64::from(source.port()),
+            Ordering::Release,
+        );
+    }
+
+    /// Forgets the connection made from `local_address`, when it failed.
+    pub(crate) fn remove(&self, local_address: SocketAddr) {
+        self.slot(local_address).tag.store(0, Ordering::Release);
+    }
+
+    /// Takes the source of the connection accepted from `peer_address` on `local_address`, if we
+    /// made it.
+    pub(crate) fn take(
+        &self,
+        peer_address: SocketAddr,
+        local_address: SocketAddr,
+    ) -> Option<SocketAddr> {
+        // Dual-stack listeners see our IPv4 connections as coming from mapped addresses.
+        let peer_address = match peer_address {
+            SocketAddr::V6(address) => match address.ip().to_ipv4_mapped() {
+                Some(ip) => SocketAddr::new(ip.into(), address.port()),
+                None => peer_address,
+            },
+            SocketAddr::V4(_) => peer_address,
+        };
+
+        let slot = self.slot(peer_address);
+        let tag = slot.tag.load(Ordering::Acquire);
+        if tag & OCCUPIED == 0 || (tag >> LISTENER_PORT_SHIFT) as u16 != local_address.port() {
+            return None;
+        }
+
+        let ip = Ipv6Addr::from(
+            (u128::from(slot.ip[0].load(Ordering::Relaxed)) << 64)
+                | u128::from(slot.ip[1].load(Ordering::Relaxed)),
+        );
+        let ip = match ip.to_ipv4_mapped() {
+            Some(ip) if tag & IPV4 != 0 => IpAddr::V4(ip),
+            _ => IpAddr::V6(ip),
+        };
+
+        // Only one of the processes accepting on the port gets it.
+        slot.tag
+            .compare_exchange(tag, 0, Ordering::AcqRel, Ordering::Relaxed)
+            .ok()?;
+
+        Some(SocketAddr::new(ip, tag as u16))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_by_peer_address() {
+        let sources = ConnectionSources::new().unwrap();
+        let local_v4: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let local_v6: SocketAddr = "[::1]:40000".parse().unwrap();
+        let listener_v4: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let listener_v6: SocketAddr = "[::1]:8080".parse().unwrap();
+        let source_v4: SocketAddr = "10.0.0.7:51234".parse().unwrap();
+        let source_v6: SocketAddr = "[fd00::7]:443".parse().unwrap();
+
+        assert_eq!(sources.take(local_v4, listener_v4), None);
+
+        sources.insert(local_v4, 8080, source_v4);
+        sources.insert(local_v6, 8080, source_v6);
+
+        assert_eq!(sources.take(local_v6, listener_v6), Some(source_v6));
+        assert_eq!(
+            sources.take("127.0.0.1:40001".parse().unwrap(), listener_v4),
+            None
+        );
+        assert_eq!(
+            sources.take("[::ffff:127.0.0.1]:40000".parse().unwrap(), listener_v6),
+            Some(source_v4)
+        );
+
+        // Taken already.
+        assert_eq!(sources.take(local_v4, listener_v4), None);
+        assert_eq!(sources.take(local_v6, listener_v6), None);
+    }
+
+    /// Connections from the same port to other listeners, or after it failed, aren't ours.
+    #[test]
+    fn source_for_listener() {
+        let sources = ConnectionSources::new().unwrap();
+        let local: SocketAddr = "127.0.0.1:40003".parse().unwrap();
+        let listener: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let source: SocketAddr = "10.0.0.9:51234".parse().unwrap();
+
+        sources.insert(local, 8080, source);
+        assert_eq!(sources.take(local, "127.0.0.1:9090".parse().unwrap()), None);
+        assert_eq!(sources.take(local, listener), Some(source));
+
+        sources.insert(local, 8080, source);
+        sources.remove(local);
+        assert_eq!(sources.take(local, listener), None);
+    }
+
+    #[test]
+    fn shared_with_forked_processes() {
+        let sources = ConnectionSources::new().unwrap();
+        let local: SocketAddr = "127.0.0.1:40002".parse().unwrap();
+        let listener: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let source: SocketAddr = "10.0.0.8:8080".parse().unwrap();
+
+        match unsafe { libc::fork() } {
+            0 => {
+                sources.insert(local, 8080, source);
+                unsafe { libc::_exit(0) };
+            }
+            -1 => panic!("fork failed with {}", io::Error::last_os_error()),
+            child => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+            }
+        }
+
+        assert_eq!(sources.take(local, listener), Some(source));
+    }
+}
@@ -1,26 +1,24 @@
 /// Tcp Traffic management, common code for stealing & mirroring
 use std::{
     borrow::Borrow,
-    collections::HashSet,
+    collections::{HashMap, HashSet, VecDeque},
     hash::{Hash, Hasher},
+    io::ErrorKind,
     net::SocketAddr,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
-    os::unix::io::RawFd,
+    sync::{LazyLock, Mutex},
 };
 
 use async_trait::async_trait;
 use mirrord_protocol::{
-    tcp::{DaemonTcp, NewTcpConnection, TcpClose, TcpData},
-    ClientCodec, Port,
+    cidr::Cidr,
+    tcp::{DaemonTcp, NewTcpConnection, SniffedFrame, TcpClose, TcpData},
+    ClientCodec, Port, ResponseError,
 };
-use tokio::net::TcpStream;
-use tracing::debug;
+use tokio::net::{TcpSocket, TcpStream};
+use tracing::{debug, error, warn};
 
-use crate::{
-    detour::DetourGuard,
-    error::LayerError,
-    socket::{SocketInformation, CONNECTION_QUEUE},
-};
+use crate::{detour::DetourGuard, error::LayerError, socket::CONNECTION_SOURCES};
 
 #[derive(Debug)]
 pub(crate) enum HookMessageTcp {
@@ -32,7 +30,6 @@ pub(crate) struct Listen {
     pub mirror_port: Port,
     pub requested_port: Port,
     pub ipv6: bool,
-    pub fd: RawFd,
 }
 
 impl PartialEq for Listen {
@@ -68,6 +65,77 @@ impl From<&Listen> for SocketAddr {
     }
 }
 
+/// The sources each port is subscribed with, `feature.network.incoming.source_filter` overridden
+/// by `port_source_filters`. Empty means every source.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SourceFilters {
+    default: Vec<Cidr>,
+    ports: HashMap<Port, Vec<Cidr>>,
+}
+
+impl SourceFilters {
+    pub(crate) fn new(default: Vec<Cidr>, ports: HashMap<Port, Vec<Cidr>>) -> Self {
+        Self { default, ports }
+    }
+
+    pub(crate) fn for_port(&self, port: Port) -> Vec<Cidr> {
+        self.ports.get(&port).unwrap_or(&self.default).clone()
+    }
+}
+
+/// Every listener of each requested port, new connections go to them in turn.
+///
+/// A port has more than one when it's bound again with `SO_REUSEPORT`, or when forked workers
+/// each listen on it (see [`crate::session`]). Only the first one subscribes with the agent.
+static LISTENERS: LazyLock<Mutex<HashMap<Port, VecDeque<Listen>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Adds `listen` to the listeners of its port, returns whether it's the first one.
+fn add_listener(listen: Listen) -> bool {
+    let mut listeners = LISTENERS.lock().unwrap();
+    let port_listeners = listeners.entry(listen.requested_port).or_default();
+    port_listeners.push_back(listen);
+
+    port_listeners.len() == 1
+}
+
+/// The listener of `port` whose turn it is.
+fn next_listener(port: Port) -> Option<Listen> {
+    let mut listeners = LISTENERS.lock().unwrap();
+    let port_listeners = listeners.get_mut(&port)?;
+
+    let listen = port_listeners.pop_front()?;
+    port_listeners.push_back(listen.clone());
+    Some(listen)
+}
+
+fn remove_listener(listen: &Listen) {
+    if let Some(port_listeners) = LISTENERS.lock().unwrap().get_mut(&listen.requested_port) {
+        port_listeners.retain(|listener| listener.mirror_port != listen.mirror_port);
+    }
+}
+
+/// Connects to `listen`, recording where the connection really comes from for `accept`.
+async fn connect_listener(listen: &Listen, source: SocketAddr) -> std::io::Result<TcpStream> {
+    let address: SocketAddr = listen.into();
+
+    let _ = DetourGuard::new();
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }?;
+    socket.bind(SocketAddr::new(address.ip(), 0))?;
+    let local_address = socket.local_addr()?;
+    CONNECTION_SOURCES.insert(local_address, address.port(), source);
+
+    let connected = socket.connect(address).await;
+    if connected.is_err() {
+        CONNECTION_SOURCES.remove(local_address);
+    }
+
+    connected
+}
+
 #[async_trait]
 pub(crate) trait TcpHandler {
     fn ports(&self) -> &HashSet<Listen>;
@@ -87,6 +155,25 @@ pub(crate) trait TcpHandler {
                 debug!("daemon subscribed");
                 Ok(())
             }
+            DaemonTcp::SubscribeFailed(fail) => {
+                error!("daemon failed subscribing with {}", fail);
+
+                // Someone else gets this port's traffic, our filter is invalid, or the agent
+                // couldn't redirect it, the local app won't get any.
+                if let ResponseError::PortAlreadyStolen(port)
+                | ResponseError::InvalidHttpFilter(port, _)
+                | ResponseError::RedirectFailed(port, _) = fail
+                {
+                    self.ports_mut().remove(&port);
+                    LISTENERS.lock().unwrap().remove(&port);
+                }
+
+                Ok(())
+            }
+            DaemonTcp::Frame(frame) => self.handle_frame(frame),
+            DaemonTcp::RemoteData(tcp_data) => self.handle_remote_data(tcp_data).await,
+            DaemonTcp::Shutdown(tcp_close) => self.handle_shutdown(tcp_close).await,
+            DaemonTcp::Reset(tcp_close) => self.handle_reset(tcp_close),
         };
 
         debug!("handle_incoming_message -> handled {:#?}", handled);
@@ -104,44 +191,50 @@ pub(crate) trait TcpHandler {
         >,
     ) -> Result<(), LayerError> {
         match message {
-            HookMessageTcp::Listen(listen) => self.handle_listen(listen, codec).await,
+            HookMessageTcp::Listen(listen) => {
+                // The port is already subscribed, this listener only shares its connections.
+                if !add_listener(listen.clone()) {
+                    debug!(
+                        "handle_hook_message -> another listener for port {}",
+                        listen.requested_port
+                    );
+                    return Ok(());
+                }
+
+                self.handle_listen(listen, codec).await
+            }
         }
     }
 
     /// Handle NewConnection messages
     async fn handle_new_connection(&mut self, conn: NewTcpConnection) -> Result<(), LayerError>;
 
-    /// Connects to the local listening socket, add it to the queue and return the stream.
-    /// Find better name
+    /// Connects to the local listening socket whose turn it is, and returns the stream.
     #[tracing::instrument(level = "trace", skip(self))]
     async fn create_local_stream(
         &mut self,
         tcp_connection: &NewTcpConnection,
     ) -> Result<TcpStream, LayerError> {
         let destination_port = tcp_connection.destination_port;
+        let source = SocketAddr::new(tcp_connection.address, tcp_connection.source_port);
 
-        let listen = self
-            .ports()
-            .get(&destination_port)
-            .ok_or(LayerError::PortNotFound(destination_port))?;
-
-        let addr: SocketAddr = listen.into();
+        // Listeners that are gone (closed, or their worker exited) refuse the connection, it goes
+        // to the next one instead.
+        loop {
+            let listen = next_listener(destination_port)
+                .ok_or(LayerError::PortNotFound(destination_port))?;
 
-        let info = SocketInformation::new(SocketAddr::new(
-            tcp_connection.address,
-            tcp_connection.source_port,
-        ));
-        {
-            CONNECTION_QUEUE.lock().unwrap().add(&listen.fd, info);
+            match connect_listener(&listen, source).await {
+                Err(fail) if fail.kind() == ErrorKind::ConnectionRefused => {
+                    warn!(
+                        "create_local_stream -> listener {:#?} is gone, removing it",
+                        listen
+                    );
+                    remove_listener(&listen);
+                }
+                connected => return connected.map_err(From::from),
+            }
         }
-
-        #[allow(clippy::let_and_return)]
-        let tcp_stream = {
-            let _ = DetourGuard::new();
-            TcpStream::connect(addr).await.map_err(From::from)
-        };
-
-        tcp_stream
     }
 
     /// Handle New Data messages
@@ -150,6 +243,27 @@ pub(crate) trait TcpHandler {
     /// Handle connection close
     fn handle_close(&mut self, close: TcpClose) -> Result<(), LayerError>;
 
+    /// Handle the remote peer being done sending, it may still read what we send.
+    async fn handle_shutdown(&mut self, close: TcpClose) -> Result<(), LayerError> {
+        self.handle_close(close)
+    }
+
+    /// Handle the remote peer resetting the connection.
+    fn handle_reset(&mut self, close: TcpClose) -> Result<(), LayerError> {
+        self.handle_close(close)
+    }
+
+    /// Handle a frame sniffed for our ports, only sent to handlers that asked for them.
+    fn handle_frame(&mut self, _frame: SniffedFrame) -> Result<(), LayerError> {
+        Ok(())
+    }
+
+    /// Handle what the remote service answered on a connection, only sent to handlers that
+    /// asked for it.
+    async fn handle_remote_data(&mut self, _data: TcpData) -> Result<(), LayerError> {
+        Ok(())
+    }
+
     /// Handle listen request
     async fn handle_listen(
         &mut self,
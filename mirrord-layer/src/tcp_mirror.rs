@@ -8,7 +8,9 @@ use std::{
 use async_trait::async_trait;
 use futures::SinkExt;
 use mirrord_protocol::{
-    tcp::{LayerTcp, NewTcpConnection, TcpClose, TcpData},
+    tcp::{
+        LayerTcp, MirrorLimits, MirrorSession, NewTcpConnection, SniffedFrame, TcpClose, TcpData,
+    },
     ClientCodec, ClientMessage, ConnectionId,
 };
 use tokio::{
@@ -23,19 +25,32 @@ use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tracing::{debug, error, info, warn};
 
 use crate::{
+    capture::PcapWriter,
     error::{LayerError, Result},
-    tcp::{Listen, TcpHandler},
+    shadow::{compare_connection, ShadowEvent, ShadowReport},
+    socket::{take_user_shutdown, user_connection},
+    tcp::{Listen, SourceFilters, TcpHandler},
 };
 
-#[tracing::instrument(level = "trace", skip(remote_stream))]
-async fn tcp_tunnel(mut local_stream: TcpStream, remote_stream: Receiver<Vec<u8>>) {
+/// How many [`ShadowEvent`]s of a connection wait for its comparison, before mirroring it waits
+/// too.
+const SHADOW_EVENTS_CAPACITY: usize = 1000;
+
+#[tracing::instrument(level = "trace", skip(remote_stream, shadow))]
+async fn tcp_tunnel(
+    mut local_stream: TcpStream,
+    remote_stream: Receiver<Vec<u8>>,
+    shadow: Option<Sender<ShadowEvent>>,
+) {
     let mut remote_stream = ReceiverStream::new(remote_stream);
     let mut buffer = vec![0; 1024];
     let mut remote_stream_closed = false;
+    let mut local_stream_closed = false;
     loop {
         select! {
-            // Read the application's response from the socket and discard the data, so that the socket doesn't fill up.
-            read = local_stream.read(&mut buffer) => {
+            // Read the application's response from the socket and discard the data (unless it's
+            // shadow compared), so that the socket doesn't fill up.
+            read = local_stream.read(&mut buffer), if !local_stream_closed => {
                 match read {
                     Err(fail) if fail.kind() == std::io::ErrorKind::WouldBlock => {
                         continue;
@@ -44,11 +59,19 @@ async fn tcp_tunnel(mut local_stream: TcpStream, remote_stream: Receiver<Vec<u8>
                         info!("Failed reading local_stream with {:#?}", fail);
                         break;
                     }
+                    // The application is done sending, but the mirrored peer may not be. If it closed
+                    // the socket altogether, the next write fails.
                     Ok(read_amount) if read_amount == 0 => {
-                        warn!("tcp_tunnel -> exiting due to local stream closed!");
-                        break;
+                        debug!("local stream shut down");
+                        take_user_shutdown(user_connection(&local_stream));
+                        local_stream_closed = true;
                     },
-                    Ok(_) => {}
+                    Ok(read_amount) => {
+                        if let Some(shadow) = &shadow {
+                            let event = ShadowEvent::Local(buffer[..read_amount].to_vec());
+                            let _ = shadow.send(event).await;
+                        }
+                    }
                 }
             },
             bytes = remote_stream.next(), if !remote_stream_closed => {
@@ -79,6 +102,8 @@ async fn tcp_tunnel(mut local_stream: TcpStream, remote_stream: Receiver<Vec<u8>
 struct Connection {
     writer: Sender<Vec<u8>>,
     id: ConnectionId,
+    /// Gets the requests and the remote responses, when shadow comparing.
+    shadow: Option<Sender<ShadowEvent>>,
 }
 
 impl Eq for Connection {}
@@ -96,13 +121,24 @@ impl Hash for Connection {
 }
 
 impl Connection {
-    pub fn new(id: ConnectionId, writer: Sender<Vec<u8>>) -> Self {
-        Self { id, writer }
+    pub fn new(
+        id: ConnectionId,
+        writer: Sender<Vec<u8>>,
+        shadow: Option<Sender<ShadowEvent>>,
+    ) -> Self {
+        Self { id, writer, shadow }
     }
 
     pub async fn write(&mut self, data: Vec<u8>) -> Result<()> {
         self.writer.send(data).await.map_err(From::from)
     }
+
+    /// A comparison that stopped (the connection isn't HTTP) doesn't affect the mirroring.
+    pub async fn shadow(&self, event: ShadowEvent) {
+        if let Some(shadow) = &self.shadow {
+            let _ = shadow.send(event).await;
+        }
+    }
 }
 
 impl Borrow<ConnectionId> for Connection {
@@ -116,6 +152,15 @@ impl Borrow<ConnectionId> for Connection {
 pub struct TcpMirrorHandler {
     ports: HashSet<Listen>,
     connections: HashSet<Connection>,
+    /// What we ask the agent for once, see [`Self::start_session`].
+    session: MirrorSession,
+    /// The sources each port is mirrored from.
+    source_filters: SourceFilters,
+    /// When set, the frames the agent sniffs for our ports are saved here.
+    capture: Option<PcapWriter>,
+    /// When set, the local responses are compared with the remote ones and the differences are
+    /// reported here.
+    shadow: Option<ShadowReport>,
 }
 
 #[async_trait]
@@ -127,10 +172,20 @@ impl TcpHandler for TcpMirrorHandler {
 
         let (sender, receiver) = channel::<Vec<u8>>(1000);
 
-        let new_connection = Connection::new(tcp_connection.connection_id, sender);
+        let shadow = self.shadow.clone().map(|report| {
+            let (shadow_sender, shadow_receiver) = channel(SHADOW_EVENTS_CAPACITY);
+            task::spawn(compare_connection(
+                tcp_connection.connection_id,
+                shadow_receiver,
+                report,
+            ));
+            shadow_sender
+        });
+
+        let new_connection = Connection::new(tcp_connection.connection_id, sender, shadow.clone());
         self.connections.insert(new_connection);
 
-        task::spawn(async move { tcp_tunnel(stream, receiver).await });
+        task::spawn(async move { tcp_tunnel(stream, receiver, shadow).await });
 
         Ok(())
     }
@@ -150,6 +205,10 @@ impl TcpHandler for TcpMirrorHandler {
             data.bytes.len(),
             connection.id
         );
+        connection
+            .shadow(ShadowEvent::Request(data.bytes.clone()))
+            .await;
+
         // TODO: Due to the above, if we fail here this connection is leaked (-agent won't be told
         // that we just removed it).
         connection.write(data.bytes).await?;
@@ -172,6 +231,27 @@ impl TcpHandler for TcpMirrorHandler {
             .ok_or(LayerError::NoConnectionId(connection_id))
     }
 
+    /// Failing to save a frame stops the capture, but not the mirroring.
+    fn handle_frame(&mut self, frame: SniffedFrame) -> Result<()> {
+        if let Some(capture) = &mut self.capture {
+            if let Err(fail) = capture.write(&frame) {
+                error!("handle_frame -> failed saving capture with {:#?}", fail);
+                self.capture = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The connection might be gone already, the remote service can answer after we closed it.
+    async fn handle_remote_data(&mut self, data: TcpData) -> Result<()> {
+        if let Some(connection) = self.connections.get(&data.connection_id) {
+            connection.shadow(ShadowEvent::Remote(data.bytes)).await;
+        }
+
+        Ok(())
+    }
+
     fn ports(&self) -> &HashSet<Listen> {
         &self.ports
     }
@@ -197,8 +277,151 @@ impl TcpHandler for TcpMirrorHandler {
             .ok_or(LayerError::ListenAlreadyExists)?;
 
         codec
-            .send(ClientMessage::Tcp(LayerTcp::PortSubscribe(port)))
+            .send(ClientMessage::Tcp(LayerTcp::PortSubscribe(
+                port,
+                self.source_filters.for_port(port),
+            )))
+            .await
+            .map_err(From::from)
+    }
+}
+
+impl TcpMirrorHandler {
+    pub(crate) fn new(
+        limits: Option<MirrorLimits>,
+        source_filters: SourceFilters,
+        capture: Option<PcapWriter>,
+        shadow: Option<ShadowReport>,
+    ) -> Self {
+        let session = MirrorSession {
+            limits,
+            capture_frames: capture.is_some(),
+            shadow_compare: shadow.is_some(),
+        };
+
+        Self {
+            session,
+            source_filters,
+            capture,
+            shadow,
+            ..Default::default()
+        }
+    }
+
+    /// Sends our settings to the agent, before anything gets subscribed. Nothing is sent when
+    /// they're all defaults.
+    pub(crate) async fn start_session(
+        &self,
+        codec: &mut actix_codec::Framed<
+            impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send,
+            ClientCodec,
+        >,
+    ) -> Result<()> {
+        if self.session == MirrorSession::default() {
+            return Ok(());
+        }
+
+        codec
+            .send(ClientMessage::Tcp(LayerTcp::Session(self.session.clone())))
             .await
             .map_err(From::from)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mirrord_protocol::{cidr::Cidr, DaemonCodec};
+    use tokio::{io::duplex, net::TcpListener};
+
+    use super::*;
+
+    /// Only the settings that aren't defaults go to the agent, in a single message.
+    #[tokio::test]
+    async fn session_sent_when_configured() {
+        let (layer, agent) = duplex(1024);
+        let mut codec = actix_codec::Framed::new(layer, ClientCodec::new());
+        let mut agent = actix_codec::Framed::new(agent, DaemonCodec::new());
+
+        TcpMirrorHandler::default()
+            .start_session(&mut codec)
+            .await
+            .unwrap();
+
+        let limits = MirrorLimits {
+            max_connections: Some(2),
+            ..Default::default()
+        };
+        TcpMirrorHandler::new(Some(limits), SourceFilters::default(), None, None)
+            .start_session(&mut codec)
+            .await
+            .unwrap();
+        drop(codec);
+
+        assert_eq!(
+            agent.next().await.unwrap().unwrap(),
+            ClientMessage::Tcp(LayerTcp::Session(MirrorSession {
+                limits: Some(limits),
+                ..Default::default()
+            }))
+        );
+        assert!(agent.next().await.is_none());
+    }
+
+    /// Each port subscribes with its own sources, or `source_filter`'s when it has none.
+    #[tokio::test]
+    async fn port_subscribed_with_sources() {
+        let (layer, agent) = duplex(1024);
+        let mut codec = actix_codec::Framed::new(layer, ClientCodec::new());
+        let mut agent = actix_codec::Framed::new(agent, DaemonCodec::new());
+
+        let default: Cidr = "10.0.0.0/8".parse().unwrap();
+        let internal: Cidr = "192.168.0.0/16".parse().unwrap();
+        let source_filters =
+            SourceFilters::new(vec![default], HashMap::from([(8080, vec![internal])]));
+        let mut handler = TcpMirrorHandler::new(None, source_filters, None, None);
+
+        for port in [80, 8080] {
+            let listen = Listen {
+                mirror_port: port + 1000,
+                requested_port: port,
+                ipv6: false,
+            };
+            handler.handle_listen(listen, &mut codec).await.unwrap();
+        }
+
+        assert_eq!(
+            agent.next().await.unwrap().unwrap(),
+            ClientMessage::Tcp(LayerTcp::PortSubscribe(80, vec![default]))
+        );
+        assert_eq!(
+            agent.next().await.unwrap().unwrap(),
+            ClientMessage::Tcp(LayerTcp::PortSubscribe(8080, vec![internal]))
+        );
+    }
+
+    /// The application shutting down its side doesn't end the mirrored connection.
+    #[tokio::test]
+    async fn local_half_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut application, _) = listener.accept().await.unwrap();
+
+        let (sender, receiver) = channel(8);
+        let tunnel = task::spawn(tcp_tunnel(local_stream, receiver, None));
+
+        application.shutdown().await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+
+        sender.send(b"request".to_vec()).await.unwrap();
+        drop(sender);
+
+        let mut request = Vec::new();
+        application.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+        tunnel.await.unwrap();
+    }
+}
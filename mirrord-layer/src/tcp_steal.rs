@@ -1,31 +1,77 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::ErrorKind,
+    net::SocketAddr,
+    os::unix::io::{AsRawFd, RawFd},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Result;
 use async_trait::async_trait;
 use futures::SinkExt;
 use mirrord_protocol::{
-    tcp::{LayerTcpSteal, NewTcpConnection, TcpClose, TcpData},
+    tcp::{
+        HttpFilter, LayerTcpSteal, NewTcpConnection, StealFallback, StealSession, StealType,
+        TcpClose, TcpData, TlsCertificate,
+    },
+    tls::unverified_client_config,
     ClientCodec, ClientMessage, ConnectionId,
 };
+use socket2::SockRef;
 use streammap_ext::StreamMap;
 use tokio::{
-    io::{AsyncWriteExt, ReadHalf, WriteHalf},
-    net::TcpStream,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{ClientConfig, ServerName},
+    TlsConnector,
 };
 use tokio_stream::StreamExt;
 use tokio_util::io::ReaderStream;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use crate::{
     error::LayerError,
-    tcp::{Listen, TcpHandler},
+    socket::{take_user_shutdown, user_connection},
+    tcp::{Listen, SourceFilters, TcpHandler},
 };
 
+/// Connections to the local app, encrypted when `tls_local` is set.
+trait LocalStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> LocalStream for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+/// How long the TLS handshake with the local app gets.
+const LOCAL_TLS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The socket under a connection to the local app, which may be wrapped in TLS.
+struct LocalSocket {
+    fd: RawFd,
+    /// See [`user_connection`].
+    user_connection: Option<(SocketAddr, SocketAddr)>,
+}
+
 #[derive(Default)]
 pub struct TcpStealHandler {
     ports: HashSet<Listen>,
-    write_streams: HashMap<ConnectionId, WriteHalf<TcpStream>>,
-    read_streams: StreamMap<ConnectionId, ReaderStream<ReadHalf<TcpStream>>>,
+    write_streams: HashMap<ConnectionId, WriteHalf<Box<dyn LocalStream>>>,
+    read_streams: StreamMap<ConnectionId, ReaderStream<ReadHalf<Box<dyn LocalStream>>>>,
+    local_sockets: HashMap<ConnectionId, LocalSocket>,
+    /// Connections the remote peer shut down, we won't write to the local app anymore.
+    remote_shut_down: HashSet<ConnectionId>,
+    /// When set, only HTTP requests matching the filter are stolen.
+    http_filter: Option<HttpFilter>,
+    /// The sources each port is stolen from.
+    source_filters: SourceFilters,
+    /// What we ask the agent for once, see [`Self::start_session`].
+    session: StealSession,
+    /// When set, the connections to the local app are encrypted again, negotiating the protocol
+    /// in [`NewTcpConnection::alpn_protocol`].
+    local_tls: Option<Arc<ClientConfig>>,
+    /// Tells the agent whether new connections reached the local app, sent by [`Self::next`].
+    responses: VecDeque<ClientMessage>,
 }
 
 #[async_trait]
@@ -35,13 +81,30 @@ impl TcpHandler for TcpStealHandler {
         &mut self,
         tcp_connection: NewTcpConnection,
     ) -> Result<(), LayerError> {
-        let stream = self.create_local_stream(&tcp_connection).await?;
+        let connection_id = tcp_connection.connection_id;
 
-        let (read_half, write_half) = tokio::io::split(stream);
-        self.write_streams
-            .insert(tcp_connection.connection_id, write_half);
-        self.read_streams
-            .insert(tcp_connection.connection_id, ReaderStream::new(read_half));
+        // Failing here is expected when the local app isn't listening (yet), so we let the agent
+        // know instead of bringing the layer down.
+        let (stream, local_socket) = match self.connect_local(&tcp_connection).await {
+            Ok(connected) => connected,
+            Err(fail) => {
+                warn!(
+                    "handle_new_connection -> failed connecting {:#?} to the local app with {:#?}",
+                    connection_id, fail
+                );
+                self.responses.push_back(ClientMessage::TcpSteal(
+                    LayerTcpSteal::ConnectionUnsubscribe(connection_id),
+                ));
+
+                return Ok(());
+            }
+        };
+        self.responses
+            .push_back(ClientMessage::TcpSteal(LayerTcpSteal::ConnectionAccepted(
+                connection_id,
+            )));
+
+        self.add_connection(connection_id, stream, local_socket);
 
         Ok(())
     }
@@ -50,19 +113,37 @@ impl TcpHandler for TcpStealHandler {
     async fn handle_new_data(&mut self, data: TcpData) -> Result<(), LayerError> {
         // TODO: "remove -> op -> insert" pattern here, maybe we could improve the overlying
         // abstraction to use something that has mutable access.
-        let mut connection = self
-            .write_streams
-            .remove(&data.connection_id)
-            .ok_or(LayerError::NoConnectionId(data.connection_id))?;
+        // Data for connections we refused may still be on its way.
+        let mut connection = match self.write_streams.remove(&data.connection_id) {
+            Some(connection) => connection,
+            None => {
+                debug!(
+                    "handle_new_data -> dropping data for refused connection {:#?}",
+                    data.connection_id
+                );
+
+                return Ok(());
+            }
+        };
 
         debug!(
             "handle_new_data -> writing {:#?} bytes to id {:#?}",
             data.bytes.len(),
             data.connection_id
         );
-        // TODO: Due to the above, if we fail here this connection is leaked (-agent won't be told
-        // that we just removed it).
-        connection.write_all(&data.bytes[..]).await?;
+        if let Err(fail) = connection.write_all(&data.bytes[..]).await {
+            warn!(
+                "handle_new_data -> failed writing to {:#?} with {:#?}, resetting it",
+                data.connection_id, fail
+            );
+            self.reset_local(data.connection_id);
+            self.responses
+                .push_back(ClientMessage::TcpSteal(LayerTcpSteal::Reset(
+                    data.connection_id,
+                )));
+
+            return Ok(());
+        }
 
         self.write_streams.insert(data.connection_id, connection);
 
@@ -74,8 +155,37 @@ impl TcpHandler for TcpStealHandler {
         let TcpClose { connection_id } = close;
 
         // Dropping the connection -> Sender drops -> Receiver disconnects -> tcp_tunnel ends
-        let _ = self.read_streams.remove(&connection_id);
-        let _ = self.write_streams.remove(&connection_id);
+        self.remove_connection(connection_id);
+
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    async fn handle_shutdown(&mut self, close: TcpClose) -> Result<(), LayerError> {
+        let TcpClose { connection_id } = close;
+
+        // The local app already shut down its side, nothing is left of this connection.
+        if !self.read_streams.contains_key(&connection_id) {
+            self.remove_connection(connection_id);
+            return Ok(());
+        }
+
+        if let Some(write_half) = self.write_streams.get_mut(&connection_id) {
+            if let Err(fail) = write_half.shutdown().await {
+                warn!(
+                    "handle_shutdown -> failed shutting down {:#?} with {:#?}",
+                    connection_id, fail
+                );
+            }
+        }
+        self.remote_shut_down.insert(connection_id);
+
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn handle_reset(&mut self, close: TcpClose) -> Result<(), LayerError> {
+        self.reset_local(close.connection_id);
 
         Ok(())
     }
@@ -104,15 +214,143 @@ impl TcpHandler for TcpStealHandler {
             .then_some(())
             .ok_or(LayerError::ListenAlreadyExists)?;
 
+        let steal_type = match &self.http_filter {
+            Some(filter) => StealType::FilteredHttp(port, filter.clone()),
+            None => StealType::All(port),
+        };
+
         codec
-            .send(ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(port)))
+            .send(ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(
+                steal_type,
+                self.source_filters.for_port(port),
+            )))
             .await
             .map_err(From::from)
     }
 }
 
 impl TcpStealHandler {
+    pub fn new(
+        http_filter: Option<HttpFilter>,
+        fallback: Option<StealFallback>,
+        source_filters: SourceFilters,
+        tls_certificate: Option<TlsCertificate>,
+        tls_local: bool,
+    ) -> Self {
+        // The local app usually serves the remote certificate, which isn't valid for its
+        // address.
+        let local_tls = (tls_certificate.is_some() && tls_local)
+            .then(|| Arc::new(unverified_client_config(Vec::new())));
+
+        Self {
+            http_filter,
+            source_filters,
+            session: StealSession {
+                fallback,
+                tls_termination: tls_certificate,
+            },
+            local_tls,
+            ..Default::default()
+        }
+    }
+
+    /// Sends our settings to the agent, before anything gets subscribed. Nothing is sent when
+    /// they're all defaults.
+    pub(crate) async fn start_session(
+        &self,
+        codec: &mut actix_codec::Framed<
+            impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send,
+            ClientCodec,
+        >,
+    ) -> Result<(), LayerError> {
+        if self.session == StealSession::default() {
+            return Ok(());
+        }
+
+        codec
+            .send(ClientMessage::TcpSteal(LayerTcpSteal::Session(
+                self.session.clone(),
+            )))
+            .await
+            .map_err(From::from)
+    }
+
+    /// [`TcpHandler::create_local_stream`], with the TLS handshake when `tls_local` is set.
+    async fn connect_local(
+        &mut self,
+        tcp_connection: &NewTcpConnection,
+    ) -> Result<(Box<dyn LocalStream>, LocalSocket), LayerError> {
+        let stream = self.create_local_stream(tcp_connection).await?;
+        let local_socket = LocalSocket {
+            fd: stream.as_raw_fd(),
+            user_connection: user_connection(&stream),
+        };
+
+        match &self.local_tls {
+            Some(config) => {
+                let config = match &tcp_connection.alpn_protocol {
+                    Some(alpn_protocol) => {
+                        let mut config = ClientConfig::clone(config);
+                        config.alpn_protocols = vec![alpn_protocol.clone()];
+                        Arc::new(config)
+                    }
+                    None => config.clone(),
+                };
+
+                let server_name = ServerName::IpAddress(stream.peer_addr()?.ip());
+                let stream = timeout(
+                    LOCAL_TLS_TIMEOUT,
+                    TlsConnector::from(config).connect(server_name, stream),
+                )
+                .await
+                .map_err(std::io::Error::from)??;
+                Ok((Box::new(stream), local_socket))
+            }
+            None => Ok((Box::new(stream), local_socket)),
+        }
+    }
+
+    fn add_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        stream: Box<dyn LocalStream>,
+        local_socket: LocalSocket,
+    ) {
+        let (read_half, write_half) = tokio::io::split(stream);
+        self.local_sockets.insert(connection_id, local_socket);
+        self.write_streams.insert(connection_id, write_half);
+        self.read_streams
+            .insert(connection_id, ReaderStream::new(read_half));
+    }
+
+    fn remove_connection(&mut self, connection_id: ConnectionId) {
+        // Dropping the connection -> Sender drops -> Receiver disconnects -> tcp_tunnel ends
+        let _ = self.read_streams.remove(&connection_id);
+        let _ = self.write_streams.remove(&connection_id);
+        self.local_sockets.remove(&connection_id);
+        self.remote_shut_down.remove(&connection_id);
+    }
+
+    /// Closes the connection to the local app with a RST, instead of the usual FIN.
+    fn reset_local(&mut self, connection_id: ConnectionId) {
+        if let Some(local_socket) = self.local_sockets.get(&connection_id) {
+            // The socket is still open, the streams we're about to drop own it.
+            if let Err(fail) = SockRef::from(&local_socket.fd).set_linger(Some(Duration::ZERO)) {
+                warn!(
+                    "reset_local -> failed setting SO_LINGER on {:#?} with {:#?}",
+                    connection_id, fail
+                );
+            }
+        }
+
+        self.remove_connection(connection_id);
+    }
+
     pub async fn next(&mut self) -> Option<ClientMessage> {
+        if let Some(response) = self.responses.pop_front() {
+            return Some(response);
+        }
+
         let (connection_id, value) = self.read_streams.next().await?;
         match value {
             Some(Ok(bytes)) => Some(ClientMessage::TcpSteal(LayerTcpSteal::Data(TcpData {
@@ -120,12 +358,141 @@ impl TcpStealHandler {
                 bytes: bytes.to_vec(),
             }))),
             Some(Err(err)) => {
-                error!("connection id {connection_id:?} read error: {err:?}");
-                None
+                if err.kind() != ErrorKind::ConnectionReset {
+                    error!("connection id {connection_id:?} read error: {err:?}");
+                }
+
+                self.remove_connection(connection_id);
+                Some(ClientMessage::TcpSteal(LayerTcpSteal::Reset(connection_id)))
+            }
+            // The local app only shut down its side, it may still read what the remote peer
+            // sends.
+            None if take_user_shutdown(
+                self.local_sockets
+                    .get(&connection_id)
+                    .and_then(|local_socket| local_socket.user_connection),
+            ) =>
+            {
+                if self.remote_shut_down.contains(&connection_id) {
+                    self.remove_connection(connection_id);
+                }
+
+                Some(ClientMessage::TcpSteal(LayerTcpSteal::Shutdown(
+                    connection_id,
+                )))
+            }
+            None => {
+                self.remove_connection(connection_id);
+                Some(ClientMessage::TcpSteal(
+                    LayerTcpSteal::ConnectionUnsubscribe(connection_id),
+                ))
             }
-            None => Some(ClientMessage::TcpSteal(
-                LayerTcpSteal::ConnectionUnsubscribe(connection_id),
-            )),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::socket::half_close::record_user_shutdown;
+
+    /// Adds connection `connection_id` to `handler`, returning the local app's side of it.
+    async fn connect(handler: &mut TcpStealHandler, connection_id: ConnectionId) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (local_app, _) = listener.accept().await.unwrap();
+
+        let local_socket = LocalSocket {
+            fd: stream.as_raw_fd(),
+            user_connection: user_connection(&stream),
+        };
+        handler.add_connection(connection_id, Box::new(stream), local_socket);
+
+        local_app
+    }
+
+    /// The local app shutting down its side still gets what the remote peer sends.
+    #[tokio::test]
+    async fn local_half_close() {
+        let mut handler = TcpStealHandler::default();
+        let mut local_app = connect(&mut handler, 1).await;
+
+        // What `shutdown_detour` does.
+        record_user_shutdown(local_app.as_raw_fd()).unwrap();
+        local_app.shutdown().await.unwrap();
+
+        assert_eq!(
+            handler.next().await,
+            Some(ClientMessage::TcpSteal(LayerTcpSteal::Shutdown(1)))
+        );
+
+        handler
+            .handle_new_data(TcpData {
+                connection_id: 1,
+                bytes: b"response".to_vec(),
+            })
+            .await
+            .unwrap();
+        handler
+            .handle_shutdown(TcpClose { connection_id: 1 })
+            .await
+            .unwrap();
+
+        let mut response = Vec::new();
+        local_app.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+        assert!(handler.local_sockets.is_empty());
+    }
+
+    /// A local app that closes without shutting down unsubscribes the connection.
+    #[tokio::test]
+    async fn local_close() {
+        let mut handler = TcpStealHandler::default();
+        drop(connect(&mut handler, 1).await);
+
+        assert_eq!(
+            handler.next().await,
+            Some(ClientMessage::TcpSteal(
+                LayerTcpSteal::ConnectionUnsubscribe(1)
+            ))
+        );
+        assert!(handler.local_sockets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn local_reset() {
+        let mut handler = TcpStealHandler::default();
+        let local_app = connect(&mut handler, 1).await;
+
+        local_app.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(local_app);
+
+        assert_eq!(
+            handler.next().await,
+            Some(ClientMessage::TcpSteal(LayerTcpSteal::Reset(1)))
+        );
+        assert!(handler.local_sockets.is_empty());
+    }
+
+    /// The remote peer resetting resets the local app's connection too.
+    #[tokio::test]
+    async fn remote_reset() {
+        let mut handler = TcpStealHandler::default();
+        let mut local_app = connect(&mut handler, 1).await;
+
+        handler.handle_reset(TcpClose { connection_id: 1 }).unwrap();
+
+        let mut buffer = [0; 8];
+        assert_eq!(
+            local_app.read(&mut buffer).await.unwrap_err().kind(),
+            ErrorKind::ConnectionReset
+        );
+    }
+}
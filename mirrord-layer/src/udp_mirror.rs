@@ -0,0 +1,274 @@
+//! Incoming Udp traffic mirroring.
+//!
+//! The agent sends every datagram it sniffs on a port the user process bound to, we send it to
+//! the (local) bound socket from a socket of our own per original sender. `recvfrom` then reports
+//! the original sender address, see [`MIRRORED_DATAGRAM_SOURCES`].
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use futures::SinkExt;
+use mirrord_protocol::{
+    udp::{DaemonUdp, LayerUdp, UdpDatagram},
+    ClientCodec, ClientMessage, Port,
+};
+use tokio::net::UdpSocket;
+use tracing::{debug, trace};
+
+use crate::{
+    error::{LayerError, Result},
+    socket::MIRRORED_DATAGRAM_SOURCES,
+};
+
+/// Senders that didn't deliver anything for this long are closed.
+const SENDER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// We never keep more senders than this, the least recently used one is closed to make room.
+const MAX_SENDERS: usize = 1024;
+
+#[derive(Debug)]
+pub(crate) enum HookMessageUdp {
+    Bind(UdpBind),
+    /// The user closed the socket, we stop mirroring its port.
+    Close(UdpBind),
+}
+
+/// A Udp socket was bound to `requested_port`, the layer actually bound it to `mirror_address`.
+#[derive(Debug, Clone)]
+pub(crate) struct UdpBind {
+    pub mirror_address: SocketAddr,
+    pub requested_port: Port,
+}
+
+/// Handles incoming Udp mirroring.
+#[derive(Default)]
+pub(crate) struct UdpMirrorHandler {
+    /// Where the datagrams of each subscribed port are delivered.
+    ports: HashMap<Port, SocketAddr>,
+    /// Sockets we deliver datagrams from, by original sender address.
+    senders: HashMap<SocketAddr, Sender>,
+}
+
+struct Sender {
+    socket: UdpSocket,
+    last_used: Instant,
+}
+
+/// The socket might be bound to the unspecified address, we deliver on localhost.
+fn delivery_address(mirror_address: SocketAddr) -> SocketAddr {
+    match mirror_address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), mirror_address.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), mirror_address.port())
+        }
+        _ => mirror_address,
+    }
+}
+
+impl UdpMirrorHandler {
+    #[tracing::instrument(level = "trace", skip(self, codec))]
+    pub(crate) async fn handle_hook_message(
+        &mut self,
+        message: HookMessageUdp,
+        codec: &mut actix_codec::Framed<
+            impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send,
+            ClientCodec,
+        >,
+    ) -> Result<()> {
+        match message {
+            HookMessageUdp::Bind(UdpBind {
+                mirror_address,
+                requested_port,
+            }) => {
+                // Binding the same port again (after closing the previous socket) only moves
+                // where we deliver.
+                self.ports
+                    .insert(requested_port, delivery_address(mirror_address));
+
+                codec
+                    .send(ClientMessage::Udp(LayerUdp::PortSubscribe(requested_port)))
+                    .await
+                    .map_err(From::from)
+            }
+            HookMessageUdp::Close(UdpBind {
+                mirror_address,
+                requested_port,
+            }) => {
+                // The port may already belong to a newer socket.
+                if self.ports.get(&requested_port) != Some(&delivery_address(mirror_address)) {
+                    return Ok(());
+                }
+
+                self.ports.remove(&requested_port);
+
+                codec
+                    .send(ClientMessage::Udp(LayerUdp::PortUnsubscribe(
+                        requested_port,
+                    )))
+                    .await
+                    .map_err(From::from)
+            }
+        }
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub(crate) async fn handle_daemon_message(&mut self, message: DaemonUdp) -> Result<()> {
+        match message {
+            DaemonUdp::Datagram(datagram) => self.handle_datagram(datagram).await,
+            DaemonUdp::Subscribed => {
+                debug!("daemon subscribed udp");
+                Ok(())
+            }
+        }
+    }
+
+    async fn handle_datagram(&mut self, datagram: UdpDatagram) -> Result<()> {
+        let UdpDatagram {
+            source,
+            destination_port,
+            bytes,
+        } = datagram;
+
+        let mirror_address = *self
+            .ports
+            .get(&destination_port)
+            .ok_or(LayerError::PortNotFound(destination_port))?;
+
+        let now = Instant::now();
+        if !self.senders.contains_key(&source) {
+            self.evict_senders(now);
+
+            let local_address = match mirror_address {
+                SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+                SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0),
+            };
+            let sender = UdpSocket::bind(local_address).await?;
+
+            MIRRORED_DATAGRAM_SOURCES
+                .lock()
+                .unwrap()
+                .insert(sender.local_addr()?, source);
+
+            self.senders.insert(
+                source,
+                Sender {
+                    socket: sender,
+                    last_used: now,
+                },
+            );
+        }
+        let sender = self.senders.get_mut(&source).unwrap();
+        sender.last_used = now;
+
+        trace!(
+            "handle_datagram -> {} bytes from {source:?} to {mirror_address:?}",
+            bytes.len()
+        );
+        sender.socket.send_to(&bytes, mirror_address).await?;
+
+        Ok(())
+    }
+
+    /// Closes the senders that have been idle for [`SENDER_IDLE_TIMEOUT`], and the least recently
+    /// used one if we'd still go over [`MAX_SENDERS`].
+    fn evict_senders(&mut self, now: Instant) {
+        let mut evicted = Vec::new();
+
+        self.senders.retain(|_, sender| {
+            let idle = now.duration_since(sender.last_used) >= SENDER_IDLE_TIMEOUT;
+            if idle {
+                evicted.push(sender.socket.local_addr());
+            }
+            !idle
+        });
+
+        if self.senders.len() >= MAX_SENDERS {
+            let least_recently_used = self
+                .senders
+                .iter()
+                .min_by_key(|(_, sender)| sender.last_used)
+                .map(|(source, _)| *source);
+
+            if let Some(sender) =
+                least_recently_used.and_then(|source| self.senders.remove(&source))
+            {
+                evicted.push(sender.socket.local_addr());
+            }
+        }
+
+        let mut sources = MIRRORED_DATAGRAM_SOURCES.lock().unwrap();
+        for local_address in evicted.into_iter().flatten() {
+            sources.remove(&local_address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn delivers_datagrams_per_source() {
+        let bound = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut handler = UdpMirrorHandler::default();
+        handler.ports.insert(8125, bound.local_addr().unwrap());
+
+        let sources: [SocketAddr; 2] = [
+            "10.0.0.1:4000".parse().unwrap(),
+            "10.0.0.2:4000".parse().unwrap(),
+        ];
+        for (source, bytes) in sources.iter().zip([b"first", b"other"]) {
+            handler
+                .handle_datagram(UdpDatagram {
+                    source: *source,
+                    destination_port: 8125,
+                    bytes: bytes.to_vec(),
+                })
+                .await
+                .unwrap();
+
+            let mut buffer = [0; 16];
+            let (amount, received_from) = bound.recv_from(&mut buffer).await.unwrap();
+
+            assert_eq!(&buffer[..amount], bytes);
+            assert_eq!(
+                MIRRORED_DATAGRAM_SOURCES
+                    .lock()
+                    .unwrap()
+                    .get(&received_from),
+                Some(source)
+            );
+        }
+
+        assert_eq!(handler.senders.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn evicts_idle_senders() {
+        let bound = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut handler = UdpMirrorHandler::default();
+        handler.ports.insert(8126, bound.local_addr().unwrap());
+
+        handler
+            .handle_datagram(UdpDatagram {
+                source: "10.0.0.3:4000".parse().unwrap(),
+                destination_port: 8126,
+                bytes: b"idle".to_vec(),
+            })
+            .await
+            .unwrap();
+        let (_, received_from) = bound.recv_from(&mut [0; 16]).await.unwrap();
+
+        handler.evict_senders(Instant::now() + SENDER_IDLE_TIMEOUT);
+
+        assert!(handler.senders.is_empty());
+        assert!(!MIRRORED_DATAGRAM_SOURCES
+            .lock()
+            .unwrap()
+            .contains_key(&received_from));
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use bincode::{Decode, Encode};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// A range of addresses in CIDR notation (`"10.0.0.0/8"`), or a single address (`"10.0.0.1"`).
+#[derive(Encode, Decode, Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    pub address: IpAddr,
+    /// Longer prefixes than the address family has are treated as a single address.
+    pub prefix_len: u8,
+}
+
+#[derive(Error, Debug)]
+#[error("could not parse CIDR `{0}`, values must be an IP address or `address/prefix`")]
+pub struct CidrParseError(String);
+
+impl Cidr {
+    pub fn new(address: IpAddr, prefix_len: u8) -> Self {
+        Cidr {
+            address,
+            prefix_len,
+        }
+    }
+
+    /// The first address of the range, `address` without its host bits (tools like libpcap
+    /// reject ranges that have them set).
+    pub fn network(&self) -> IpAddr {
+        match self.address {
+            IpAddr::V4(address) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len.min(32)))
+                    .unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(address) & mask))
+            }
+            IpAddr::V6(address) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len.min(128)))
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(address) & mask))
+            }
+        }
+    }
+
+    /// Whether `address` is in the range, IPv4 addresses are never in IPv6 ranges (or the other
+    /// way around).
+    pub fn contains(&self, address: IpAddr) -> bool {
+        address.is_ipv4() == self.address.is_ipv4()
+            && Cidr::new(address, self.prefix_len).network() == self.network()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let max_len = if self.address.is_ipv4() { 32 } else { 128 };
+        write!(f, "{}/{}", self.network(), self.prefix_len.min(max_len))
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let error = || CidrParseError(val.to_owned());
+
+        let (address, prefix_len) = match val.trim().split_once('/') {
+            Some((address, prefix_len)) => (
+                address.parse::<IpAddr>().map_err(|_| error())?,
+                Some(prefix_len.parse::<u8>().map_err(|_| error())?),
+            ),
+            None => (val.trim().parse::<IpAddr>().map_err(|_| error())?, None),
+        };
+
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        match prefix_len {
+            Some(prefix_len) if prefix_len > max_len => Err(error()),
+            prefix_len => Ok(Cidr::new(address, prefix_len.unwrap_or(max_len))),
+        }
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = CidrParseError;
+
+    fn try_from(val: String) -> Result<Self, Self::Error> {
+        val.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cidr() {
+        let cidr = Cidr::new("10.1.2.3".parse().unwrap(), 8);
+
+        assert_eq!(cidr.to_string(), "10.0.0.0/8");
+        assert!(cidr.contains("10.200.0.1".parse().unwrap()));
+        assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::a01:203".parse().unwrap()));
+        assert!(!cidr.contains("::ffff:10.0.0.1".parse().unwrap()));
+
+        let cidr = Cidr::new("fd00::1".parse().unwrap(), 16);
+        assert_eq!(cidr.to_string(), "fd00::/16");
+        assert!(cidr.contains("fd00:1::1".parse().unwrap()));
+        assert!(!cidr.contains("fd01::1".parse().unwrap()));
+
+        assert!(Cidr::new("0.0.0.0".parse().unwrap(), 0).contains("8.8.8.8".parse().unwrap()));
+        assert!(Cidr::new("::".parse().unwrap(), 0).contains("fd00::1".parse().unwrap()));
+        assert_eq!(
+            Cidr::new("10.1.2.3".parse().unwrap(), 40).to_string(),
+            "10.1.2.3/32"
+        );
+    }
+
+    #[test]
+    fn parse_cidr() {
+        assert_eq!(
+            "10.8.0.0/16".parse::<Cidr>().unwrap(),
+            Cidr::new("10.8.0.0".parse().unwrap(), 16)
+        );
+        assert_eq!(
+            " 10.9.1.4 ".parse::<Cidr>().unwrap(),
+            Cidr::new("10.9.1.4".parse().unwrap(), 32)
+        );
+        assert_eq!(
+            "fd00::1".parse::<Cidr>().unwrap(),
+            Cidr::new("fd00::1".parse().unwrap(), 128)
+        );
+
+        for invalid in ["10.0.0.0/33", "10.0.0/8", "fd00::/129", "10.0.0.0/"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -15,7 +15,8 @@ use crate::{
         tcp::{DaemonTcpOutgoing, LayerTcpOutgoing},
         udp::{DaemonUdpOutgoing, LayerUdpOutgoing},
     },
-    tcp::{DaemonTcp, LayerTcp, LayerTcpSteal},
+    tcp::{DaemonTcp, LayerTcp, LayerTcpSteal, TcpRecord},
+    udp::{DaemonUdp, LayerUdp},
     ResponseError,
 };
 
@@ -189,6 +190,7 @@ pub enum ClientMessage {
     TcpSteal(LayerTcpSteal),
     TcpOutgoing(LayerTcpOutgoing),
     UdpOutgoing(LayerUdpOutgoing),
+    Udp(LayerUdp),
     FileRequest(FileRequest),
     GetEnvVarsRequest(GetEnvVarsRequest),
     Ping,
@@ -306,6 +308,7 @@ pub enum DaemonMessage {
     TcpSteal(DaemonTcp),
     TcpOutgoing(DaemonTcpOutgoing),
     UdpOutgoing(DaemonUdpOutgoing),
+    Udp(DaemonUdp),
     LogMessage(LogMessage),
     File(FileResponse),
     Pong,
@@ -415,12 +418,67 @@ impl Encoder<DaemonMessage> for DaemonCodec {
     }
 }
 
+/// Reads and writes the [`TcpRecord`]s of a recording file, one after the other.
+pub struct RecordingCodec {
+    config: bincode::config::Configuration,
+}
+
+impl RecordingCodec {
+    pub fn new() -> Self {
+        RecordingCodec {
+            config: bincode::config::standard(),
+        }
+    }
+}
+
+impl Default for RecordingCodec {
+    fn default() -> Self {
+        RecordingCodec::new()
+    }
+}
+
+impl Decoder for RecordingCodec {
+    type Item = TcpRecord;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        match bincode::decode_from_slice(&src[..], self.config) {
+            Ok((record, read)) => {
+                src.advance(read);
+                Ok(Some(record))
+            }
+            Err(DecodeError::UnexpectedEnd) => Ok(None),
+            Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+        }
+    }
+}
+
+impl Encoder<TcpRecord> for RecordingCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, record: TcpRecord, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let encoded = match bincode::encode_to_vec(record, self.config) {
+            Ok(encoded) => encoded,
+            Err(err) => {
+                return Err(io::Error::new(io::ErrorKind::Other, err.to_string()));
+            }
+        };
+        dst.reserve(encoded.len());
+        dst.put(&encoded[..]);
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use bytes::BytesMut;
 
     use super::*;
-    use crate::tcp::TcpData;
+    use crate::{
+        tcp::{HttpFilter, StealType, TcpClose, TcpData, TcpRecordOrigin},
+        udp::UdpDatagram,
+    };
 
     #[test]
     fn sanity_client_encode_decode() {
@@ -428,7 +486,7 @@ mod tests {
         let mut daemon_codec = DaemonCodec::new();
         let mut buf = BytesMut::new();
 
-        let msg = ClientMessage::Tcp(LayerTcp::PortSubscribe(1));
+        let msg = ClientMessage::Tcp(LayerTcp::PortSubscribe(1, vec![]));
 
         client_codec.encode(msg.clone(), &mut buf).unwrap();
 
@@ -438,6 +496,51 @@ mod tests {
         assert!(buf.is_empty());
     }
 
+    #[test]
+    fn steal_filtered_http_encode_decode() {
+        let mut client_codec = ClientCodec::new();
+        let mut daemon_codec = DaemonCodec::new();
+        let mut buf = BytesMut::new();
+
+        let msg = ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(
+            StealType::FilteredHttp(
+                80,
+                HttpFilter {
+                    header: Some("x-mirrord-user: alice".to_owned()),
+                    path: None,
+                },
+            ),
+            vec!["10.0.0.0/8".parse().unwrap()],
+        ));
+
+        client_codec.encode(msg.clone(), &mut buf).unwrap();
+
+        let decoded = daemon_codec.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(decoded, msg);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn udp_datagram_encode_decode() {
+        let mut client_codec = ClientCodec::new();
+        let mut daemon_codec = DaemonCodec::new();
+        let mut buf = BytesMut::new();
+
+        let msg = DaemonMessage::Udp(DaemonUdp::Datagram(UdpDatagram {
+            source: "10.0.0.1:4000".parse().unwrap(),
+            destination_port: 8125,
+            bytes: b"requests:1|c".to_vec(),
+        }));
+
+        daemon_codec.encode(msg.clone(), &mut buf).unwrap();
+
+        let decoded = client_codec.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(decoded, msg);
+        assert!(buf.is_empty());
+    }
+
     #[test]
     fn sanity_daemon_encode_decode() {
         let mut client_codec = ClientCodec::new();
@@ -457,6 +560,36 @@ mod tests {
         assert!(buf.is_empty());
     }
 
+    #[test]
+    fn recording_encode_decode() {
+        let mut codec = RecordingCodec::new();
+        let mut buf = BytesMut::new();
+
+        let records = vec![
+            TcpRecord {
+                elapsed_us: 0,
+                origin: TcpRecordOrigin::Mirror,
+                message: DaemonTcp::Data(TcpData {
+                    connection_id: 1,
+                    bytes: b"GET / HTTP/1.1\r\n\r\n".to_vec(),
+                }),
+            },
+            TcpRecord {
+                elapsed_us: 1500,
+                origin: TcpRecordOrigin::Steal,
+                message: DaemonTcp::Close(TcpClose { connection_id: 1 }),
+            },
+        ];
+
+        for record in records.clone() {
+            codec.encode(record, &mut buf).unwrap();
+        }
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(records[0].clone()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(records[1].clone()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
     #[test]
     fn decode_client_invalid_data() {
         let mut codec = ClientCodec::new();
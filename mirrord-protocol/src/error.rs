@@ -6,6 +6,8 @@ use std::{
 use bincode::{Decode, Encode};
 use thiserror::Error;
 
+use crate::Port;
+
 #[derive(Encode, Decode, Debug, PartialEq, Clone, Eq, Error)]
 pub enum ResponseError {
     #[error("Index allocator is full, operation `{0}` failed!")]
@@ -28,6 +30,18 @@ pub enum ResponseError {
 
     #[error("Remote operation failed with `{0}`")]
     Remote(#[from] RemoteError),
+
+    #[error("Port `{0}` is already being stolen by another mirrord client!")]
+    PortAlreadyStolen(Port),
+
+    #[error("Failed loading the TLS certificate to steal with: `{0}`")]
+    InvalidTlsCertificate(String),
+
+    #[error("Invalid HTTP filter for port `{0}`: `{1}`")]
+    InvalidHttpFilter(Port, String),
+
+    #[error("Failed redirecting port `{0}` to the agent: `{1}`")]
+    RedirectFailed(Port, String),
 }
 
 #[derive(Encode, Decode, Debug, PartialEq, Clone, Eq, Error)]
@@ -1,10 +1,14 @@
 #![feature(const_trait_impl)]
 #![feature(io_error_more)]
 
+pub mod cidr;
 pub mod codec;
 pub mod error;
 pub mod outgoing;
 pub mod tcp;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod udp;
 
 use std::{collections::HashSet, ops::Deref};
 
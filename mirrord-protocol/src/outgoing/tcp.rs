@@ -6,6 +6,10 @@ pub enum LayerTcpOutgoing {
     Connect(LayerConnect),
     Write(LayerWrite),
     Close(LayerClose),
+    /// `user` is done sending (`shutdown(SHUT_WR)`), but still reads.
+    Shutdown(LayerClose),
+    /// `user` reset the connection.
+    Reset(LayerClose),
 }
 
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
@@ -13,4 +17,8 @@ pub enum DaemonTcpOutgoing {
     Connect(RemoteResult<DaemonConnect>),
     Read(RemoteResult<DaemonRead>),
     Close(ConnectionId),
+    /// The remote host is done sending, but still reads.
+    Shutdown(ConnectionId),
+    /// The remote host reset the connection.
+    Reset(ConnectionId),
 }
@@ -1,6 +1,5 @@
 use crate::types::*;
 
-
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
 pub enum StealClientMessage {
     PortSteal(Port),
@@ -13,4 +12,4 @@ pub enum StealDaemonMessage {
     NewConnection(NewTcpConnection),
     TCPData(TCPData),
     TCPClose(TCPClose),
-}
\ No newline at end of file
+}
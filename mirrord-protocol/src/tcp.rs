@@ -2,7 +2,7 @@ use std::{fmt, net::IpAddr};
 
 use bincode::{Decode, Encode};
 
-use crate::{ConnectionId, Port};
+use crate::{cidr::Cidr, ConnectionId, Port, ResponseError};
 
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
 pub struct NewTcpConnection {
@@ -10,6 +10,9 @@ pub struct NewTcpConnection {
     pub address: IpAddr,
     pub destination_port: Port,
     pub source_port: Port,
+    /// The protocol the agent negotiated with ALPN when it terminated TLS on the connection, for
+    /// the layer to negotiate with the local app.
+    pub alpn_protocol: Option<Vec<u8>>,
 }
 
 #[derive(Encode, Decode, PartialEq, Eq, Clone)]
@@ -35,9 +38,36 @@ pub struct TcpClose {
 /// Messages related to Tcp handler from client.
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
 pub enum LayerTcp {
-    PortSubscribe(Port),
+    /// Mirrors the port, only the connections from these sources when the list isn't empty.
+    PortSubscribe(Port, Vec<Cidr>),
     ConnectionUnsubscribe(ConnectionId),
     PortUnsubscribe(Port),
+    /// The client's mirror settings, sent once before it subscribes to anything.
+    Session(MirrorSession),
+}
+
+/// Settings that apply to every port a client mirrors, see [`LayerTcp::Session`].
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone, Default)]
+pub struct MirrorSession {
+    pub limits: Option<MirrorLimits>,
+    /// Sends the client every frame the sniffer captures for its ports (TCP and UDP), as
+    /// [`DaemonTcp::Frame`].
+    pub capture_frames: bool,
+    /// Also sends the client what the remote service answers on its mirrored connections, as
+    /// [`DaemonTcp::RemoteData`].
+    pub shadow_compare: bool,
+}
+
+/// Caps what gets mirrored to a client, checked whenever a new connection arrives. Connections
+/// that don't make it aren't mirrored at all (they keep going to the remote pod).
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct MirrorLimits {
+    /// Percentage (0-100) of new connections that get mirrored.
+    pub sample_percent: Option<u8>,
+    /// How many connections are mirrored at once.
+    pub max_connections: Option<u32>,
+    /// New connections aren't mirrored while the mirrored bytes of the last second are over this.
+    pub bytes_per_second: Option<u64>,
 }
 
 /// Messages related to Tcp handler from server.
@@ -49,13 +79,139 @@ pub enum DaemonTcp {
     /// Used to notify the subscription occured, needed for e2e tests to remove sleeps and
     /// flakiness.
     Subscribed,
+    /// The port couldn't be subscribed to, e.g. another client is already stealing it.
+    SubscribeFailed(ResponseError),
+    /// See [`MirrorSession::capture_frames`].
+    Frame(SniffedFrame),
+    /// Bytes the remote service sent back on a mirrored connection, see
+    /// [`MirrorSession::shadow_compare`].
+    RemoteData(TcpData),
+    /// The remote peer of a stolen connection is done sending (`shutdown(SHUT_WR)`), it may
+    /// still read.
+    Shutdown(TcpClose),
+    /// The remote peer of a stolen connection reset it.
+    Reset(TcpClose),
+}
+
+/// A [`DaemonTcp`] message as the layer recorded it, see `feature.network.incoming.record_file`.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct TcpRecord {
+    /// Microseconds since the recording started.
+    pub elapsed_us: u64,
+    pub origin: TcpRecordOrigin,
+    pub message: DaemonTcp,
+}
+
+/// Whether a [`TcpRecord`] came from the sniffer or the stealer, their connection ids overlap.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum TcpRecordOrigin {
+    Mirror,
+    Steal,
+}
+
+/// A frame the sniffer captured, as Ethernet (the ones of other link types get an Ethernet header
+/// with zeroed addresses).
+#[derive(Encode, Decode, PartialEq, Eq, Clone)]
+pub struct SniffedFrame {
+    /// When it was captured, in microseconds since the Unix epoch.
+    pub timestamp_us: u64,
+    pub bytes: Vec<u8>,
+}
+
+impl fmt::Debug for SniffedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SniffedFrame")
+            .field("timestamp_us", &self.timestamp_us)
+            .field("bytes (length)", &self.bytes.len())
+            .finish()
+    }
+}
+
+/// Selects which HTTP requests are stolen from a port, a request has to match every filter that
+/// is set. Filters are regexes.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone, Default)]
+pub struct HttpFilter {
+    /// Matched against every header line, formatted as `name: value`.
+    pub header: Option<String>,
+    /// Matched against the request path.
+    pub path: Option<String>,
+}
+
+/// Describes what gets stolen from a port.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub enum StealType {
+    /// Every connection to the port.
+    All(Port),
+    /// Only HTTP requests matching the filter, the rest keep going to the original destination.
+    FilteredHttp(Port, HttpFilter),
+}
+
+impl StealType {
+    pub fn port(&self) -> Port {
+        match self {
+            StealType::All(port) | StealType::FilteredHttp(port, _) => *port,
+        }
+    }
+}
+
+/// Stolen connections that the layer refuses, or doesn't accept within `accept_timeout_ms`, are
+/// forwarded to their original destination instead of being closed. So are connections to ports
+/// that were just unsubscribed.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct StealFallback {
+    pub accept_timeout_ms: u64,
 }
 
 /// Messages related to Steal Tcp handler from client.
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
 pub enum LayerTcpSteal {
-    PortSubscribe(Port),
+    /// Steals the port, only the connections from these sources when the list isn't empty.
+    PortSubscribe(StealType, Vec<Cidr>),
     ConnectionUnsubscribe(ConnectionId),
     PortUnsubscribe(Port),
     Data(TcpData),
+    /// The stolen connection reached the local app.
+    ConnectionAccepted(ConnectionId),
+    /// The local app is done sending on a stolen connection, the agent shuts down its side
+    /// towards the remote peer.
+    Shutdown(ConnectionId),
+    /// The local app reset a stolen connection, the agent resets it too.
+    Reset(ConnectionId),
+    /// The client's steal settings, sent once before it subscribes to anything.
+    Session(StealSession),
+}
+
+/// Settings that apply to every port a client steals, see [`LayerTcpSteal::Session`].
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone, Default)]
+pub struct StealSession {
+    /// Enables forwarding stolen connections to their original destination.
+    pub fallback: Option<StealFallback>,
+    /// Stolen connections are TLS, the agent terminates it with this certificate and steals the
+    /// plaintext.
+    pub tls_termination: Option<TlsCertificate>,
+}
+
+/// Certificate chain and private key (both PEM) the agent terminates stolen TLS connections with.
+#[derive(Encode, Decode, PartialEq, Eq, Clone)]
+pub enum TlsCertificate {
+    /// Files in the target container.
+    ContainerPath { certificate: String, key: String },
+    /// The contents of the files, e.g. from a Kubernetes Secret.
+    Pem { certificate: Vec<u8>, key: Vec<u8> },
+}
+
+impl fmt::Debug for TlsCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsCertificate::ContainerPath { certificate, key } => f
+                .debug_struct("ContainerPath")
+                .field("certificate", certificate)
+                .field("key", key)
+                .finish(),
+            TlsCertificate::Pem { certificate, .. } => f
+                .debug_struct("Pem")
+                .field("certificate (length)", &certificate.len())
+                .finish_non_exhaustive(),
+        }
+    }
 }
@@ -0,0 +1,39 @@
+//! What the agent and the layer share to encrypt connections they terminated TLS on again,
+//! behind the `tls` feature.
+
+use std::{sync::Arc, time::SystemTime};
+
+use tokio_rustls::rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, Error, ServerName,
+};
+
+/// Accepts any certificate. The servers we encrypt again for (the original destination, or the
+/// local app) serve the certificate we terminated with, which usually isn't valid for the address
+/// we connect to.
+pub struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// A client config offering `alpn_protocols`, that trusts any server, see [`NoVerification`].
+pub fn unverified_client_config(alpn_protocols: Vec<Vec<u8>>) -> ClientConfig {
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(NoVerification))
+        .with_no_client_auth();
+    config.alpn_protocols = alpn_protocols;
+
+    config
+}
@@ -0,0 +1,38 @@
+use std::{fmt, net::SocketAddr};
+
+use bincode::{Decode, Encode};
+
+use crate::Port;
+
+/// A datagram sent to a subscribed port, `source` is the address of the original sender.
+#[derive(Encode, Decode, PartialEq, Eq, Clone)]
+pub struct UdpDatagram {
+    pub source: SocketAddr,
+    pub destination_port: Port,
+    pub bytes: Vec<u8>,
+}
+
+impl fmt::Debug for UdpDatagram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UdpDatagram")
+            .field("source", &self.source)
+            .field("destination_port", &self.destination_port)
+            .field("bytes (length)", &self.bytes.len())
+            .finish()
+    }
+}
+
+/// Messages related to incoming Udp from client.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub enum LayerUdp {
+    PortSubscribe(Port),
+    PortUnsubscribe(Port),
+}
+
+/// Messages related to incoming Udp from server.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub enum DaemonUdp {
+    Datagram(UdpDatagram),
+    /// Used to notify the subscription occured.
+    Subscribed,
+}